@@ -0,0 +1,8 @@
+// Copyright 2022 Oxide Computer Company
+
+use progenitor::generate_api;
+
+generate_api!(
+    spec = "../openapi/downstairs-admin.json",
+    derives = [schemars::JsonSchema],
+);
@@ -34,5 +34,10 @@ if [ "$val" != '""' ]; then
         args+=( "$val" )
 fi
 
+val="$(svcprop -c -p config/admin "${SMF_FMRI}")"
+if [ "$val" = 'true' ]; then
+        args+=( '--admin' )
+fi
+
 exec /opt/oxide/crucible/bin/crucible-downstairs run "${args[@]}"
 
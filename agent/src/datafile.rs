@@ -2,7 +2,7 @@
 
 use super::model::*;
 use anyhow::{anyhow, bail, Result};
-use crucible_common::write_json;
+use crucible_common::{write_json, ADMIN_PORT_OFFSET, REPAIR_PORT_OFFSET};
 use serde::{Deserialize, Serialize};
 use slog::{crit, error, info, Logger};
 use std::collections::BTreeMap;
@@ -116,6 +116,16 @@ impl DataFile {
 
     fn get_free_port(&self, inner: &MutexGuard<Inner>) -> Result<u16> {
         for port_number in self.port_min..=self.port_max {
+            /*
+             * The downstairs needs the repair and admin ports that go
+             * with this one as well.
+             */
+            if port_number.checked_add(REPAIR_PORT_OFFSET).is_none()
+                || port_number.checked_add(ADMIN_PORT_OFFSET).is_none()
+            {
+                break;
+            }
+
             let mut region_uses_port = false;
             let mut running_snapshot_uses_port = false;
 
@@ -130,7 +140,7 @@ impl DataFile {
                     continue;
                 }
 
-                if port_number == region.port_number {
+                if ports_collide(port_number, region.port_number) {
                     region_uses_port = true;
                     break;
                 }
@@ -148,7 +158,8 @@ impl DataFile {
                         continue;
                     }
 
-                    if port_number == running_snapshot.port_number {
+                    if ports_collide(port_number, running_snapshot.port_number)
+                    {
                         running_snapshot_uses_port = true;
                         break 'outer;
                     }
@@ -465,6 +476,35 @@ impl DataFile {
         Ok(())
     }
 
+    /**
+     * Record that a region now has more extents.  The downstairs serving
+     * it has already made them.
+     */
+    pub fn extended(&self, id: &RegionId, extent_count: u64) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut r = match inner.regions.get_mut(id) {
+            Some(r) => r,
+            None => bail!("region {:?} not found", id),
+        };
+        match &r.state {
+            State::Created => (),
+            x => bail!("extended region in weird state {:?}", x),
+        }
+
+        info!(
+            self.log,
+            "region {} extent count: {} -> {}",
+            r.id.0,
+            r.extent_count,
+            extent_count,
+        );
+        r.extent_count = extent_count;
+
+        self.store(inner);
+        Ok(())
+    }
+
     /**
      * Mark a particular region as destroyed.
      */
@@ -697,12 +737,58 @@ impl DataFile {
     }
 }
 
+/*
+ * The ports a downstairs listening on this one uses: its own, and the ones
+ * its repair server and admin API listen on.
+ */
+fn downstairs_ports(port: u16) -> Vec<u16> {
+    [
+        Some(port),
+        port.checked_add(REPAIR_PORT_OFFSET),
+        port.checked_add(ADMIN_PORT_OFFSET),
+    ]
+    .iter()
+    .flatten()
+    .copied()
+    .collect()
+}
+
+/*
+ * Whether downstairs listening on these two ports would want any of the
+ * same ports.
+ */
+fn ports_collide(a: u16, b: u16) -> bool {
+    let b = downstairs_ports(b);
+    downstairs_ports(a).iter().any(|p| b.contains(p))
+}
+
 #[cfg(test)]
 mod test {
+    use super::{ports_collide, ADMIN_PORT_OFFSET, REPAIR_PORT_OFFSET};
     use anyhow::{bail, Result};
     use chrono::{DateTime, TimeZone, Utc};
     use std::process::Command;
 
+    #[test]
+    fn test_ports_collide() {
+        assert!(ports_collide(4000, 4000));
+        assert!(!ports_collide(4000, 4001));
+
+        // One downstairs' repair or admin port is another's port.
+        assert!(ports_collide(4000, 4000 + REPAIR_PORT_OFFSET));
+        assert!(ports_collide(4000 + ADMIN_PORT_OFFSET, 4000));
+
+        // Two downstairs' repair and admin ports are the same.
+        assert!(ports_collide(
+            4000 + ADMIN_PORT_OFFSET,
+            4000 + REPAIR_PORT_OFFSET
+        ));
+        assert!(ports_collide(
+            4000,
+            4000 + REPAIR_PORT_OFFSET - ADMIN_PORT_OFFSET
+        ));
+    }
+
     #[test]
     fn test_stat_parsing() -> Result<()> {
         // Test round trip
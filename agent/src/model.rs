@@ -1,8 +1,10 @@
 // Copyright 2021 Oxide Computer Company
 
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::Path;
 
 use chrono::prelude::*;
+use crucible_common::ADMIN_PORT_OFFSET;
 use crucible_smf::scf_type_t::{self, *};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -63,6 +65,11 @@ impl Region {
                 typ: SCF_TYPE_COUNT,
                 val: self.port_number.to_string(),
             },
+            SmfProperty {
+                name: "admin",
+                typ: SCF_TYPE_BOOLEAN,
+                val: "true".to_string(),
+            },
         ];
 
         if self.cert_pem.is_some() {
@@ -103,6 +110,17 @@ impl Region {
 
         results
     }
+
+    /**
+     * Where the downstairs for this region serves its admin API.  Nobody
+     * but us should be using that, so it only listens on loopback.
+     */
+    pub fn admin_address(&self) -> SocketAddr {
+        SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            self.port_number + ADMIN_PORT_OFFSET,
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -120,6 +138,11 @@ pub struct CreateRegion {
     // TODO base64 encoded der too?
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct ExtendRegion {
+    pub extent_count: u64,
+}
+
 impl CreateRegion {
     pub fn mismatch(&self, r: &Region) -> Option<String> {
         if self.block_size != r.block_size {
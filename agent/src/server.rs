@@ -13,6 +13,7 @@ use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::result::Result as SResult;
 use std::sync::Arc;
+use uuid::Uuid;
 
 trait AnyhowFromString<T> {
     fn or_bail(self, msg: &str) -> Result<T>;
@@ -110,6 +111,93 @@ async fn region_delete(
     }
 }
 
+/**
+ * Grow a region to the given number of extents while its downstairs is
+ * running.  Any upstairs connected to it finds out about the new size.
+ */
+#[endpoint {
+    method = POST,
+    path = "/crucible/0/regions/{id}/extend",
+}]
+async fn region_extend(
+    rc: Arc<RequestContext<Arc<DataFile>>>,
+    path: TypedPath<RegionPath>,
+    body: TypedBody<model::ExtendRegion>,
+) -> SResult<HttpResponseOk<model::Region>, HttpError> {
+    let p = path.into_inner();
+    let extent_count = body.into_inner().extent_count;
+
+    let r = match rc.context().get(&p.id) {
+        Some(r) => r,
+        None => {
+            return Err(HttpError::for_not_found(
+                None,
+                format!("region {:?} not found", p.id),
+            ));
+        }
+    };
+
+    if r.state != model::State::Created {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("region {:?} is {:?}, not created", p.id, r.state),
+        ));
+    }
+
+    if extent_count < r.extent_count || extent_count > u32::MAX as u64 {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!(
+                "can not extend region {:?} from {} to {} extents",
+                p.id, r.extent_count, extent_count
+            ),
+        ));
+    }
+
+    let uuid = match Uuid::parse_str(&p.id.0) {
+        Ok(uuid) => uuid,
+        Err(e) => {
+            return Err(HttpError::for_bad_request(
+                None,
+                format!("region {:?} is not a UUID: {}", p.id, e),
+            ));
+        }
+    };
+
+    /*
+     * The downstairs does the work, through the admin API it serves just
+     * for us.
+     */
+    let admin =
+        admin_client::Client::new(&format!("http://{}", r.admin_address()));
+    let extended = admin
+        .extend_region(
+            &uuid,
+            &admin_client::types::ExtendRegion {
+                extent_count: extent_count as u32,
+            },
+        )
+        .await;
+    if let Err(e) = extended {
+        return Err(HttpError::for_internal_error(format!(
+            "region extend failure: {:?}",
+            e
+        )));
+    }
+
+    if let Err(e) = rc.context().extended(&p.id, extent_count) {
+        return Err(HttpError::for_internal_error(e.to_string()));
+    }
+
+    match rc.context().get(&p.id) {
+        Some(r) => Ok(HttpResponseOk(r)),
+        None => Err(HttpError::for_not_found(
+            None,
+            format!("region {:?} not found", p.id),
+        )),
+    }
+}
+
 #[derive(Serialize, JsonSchema)]
 pub struct GetSnapshotResponse {
     snapshots: Vec<model::Snapshot>,
@@ -360,6 +448,8 @@ pub fn make_api() -> Result<dropshot::ApiDescription<Arc<DataFile>>> {
     api.register(region_get).or_bail("registration failure")?;
     api.register(region_delete)
         .or_bail("registration failure")?;
+    api.register(region_extend)
+        .or_bail("registration failure")?;
 
     api.register(region_get_snapshots)
         .or_bail("registration failure")?;
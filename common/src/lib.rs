@@ -12,13 +12,19 @@ use tempfile::NamedTempFile;
 
 mod region;
 pub use region::{
-    Block, RegionDefinition, RegionOptions, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
+    Block, MetadataBackend, RegionDefinition, RegionLayout, RegionOptions,
+    MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
 };
 
 pub mod x509;
 
 pub const REPAIR_PORT_OFFSET: u16 = 4000;
 
+/*
+ * A downstairs serves its admin API on loopback, at its port plus this.
+ */
+pub const ADMIN_PORT_OFFSET: u16 = 2000;
+
 #[derive(thiserror::Error, Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum CrucibleError {
     #[error("Error: {0}")]
@@ -110,6 +116,9 @@ pub enum CrucibleError {
 
     #[error("Generation number is too low: {0}")]
     GenerationNumberTooLow(String),
+
+    #[error("Compare failed, blocks do not hold the expected data")]
+    CompareMismatch,
 }
 
 impl From<std::io::Error> for CrucibleError {
@@ -100,6 +100,100 @@ impl Block {
     }
 }
 
+/**
+ * Where a downstairs keeps the metadata for each extent: the generation
+ * and flush numbers, the dirty bit, and the hashes and encryption
+ * contexts of the blocks written since the last flush.
+ */
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataBackend {
+    /**
+     * A SQLite database next to each extent file.
+     */
+    Sqlite,
+    /**
+     * A file next to each extent file with a fixed size slot for each
+     * block.
+     */
+    Sidecar,
+}
+
+impl Default for MetadataBackend {
+    fn default() -> Self {
+        MetadataBackend::Sqlite
+    }
+}
+
+impl std::str::FromStr for MetadataBackend {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "sqlite" => MetadataBackend::Sqlite,
+            "sidecar" => MetadataBackend::Sidecar,
+            _ => {
+                bail!("not a valid metadata backend: {}", s);
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for MetadataBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetadataBackend::Sqlite => write!(f, "sqlite"),
+            MetadataBackend::Sidecar => write!(f, "sidecar"),
+        }
+    }
+}
+
+/**
+ * How a downstairs lays out the files for a region.
+ */
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionLayout {
+    /**
+     * A data file, plus its metadata files, for each extent, in a
+     * directory hierarchy under the region directory.
+     */
+    PerExtent,
+    /**
+     * One data file holding every extent back to back, and one metadata
+     * file holding a sidecar for each extent.  Both are preallocated to
+     * the size of the region.
+     */
+    SingleFile,
+}
+
+impl Default for RegionLayout {
+    fn default() -> Self {
+        RegionLayout::PerExtent
+    }
+}
+
+impl std::str::FromStr for RegionLayout {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().replace('_', "-").as_str() {
+            "per-extent" => RegionLayout::PerExtent,
+            "single-file" => RegionLayout::SingleFile,
+            _ => {
+                bail!("not a valid region layout: {}", s);
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for RegionLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegionLayout::PerExtent => write!(f, "per-extent"),
+            RegionLayout::SingleFile => write!(f, "single-file"),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq)]
 pub struct RegionDefinition {
     /**
@@ -126,6 +220,22 @@ pub struct RegionDefinition {
      * region data will be encrypted
      */
     encrypted: bool,
+
+    /**
+     * How new extents keep their metadata.  Only the downstairs cares,
+     * so this never goes over the wire.  The downstairs keeps it in
+     * region.json itself.
+     */
+    #[serde(skip)]
+    metadata_backend: MetadataBackend,
+
+    /**
+     * How the files for this region are laid out.  Like the metadata
+     * backend, this is the downstairs' business alone and never goes
+     * over the wire.
+     */
+    #[serde(skip)]
+    layout: RegionLayout,
 }
 
 impl RegionDefinition {
@@ -137,6 +247,8 @@ impl RegionDefinition {
             extent_count: 0,
             uuid: opts.uuid,
             encrypted: opts.encrypted,
+            metadata_backend: opts.metadata_backend,
+            layout: opts.layout,
         })
     }
 
@@ -179,6 +291,22 @@ impl RegionDefinition {
     pub fn get_encrypted(&self) -> bool {
         self.encrypted
     }
+
+    pub fn metadata_backend(&self) -> MetadataBackend {
+        self.metadata_backend
+    }
+
+    pub fn set_metadata_backend(&mut self, backend: MetadataBackend) {
+        self.metadata_backend = backend;
+    }
+
+    pub fn layout(&self) -> RegionLayout {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: RegionLayout) {
+        self.layout = layout;
+    }
 }
 
 /**
@@ -193,6 +321,8 @@ impl Default for RegionDefinition {
             extent_count: 0,
             uuid: Uuid::nil(),
             encrypted: false,
+            metadata_backend: MetadataBackend::Sqlite,
+            layout: RegionLayout::PerExtent,
         }
     }
 }
@@ -218,6 +348,18 @@ pub struct RegionOptions {
      * region data will be encrypted
      */
     encrypted: bool,
+
+    /**
+     * How extents keep their metadata
+     */
+    #[serde(default)]
+    metadata_backend: MetadataBackend,
+
+    /**
+     * How the region files are laid out
+     */
+    #[serde(default)]
+    layout: RegionLayout,
 }
 
 impl RegionOptions {
@@ -252,6 +394,21 @@ impl RegionOptions {
             );
         }
 
+        /*
+         * There is no sharing a SQLite database between extents, so a
+         * single file region keeps its metadata in sidecars.
+         */
+        if self.layout == RegionLayout::SingleFile
+            && self.metadata_backend != MetadataBackend::Sidecar
+        {
+            bail!(
+                "a {} region needs {} metadata, not {}",
+                self.layout,
+                MetadataBackend::Sidecar,
+                self.metadata_backend,
+            );
+        }
+
         Ok(())
     }
 
@@ -270,6 +427,14 @@ impl RegionOptions {
     pub fn set_encrypted(&mut self, encrypted: bool) {
         self.encrypted = encrypted;
     }
+
+    pub fn set_metadata_backend(&mut self, backend: MetadataBackend) {
+        self.metadata_backend = backend;
+    }
+
+    pub fn set_layout(&mut self, layout: RegionLayout) {
+        self.layout = layout;
+    }
 }
 
 impl Default for RegionOptions {
@@ -281,6 +446,8 @@ impl Default for RegionOptions {
             extent_size: Block::new(100, 9),
             uuid: Uuid::nil(),
             encrypted: false,
+            metadata_backend: MetadataBackend::Sqlite,
+            layout: RegionLayout::PerExtent,
         }
     }
 }
@@ -3,6 +3,7 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use uuid::Uuid;
 #[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
@@ -43,6 +44,7 @@ pub struct CrucibleOpts {
     pub root_cert_pem: Option<String>,
     pub control: Option<SocketAddr>,
     pub read_only: bool,
+    pub capture_dir: Option<PathBuf>,
 }
 
 impl CrucibleOpts {
@@ -234,6 +234,25 @@ fn cmd_read<T: BlockIO>(opt: &Opt, crucible: Arc<T>) -> Result<()> {
     Ok(())
 }
 
+// Write a block-aligned buffer to the region. When the buffer is all zeros,
+// ask for a write zeroes instead so we don't ship the zeros around.
+fn write_or_zero<T: BlockIO>(
+    crucible: &Arc<T>,
+    offset: Block,
+    data: Bytes,
+) -> Result<BlockReqWaiter, CrucibleError> {
+    if !data.is_empty() && data.iter().all(|x| *x == 0) {
+        let block_size = crucible.get_block_size()?;
+        let len = Block::new(
+            data.len() as u64 / block_size,
+            block_size.trailing_zeros(),
+        );
+        crucible.write_zeroes(offset, len)
+    } else {
+        crucible.write(offset, data)
+    }
+}
+
 fn cmd_write<T: BlockIO>(opt: &Opt, crucible: Arc<T>) -> Result<()> {
     // A lot of this is going to be repeat from cmd_read, but it is
     // subtly different to handle things like read-modify-write for partial
@@ -351,7 +370,7 @@ fn cmd_write<T: BlockIO>(opt: &Opt, crucible: Arc<T>) -> Result<()> {
         if uflow_remainder == 0 {
             // no need to RMW, just write
             w_buf.resize(n_read, 0);
-            let w_waiter = crucible.write(offset, w_buf.freeze())?;
+            let w_waiter = write_or_zero(&crucible, offset, w_buf.freeze())?;
             waiters.push_back(w_waiter);
         } else {
             // RMW oof
@@ -422,7 +441,7 @@ fn cmd_write<T: BlockIO>(opt: &Opt, crucible: Arc<T>) -> Result<()> {
         } else {
             eprintln!("writing full iocmd");
             // good to go for a write
-            let w_waiter = crucible.write(offset, w_buf.freeze())?;
+            let w_waiter = write_or_zero(&crucible, offset, w_buf.freeze())?;
             waiters.push_back(w_waiter);
         }
 
@@ -164,6 +164,11 @@ pub struct Opt {
     #[clap(long, global = true, action)]
     flush_timeout: Option<u32>,
 
+    /// Record every message exchanged with each downstairs to a capture
+    /// file in this directory.
+    #[clap(long, global = true, name = "CAPTURE_DIR", action)]
+    capture_dir: Option<PathBuf>,
+
     /// IP:Port for the Oximeter register address, which is Nexus.
     #[clap(long, global = true, default_value = "127.0.0.1:12221", action)]
     metric_register: SocketAddr,
@@ -467,6 +472,7 @@ fn main() -> Result<()> {
         root_cert_pem: opt.root_cert_pem,
         control: opt.control,
         read_only: false,
+        capture_dir: opt.capture_dir,
     };
 
     /*
@@ -3,6 +3,7 @@ use super::*;
 
 use dropshot::{
     endpoint, ApiDescription, ConfigDropshot, HttpError, HttpResponseCreated,
+    HttpResponseDeleted, HttpResponseOk, HttpResponseUpdatedNoContent,
     HttpServerStarter, Path, RequestContext, TypedBody,
 };
 use schemars::JsonSchema;
@@ -25,6 +26,8 @@ pub struct RunDownstairsForRegionParams {
     key_pem: Option<String>,
     root_cert_pem: Option<String>,
     read_only: bool,
+    /// Blocks a second to scrub the region at, if at all.
+    scrub_rate: Option<u64>,
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -64,9 +67,16 @@ pub async fn run_downstairs_for_region(
         run_params.lossy,
         run_params.return_errors,
         run_params.read_only,
+        Compression::None,
+        None,
+        IoBackend::Std,
     )
     .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
 
+    if let Some(scrub_rate) = run_params.scrub_rate.filter(|r| *r > 0) {
+        tokio::spawn(scrub_region(d.clone(), scrub_rate));
+    }
+
     let dd = d.clone();
     tokio::spawn(async move {
         // XXX result eaten here!
@@ -87,17 +97,170 @@ pub async fn run_downstairs_for_region(
     Ok(HttpResponseCreated(DownstairsRunningResponse { uuid }))
 }
 
+/**
+ * What the scrubber of a running downstairs has found so far.
+ */
+#[endpoint {
+    method = GET,
+    path = "/regions/{uuid}/scrub"
+}]
+pub async fn get_scrub_report(
+    rqctx: Arc<RequestContext<Arc<ServerContext>>>,
+    path_param: Path<RunDownstairsforRegionPath>,
+) -> Result<HttpResponseOk<ScrubReport>, HttpError> {
+    let apictx = rqctx.context();
+    let uuid = path_param.into_inner().uuid;
+
+    let downstairs = apictx.downstairs.lock().await;
+    let d = downstairs.get(&uuid).ok_or_else(|| {
+        HttpError::for_not_found(
+            None,
+            format!("downstairs {} not running", uuid),
+        )
+    })?;
+
+    let report = d.lock().await.scrub_report();
+    Ok(HttpResponseOk(report))
+}
+
+/*
+ * Find the running downstairs for a region.
+ */
+async fn running_downstairs(
+    apictx: &ServerContext,
+    uuid: Uuid,
+) -> Result<Arc<Mutex<Downstairs>>, HttpError> {
+    let downstairs = apictx.downstairs.lock().await;
+    downstairs.get(&uuid).cloned().ok_or_else(|| {
+        HttpError::for_not_found(
+            None,
+            format!("downstairs {} not running", uuid),
+        )
+    })
+}
+
+/**
+ * The faults a running downstairs is injecting, and how many jobs each
+ * has applied to and been injected into so far.
+ */
+#[endpoint {
+    method = GET,
+    path = "/regions/{uuid}/faults"
+}]
+pub async fn get_faults(
+    rqctx: Arc<RequestContext<Arc<ServerContext>>>,
+    path_param: Path<RunDownstairsforRegionPath>,
+) -> Result<HttpResponseOk<Vec<FaultReport>>, HttpError> {
+    let uuid = path_param.into_inner().uuid;
+    let d = running_downstairs(rqctx.context(), uuid).await?;
+
+    let report = d.lock().await.fault_report();
+    Ok(HttpResponseOk(report))
+}
+
+/**
+ * Replace the faults a running downstairs injects into its jobs.
+ */
+#[endpoint {
+    method = PUT,
+    path = "/regions/{uuid}/faults"
+}]
+pub async fn put_faults(
+    rqctx: Arc<RequestContext<Arc<ServerContext>>>,
+    path_param: Path<RunDownstairsforRegionPath>,
+    faults: TypedBody<Vec<Fault>>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let uuid = path_param.into_inner().uuid;
+    let d = running_downstairs(rqctx.context(), uuid).await?;
+
+    d.lock().await.set_faults(faults.into_inner());
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+/**
+ * Stop injecting faults into a running downstairs' jobs.
+ */
+#[endpoint {
+    method = DELETE,
+    path = "/regions/{uuid}/faults"
+}]
+pub async fn delete_faults(
+    rqctx: Arc<RequestContext<Arc<ServerContext>>>,
+    path_param: Path<RunDownstairsforRegionPath>,
+) -> Result<HttpResponseDeleted, HttpError> {
+    let uuid = path_param.into_inner().uuid;
+    let d = running_downstairs(rqctx.context(), uuid).await?;
+
+    d.lock().await.set_faults(Vec::new());
+    Ok(HttpResponseDeleted())
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ExtendRegion {
+    extent_count: u32,
+}
+
+/**
+ * Grow a running downstairs' region to the given number of extents.
+ *
+ * The new extents are created and opened while the downstairs keeps
+ * serving the region, and any connected upstairs that supports it is
+ * told the new size.  The region can not shrink.
+ */
+#[endpoint {
+    method = POST,
+    path = "/regions/{uuid}/extend"
+}]
+pub async fn extend_region(
+    rqctx: Arc<RequestContext<Arc<ServerContext>>>,
+    path_param: Path<RunDownstairsforRegionPath>,
+    body: TypedBody<ExtendRegion>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let uuid = path_param.into_inner().uuid;
+    let extent_count = body.into_inner().extent_count;
+    let d = running_downstairs(rqctx.context(), uuid).await?;
+
+    let d = d.lock().await;
+    d.extend_region(extent_count).await.map_err(|e| {
+        HttpError::for_bad_request(
+            None,
+            format!("extend to {} extents: {:#}", extent_count, e),
+        )
+    })?;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
 fn register_endpoints(
     api_description: &mut ApiDescription<Arc<ServerContext>>,
 ) -> Result<(), String> {
     api_description.register(run_downstairs_for_region)?;
+    api_description.register(get_scrub_report)?;
+    api_description.register(get_faults)?;
+    api_description.register(put_faults)?;
+    api_description.register(delete_faults)?;
+    api_description.register(extend_region)?;
 
     Ok(())
 }
 
+pub fn write_openapi<W: Write>(f: &mut W) -> Result<()> {
+    let mut api = ApiDescription::<Arc<ServerContext>>::new();
+    if let Err(s) = register_endpoints(&mut api) {
+        anyhow::bail!("Error from register_endpoints: {}", s);
+    }
+    api.openapi("Downstairs Admin", "0.0.0").write(f)?;
+    Ok(())
+}
+
+/*
+ * Serve the admin API.  The given downstairs are running already, and the
+ * API can be used to look after them as though it had started them.
+ */
 pub async fn run_dropshot(
     bind_address: SocketAddr,
     log: &slog::Logger,
+    downstairs: HashMap<Uuid, Arc<Mutex<Downstairs>>>,
 ) -> Result<()> {
     let config = ConfigDropshot {
         bind_address,
@@ -111,7 +274,7 @@ pub async fn run_dropshot(
     }
 
     let ctx = Arc::new(ServerContext {
-        downstairs: Mutex::new(HashMap::default()),
+        downstairs: Mutex::new(downstairs),
     });
 
     let http_server =
@@ -127,3 +290,29 @@ pub async fn run_dropshot(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openapiv3::OpenAPI;
+
+    #[test]
+    fn test_crucible_admin_openapi() {
+        let mut raw = Vec::new();
+        write_openapi(&mut raw).unwrap();
+        let actual = String::from_utf8(raw).unwrap();
+
+        // Make sure the result parses as a valid OpenAPI spec.
+        let spec = serde_json::from_str::<OpenAPI>(&actual)
+            .expect("output was not valid OpenAPI");
+
+        // Check for lint errors.
+        let errors = openapi_lint::validate(&spec);
+        assert!(errors.is_empty(), "{}", errors.join("\n\n"));
+
+        expectorate::assert_contents(
+            "../openapi/downstairs-admin.json",
+            &actual,
+        );
+    }
+}
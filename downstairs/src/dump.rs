@@ -1,6 +1,7 @@
 // Copyright 2021 Oxide Computer Company
 use super::*;
 use crate::region::ExtentMeta;
+use crucible_protocol::SpaceUsage;
 use std::convert::TryInto;
 
 use sha2::{Digest, Sha256};
@@ -8,6 +9,10 @@ use sha2::{Digest, Sha256};
 #[derive(Debug, Default)]
 struct ExtInfo {
     ei_hm: HashMap<u32, ExtentMeta>,
+    /*
+     * Written blocks in this extent, with the same index as ei_hm.
+     */
+    ei_allocated: HashMap<u32, u64>,
 }
 
 /*
@@ -16,7 +21,8 @@ struct ExtInfo {
  * If a specific extent is requested, only dump info on that extent. If a
  * specific block offset is supplied, show details for only that block.
  *
- * If you don't want color, then set nc to true.
+ * If you don't want color, then set nc to true.  If you want something a
+ * program can read instead of the tables, set json to true.
  */
 pub fn dump_region(
     region_dir: Vec<PathBuf>,
@@ -24,6 +30,7 @@ pub fn dump_region(
     block: Option<u64>,
     only_show_differences: bool,
     nc: bool,
+    json: bool,
 ) -> Result<()> {
     if cmp_extent.is_some() && block.is_some() {
         bail!("Either a specific block, or a specific extent, not both");
@@ -40,6 +47,7 @@ pub fn dump_region(
     let dir_count = region_dir.len();
     let mut blocks_per_extent = 0;
     let mut total_extents = 0;
+    let mut usage = Vec::with_capacity(dir_count);
 
     assert!(!region_dir.is_empty());
     for (index, dir) in region_dir.iter().enumerate() {
@@ -50,6 +58,8 @@ pub fn dump_region(
         total_extents = region.def().extent_count();
 
         let max_block = total_extents as u64 * blocks_per_extent;
+        usage.push(region.space_usage()?);
+
         /*
          * The extent number is the index in the overall hashmap.
          * For each entry in all_extents hashmap, we have an ExtInfo
@@ -108,6 +118,8 @@ pub fn dump_region(
              */
             let ei = all_extents.entry(en).or_default();
             ei.ei_hm.insert(index as u32, extent_info);
+            ei.ei_allocated
+                .insert(index as u32, inner.written_blocks().unwrap());
         }
     }
 
@@ -132,6 +144,22 @@ pub fn dump_region(
 
         let en = all_extents.get(&ce).unwrap();
 
+        if json {
+            let report = extent_report(
+                &region_dir,
+                en,
+                ce,
+                block,
+                blocks_per_extent,
+                only_show_differences,
+            )?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if block.is_none() && report.summary.blocks_different > 0 {
+                bail!("Difference found!");
+            }
+            return Ok(());
+        }
+
         /*
          * If we only want details about one block, show that
          */
@@ -158,6 +186,21 @@ pub fn dump_region(
         return Ok(());
     };
 
+    if json {
+        let report = region_report(
+            &region_dir,
+            &all_extents,
+            &usage,
+            blocks_per_extent,
+            only_show_differences,
+        );
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if report.extents_different > 0 {
+            bail!("Difference in extent metadata found!");
+        }
+        return Ok(());
+    }
+
     /*
      * Print out the extent info one extent at a time, in order
      */
@@ -177,6 +220,8 @@ pub fn dump_region(
     // once we start the loop.
     let mut gen_width = 4;
     let mut fl_width = 3;
+    // No extent has more written blocks than it has blocks.
+    let al_width = std::cmp::max(3, blocks_per_extent.to_string().len() + 1);
 
     // If our extent is invalid, then there is some other problem,
     // but, whomever is using this tool is probably trying to figure
@@ -259,6 +304,11 @@ pub fn dump_region(
                     print!(" D{}", i);
                 }
 
+                print!(" ");
+                for i in 0..dir_count {
+                    print!(" {:>0width$}{}", "AL", i, width = (al_width - 1));
+                }
+
                 if nc {
                     print!(" DIFF");
                 }
@@ -312,6 +362,18 @@ pub fn dump_region(
                     print!("  {}F", sgr(32, nc));
                 }
             }
+            // Clear color
+            print!("{} ", sgr(0, nc));
+
+            // Written blocks.  These are not compared, as a block written
+            // with zeros and one never written read back the same.
+            for i in 0..dir_count {
+                print!(
+                    " {:>width$}",
+                    ei.ei_allocated.get(&(i as u32)).unwrap(),
+                    width = al_width
+                );
+            }
             if nc && different {
                 println!(" <---");
             } else {
@@ -327,6 +389,19 @@ pub fn dump_region(
     }
 
     println!("Max gen: {},  Max flush: {}", max_gen, max_flush);
+
+    for (i, (dir, usage)) in region_dir.iter().zip(usage.iter()).enumerate() {
+        println!(
+            "Region {} {:?}: {} of {} bytes allocated ({:.1}%), {} on disk",
+            i,
+            dir,
+            usage.allocated_bytes,
+            usage.logical_bytes,
+            100.0 * usage.allocated_bytes as f64
+                / std::cmp::max(1, usage.logical_bytes) as f64,
+            usage.physical_bytes,
+        );
+    }
     if difference_found {
         bail!("Difference in extent metadata found!");
     }
@@ -334,6 +409,281 @@ pub fn dump_region(
     Ok(())
 }
 
+/*
+ * What --json prints.  These mirror the tables, with a value in the
+ * regions list for each region directory, in the order they were given.
+ * A missing extent in one of the directories shows up as null.
+ */
+#[derive(Debug, Serialize)]
+struct RegionReport {
+    regions: Vec<RegionUsageReport>,
+    extents: Vec<ExtentMetaReport>,
+    max_gen: u64,
+    max_flush: u64,
+    extents_different: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct RegionUsageReport {
+    dir: PathBuf,
+    logical_bytes: u64,
+    allocated_bytes: u64,
+    physical_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ExtentMetaReport {
+    extent: u32,
+    first_block: u64,
+    last_block: u64,
+    regions: Vec<Option<ExtentRegionMeta>>,
+    different: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct ExtentRegionMeta {
+    gen: u64,
+    flush: u64,
+    dirty: bool,
+    written_blocks: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ExtentReport {
+    extent: u32,
+    regions: Vec<Option<ExtentRegionMeta>>,
+    blocks: Vec<BlockReport>,
+    summary: MismatchSummary,
+}
+
+#[derive(Debug, Serialize)]
+struct BlockReport {
+    block: u64,
+    regions: Vec<BlockRegionReport>,
+    data_different: bool,
+    encryption_contexts_different: bool,
+    hashes_different: bool,
+}
+
+/*
+ * Hashes and nonces are hex, the same as the tables show them.
+ */
+#[derive(Debug, Serialize)]
+struct BlockRegionReport {
+    data_sha256: String,
+    hashes: Vec<String>,
+    encryption_contexts: Vec<EncryptionContextReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct EncryptionContextReport {
+    nonce: String,
+    tag: String,
+}
+
+/*
+ * Counts of the blocks compared, and of the blocks where each part
+ * differs between the regions.  A block can count in more than one part.
+ */
+#[derive(Debug, Default, Serialize)]
+struct MismatchSummary {
+    blocks_compared: u64,
+    blocks_different: u64,
+    data_different: u64,
+    encryption_contexts_different: u64,
+    hashes_different: u64,
+}
+
+fn extent_region_meta(
+    ei: &ExtInfo,
+    dir_count: usize,
+) -> Vec<Option<ExtentRegionMeta>> {
+    (0..dir_count as u32)
+        .map(|i| {
+            let em = ei.ei_hm.get(&i)?;
+            Some(ExtentRegionMeta {
+                gen: em.gen_number,
+                flush: em.flush_number,
+                dirty: em.dirty,
+                written_blocks: *ei.ei_allocated.get(&i)?,
+            })
+        })
+        .collect()
+}
+
+/*
+ * Build the --json version of the extent table.  The written block
+ * counts are left out of the comparison for the same reason the table
+ * leaves them out.
+ */
+fn region_report(
+    region_dir: &[PathBuf],
+    all_extents: &HashMap<u32, ExtInfo>,
+    usage: &[SpaceUsage],
+    blocks_per_extent: u64,
+    only_show_differences: bool,
+) -> RegionReport {
+    let dir_count = region_dir.len();
+
+    let mut ext_num = all_extents.keys().copied().collect::<Vec<u32>>();
+    ext_num.sort_unstable();
+
+    let mut report = RegionReport {
+        regions: region_dir
+            .iter()
+            .zip(usage.iter())
+            .map(|(dir, usage)| RegionUsageReport {
+                dir: dir.clone(),
+                logical_bytes: usage.logical_bytes,
+                allocated_bytes: usage.allocated_bytes,
+                physical_bytes: usage.physical_bytes,
+            })
+            .collect(),
+        extents: Vec::with_capacity(ext_num.len()),
+        max_gen: 0,
+        max_flush: 0,
+        extents_different: 0,
+    };
+
+    for en in ext_num {
+        let regions = extent_region_meta(&all_extents[&en], dir_count);
+
+        let mut different = false;
+        for meta in regions.iter() {
+            match (meta, &regions[0]) {
+                (Some(meta), Some(first)) => {
+                    different |= meta.gen != first.gen
+                        || meta.flush != first.flush
+                        || meta.dirty != first.dirty;
+                    report.max_gen = std::cmp::max(report.max_gen, meta.gen);
+                    report.max_flush =
+                        std::cmp::max(report.max_flush, meta.flush);
+                }
+                _ => different = true,
+            }
+        }
+
+        if different {
+            report.extents_different += 1;
+        } else if only_show_differences {
+            continue;
+        }
+
+        let first_block = blocks_per_extent * en as u64;
+        report.extents.push(ExtentMetaReport {
+            extent: en,
+            first_block,
+            last_block: first_block + blocks_per_extent - 1,
+            regions,
+            different,
+        });
+    }
+
+    report
+}
+
+/*
+ * Build the --json version of an extent's block by block comparison, or
+ * of a single block if one was asked for.
+ */
+fn extent_report(
+    region_dir: &[PathBuf],
+    ei: &ExtInfo,
+    cmp_extent: u32,
+    block: Option<u64>,
+    blocks_per_extent: u64,
+    only_show_differences: bool,
+) -> Result<ExtentReport> {
+    let regions = region_dir
+        .iter()
+        .map(|dir| Region::open(dir, Default::default(), false, true))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let blocks = match block {
+        Some(block) => {
+            let block = block % blocks_per_extent;
+            block..block + 1
+        }
+        None => 0..blocks_per_extent,
+    };
+
+    let mut report = ExtentReport {
+        extent: cmp_extent,
+        regions: extent_region_meta(ei, region_dir.len()),
+        blocks: Vec::new(),
+        summary: MismatchSummary::default(),
+    };
+
+    for block in blocks {
+        let mut dvec = Vec::with_capacity(regions.len());
+        for region in regions.iter() {
+            let mut responses = region.region_read(
+                &[ReadRequest {
+                    eid: cmp_extent as u64,
+                    offset: Block::new_with_ddef(block, &region.def()),
+                    num_blocks: 1,
+                }],
+                0,
+            )?;
+            dvec.push(responses.pop().unwrap());
+        }
+
+        let data_different =
+            !is_all_same(&dvec.iter().map(|x| &x.data).collect::<Vec<_>>());
+        let encryption_contexts_different = !is_all_same(
+            &dvec
+                .iter()
+                .map(|x| &x.blocks[0].encryption_contexts)
+                .collect::<Vec<_>>(),
+        );
+        let hashes_different = !is_all_same(
+            &dvec.iter().map(|x| &x.blocks[0].hashes).collect::<Vec<_>>(),
+        );
+        let different =
+            data_different || encryption_contexts_different || hashes_different;
+
+        let summary = &mut report.summary;
+        summary.blocks_compared += 1;
+        summary.blocks_different += different as u64;
+        summary.data_different += data_different as u64;
+        summary.encryption_contexts_different +=
+            encryption_contexts_different as u64;
+        summary.hashes_different += hashes_different as u64;
+
+        if only_show_differences && !different {
+            continue;
+        }
+
+        report.blocks.push(BlockReport {
+            block: blocks_per_extent * cmp_extent as u64 + block,
+            regions: dvec
+                .iter()
+                .map(|response| BlockRegionReport {
+                    data_sha256: hex::encode(Sha256::digest(&response.data)),
+                    hashes: response.blocks[0]
+                        .hashes
+                        .iter()
+                        .map(|h| hex::encode(h.to_le_bytes()))
+                        .collect(),
+                    encryption_contexts: response.blocks[0]
+                        .encryption_contexts
+                        .iter()
+                        .map(|ctx| EncryptionContextReport {
+                            nonce: hex::encode(&ctx.nonce),
+                            tag: hex::encode(&ctx.tag),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            data_different,
+            encryption_contexts_different,
+            hashes_different,
+        });
+    }
+
+    Ok(report)
+}
+
 // Print the ASCII color code of the given value
 // Clear: 0, Green: 32, Red: 31, Blue: 34
 // If we don't want to print any color, then set no_color to true when
@@ -540,6 +890,7 @@ fn show_extent(
                 &[ReadRequest {
                     eid: cmp_extent as u64,
                     offset: Block::new_with_ddef(block, &region.def()),
+                    num_blocks: 1,
                 }],
                 0,
             )?;
@@ -568,8 +919,11 @@ fn show_extent(
         }
 
         // then, compare encryption_context_columns
-        let (status_letters, ec_different) =
-            return_status_letters(&dvec, |x| &x.encryption_contexts, nc);
+        let (status_letters, ec_different) = return_status_letters(
+            &dvec,
+            |x| &x.blocks[0].encryption_contexts,
+            nc,
+        );
 
         // Print nonce status letters
         for dir_index in 0..dir_count {
@@ -579,7 +933,7 @@ fn show_extent(
 
         // then, compare hashes
         let (status_letters, hashes_different) =
-            return_status_letters(&dvec, |x| &x.hashes, nc);
+            return_status_letters(&dvec, |x| &x.blocks[0].hashes, nc);
 
         // Print hash status letters
         for dir_index in 0..dir_count {
@@ -664,6 +1018,7 @@ fn show_extent_block(
             &[ReadRequest {
                 eid: cmp_extent as u64,
                 offset: Block::new_with_ddef(block_in_extent, &region.def()),
+                num_blocks: 1,
             }],
             0,
         )?;
@@ -703,7 +1058,7 @@ fn show_extent_block(
      * Compare encryption contexts
      */
     let (_, different) =
-        return_status_letters(&dvec, |x| &x.encryption_contexts, nc);
+        return_status_letters(&dvec, |x| &x.blocks[0].encryption_contexts, nc);
 
     if !only_show_differences || different {
         /*
@@ -718,7 +1073,7 @@ fn show_extent_block(
 
             max_nonce_depth = std::cmp::max(
                 max_nonce_depth,
-                response.encryption_contexts.len(),
+                response.blocks[0].encryption_contexts.len(),
             );
         }
         if !only_show_differences {
@@ -741,7 +1096,7 @@ fn show_extent_block(
             let mut all_same_len = true;
             let mut nonces = Vec::with_capacity(dir_count);
             for response in dvec.iter() {
-                let ctxs = &response.encryption_contexts;
+                let ctxs = &response.blocks[0].encryption_contexts;
                 print!(
                     "{:^24} ",
                     if depth < ctxs.len() {
@@ -772,7 +1127,7 @@ fn show_extent_block(
 
             max_tag_depth = std::cmp::max(
                 max_tag_depth,
-                response.encryption_contexts.len(),
+                response.blocks[0].encryption_contexts.len(),
             );
         }
         if !only_show_differences {
@@ -795,7 +1150,7 @@ fn show_extent_block(
             let mut all_same_len = true;
             let mut tags = Vec::with_capacity(dir_count);
             for response in dvec.iter() {
-                let ctxs = &response.encryption_contexts;
+                let ctxs = &response.blocks[0].encryption_contexts;
                 print!(
                     "{:^32} ",
                     if depth < ctxs.len() {
@@ -818,7 +1173,8 @@ fn show_extent_block(
     /*
      * Compare integrity hashes
      */
-    let (_, different) = return_status_letters(&dvec, |x| &x.hashes, nc);
+    let (_, different) =
+        return_status_letters(&dvec, |x| &x.blocks[0].hashes, nc);
 
     if !only_show_differences || different {
         /*
@@ -832,7 +1188,7 @@ fn show_extent_block(
             print!("{:^16} ", dir_index);
 
             max_hash_depth =
-                std::cmp::max(max_hash_depth, response.hashes.len());
+                std::cmp::max(max_hash_depth, response.blocks[0].hashes.len());
         }
         if !only_show_differences {
             print!(" {:<5}", "DIFF");
@@ -856,9 +1212,11 @@ fn show_extent_block(
             for response in dvec.iter() {
                 print!(
                     "{:^16} ",
-                    if depth < response.hashes.len() {
-                        hashes.push(&response.hashes[depth]);
-                        hex::encode(&response.hashes[depth].to_le_bytes())
+                    if depth < response.blocks[0].hashes.len() {
+                        hashes.push(&response.blocks[0].hashes[depth]);
+                        hex::encode(
+                            &response.blocks[0].hashes[depth].to_le_bytes(),
+                        )
                     } else {
                         all_same_len = false;
                         "".to_string()
@@ -878,6 +1236,8 @@ fn show_extent_block(
 #[cfg(test)]
 mod test {
     use super::*;
+    use bytes::Bytes;
+    use tempfile::tempdir;
 
     #[test]
     fn color_compare() {
@@ -966,4 +1326,71 @@ mod test {
         let colors = color_vec(&cm);
         assert_eq!(colors, vec![32, 31, 31]);
     }
+
+    #[test]
+    fn json_reports_differences() -> Result<()> {
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dirs = [tempdir()?, tempdir()?];
+        let mut regions = Vec::new();
+        for dir in dirs.iter() {
+            let mut region = Region::create(&dir, region_options.clone())?;
+            region.extend(2)?;
+            regions.push(region);
+        }
+        let region_dir: Vec<PathBuf> =
+            dirs.iter().map(|d| d.path().to_path_buf()).collect();
+
+        dump_region(region_dir.clone(), None, None, false, true, true)?;
+
+        // Write block 12 (extent 1, block 2) to only the first region.
+        let data = Bytes::from(vec![7; 512]);
+        let hash = integrity_hash(&[&data[..]]);
+        regions[0].region_write(
+            &[crucible_protocol::Write {
+                eid: 1,
+                offset: Block::new_512(2),
+                data,
+                encryption_context: None,
+                hash,
+            }],
+            1,
+            false,
+        )?;
+        regions[0].region_flush(1, 1, &None, 2)?;
+
+        assert!(
+            dump_region(region_dir.clone(), None, None, true, true, true)
+                .is_err()
+        );
+
+        let report =
+            extent_report(&region_dir, &ExtInfo::default(), 1, None, 10, true)?;
+        assert_eq!(report.summary.blocks_compared, 10);
+        assert_eq!(report.summary.blocks_different, 1);
+        assert_eq!(report.summary.data_different, 1);
+        assert_eq!(report.summary.hashes_different, 1);
+        assert_eq!(report.summary.encryption_contexts_different, 0);
+        assert_eq!(report.blocks.len(), 1);
+        assert_eq!(report.blocks[0].block, 12);
+        assert_eq!(report.blocks[0].regions[1].hashes.len(), 0);
+
+        let report = extent_report(
+            &region_dir,
+            &ExtInfo::default(),
+            1,
+            Some(13),
+            10,
+            false,
+        )?;
+        assert_eq!(report.summary.blocks_compared, 1);
+        assert_eq!(report.summary.blocks_different, 0);
+        assert_eq!(report.blocks[0].block, 13);
+
+        Ok(())
+    }
 }
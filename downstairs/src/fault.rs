@@ -0,0 +1,325 @@
+// Copyright 2022 Oxide Computer Company
+/*
+ * Faults a test can have a running downstairs inject through the admin
+ * server, so it gets the failure it wants on the job it wants, instead of
+ * the random ones that --lossy and --return-errors give.
+ *
+ * Each fault says which jobs it applies to, by operation and by the
+ * extents a job uses, and what to do to them.  Every fault that applies
+ * to a job counts it, and does its thing to it once it has let the jobs
+ * it was asked to skip through.
+ */
+use super::*;
+
+use schemars::JsonSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultOp {
+    Read,
+    Write,
+    WriteUnwritten,
+    Flush,
+    Discard,
+    WriteZeroes,
+    CompareAndWrite,
+}
+
+impl FaultOp {
+    fn of(work: &IOop) -> FaultOp {
+        match work {
+            IOop::Read { .. } => FaultOp::Read,
+            IOop::Write { .. } => FaultOp::Write,
+            IOop::WriteUnwritten { .. } => FaultOp::WriteUnwritten,
+            IOop::Flush { .. } => FaultOp::Flush,
+            IOop::Discard { .. } => FaultOp::Discard,
+            IOop::WriteZeroes { .. } => FaultOp::WriteZeroes,
+            IOop::CompareAndWrite { .. } => FaultOp::CompareAndWrite,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultAction {
+    /// Wait this long before doing the job.
+    Delay { millis: u64 },
+    /// Answer the job with this error instead of doing it.
+    Fail {
+        #[schemars(with = "serde_json::Value")]
+        error: CrucibleError,
+    },
+    /// Flip a bit in every block a read returns from the fault's extents.
+    Corrupt,
+    /// Do the job, but drop the connection to the upstairs instead of
+    /// answering it.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct Fault {
+    /// Operations this applies to, or all of them if empty.
+    #[serde(default)]
+    pub ops: Vec<FaultOp>,
+    /// Extents this applies to, or all of them if empty.  A flush uses
+    /// every extent.
+    #[serde(default)]
+    pub extents: Vec<u64>,
+    /// How many jobs this applies to to let through untouched first.
+    #[serde(default)]
+    pub skip: u64,
+    /// How many jobs to do this to after that, or every one if not given.
+    #[serde(default)]
+    pub count: Option<u64>,
+    pub action: FaultAction,
+}
+
+impl Fault {
+    fn applies(&self, work: &IOop) -> bool {
+        let op = FaultOp::of(work);
+        if self.action == FaultAction::Corrupt && op != FaultOp::Read {
+            return false;
+        }
+        if !self.ops.is_empty() && !self.ops.contains(&op) {
+            return false;
+        }
+        if self.extents.is_empty() {
+            return true;
+        }
+
+        let extents = ExtentUse::of(work);
+        extents.writes_all
+            || self.extents.iter().any(|e| {
+                extents.reads.contains(e) || extents.writes.contains(e)
+            })
+    }
+}
+
+/**
+ * A fault, and how many jobs it has applied to and been injected into.
+ */
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FaultReport {
+    pub fault: Fault,
+    pub matched: u64,
+    pub injected: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    faults: Vec<FaultReport>,
+}
+
+/*
+ * What to do to one job.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Injection {
+    pub delay: Duration,
+    pub error: Option<CrucibleError>,
+    pub corrupt: Option<Vec<u64>>,
+    pub disconnect: bool,
+}
+
+impl FaultInjector {
+    /*
+     * Replace whatever faults we had with these, counting from zero.
+     */
+    pub fn set(&mut self, faults: Vec<Fault>) {
+        self.faults = faults
+            .into_iter()
+            .map(|fault| FaultReport {
+                fault,
+                matched: 0,
+                injected: 0,
+            })
+            .collect();
+    }
+
+    pub fn report(&self) -> Vec<FaultReport> {
+        self.faults.clone()
+    }
+
+    /*
+     * Count a job against every fault that applies to it, and return what
+     * those faults want done to it.  If more than one wants it failed or
+     * its reads corrupted, the first one given wins.
+     */
+    pub(crate) fn check(&mut self, work: &IOop) -> Injection {
+        let mut injection = Injection::default();
+
+        for fr in self.faults.iter_mut() {
+            if !fr.fault.applies(work) {
+                continue;
+            }
+            fr.matched += 1;
+            if fr.matched <= fr.fault.skip {
+                continue;
+            }
+            if let Some(count) = fr.fault.count {
+                if fr.injected >= count {
+                    continue;
+                }
+            }
+            fr.injected += 1;
+
+            match &fr.fault.action {
+                FaultAction::Delay { millis } => {
+                    injection.delay += Duration::from_millis(*millis);
+                }
+                FaultAction::Fail { error } => {
+                    if injection.error.is_none() {
+                        injection.error = Some(error.clone());
+                    }
+                }
+                FaultAction::Corrupt => {
+                    if injection.corrupt.is_none() {
+                        injection.corrupt = Some(fr.fault.extents.clone());
+                    }
+                }
+                FaultAction::Disconnect => {
+                    injection.disconnect = true;
+                }
+            }
+        }
+
+        injection
+    }
+}
+
+/*
+ * Flip the low bit of the first byte of every block in the responses
+ * for the given extents, or for all of them if there are none.  The
+ * hashes are left as they were, so the upstairs can tell.
+ */
+pub(crate) fn corrupt_responses(
+    responses: &mut [ReadResponse],
+    extents: &[u64],
+) {
+    for response in responses.iter_mut() {
+        if !extents.is_empty() && !extents.contains(&response.eid) {
+            continue;
+        }
+        if response.blocks.is_empty() {
+            continue;
+        }
+        let block_size = response.data.len() / response.blocks.len();
+        for block in response.data.chunks_mut(block_size) {
+            block[0] ^= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(eid: u64) -> IOop {
+        IOop::Read {
+            dependencies: Vec::new(),
+            requests: vec![ReadRequest {
+                eid,
+                offset: Block::new_512(0),
+                num_blocks: 1,
+            }],
+        }
+    }
+
+    fn flush() -> IOop {
+        IOop::Flush {
+            dependencies: Vec::new(),
+            flush_number: 1,
+            gen_number: 1,
+            snapshot_details: None,
+        }
+    }
+
+    #[test]
+    fn fault_applies() {
+        let fault = Fault {
+            ops: vec![FaultOp::Read, FaultOp::Flush],
+            extents: vec![2],
+            skip: 0,
+            count: None,
+            action: FaultAction::Delay { millis: 1 },
+        };
+        assert!(fault.applies(&read(2)));
+        assert!(!fault.applies(&read(1)));
+        assert!(fault.applies(&flush()));
+
+        let fault = Fault {
+            ops: Vec::new(),
+            extents: Vec::new(),
+            action: FaultAction::Corrupt,
+            ..fault
+        };
+        assert!(fault.applies(&read(1)));
+        assert!(!fault.applies(&flush()));
+    }
+
+    #[test]
+    fn fault_skip_and_count() {
+        let mut fi = FaultInjector::default();
+        fi.set(vec![
+            Fault {
+                ops: vec![FaultOp::Read],
+                extents: vec![1],
+                skip: 1,
+                count: Some(2),
+                action: FaultAction::Fail {
+                    error: CrucibleError::IoError("injected".to_string()),
+                },
+            },
+            Fault {
+                ops: Vec::new(),
+                extents: Vec::new(),
+                skip: 3,
+                count: Some(1),
+                action: FaultAction::Disconnect,
+            },
+        ]);
+
+        let failed = (0..5)
+            .map(|_| fi.check(&read(1)))
+            .map(|i| (i.error.is_some(), i.disconnect))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failed,
+            vec![
+                (false, false),
+                (true, false),
+                (true, false),
+                (false, true),
+                (false, false),
+            ]
+        );
+        assert!(fi.check(&read(0)).error.is_none());
+
+        let report = fi.report();
+        assert_eq!((report[0].matched, report[0].injected), (5, 2));
+        assert_eq!((report[1].matched, report[1].injected), (6, 1));
+    }
+
+    #[test]
+    fn fault_json() {
+        let faults: Vec<Fault> = serde_json::from_str(
+            r#"[
+                {
+                    "ops": ["read", "write_unwritten"],
+                    "action": { "fail": { "error": { "IoError": "boom" } } }
+                },
+                { "skip": 100, "count": 1, "action": "disconnect" }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(faults[0].ops, vec![FaultOp::Read, FaultOp::WriteUnwritten]);
+        assert_eq!(
+            faults[0].action,
+            FaultAction::Fail {
+                error: CrucibleError::IoError("boom".to_string())
+            }
+        );
+        assert_eq!(faults[1].skip, 100);
+        assert_eq!(faults[1].action, FaultAction::Disconnect);
+    }
+}
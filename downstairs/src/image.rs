@@ -0,0 +1,700 @@
+// Copyright 2022 Oxide Computer Company
+/*
+ * Disk image formats a region can be exported to and imported from.
+ *
+ * raw is every byte of the disk, in order.  raw-sparse is the same, but
+ * with holes where the disk is all zeros, which includes every block that
+ * was never written, so it only takes up space for the rest.  qcow2 is
+ * the format QEMU uses, and it only stores the clusters that have data,
+ * so it stays small wherever it is copied to.
+ */
+use std::convert::TryInto;
+use std::io::BufWriter;
+use std::os::unix::fs::FileExt;
+
+use super::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    RawSparse,
+    Qcow2,
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().replace('_', "-").as_str() {
+            "raw" => ImageFormat::Raw,
+            "raw-sparse" => ImageFormat::RawSparse,
+            "qcow2" => ImageFormat::Qcow2,
+            _ => {
+                bail!("not a valid image format: {}", s);
+            }
+        })
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageFormat::Raw => write!(f, "raw"),
+            ImageFormat::RawSparse => write!(f, "raw-sparse"),
+            ImageFormat::Qcow2 => write!(f, "qcow2"),
+        }
+    }
+}
+
+/*
+ * Where an export writes the blocks of the disk, in order.
+ */
+pub(crate) trait ImageWriter {
+    fn write_block(&mut self, data: &[u8]) -> Result<()>;
+
+    /*
+     * The next len bytes of the disk are zeros, which a format with holes
+     * need not write at all.
+     */
+    fn write_zeroes(&mut self, len: u64) -> Result<()>;
+
+    /*
+     * Called after the last block, to write out anything that is still
+     * to go.
+     */
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/*
+ * Create an image at path for a disk of the given number of blocks.
+ */
+pub(crate) fn create_image<P: AsRef<Path>>(
+    format: ImageFormat,
+    path: P,
+    block_size: u64,
+    blocks: u64,
+) -> Result<Box<dyn ImageWriter>> {
+    let file = File::create(path)?;
+    let size = block_size * blocks;
+
+    Ok(match format {
+        ImageFormat::Raw => Box::new(RawWriter {
+            file: BufWriter::new(file),
+        }),
+        ImageFormat::RawSparse => {
+            file.set_len(size)?;
+            Box::new(SparseWriter { file, pos: 0 })
+        }
+        ImageFormat::Qcow2 => {
+            Box::new(Qcow2Writer::new(file, size, QCOW2_CLUSTER_BITS))
+        }
+    })
+}
+
+struct RawWriter {
+    file: BufWriter<File>,
+}
+
+impl ImageWriter for RawWriter {
+    fn write_block(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    fn write_zeroes(&mut self, len: u64) -> Result<()> {
+        std::io::copy(&mut std::io::repeat(0).take(len), &mut self.file)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/*
+ * The file starts out as one big hole the size of the disk, and we only
+ * write the blocks that are not all zeros.
+ */
+struct SparseWriter {
+    file: File,
+    pos: u64,
+}
+
+impl ImageWriter for SparseWriter {
+    fn write_block(&mut self, data: &[u8]) -> Result<()> {
+        if data.iter().any(|b| *b != 0) {
+            self.file.write_all_at(data, self.pos)?;
+        }
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_zeroes(&mut self, len: u64) -> Result<()> {
+        self.pos += len;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/*
+ * qcow2, as described in docs/interop/qcow2.txt in the QEMU source.
+ *
+ * The disk is split into clusters, and the image is made of clusters of
+ * the same size.  An L1 table points to L2 tables, and each of those
+ * points to the clusters holding the data for a run of the disk.  A
+ * cluster of the disk with no data cluster reads as zeros.  Every
+ * cluster of the image also has a reference count, found through the
+ * refcount table and the refcount blocks it points to.
+ */
+const QCOW2_MAGIC: [u8; 4] = *b"QFI\xfb";
+const QCOW2_V3_HEADER_LENGTH: usize = 104;
+
+/*
+ * The sizes we write images with, which are what qemu-img uses by
+ * default: 64 KiB clusters and 16 bit reference counts.
+ */
+const QCOW2_CLUSTER_BITS: u32 = 16;
+const QCOW2_REFCOUNT_ORDER: u32 = 4;
+
+/*
+ * Where in an L1 or L2 entry the offset of the cluster it points to is.
+ */
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/*
+ * The cluster pointed to has a reference count of exactly one.
+ */
+const QCOW2_COPIED: u64 = 1 << 63;
+const QCOW2_COMPRESSED: u64 = 1 << 62;
+/*
+ * In an L2 entry of a version 3 image, the cluster reads as all zeros.
+ */
+const QCOW2_ZERO: u64 = 1;
+/*
+ * The only incompatible feature we can read an image with: it was not
+ * closed cleanly, so its reference counts may be wrong.  We don't look at
+ * them anyway.
+ */
+const QCOW2_DIRTY: u64 = 1;
+
+fn div_round_up(n: u64, d: u64) -> u64 {
+    (n + d - 1) / d
+}
+
+/*
+ * We write the image front to back, with the header in the first cluster
+ * and the L1 table right after it.  Data clusters and L2 tables follow in
+ * the order we fill them, and the reference counts, which we only know
+ * once everything else is written, go at the end.
+ */
+struct Qcow2Writer {
+    file: File,
+    size: u64,
+    cluster_bits: u32,
+
+    /*
+     * Where in the disk the next block is.
+     */
+    pos: u64,
+
+    /*
+     * The disk cluster we are filling, and if any of it is not zeros.
+     */
+    cluster: Vec<u8>,
+    cluster_index: u64,
+    cluster_used: bool,
+
+    l1: Vec<u64>,
+    /*
+     * The L2 table we are filling, and which L1 entry it is for.
+     */
+    l2: Vec<u64>,
+    l2_index: Option<u64>,
+
+    /*
+     * The first cluster of the image not in use yet.
+     */
+    next_cluster: u64,
+}
+
+impl Qcow2Writer {
+    fn new(file: File, size: u64, cluster_bits: u32) -> Qcow2Writer {
+        let cluster_size = 1u64 << cluster_bits;
+        let l2_entries = cluster_size / 8;
+        let l1_size = div_round_up(size, cluster_size * l2_entries);
+
+        Qcow2Writer {
+            file,
+            size,
+            cluster_bits,
+            pos: 0,
+            cluster: vec![0; cluster_size as usize],
+            cluster_index: 0,
+            cluster_used: false,
+            l1: vec![0; l1_size as usize],
+            l2: vec![0; l2_entries as usize],
+            l2_index: None,
+            next_cluster: 1 + div_round_up(l1_size * 8, cluster_size).max(1),
+        }
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn alloc_cluster(&mut self) -> u64 {
+        let offset = self.next_cluster * self.cluster_size();
+        self.next_cluster += 1;
+        offset
+    }
+
+    fn flush_cluster(&mut self) -> Result<()> {
+        if !self.cluster_used {
+            return Ok(());
+        }
+
+        let offset = self.alloc_cluster();
+        self.file.write_all_at(&self.cluster, offset)?;
+
+        let l1_index = self.cluster_index / self.l2.len() as u64;
+        if self.l2_index != Some(l1_index) {
+            self.flush_l2()?;
+            self.l2_index = Some(l1_index);
+        }
+        let l2_index = self.cluster_index % self.l2.len() as u64;
+        self.l2[l2_index as usize] = offset | QCOW2_COPIED;
+
+        self.cluster.iter_mut().for_each(|b| *b = 0);
+        self.cluster_used = false;
+        Ok(())
+    }
+
+    fn flush_l2(&mut self) -> Result<()> {
+        if let Some(l1_index) = self.l2_index.take() {
+            let offset = self.alloc_cluster();
+            self.file.write_all_at(&table_bytes(&self.l2), offset)?;
+            self.l1[l1_index as usize] = offset | QCOW2_COPIED;
+            self.l2.iter_mut().for_each(|e| *e = 0);
+        }
+        Ok(())
+    }
+
+    /*
+     * Every cluster in the image so far has a reference count of one, as
+     * do the refcount blocks and refcount table we are about to add to
+     * the end.  Return where the table is, and how many clusters long.
+     */
+    fn write_refcounts(&mut self) -> Result<(u64, u64)> {
+        let cluster_size = self.cluster_size();
+        let per_block = (cluster_size * 8) >> QCOW2_REFCOUNT_ORDER;
+
+        /*
+         * The refcounts have to count themselves, so keep adding room
+         * for them until there is enough.
+         */
+        let mut blocks = 0;
+        let mut table_clusters = 0;
+        loop {
+            let total = self.next_cluster + blocks + table_clusters;
+            let need_blocks = div_round_up(total, per_block);
+            let need_table = div_round_up(need_blocks * 8, cluster_size);
+            if need_blocks == blocks && need_table == table_clusters {
+                break;
+            }
+            blocks = need_blocks;
+            table_clusters = need_table;
+        }
+        let total = self.next_cluster + blocks + table_clusters;
+
+        let mut table = Vec::with_capacity(blocks as usize);
+        for block in 0..blocks {
+            let mut buf = vec![0u8; cluster_size as usize];
+            let first = block * per_block;
+            for (i, count) in buf.chunks_exact_mut(2).enumerate() {
+                if first + i as u64 >= total {
+                    break;
+                }
+                count.copy_from_slice(&1u16.to_be_bytes());
+            }
+
+            let offset = self.alloc_cluster();
+            self.file.write_all_at(&buf, offset)?;
+            table.push(offset);
+        }
+
+        let table_offset = self.next_cluster * cluster_size;
+        self.file.write_all_at(&table_bytes(&table), table_offset)?;
+        self.next_cluster += table_clusters;
+        assert_eq!(self.next_cluster, total);
+
+        /*
+         * Leave the file a whole number of clusters long.
+         */
+        self.file.set_len(total * cluster_size)?;
+
+        Ok((table_offset, table_clusters))
+    }
+
+    fn write_header(
+        &self,
+        refcount_table_offset: u64,
+        refcount_table_clusters: u64,
+    ) -> Result<()> {
+        let mut header = [0u8; QCOW2_V3_HEADER_LENGTH];
+        let mut put = |at: usize, bytes: &[u8]| {
+            header[at..at + bytes.len()].copy_from_slice(bytes);
+        };
+
+        put(0, &QCOW2_MAGIC);
+        put(4, &3u32.to_be_bytes());
+        put(20, &self.cluster_bits.to_be_bytes());
+        put(24, &self.size.to_be_bytes());
+        put(36, &(self.l1.len() as u32).to_be_bytes());
+        put(40, &self.cluster_size().to_be_bytes());
+        put(48, &refcount_table_offset.to_be_bytes());
+        put(56, &(refcount_table_clusters as u32).to_be_bytes());
+        put(96, &QCOW2_REFCOUNT_ORDER.to_be_bytes());
+        put(100, &(QCOW2_V3_HEADER_LENGTH as u32).to_be_bytes());
+
+        /*
+         * The header extensions that follow would end with one of type
+         * zero, and the rest of the cluster is zeros already.
+         */
+        self.file.write_all_at(&header, 0)?;
+        Ok(())
+    }
+}
+
+impl ImageWriter for Qcow2Writer {
+    fn write_block(&mut self, data: &[u8]) -> Result<()> {
+        let cluster_index = self.pos / self.cluster_size();
+        if cluster_index != self.cluster_index {
+            self.flush_cluster()?;
+            self.cluster_index = cluster_index;
+        }
+
+        if data.iter().any(|b| *b != 0) {
+            let at = (self.pos % self.cluster_size()) as usize;
+            self.cluster[at..at + data.len()].copy_from_slice(data);
+            self.cluster_used = true;
+        }
+
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    /*
+     * Whatever cluster the next block lands in, write_block notices it is
+     * not the one we were filling.
+     */
+    fn write_zeroes(&mut self, len: u64) -> Result<()> {
+        self.pos += len;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush_cluster()?;
+        self.flush_l2()?;
+
+        let (table_offset, table_clusters) = self.write_refcounts()?;
+        self.file
+            .write_all_at(&table_bytes(&self.l1), self.cluster_size())?;
+        self.write_header(table_offset, table_clusters)?;
+        self.file.sync_all()?;
+
+        Ok(())
+    }
+}
+
+fn table_bytes(table: &[u64]) -> Vec<u8> {
+    table.iter().flat_map(|e| e.to_be_bytes()).collect()
+}
+
+/*
+ * Do these bytes from the start of an image say it is qcow2?
+ */
+pub(crate) fn has_qcow2_magic(start: &[u8]) -> bool {
+    start.starts_with(&QCOW2_MAGIC)
+}
+
+/*
+ * Does this file hold a qcow2 image?
+ */
+pub(crate) fn is_qcow2(file: &File) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(()) => Ok(has_qcow2_magic(&magic)),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/*
+ * Reads the disk in a qcow2 image.  We can read any image qemu-img makes
+ * by default, but not ones that are encrypted, compressed, or only hold
+ * the changes to a backing file.
+ */
+pub(crate) struct Qcow2Reader {
+    file: File,
+    version: u32,
+    size: u64,
+    cluster_bits: u32,
+    l1: Vec<u64>,
+
+    /*
+     * The last L2 table we read, and which L1 entry it is for.
+     */
+    l2: Vec<u64>,
+    l2_index: Option<u64>,
+}
+
+impl Qcow2Reader {
+    pub fn open(file: File) -> Result<Qcow2Reader> {
+        /*
+         * A version 2 header is only 72 bytes, and the image could be
+         * that short if the disk is empty.
+         */
+        let mut header = [0u8; QCOW2_V3_HEADER_LENGTH];
+        let len = file.metadata()?.len().min(header.len() as u64) as usize;
+        file.read_exact_at(&mut header[..len], 0)?;
+
+        let u32_at = |at: usize| {
+            u32::from_be_bytes(header[at..at + 4].try_into().unwrap())
+        };
+        let u64_at = |at: usize| {
+            u64::from_be_bytes(header[at..at + 8].try_into().unwrap())
+        };
+
+        if header[0..4] != QCOW2_MAGIC {
+            bail!("not a qcow2 image");
+        }
+        let version = u32_at(4);
+        if version != 2 && version != 3 {
+            bail!("qcow2 version {} is not supported", version);
+        }
+        if u64_at(8) != 0 {
+            bail!("qcow2 images with a backing file are not supported");
+        }
+        let cluster_bits = u32_at(20);
+        if !(9..=21).contains(&cluster_bits) {
+            bail!("qcow2 cluster bits {} is not valid", cluster_bits);
+        }
+        let size = u64_at(24);
+        if u32_at(32) != 0 {
+            bail!("encrypted qcow2 images are not supported");
+        }
+        if version == 3 {
+            let incompatible = u64_at(72);
+            if incompatible & !QCOW2_DIRTY != 0 {
+                bail!(
+                    "qcow2 incompatible features {:#x} are not supported",
+                    incompatible
+                );
+            }
+        }
+
+        let l1_size = u32_at(36) as u64;
+        let l2_entries = 1 << (cluster_bits - 3);
+        if l1_size < div_round_up(size, l2_entries << cluster_bits) {
+            bail!("qcow2 L1 table of {} is too small", l1_size);
+        }
+        let mut buf = vec![0u8; l1_size as usize * 8];
+        file.read_exact_at(&mut buf, u64_at(40))?;
+        let l1 = buf
+            .chunks_exact(8)
+            .map(|e| u64::from_be_bytes(e.try_into().unwrap()))
+            .collect();
+
+        Ok(Qcow2Reader {
+            file,
+            version,
+            size,
+            cluster_bits,
+            l1,
+            l2: Vec::new(),
+            l2_index: None,
+        })
+    }
+
+    /*
+     * The size of the disk, in bytes.
+     */
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /*
+     * Where in the image the data for a cluster of the disk is, if it has
+     * any.
+     */
+    fn cluster_offset(&mut self, cluster_index: u64) -> Result<Option<u64>> {
+        let l2_entries = 1 << (self.cluster_bits - 3);
+        let l1_index = cluster_index / l2_entries;
+
+        if self.l2_index != Some(l1_index) {
+            let l2_offset = self.l1[l1_index as usize] & QCOW2_OFFSET_MASK;
+            if l2_offset == 0 {
+                return Ok(None);
+            }
+
+            let mut buf = vec![0u8; 1 << self.cluster_bits];
+            self.file.read_exact_at(&mut buf, l2_offset)?;
+            self.l2 = buf
+                .chunks_exact(8)
+                .map(|e| u64::from_be_bytes(e.try_into().unwrap()))
+                .collect();
+            self.l2_index = Some(l1_index);
+        }
+
+        let entry = self.l2[(cluster_index % l2_entries) as usize];
+        if entry & QCOW2_COMPRESSED != 0 {
+            bail!("compressed qcow2 clusters are not supported");
+        }
+        if self.version == 3 && entry & QCOW2_ZERO != 0 {
+            return Ok(None);
+        }
+        match entry & QCOW2_OFFSET_MASK {
+            0 => Ok(None),
+            offset => Ok(Some(offset)),
+        }
+    }
+
+    /*
+     * Read the disk from offset into buf, which must be all zeros to start
+     * with, as we only write to it where the image has data.  Return how
+     * much of buf that covers, which is less than all of it only at the
+     * end of the disk.
+     */
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let len = (buf.len() as u64).min(self.size.saturating_sub(offset));
+        let cluster_size = 1u64 << self.cluster_bits;
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let in_cluster = pos % cluster_size;
+            let n = (cluster_size - in_cluster).min(len - done);
+
+            if let Some(host) = self.cluster_offset(pos / cluster_size)? {
+                let to = &mut buf[done as usize..(done + n) as usize];
+                self.file.read_exact_at(to, host + in_cluster)?;
+            }
+
+            done += n;
+        }
+
+        Ok(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn image_format_from_str() {
+        for format in
+            [ImageFormat::Raw, ImageFormat::RawSparse, ImageFormat::Qcow2]
+        {
+            let parsed: ImageFormat = format.to_string().parse().unwrap();
+            assert_eq!(parsed, format);
+        }
+        assert_eq!(
+            "raw_sparse".parse::<ImageFormat>().unwrap(),
+            ImageFormat::RawSparse
+        );
+        assert!("vmdk".parse::<ImageFormat>().is_err());
+    }
+
+    fn fill(block: u64) -> u8 {
+        /*
+         * Every third pair of blocks is zeros.
+         */
+        if (block / 2) % 3 == 0 {
+            0
+        } else {
+            (block % 251) as u8 + 1
+        }
+    }
+
+    #[test]
+    fn qcow2_round_trip() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("disk.qcow2");
+
+        /*
+         * With 1 KiB clusters, an L2 table covers 128 of them and a
+         * refcount block 512.  Make a disk a few blocks too big for eight
+         * L2 tables, so the last block needs a ninth, and with more
+         * clusters of data than one refcount block covers.
+         */
+        let cluster_bits = 10;
+        let block_size = 512;
+        let blocks = 8 * 128 * 2 + 3;
+
+        let file = File::create(&path)?;
+        let mut image: Box<dyn ImageWriter> =
+            Box::new(Qcow2Writer::new(file, blocks * block_size, cluster_bits));
+        for block in 0..blocks {
+            image.write_block(&vec![fill(block); block_size as usize])?;
+        }
+        image.finish()?;
+
+        let file = File::open(&path)?;
+        assert!(is_qcow2(&file)?);
+
+        /*
+         * Every cluster in the file has a reference count of one.
+         */
+        let image = std::fs::read(&path)?;
+        let be64 = |at: u64| {
+            let at = at as usize;
+            u64::from_be_bytes(image[at..at + 8].try_into().unwrap())
+        };
+        let table_offset = be64(48);
+        let clusters = image.len() as u64 >> cluster_bits;
+        for cluster in 0..clusters {
+            let block_offset = be64(table_offset + cluster / 512 * 8);
+            let at = (block_offset + cluster % 512 * 2) as usize;
+            assert_eq!(image[at..at + 2], [0, 1], "cluster {}", cluster);
+        }
+        assert_eq!(be64(table_offset + clusters / 512 * 8 + 8), 0);
+
+        let mut reader = Qcow2Reader::open(file)?;
+        assert_eq!(reader.size(), blocks * block_size);
+        for block in 0..blocks {
+            let mut buf = vec![0u8; block_size as usize];
+            let n = reader.read_at(block * block_size, &mut buf)?;
+            assert_eq!(n, block_size as usize);
+            assert!(buf.iter().all(|b| *b == fill(block)), "block {}", block);
+        }
+
+        /*
+         * A read past the end gets only what there is.
+         */
+        let mut buf = vec![0u8; 4 * block_size as usize];
+        let n = reader.read_at((blocks - 1) * block_size, &mut buf)?;
+        assert_eq!(n, block_size as usize);
+        assert_eq!(reader.read_at(blocks * block_size, &mut buf)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_is_not_qcow2() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("disk.raw");
+
+        std::fs::write(&path, b"QF")?;
+        assert!(!is_qcow2(&File::open(&path)?)?);
+
+        std::fs::write(&path, vec![0u8; 4096])?;
+        assert!(!is_qcow2(&File::open(&path)?)?);
+        assert!(Qcow2Reader::open(File::open(&path)?).is_err());
+
+        Ok(())
+    }
+}
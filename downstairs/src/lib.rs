@@ -7,40 +7,100 @@
 
 use futures::executor;
 use futures::lock::{Mutex, MutexGuard};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
 use crucible::*;
-use crucible_common::{Block, CrucibleError, MAX_BLOCK_SIZE};
+use crucible_common::{
+    read_json_maybe, write_json, Block, CrucibleError, MetadataBackend,
+    RegionDefinition, RegionLayout, MAX_BLOCK_SIZE,
+};
 
 use anyhow::{bail, Result};
 use bytes::BytesMut;
+use futures::stream::FuturesUnordered;
 use futures::{SinkExt, StreamExt};
+use opentelemetry::trace::{
+    SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+};
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{watch, OwnedRwLockReadGuard, RwLock};
 use tokio::time::{sleep_until, Instant};
 use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
 pub mod admin;
 mod dump;
+mod fault;
+mod image;
+mod mend;
+mod metadata;
 pub mod region;
 pub mod repair;
+mod scrub;
+mod snapshot;
 mod stats;
+mod uring;
+mod verify;
 
 use region::Region;
 
 pub use admin::run_dropshot;
 pub use dump::dump_region;
+pub use fault::{Fault, FaultAction, FaultOp, FaultReport};
+pub use image::ImageFormat;
+pub use mend::mend_regions;
+pub use region::IoBackend;
+pub use scrub::{scrub_region, ScrubReport};
 pub use stats::*;
+pub use verify::verify_region;
+
+/*
+ * Make span a child of the span the upstairs sent a job under, if it sent
+ * one.  This only matters when we are exporting traces.
+ */
+fn set_trace_parent(span: &tracing::Span, trace: Option<TraceContext>) {
+    if let Some(trace) = trace {
+        let remote = SpanContext::new(
+            TraceId::from_bytes(trace.trace_id),
+            SpanId::from_bytes(trace.span_id),
+            TraceFlags::new(trace.flags),
+            true,
+            TraceState::default(),
+        );
+        span.set_parent(
+            opentelemetry::Context::new().with_remote_span_context(remote),
+        );
+    }
+}
+
+/*
+ * The job an IO message from the upstairs is for.
+ */
+fn io_job_id(m: &Message) -> Option<u64> {
+    match m {
+        Message::Write { job_id, .. }
+        | Message::Flush { job_id, .. }
+        | Message::ReadRequest { job_id, .. }
+        | Message::ReadRangeRequest { job_id, .. }
+        | Message::WriteUnwritten { job_id, .. }
+        | Message::Discard { job_id, .. }
+        | Message::WriteZeroes { job_id, .. }
+        | Message::CompareAndWrite { job_id, .. } => Some(*job_id),
+        _ => None,
+    }
+}
 
 fn deadline_secs(secs: u64) -> Instant {
     Instant::now()
@@ -50,7 +110,7 @@ fn deadline_secs(secs: u64) -> Instant {
 
 /*
  * Export the contents or partial contents of a Downstairs Region to
- * the file indicated.
+ * the file indicated, as an image in the given format.
  *
  * We will start from the provided start_block.
  * We will stop after "count" blocks are written to the export_path.
@@ -60,6 +120,7 @@ pub fn downstairs_export<P: AsRef<Path> + std::fmt::Debug>(
     export_path: P,
     start_block: u64,
     mut count: u64,
+    format: ImageFormat,
 ) -> Result<()> {
     /*
      * Export an existing downstairs region to a file
@@ -69,11 +130,18 @@ pub fn downstairs_export<P: AsRef<Path> + std::fmt::Debug>(
     assert!(block_size > 0);
     assert!(space_per_extent > 0);
     assert!(extent_count > 0);
-    assert!(space_per_extent > 0);
     let file_size = space_per_extent * extent_count as u64;
 
-    if count == 0 {
-        count = extent_size.value * extent_count as u64;
+    let total_blocks = extent_size.value * extent_count as u64;
+    if start_block >= total_blocks {
+        bail!(
+            "Start block {} is past the end of the region at {}",
+            start_block,
+            total_blocks
+        );
+    }
+    if count == 0 || count > total_blocks - start_block {
+        count = total_blocks - start_block;
     }
 
     println!(
@@ -81,85 +149,252 @@ pub fn downstairs_export<P: AsRef<Path> + std::fmt::Debug>(
         file_size, space_per_extent, extent_count
     );
     println!(
-        "Exporting from start_block: {}  count:{}",
-        start_block, count
+        "Exporting from start_block: {}  count:{}  format:{}",
+        start_block, count, format
     );
 
-    let mut out_file = File::create(export_path)?;
-    let mut blocks_copied = 0;
-
-    'eid_loop: for eid in 0..extent_count {
-        let extent_offset = space_per_extent * eid as u64;
-        for block_offset in 0..extent_size.value {
-            if (extent_offset + block_offset) >= start_block {
-                blocks_copied += 1;
+    /*
+     * Read a run of blocks at a time, never past the end of an extent.
+     * Blocks that were never written are zeros, and the metadata tells us
+     * which those are, so we don't read them.
+     */
+    const EXPORT_CHUNK_BLOCKS: u64 = 256;
+
+    let mut image =
+        image::create_image(format, &export_path, block_size, count)?;
+    let end_block = start_block + count;
+    let mut block = start_block;
+    let mut read_blocks = 0;
+    while block < end_block {
+        let eid = block / extent_size.value;
+        let offset = block % extent_size.value;
+        let num_blocks = (extent_size.value - offset)
+            .min(end_block - block)
+            .min(EXPORT_CHUNK_BLOCKS);
+        let written = region.blocks_written(
+            eid as u32,
+            Block::new_with_ddef(offset, &region.def()),
+            num_blocks,
+        )?;
 
-                let mut responses = region.region_read(
+        /*
+         * Go through the chunk a run of written or unwritten blocks at a
+         * time.
+         */
+        let mut i = 0;
+        while i < written.len() {
+            let run = written[i..]
+                .iter()
+                .take_while(|w| **w == written[i])
+                .count() as u64;
+
+            if written[i] {
+                let responses = region.region_read(
                     &[ReadRequest {
-                        eid: eid as u64,
+                        eid,
                         offset: Block::new_with_ddef(
-                            block_offset,
+                            offset + i as u64,
                             &region.def(),
                         ),
+                        num_blocks: run,
                     }],
                     0,
                 )?;
-                let response = responses.pop().unwrap();
-
-                out_file.write_all(&response.data).unwrap();
 
-                if blocks_copied >= count {
-                    break 'eid_loop;
+                for data in responses[0].data.chunks(block_size as usize) {
+                    image.write_block(data)?;
                 }
+                read_blocks += run;
+            } else {
+                image.write_zeroes(run * block_size)?;
             }
+
+            i += run as usize;
         }
+
+        block += num_blocks;
     }
+    image.finish()?;
 
-    println!("Read and wrote out {} blocks", blocks_copied);
+    println!(
+        "Wrote out {} blocks, {} of them read from the region",
+        count, read_blocks
+    );
 
     Ok(())
 }
 
+/*
+ * Where an import keeps how far it has got, so one that is interrupted
+ * can be resumed.  It is only there while an import is running, or after
+ * one that did not finish.
+ */
+fn import_checkpoint_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+    dir.as_ref().join("import.json")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportCheckpoint {
+    /*
+     * Everything in the image before this byte is flushed to the region.
+     * This is always at the end of an extent.
+     */
+    bytes: u64,
+}
+
+/*
+ * How often an import says how far it has got.
+ */
+const IMPORT_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/*
+ * What an import reads the image from.
+ */
+enum ImportSource {
+    /*
+     * A raw image, from a file or a pipe, which we read front to back.
+     */
+    Raw(Box<dyn Read>),
+    Qcow2(image::Qcow2Reader),
+}
+
+/*
+ * Open the image at import_path, or stdin if that is "-", ready to read
+ * from start.  Also return how big the image is, if we can know.
+ */
+fn open_import<P: AsRef<Path> + std::fmt::Debug>(
+    import_path: P,
+    start: u64,
+) -> Result<(ImportSource, Option<u64>)> {
+    if import_path.as_ref() != Path::new("-") {
+        let mut f = File::open(&import_path)?;
+        if image::is_qcow2(&f)? {
+            println!("Importing {:?} as a qcow2 image", import_path);
+            let image = image::Qcow2Reader::open(f)?;
+            let size = image.size();
+            return Ok((ImportSource::Qcow2(image), Some(size)));
+        }
+
+        let size = f.metadata()?.len();
+        f.seek(SeekFrom::Start(start))?;
+        return Ok((ImportSource::Raw(Box::new(f)), Some(size)));
+    }
+
+    /*
+     * We can't go back and forth in a pipe like we need to for qcow2, so
+     * only raw images can come this way.
+     */
+    let mut stdin = std::io::stdin();
+    let mut magic = vec![0u8; 4];
+    let n = read_full(&mut stdin, &mut magic)?;
+    magic.truncate(n);
+    if image::has_qcow2_magic(&magic) {
+        bail!("A qcow2 image can only be imported from a file, not stdin");
+    }
+    println!("Importing a raw image from stdin");
+    let mut input = std::io::Cursor::new(magic).chain(stdin);
+
+    /*
+     * The image should be the same one again, so skip what we already
+     * have of it.
+     */
+    let skipped =
+        std::io::copy(&mut input.by_ref().take(start), &mut std::io::sink())?;
+    if skipped != start {
+        bail!(
+            "Image ended after {} bytes, before where the import got to",
+            skipped
+        );
+    }
+
+    Ok((ImportSource::Raw(Box::new(input)), None))
+}
+
 /*
  * Import the contents of a file into a new Region.
+ * The file can be a raw image, or a qcow2 image, which we detect.  An
+ * import_path of "-" reads a raw image from stdin.
  * The total size of the region will be rounded up to the next largest
  * extent multiple.
+ * Blocks of the file that are all zeros are left unwritten, as that is
+ * what an unwritten block reads back as anyway, so the region only takes
+ * up space for the rest.
+ *
+ * Each extent is flushed once it is imported, and we keep track of how
+ * far that has got.  With resume, an import that was interrupted picks
+ * up again from there, given the same image.
  */
 pub fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
     region: &mut Region,
     import_path: P,
+    resume: bool,
+) -> Result<()> {
+    let checkpoint_path = import_checkpoint_path(&region.dir);
+    let start = if resume {
+        match read_json_maybe::<_, ImportCheckpoint>(&checkpoint_path)? {
+            Some(checkpoint) => checkpoint.bytes,
+            None => {
+                bail!("No interrupted import to resume in {:?}", region.dir)
+            }
+        }
+    } else {
+        0
+    };
+
+    let (source, file_size) = open_import(&import_path, start)?;
+    println!("Importing {:?} to region", import_path);
+    import_image(region, source, file_size, start)?;
+
+    std::fs::remove_file(&checkpoint_path)?;
+    Ok(())
+}
+
+fn import_image(
+    region: &mut Region,
+    mut source: ImportSource,
+    file_size: Option<u64>,
+    start: u64,
 ) -> Result<()> {
-    /*
-     * Open the file to import and determine how many extents we will need
-     * based on the length.
-     */
-    let mut f = File::open(&import_path)?;
-    let file_size = f.metadata()?.len();
     let (_, extent_size, _) = region.region_def();
     let space_per_extent = extent_size.byte_value();
+    assert_eq!(start % space_per_extent, 0);
 
-    let mut extents_needed = file_size / space_per_extent;
-    if file_size % space_per_extent != 0 {
-        extents_needed += 1;
-    }
-    println!(
-        "Import file_size: {}  Extent size: {}  Needed extents: {}",
-        file_size, space_per_extent, extents_needed
-    );
+    /*
+     * Determine how many extents we will need based on the length, if we
+     * know it.  If we don't, we add them as the data comes in.
+     */
+    if let Some(file_size) = file_size {
+        let mut extents_needed = file_size / space_per_extent;
+        if file_size % space_per_extent != 0 {
+            extents_needed += 1;
+        }
+        println!(
+            "Import file_size: {}  Extent size: {}  Needed extents: {}",
+            file_size, space_per_extent, extents_needed
+        );
 
-    if extents_needed > region.def().extent_count().into() {
-        /*
-         * The file to import would require more extents than we have.
-         * Extend the region to fit the file.
-         */
-        println!("Extending region to fit image");
-        region.extend(extents_needed as u32)?;
-    } else {
-        println!("Region already large enough for image");
+        if extents_needed > region.def().extent_count().into() {
+            /*
+             * The file to import would require more extents than we
+             * have.  Extend the region to fit the file.
+             */
+            println!("Extending region to fit image");
+            region.extend(extents_needed as u32)?;
+        } else {
+            println!("Region already large enough for image");
+        }
     }
 
-    println!("Importing {:?} to region", import_path);
-    let rm = region.def();
+    let mut checkpoint = ImportCheckpoint { bytes: start };
+    let checkpoint_path = import_checkpoint_path(&region.dir);
+    write_json(&checkpoint_path, &checkpoint, true)?;
+    if start > 0 {
+        println!(
+            "Resuming import after {} extents ({} bytes)",
+            start / space_per_extent,
+            start
+        );
+    }
 
     /*
      * We want to read and write large chunks of data, rather than individual
@@ -169,40 +404,32 @@ pub fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
     const CHUNK_SIZE: usize = 32 * 1024 * 1024;
     assert_eq!(CHUNK_SIZE % MAX_BLOCK_SIZE, 0);
 
-    let mut offset = Block::new_with_ddef(0, &region.def());
+    let started = std::time::Instant::now();
+    let mut reported = started;
+
+    let mut offset =
+        Block::new_with_ddef(start / region.def().block_size(), &region.def());
     loop {
         let mut buffer = vec![0; CHUNK_SIZE];
 
         /*
          * Read data into the buffer until it is full, or we hit EOF.
          */
-        let mut total = 0;
-        loop {
-            assert!(total <= CHUNK_SIZE);
-            if total == CHUNK_SIZE {
-                break;
-            }
-
-            /*
-             * Rust's read guarantees that if it returns Ok(n) then
-             * `0 <= n <= buffer.len()`. We have to repeatedly read until our
-             * buffer is full.
-             */
-            let n = f.read(&mut buffer[total..])?;
-
-            if n == 0 {
-                /*
-                 * We have hit EOF.  Extend the read buffer with zeroes until
-                 * it is a multiple of the block size.
-                 */
-                while !Block::is_valid_byte_size(total, &rm) {
-                    buffer[total] = 0;
-                    total += 1;
-                }
-                break;
+        let mut total = match &mut source {
+            ImportSource::Qcow2(image) => {
+                image.read_at(offset.byte_value(), &mut buffer)?
             }
+            ImportSource::Raw(f) => read_full(f, &mut buffer)?,
+        };
 
-            total += n;
+        /*
+         * If we hit EOF, extend the read buffer with zeroes until it is a
+         * multiple of the block size.
+         */
+        let rm = region.def();
+        while !Block::is_valid_byte_size(total, &rm) {
+            buffer[total] = 0;
+            total += 1;
         }
 
         if total == 0 {
@@ -212,6 +439,13 @@ pub fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
             break;
         }
 
+        let end = offset.byte_value() + total as u64;
+        let extents_needed = (end + space_per_extent - 1) / space_per_extent;
+        if extents_needed > rm.extent_count().into() {
+            region.extend(extents_needed as u32)?;
+        }
+        let rm = region.def();
+
         /*
          * Use the same function upstairs uses to decide where to put the
          * data based on the LBA offset.
@@ -222,6 +456,11 @@ pub fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
         for (eid, offset) in extent_from_offset(rm, offset, nblocks)? {
             let len = Block::new_with_ddef(1, &region.def());
             let data = &buffer[pos.bytes()..(pos.bytes() + len.bytes())];
+            if data.iter().all(|b| *b == 0) {
+                pos.advance(len);
+                continue;
+            }
+
             let mut buffer = BytesMut::with_capacity(data.len());
             buffer.resize(data.len(), 0);
             buffer.copy_from_slice(data);
@@ -238,13 +477,48 @@ pub fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
         }
 
         // We have no job ID, so it makes no sense for accounting.
-        region.region_write(&writes, 0, false)?;
+        if !writes.is_empty() {
+            region.region_write(&writes, 0, false)?;
+        }
 
         assert_eq!(nblocks, pos);
         assert_eq!(total, pos.bytes());
         offset.advance(nblocks);
+
+        /*
+         * Once we are done with an extent, flush it, and note that it
+         * need not be imported again.
+         */
+        let done = offset.byte_value() / space_per_extent * space_per_extent;
+        if done > checkpoint.bytes {
+            region.region_flush(1, 0, &None, 0)?;
+            checkpoint.bytes = done;
+            write_json(&checkpoint_path, &checkpoint, true)?;
+        }
+
+        if reported.elapsed() >= IMPORT_PROGRESS_INTERVAL {
+            print_import_progress(
+                offset.byte_value(),
+                file_size,
+                offset.byte_value() - start,
+                started.elapsed(),
+            );
+            reported = std::time::Instant::now();
+        }
     }
 
+    /*
+     * Whatever is left of the last extent has to be on disk too before
+     * the import is done.
+     */
+    region.region_flush(1, 0, &None, 0)?;
+    print_import_progress(
+        offset.byte_value(),
+        file_size,
+        offset.byte_value() - start,
+        started.elapsed(),
+    );
+
     /*
      * As there is no EOF indication in the downstairs, print the
      * number of total blocks we wrote to so the caller can, if they
@@ -252,7 +526,7 @@ pub fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
      */
     println!(
         "Populated {} extents by copying {} bytes ({} blocks)",
-        extents_needed,
+        (offset.byte_value() + space_per_extent - 1) / space_per_extent,
         offset.byte_value(),
         offset.value,
     );
@@ -260,6 +534,47 @@ pub fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
     Ok(())
 }
 
+fn print_import_progress(
+    done: u64,
+    file_size: Option<u64>,
+    this_run: u64,
+    elapsed: Duration,
+) {
+    let rate = this_run as f64 / elapsed.as_secs_f64().max(0.001) / 1048576.0;
+    match file_size {
+        Some(size) if size > 0 => println!(
+            "Imported {} of {} bytes ({:.1}%) at {:.1} MiB/s",
+            done,
+            size,
+            done.min(size) as f64 * 100.0 / size as f64,
+            rate,
+        ),
+        _ => println!("Imported {} bytes at {:.1} MiB/s", done, rate),
+    }
+}
+
+/*
+ * Read from f until buf is full or we hit EOF, and return how much we
+ * read.
+ */
+fn read_full<R: Read>(f: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        /*
+         * Rust's read guarantees that if it returns Ok(n) then
+         * `0 <= n <= buffer.len()`. We have to repeatedly read until our
+         * buffer is full.
+         */
+        let n = f.read(&mut buf[total..])?;
+        if n == 0 {
+            break;
+        }
+        total += n;
+    }
+
+    Ok(total)
+}
+
 /*
  * Debug function to dump the work list.
  */
@@ -316,6 +631,27 @@ pub async fn show_work(ds: &mut Downstairs) {
                         dsw_type = "WriteU".to_string();
                         dep_list = dependencies.to_vec();
                     }
+                    IOop::Discard {
+                        dependencies,
+                        discards: _,
+                    } => {
+                        dsw_type = "Discard".to_string();
+                        dep_list = dependencies.to_vec();
+                    }
+                    IOop::WriteZeroes {
+                        dependencies,
+                        zeroes: _,
+                    } => {
+                        dsw_type = "WriteZ".to_string();
+                        dep_list = dependencies.to_vec();
+                    }
+                    IOop::CompareAndWrite {
+                        dependencies,
+                        writes: _,
+                    } => {
+                        dsw_type = "CmpWr".to_string();
+                        dep_list = dependencies.to_vec();
+                    }
                 };
                 println!(
                     "DSW:[{:04}] {:>05} {:>05} deps:{:?}",
@@ -338,18 +674,30 @@ pub mod cdt {
     fn submit__writeunwritten__start(_: u64) {}
     fn submit__write__start(_: u64) {}
     fn submit__flush__start(_: u64) {}
+    fn submit__discard__start(_: u64) {}
+    fn submit__writezeroes__start(_: u64) {}
+    fn submit__compareandwrite__start(_: u64) {}
     fn os__read__start(_: u64) {}
     fn os__writeunwritten__start(_: u64) {}
     fn os__write__start(_: u64) {}
     fn os__flush__start(_: u64) {}
+    fn os__discard__start(_: u64) {}
+    fn os__writezeroes__start(_: u64) {}
+    fn os__compareandwrite__start(_: u64) {}
     fn os__read__done(_: u64) {}
     fn os__writeunwritten__done(_: u64) {}
     fn os__write__done(_: u64) {}
     fn os__flush__done(_: u64) {}
+    fn os__discard__done(_: u64) {}
+    fn os__writezeroes__done(_: u64) {}
+    fn os__compareandwrite__done(_: u64) {}
     fn submit__read__done(_: u64) {}
     fn submit__writeunwritten__done(_: u64) {}
     fn submit__write__done(_: u64) {}
     fn submit__flush__done(_: u64) {}
+    fn submit__discard__done(_: u64) {}
+    fn submit__writezeroes__done(_: u64) {}
+    fn submit__compareandwrite__done(_: u64) {}
 }
 /*
  * A new IO request has been received.
@@ -360,12 +708,38 @@ async fn proc_frame<WT>(
     upstairs_connection: UpstairsConnection,
     ad: &mut Arc<Mutex<Downstairs>>,
     m: &Message,
+    trace: Option<TraceContext>,
     fw: &mut Arc<Mutex<FramedWrite<WT, CrucibleEncoder>>>,
     job_channel_tx: &Arc<Mutex<Sender<u64>>>,
 ) -> Result<()>
 where
     WT: tokio::io::AsyncWrite + std::marker::Unpin + std::marker::Send,
 {
+    /*
+     * An upstairs without Features::READ_RANGES asks for one block per
+     * request, which as far as the work goes is a range of one block.
+     */
+    let range_read;
+    let m = if let Message::ReadRequest {
+        upstairs_id,
+        session_id,
+        job_id,
+        dependencies,
+        requests,
+    } = m
+    {
+        range_read = Message::ReadRangeRequest {
+            upstairs_id: *upstairs_id,
+            session_id: *session_id,
+            job_id: *job_id,
+            dependencies: dependencies.clone(),
+            requests: requests.iter().cloned().map(ReadRequest::from).collect(),
+        };
+        &range_read
+    } else {
+        m
+    };
+
     let new_ds_id = match m {
         Message::Write {
             upstairs_id,
@@ -398,7 +772,8 @@ where
             };
 
             let mut d = ad.lock().await;
-            d.add_work(upstairs_connection, *job_id, new_write).await?;
+            d.add_work(upstairs_connection, *job_id, new_write, trace)
+                .await?;
             Some(*job_id)
         }
         Message::Flush {
@@ -436,7 +811,8 @@ where
             };
 
             let mut d = ad.lock().await;
-            d.add_work(upstairs_connection, *job_id, new_flush).await?;
+            d.add_work(upstairs_connection, *job_id, new_flush, trace)
+                .await?;
             Some(*job_id)
         }
         Message::WriteUnwritten {
@@ -470,10 +846,11 @@ where
             };
 
             let mut d = ad.lock().await;
-            d.add_work(upstairs_connection, *job_id, new_write).await?;
+            d.add_work(upstairs_connection, *job_id, new_write, trace)
+                .await?;
             Some(*job_id)
         }
-        Message::ReadRequest {
+        Message::ReadRangeRequest {
             upstairs_id,
             session_id,
             job_id,
@@ -504,7 +881,118 @@ where
             };
 
             let mut d = ad.lock().await;
-            d.add_work(upstairs_connection, *job_id, new_read).await?;
+            d.add_work(upstairs_connection, *job_id, new_read, trace)
+                .await?;
+            Some(*job_id)
+        }
+        Message::Discard {
+            upstairs_id,
+            session_id,
+            job_id,
+            dependencies,
+            discards,
+        } => {
+            if upstairs_connection.upstairs_id != *upstairs_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.upstairs_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            if upstairs_connection.session_id != *session_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.session_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            cdt::submit__discard__start!(|| *job_id);
+
+            let new_discard = IOop::Discard {
+                dependencies: dependencies.to_vec(),
+                discards: discards.to_vec(),
+            };
+
+            let mut d = ad.lock().await;
+            d.add_work(upstairs_connection, *job_id, new_discard, trace)
+                .await?;
+            Some(*job_id)
+        }
+        Message::WriteZeroes {
+            upstairs_id,
+            session_id,
+            job_id,
+            dependencies,
+            zeroes,
+        } => {
+            if upstairs_connection.upstairs_id != *upstairs_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.upstairs_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            if upstairs_connection.session_id != *session_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.session_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            cdt::submit__writezeroes__start!(|| *job_id);
+
+            let new_write_zeroes = IOop::WriteZeroes {
+                dependencies: dependencies.to_vec(),
+                zeroes: zeroes.to_vec(),
+            };
+
+            let mut d = ad.lock().await;
+            d.add_work(upstairs_connection, *job_id, new_write_zeroes, trace)
+                .await?;
+            Some(*job_id)
+        }
+        Message::CompareAndWrite {
+            upstairs_id,
+            session_id,
+            job_id,
+            dependencies,
+            writes,
+        } => {
+            if upstairs_connection.upstairs_id != *upstairs_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.upstairs_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            if upstairs_connection.session_id != *session_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.session_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            cdt::submit__compareandwrite__start!(|| *job_id);
+
+            let new_compare_and_write = IOop::CompareAndWrite {
+                dependencies: dependencies.to_vec(),
+                writes: writes.to_vec(),
+            };
+
+            let mut d = ad.lock().await;
+            d.add_work(
+                upstairs_connection,
+                *job_id,
+                new_compare_and_write,
+                trace,
+            )
+            .await?;
             Some(*job_id)
         }
         Message::ExtentFlush {
@@ -520,7 +1008,8 @@ where
             );
             let msg = {
                 let d = ad.lock().await;
-                match d.region.region_flush_extent(
+                let region = d.region.read().await;
+                match region.region_flush_extent(
                     *extent_id,
                     *flush_number,
                     *gen_number,
@@ -540,14 +1029,26 @@ where
             fw.send(msg).await?;
             return Ok(());
         }
+        Message::SpaceUsagePlease => {
+            /*
+             * Looking at every file in the region can take a while, so
+             * don't hold up the Downstairs for it.
+             */
+            let region = ad.lock().await.region.clone();
+            let result = region.read().await.space_usage();
+            let mut fw = fw.lock().await;
+            fw.send(Message::SpaceUsage { result }).await?;
+            return Ok(());
+        }
         Message::ExtentClose {
             repair_id,
             extent_id,
         } => {
             println!("{} Close extent {}", repair_id, extent_id);
             let msg = {
-                let mut d = ad.lock().await;
-                match d.region.extents.get_mut(*extent_id) {
+                let d = ad.lock().await;
+                let mut region = d.region.write().await;
+                match region.extents.get_mut(*extent_id) {
                     Some(ext) => {
                         ext.close()?;
                         Message::RepairAckId {
@@ -581,9 +1082,9 @@ where
                 dest_clients
             );
             let msg = {
-                let mut d = ad.lock().await;
-                match d
-                    .region
+                let d = ad.lock().await;
+                let mut region = d.region.write().await;
+                match region
                     .repair_extent(*extent_id, *source_repair_address)
                     .await
                 {
@@ -607,8 +1108,9 @@ where
         } => {
             println!("{} Reopen extent {}", repair_id, extent_id);
             let msg = {
-                let mut d = ad.lock().await;
-                match d.region.reopen_extent(*extent_id) {
+                let d = ad.lock().await;
+                let mut region = d.region.write().await;
+                match region.reopen_extent(*extent_id) {
                     Ok(()) => Message::RepairAckId {
                         repair_id: *repair_id,
                     },
@@ -641,14 +1143,35 @@ async fn do_work_task<T>(
     upstairs_connection: UpstairsConnection,
     mut job_channel_rx: Receiver<u64>,
     fw: &mut Arc<Mutex<FramedWrite<T, CrucibleEncoder>>>,
+    features: Features,
 ) -> Result<()>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
     /*
-     * job_channel_rx is a notification that we should look for new work.
+     * Jobs we have started but not yet answered.  Each does its IO on the
+     * blocking thread pool, so a slow job does not hold up jobs for other
+     * extents.
      */
-    while job_channel_rx.recv().await.is_some() {
+    let mut running = FuturesUnordered::new();
+
+    loop {
+        /*
+         * job_channel_rx is a notification that we should look for new
+         * work.  A job finishing is too, as other jobs may be waiting for
+         * it.
+         */
+        tokio::select! {
+            n = job_channel_rx.recv() => {
+                if n.is_none() {
+                    break;
+                }
+            }
+            Some(r) = running.next() => {
+                r?;
+            }
+        }
+
         // Add a little time to completion for this operation.
         if ads.lock().await.lossy && random() && random() {
             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -691,7 +1214,8 @@ where
             /*
              * If this job is still new, take it and go to work. The
              * in_progress method will only return a job if all
-             * dependencies are met.
+             * dependencies are met, and no running job is using the
+             * extents it changes.
              */
             let job_id = ads
                 .lock()
@@ -699,26 +1223,20 @@ where
                 .in_progress(upstairs_connection, *new_id)
                 .await?;
             if let Some(job_id) = job_id {
-                let m = ads
+                let job = ads
                     .lock()
                     .await
-                    .do_work(upstairs_connection, job_id)
+                    .start_work(upstairs_connection, job_id)
                     .await?;
 
-                if let Some(m) = m {
-                    ads.lock()
-                        .await
-                        .complete_work_stat(upstairs_connection, &m, job_id)
-                        .await?;
-                    // Notify the upstairs before completing work
-                    let mut fw = fw.lock().await;
-                    fw.send(&m).await?;
-                    drop(fw);
-
-                    ads.lock()
-                        .await
-                        .complete_work(upstairs_connection, job_id, m)
-                        .await?;
+                if let Some(job) = job {
+                    running.push(finish_work(
+                        ads.clone(),
+                        fw.clone(),
+                        upstairs_connection,
+                        job,
+                        features,
+                    ));
                 }
             }
         }
@@ -728,30 +1246,118 @@ where
     Ok(())
 }
 
+/*
+ * Do the work for a job, then answer the upstairs and take the job off
+ * the work list.
+ */
+async fn finish_work<T>(
+    ads: Arc<Mutex<Downstairs>>,
+    fw: Arc<Mutex<FramedWrite<T, CrucibleEncoder>>>,
+    upstairs_connection: UpstairsConnection,
+    job: ReadyJob,
+    features: Features,
+) -> Result<()>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let job_id = job.job_id;
+    let disconnect = job.injection.disconnect;
+    let m = tokio::task::spawn_blocking(move || job.run()).await?;
+
+    if disconnect {
+        bail!("injected fault: disconnect instead of answering {}", job_id);
+    }
+
+    ads.lock()
+        .await
+        .complete_work_stat(upstairs_connection, &m, job_id)
+        .await?;
+
+    let m = if features.contains(Features::READ_RANGES) {
+        m
+    } else {
+        block_read_response(m)
+    };
+
+    // Notify the upstairs before completing work
+    let mut fw = fw.lock().await;
+    fw.send(&m).await?;
+    drop(fw);
+
+    ads.lock()
+        .await
+        .complete_work(upstairs_connection, job_id, m)
+        .await?;
+
+    Ok(())
+}
+
+/*
+ * An upstairs without Features::READ_RANGES wants every block it read
+ * back in a response of its own.
+ */
+fn block_read_response(m: Message) -> Message {
+    match m {
+        Message::ReadRangeResponse {
+            upstairs_id,
+            session_id,
+            job_id,
+            responses,
+        } => Message::ReadResponse {
+            upstairs_id,
+            session_id,
+            job_id,
+            responses: responses.map(|responses| {
+                responses
+                    .into_iter()
+                    .flat_map(ReadResponse::into_blocks)
+                    .collect()
+            }),
+        },
+        m => m,
+    }
+}
+
 async fn proc_stream(
     ads: &mut Arc<Mutex<Downstairs>>,
     stream: WrappedStream,
 ) -> Result<()> {
+    let mut decoder = CrucibleDecoder::new();
+    let mut encoder = CrucibleEncoder::new();
+
+    let (capture_dir, region_uuid) = {
+        let ds = ads.lock().await;
+        let region_uuid = ds.region.read().await.def().uuid();
+        (ds.capture_dir.clone(), region_uuid)
+    };
+    if let Some(capture_dir) = capture_dir {
+        let name = format!("downstairs-{}", region_uuid);
+        match Recorder::create_in(&capture_dir, &name, Side::Downstairs) {
+            Ok(recorder) => {
+                let recorder = Arc::new(recorder);
+                decoder.set_recorder(recorder.clone());
+                encoder.set_recorder(recorder);
+            }
+            Err(e) => {
+                println!("not capturing this connection: {:?}", e);
+            }
+        }
+    }
+
     match stream {
         WrappedStream::Http(sock) => {
             let (read, write) = sock.into_split();
 
-            let fr = FramedRead::new(read, CrucibleDecoder::new());
-            let fw = Arc::new(Mutex::new(FramedWrite::new(
-                write,
-                CrucibleEncoder::new(),
-            )));
+            let fr = FramedRead::new(read, decoder);
+            let fw = Arc::new(Mutex::new(FramedWrite::new(write, encoder)));
 
             proc(ads, fr, fw).await
         }
         WrappedStream::Https(stream) => {
             let (read, write) = tokio::io::split(stream);
 
-            let fr = FramedRead::new(read, CrucibleDecoder::new());
-            let fw = Arc::new(Mutex::new(FramedWrite::new(
-                write,
-                CrucibleEncoder::new(),
-            )));
+            let fr = FramedRead::new(read, decoder);
+            let fw = Arc::new(Mutex::new(FramedWrite::new(write, encoder)));
 
             proc(ads, fr, fw).await
         }
@@ -785,6 +1391,7 @@ where
 {
     let mut negotiated = 0;
     let mut upstairs_connection: Option<UpstairsConnection> = None;
+    let mut features = Features::empty();
 
     let (_another_upstairs_active_tx, mut another_upstairs_active_rx) =
         channel::<UpstairsConnection>(1);
@@ -898,14 +1505,19 @@ where
                         gen,
                         read_only,
                         encrypted,
+                        features: offered_features,
                     }) => {
                         if negotiated != 0 {
                             bail!("Received connect out of order {}",
                                 negotiated);
                         }
 
-                        if version != 1 {
-                            bail!("expected version 1, got {}", version);
+                        if version != CRUCIBLE_MESSAGE_VERSION {
+                            bail!(
+                                "expected version {}, got {}",
+                                CRUCIBLE_MESSAGE_VERSION,
+                                version
+                            );
                         }
 
                         // Reject an Upstairs negotiation if there is a mismatch
@@ -936,17 +1548,57 @@ where
                             }
                         }
 
+                        /*
+                         * Only use what both sides know about.  Whatever
+                         * we send back here is what this connection uses.
+                         */
+                        features = offered_features
+                            .intersection(Features::supported());
+
+                        /*
+                         * Compress with what this downstairs was told to
+                         * use, or not at all if the upstairs can't do that.
+                         */
+                        let compression = ads.lock().await.compression;
+                        features.remove(Features::COMPRESS_LZ4);
+                        features.remove(Features::COMPRESS_ZSTD);
+                        if offered_features.contains(compression.feature()) {
+                            features.insert(compression.feature());
+                        }
+
+                        /*
+                         * A compare-and-write compares plaintext, which an
+                         * encrypted region never sees.
+                         */
+                        if encrypted {
+                            features.remove(Features::COMPARE_AND_WRITE);
+                        }
+
                         negotiated = 1;
                         upstairs_connection = Some(UpstairsConnection {
                             upstairs_id,
                             session_id,
                             gen,
                         });
-                        println!("upstairs {:?} connected",
-                            upstairs_connection.unwrap());
+                        println!("upstairs {:?} connected, features {}",
+                            upstairs_connection.unwrap(), features);
 
                         let mut fw = fw.lock().await;
-                        fw.send(Message::YesItsMe { version: 1 }).await?;
+                        fw.send(Message::YesItsMe {
+                            version: CRUCIBLE_MESSAGE_VERSION,
+                            features,
+                        }).await?;
+                        fw.encoder_mut().set_compression(
+                            Compression::from_features(features)?);
+
+                        /*
+                         * Everything after YesItsMe, both ways, carries
+                         * a checksum if we agreed to that.
+                         */
+                        let checksum =
+                            features.contains(Features::FRAME_CHECKSUM);
+                        fw.encoder_mut().set_checksum(checksum);
+                        fr.decoder_mut().set_require_checksum(checksum);
                     }
                     Some(Message::PromoteToActive {
                         upstairs_id,
@@ -1021,7 +1673,8 @@ where
                         negotiated = 3;
                         let region_def = {
                             let ds = ads.lock().await;
-                            ds.region.def()
+                            let region = ds.region.read().await;
+                            region.def()
                         };
 
                         let mut fw = fw.lock().await;
@@ -1061,9 +1714,11 @@ where
                         }
                         negotiated = 4;
                         let ds = ads.lock().await;
-                        let flush_numbers = ds.region.flush_numbers()?;
-                        let gen_numbers = ds.region.gen_numbers()?;
-                        let dirty_bits = ds.region.dirty()?;
+                        let region = ds.region.read().await;
+                        let flush_numbers = region.flush_numbers()?;
+                        let gen_numbers = region.gen_numbers()?;
+                        let dirty_bits = region.dirty()?;
+                        drop(region);
                         drop(ds);
 
                         let mut fw = fw.lock().await;
@@ -1087,12 +1742,22 @@ where
         }
     }
 
-    println!("Downstairs has completed Negotiation");
+    println!(
+        "Downstairs has completed Negotiation, features {}",
+        features
+    );
     assert!(upstairs_connection.is_some());
     let upstairs_connection = upstairs_connection.unwrap();
 
-    resp_loop(ads, fr, fw, another_upstairs_active_rx, upstairs_connection)
-        .await
+    resp_loop(
+        ads,
+        fr,
+        fw,
+        another_upstairs_active_rx,
+        upstairs_connection,
+        features,
+    )
+    .await
 }
 
 /*
@@ -1106,6 +1771,7 @@ async fn resp_loop<RT, WT>(
     fw: Arc<Mutex<FramedWrite<WT, CrucibleEncoder>>>,
     mut another_upstairs_active_rx: mpsc::Receiver<UpstairsConnection>,
     upstairs_connection: UpstairsConnection,
+    features: Features,
 ) -> Result<()>
 where
     RT: tokio::io::AsyncRead + std::marker::Unpin + std::marker::Send,
@@ -1116,6 +1782,12 @@ where
 {
     let mut lossy_interval = deadline_secs(5);
 
+    /*
+     * Only an upstairs that knows the region can grow hears about it.
+     */
+    let grow = features.contains(Features::REGION_GROWTH);
+    let mut region_def_rx = ads.lock().await.region_def_tx.subscribe();
+
     // XXX flow control size to double what Upstairs has for upper limit?
     let (_job_channel_tx, job_channel_rx) = channel(200);
     let job_channel_tx = Arc::new(Mutex::new(_job_channel_tx));
@@ -1144,6 +1816,7 @@ where
                 upstairs_connection,
                 job_channel_rx,
                 &mut fwc,
+                features,
             )
             .await
         })
@@ -1155,10 +1828,32 @@ where
         let tx = job_channel_tx.clone();
         let mut fwc = fw.clone();
         tokio::spawn(async move {
+            /*
+             * A JobTrace comes just ahead of the job it is for.
+             */
+            let mut job_trace = None;
             while let Some(m) = message_channel_rx.recv().await {
-                if let Err(e) =
-                    proc_frame(upstairs_connection, &mut adc, &m, &mut fwc, &tx)
-                        .await
+                if let Message::JobTrace { job_id, trace, .. } = m {
+                    job_trace = Some((job_id, trace));
+                    continue;
+                }
+
+                let trace = match job_trace.take() {
+                    Some((job_id, trace)) if Some(job_id) == io_job_id(&m) => {
+                        Some(trace)
+                    }
+                    _ => None,
+                };
+
+                if let Err(e) = proc_frame(
+                    upstairs_connection,
+                    &mut adc,
+                    &m,
+                    trace,
+                    &mut fwc,
+                    &tx,
+                )
+                .await
                 {
                     bail!("Proc frame returns error: {}", e);
                 }
@@ -1203,6 +1898,20 @@ where
                 bail!("inactivity timeout");
             }
 
+            /*
+             * The region grew while we were connected.
+             */
+            Ok(()) = region_def_rx.changed(), if grow => {
+                let region_def = *region_def_rx.borrow();
+                println!(
+                    "Region grown to {} extents",
+                    region_def.extent_count()
+                );
+
+                let mut fw = fw.lock().await;
+                fw.send(Message::RegionGrown { region_def }).await?;
+            }
+
             /*
              * This Upstairs' thread will receive this signal when another
              * Upstairs promotes itself to active. The only way this path is
@@ -1304,13 +2013,36 @@ pub struct ActiveUpstairs {
  */
 #[derive(Debug)]
 pub struct Downstairs {
-    pub region: Region,
+    /*
+     * Jobs take the region for reading while they do their IO, without
+     * holding the Downstairs lock.  Anything that opens, closes, or
+     * replaces extents takes it for writing, and so waits for them.
+     */
+    pub region: Arc<RwLock<Region>>,
     lossy: bool,         // Test flag, enables pauses and skipped jobs
     return_errors: bool, // Test flag
     active_upstairs: HashMap<Uuid, ActiveUpstairs>,
     dss: DsStatOuter,
     read_only: bool,
     encrypted: bool,
+    compression: Compression,
+    capture_dir: Option<PathBuf>,
+
+    /*
+     * The region definition as it was when it last changed, for every
+     * connection to pass on to its upstairs.
+     */
+    region_def_tx: watch::Sender<RegionDefinition>,
+
+    /*
+     * What the scrubber has found, if it is running.
+     */
+    scrub_report: Arc<std::sync::Mutex<ScrubReport>>,
+
+    /*
+     * Faults a test has asked us to inject into jobs.
+     */
+    faults: fault::FaultInjector,
 }
 
 impl Downstairs {
@@ -1320,23 +2052,71 @@ impl Downstairs {
         return_errors: bool,
         read_only: bool,
         encrypted: bool,
+        compression: Compression,
+        capture_dir: Option<PathBuf>,
     ) -> Self {
         let dss = DsStatOuter {
             ds_stat_wrap: Arc::new(Mutex::new(DsCountStat::new(
                 region.def().uuid(),
             ))),
         };
+        let (region_def_tx, _) = watch::channel(region.def());
         Downstairs {
-            region,
+            region: Arc::new(RwLock::new(region)),
             lossy,
             return_errors,
             active_upstairs: HashMap::new(),
             dss,
             read_only,
             encrypted,
+            compression,
+            capture_dir,
+            region_def_tx,
+            scrub_report: Arc::new(std::sync::Mutex::new(
+                ScrubReport::default(),
+            )),
+            faults: fault::FaultInjector::default(),
         }
     }
 
+    pub fn scrub_report(&self) -> ScrubReport {
+        self.scrub_report.lock().unwrap().clone()
+    }
+
+    /*
+     * Replace the faults we inject into jobs from now on.  An empty list
+     * turns fault injection off.
+     */
+    pub fn set_faults(&mut self, faults: Vec<Fault>) {
+        println!("Injecting faults: {:?}", faults);
+        self.faults.set(faults);
+    }
+
+    pub fn fault_report(&self) -> Vec<FaultReport> {
+        self.faults.report()
+    }
+
+    /*
+     * Add extents to the region while we are serving it, until it has
+     * extent_count of them, and tell each connected upstairs that can
+     * hear it what the region looks like now.  Jobs already running
+     * finish first, and new ones wait for this.
+     */
+    pub async fn extend_region(
+        &self,
+        extent_count: u32,
+    ) -> Result<RegionDefinition> {
+        let region_def = {
+            let mut region = self.region.write().await;
+            region.extend(extent_count)?;
+            region.def()
+        };
+
+        self.region_def_tx.send_replace(region_def);
+
+        Ok(region_def)
+    }
+
     /*
      * Only grab the lock if the UpstairsConnection matches.
      *
@@ -1421,12 +2201,17 @@ impl Downstairs {
         upstairs_connection: UpstairsConnection,
         ds_id: u64,
         work: IOop,
+        trace: Option<TraceContext>,
     ) -> Result<()> {
         // The Upstairs will send Flushes periodically, even in read only mode
         // we have to accept them. But read-only should never accept writes!
         if self.read_only {
             let is_write = match work {
-                IOop::Write { .. } | IOop::WriteUnwritten { .. } => true,
+                IOop::Write { .. }
+                | IOop::WriteUnwritten { .. }
+                | IOop::Discard { .. }
+                | IOop::WriteZeroes { .. }
+                | IOop::CompareAndWrite { .. } => true,
                 IOop::Read { .. } | IOop::Flush { .. } => false,
             };
 
@@ -1441,6 +2226,7 @@ impl Downstairs {
             ds_id,
             work,
             state: WorkState::New,
+            trace,
         };
 
         let mut work = self.work_lock(upstairs_connection).await?;
@@ -1484,15 +2270,13 @@ impl Downstairs {
         }
     }
 
-    // Given a job ID, do the work for that IO.
-    //
-    // This method calls into the Downstair's region and performs the read /
-    // write / flush action.
-    async fn do_work(
+    // Given a job ID, get what we need to do the work for that IO
+    // without holding the Downstairs lock.
+    async fn start_work(
         &mut self,
         upstairs_connection: UpstairsConnection,
         job_id: u64,
-    ) -> Result<Option<Message>> {
+    ) -> Result<Option<ReadyJob>> {
         let job = {
             let mut work = self.work_lock(upstairs_connection).await?;
             let job = work.get_ready_job(job_id).await;
@@ -1508,109 +2292,25 @@ impl Downstairs {
             job.unwrap()
         };
 
-        match &job.work {
-            IOop::Read {
-                dependencies: _dependencies,
-                requests,
-            } => {
-                /*
-                 * Any error from an IO should be intercepted here and passed
-                 * back to the upstairs.
-                 */
-                let responses = if self.return_errors && random() && random() {
-                    println!("returning error on read!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
-                } else if !self.is_active(job.upstairs_connection) {
-                    println!("Upstairs inactive error");
-                    Err(CrucibleError::UpstairsInactive)
-                } else {
-                    self.region.region_read(requests, job_id)
-                };
-
-                Ok(Some(Message::ReadResponse {
-                    upstairs_id: job.upstairs_connection.upstairs_id,
-                    session_id: job.upstairs_connection.session_id,
-                    job_id: job.ds_id,
-                    responses,
-                }))
-            }
-            IOop::WriteUnwritten {
-                dependencies: _dependencies,
-                writes,
-            } => {
-                /*
-                 * Any error from an IO should be intercepted here and passed
-                 * back to the upstairs.
-                 */
-                let result = if self.return_errors && random() && random() {
-                    println!("returning error on writeunwritten!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
-                } else if !self.is_active(job.upstairs_connection) {
-                    println!("Upstairs inactive error");
-                    Err(CrucibleError::UpstairsInactive)
-                } else {
-                    // The region_write will handle what happens to each block
-                    // based on if they have data or not.
-                    self.region.region_write(writes, job_id, true)
-                };
-
-                Ok(Some(Message::WriteUnwrittenAck {
-                    upstairs_id: job.upstairs_connection.upstairs_id,
-                    session_id: job.upstairs_connection.session_id,
-                    job_id: job.ds_id,
-                    result,
-                }))
-            }
-            IOop::Write {
-                dependencies: _dependencies,
-                writes,
-            } => {
-                let result = if self.return_errors && random() && random() {
-                    println!("returning error on write!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
-                } else if !self.is_active(job.upstairs_connection) {
-                    println!("Upstairs inactive error");
-                    Err(CrucibleError::UpstairsInactive)
-                } else {
-                    self.region.region_write(writes, job_id, false)
-                };
-
-                Ok(Some(Message::WriteAck {
-                    upstairs_id: job.upstairs_connection.upstairs_id,
-                    session_id: job.upstairs_connection.session_id,
-                    job_id: job.ds_id,
-                    result,
-                }))
-            }
-            IOop::Flush {
-                dependencies: _dependencies,
-                flush_number,
-                gen_number,
-                snapshot_details,
-            } => {
-                let result = if self.return_errors && random() && random() {
-                    println!("returning error on flush!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
-                } else if !self.is_active(job.upstairs_connection) {
-                    println!("Upstairs inactive error");
-                    Err(CrucibleError::UpstairsInactive)
-                } else {
-                    self.region.region_flush(
-                        *flush_number,
-                        *gen_number,
-                        snapshot_details,
-                        job_id,
-                    )
-                };
+        Ok(Some(ReadyJob {
+            active: self.is_active(job.upstairs_connection),
+            return_errors: self.return_errors,
+            injection: self.faults.check(&job.work),
+            region: self.region.clone().read_owned().await,
+            job_id,
+            job,
+        }))
+    }
 
-                Ok(Some(Message::FlushAck {
-                    upstairs_id: job.upstairs_connection.upstairs_id,
-                    session_id: job.upstairs_connection.session_id,
-                    job_id: job.ds_id,
-                    result,
-                }))
-            }
-        }
+    // Given a job ID, do the work for that IO.
+    #[cfg(test)]
+    async fn do_work(
+        &mut self,
+        upstairs_connection: UpstairsConnection,
+        job_id: u64,
+    ) -> Result<Option<Message>> {
+        let job = self.start_work(upstairs_connection, job_id).await?;
+        Ok(job.map(ReadyJob::run))
     }
 
     /*
@@ -1676,10 +2376,21 @@ impl Downstairs {
                 cdt::submit__writeunwritten__done!(|| ds_id);
                 self.dss.add_write().await;
             }
-            Message::ReadResponse { .. } => {
+            Message::ReadRangeResponse { .. } => {
                 cdt::submit__read__done!(|| ds_id);
                 self.dss.add_read().await;
             }
+            Message::DiscardAck { .. } => {
+                cdt::submit__discard__done!(|| ds_id);
+            }
+            Message::WriteZeroesAck { .. } => {
+                cdt::submit__writezeroes__done!(|| ds_id);
+                self.dss.add_write().await;
+            }
+            Message::CompareAndWriteAck { .. } => {
+                cdt::submit__compareandwrite__done!(|| ds_id);
+                self.dss.add_write().await;
+            }
             _ => (),
         }
 
@@ -1784,7 +2495,7 @@ impl Downstairs {
                     assert_eq!(self.active_upstairs.len(), 1);
 
                     // Re-open any closed extents
-                    self.region.reopen_all_extents()?;
+                    self.region.write().await.reopen_all_extents()?;
 
                     println!(
                         "{:?} is now active (read-write)",
@@ -1866,7 +2577,7 @@ impl Downstairs {
                     assert_eq!(self.active_upstairs.len(), 1);
 
                     // Re-open any closed extents
-                    self.region.reopen_all_extents()?;
+                    self.region.write().await.reopen_all_extents()?;
 
                     println!(
                         "{:?} is now active (read-write)",
@@ -1919,30 +2630,322 @@ impl Downstairs {
     }
 }
 
-/*
- * The structure that tracks downstairs work in progress
- */
-#[derive(Debug, Default)]
-pub struct Work {
-    active: HashMap<u64, DownstairsWork>,
-    outstanding_deps: HashMap<u64, usize>,
+/*
+ * The structure that tracks downstairs work in progress
+ */
+#[derive(Debug, Default)]
+pub struct Work {
+    active: HashMap<u64, DownstairsWork>,
+    outstanding_deps: HashMap<u64, usize>,
+
+    /*
+     * We have to keep track of all IOs that have been issued since
+     * our last flush, as that is how we make sure dependencies are
+     * respected. The last_flush is the downstairs job ID number (ds_id
+     * typically) for the most recent flush.
+     */
+    last_flush: u64,
+    completed: Vec<u64>,
+}
+
+#[derive(Debug, Clone)]
+struct DownstairsWork {
+    upstairs_connection: UpstairsConnection,
+    ds_id: u64,
+    work: IOop,
+    state: WorkState,
+
+    /*
+     * Where the upstairs was when it sent this job, so the work we do for
+     * it shows up in the same trace.
+     */
+    trace: Option<TraceContext>,
+}
+
+/*
+ * The extents a job reads, and the ones it changes.  Two jobs can run at
+ * the same time unless one changes an extent the other uses.  A flush
+ * changes every extent, but does not get in the way of reads.
+ */
+#[derive(Debug, Default, PartialEq)]
+struct ExtentUse {
+    reads: BTreeSet<u64>,
+    writes: BTreeSet<u64>,
+    writes_all: bool,
+}
+
+impl ExtentUse {
+    fn of(work: &IOop) -> ExtentUse {
+        let mut extents = ExtentUse::default();
+        match work {
+            IOop::Read { requests, .. } => {
+                extents.reads.extend(requests.iter().map(|r| r.eid));
+            }
+            IOop::Write { writes, .. }
+            | IOop::WriteUnwritten { writes, .. } => {
+                extents.writes.extend(writes.iter().map(|w| w.eid));
+            }
+            IOop::Flush { .. } => {
+                extents.writes_all = true;
+            }
+            IOop::Discard { discards, .. } => {
+                extents.writes.extend(discards.iter().map(|d| d.eid));
+            }
+            IOop::WriteZeroes { zeroes, .. } => {
+                extents.writes.extend(zeroes.iter().map(|z| z.eid));
+            }
+            IOop::CompareAndWrite { writes, .. } => {
+                /*
+                 * The compare and the write must see no other IO in
+                 * between, so this changes every extent it reads.
+                 */
+                extents.writes.extend(writes.iter().map(|w| w.eid));
+            }
+        }
+        extents
+    }
+
+    fn conflicts(&self, other: &ExtentUse) -> bool {
+        if self.writes_all {
+            return other.writes_all || !other.writes.is_empty();
+        }
+        if other.writes_all {
+            return !self.writes.is_empty();
+        }
+
+        self.writes
+            .iter()
+            .any(|eid| other.reads.contains(eid) || other.writes.contains(eid))
+            || other.writes.iter().any(|eid| self.reads.contains(eid))
+    }
+}
+
+/*
+ * A job that is ready to be done, with the region to do it on.  Doing it
+ * can block, so it happens off the async threads and without holding the
+ * Downstairs lock.
+ */
+struct ReadyJob {
+    job_id: u64,
+    job: DownstairsWork,
+    region: OwnedRwLockReadGuard<Region>,
+    active: bool,
+    return_errors: bool,
+    injection: fault::Injection,
+}
+
+impl ReadyJob {
+    // This method calls into the Downstair's region and performs the read /
+    // write / flush action.
+    fn run(self) -> Message {
+        /*
+         * Nothing below waits on anything, so it is fine to hold the span
+         * entered for the rest of this function.
+         */
+        let span = tracing::info_span!("do_work", job_id = self.job_id);
+        set_trace_parent(&span, self.job.trace);
+        let _guard = span.enter();
+
+        if !self.injection.delay.is_zero() {
+            println!("injecting {:?} delay", self.injection.delay);
+            std::thread::sleep(self.injection.delay);
+        }
+        let injected_error = self.injection.error.clone();
+
+        match &self.job.work {
+            IOop::Read {
+                dependencies: _dependencies,
+                requests,
+            } => {
+                /*
+                 * Any error from an IO should be intercepted here and passed
+                 * back to the upstairs.
+                 */
+                let mut responses = if let Some(e) = injected_error {
+                    println!("injecting error on read!");
+                    Err(e)
+                } else if self.return_errors && random() && random() {
+                    println!("returning error on read!");
+                    Err(CrucibleError::GenericError("test error".to_string()))
+                } else if !self.active {
+                    println!("Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    self.region.region_read(requests, self.job_id)
+                };
+                if let (Ok(responses), Some(extents)) =
+                    (&mut responses, &self.injection.corrupt)
+                {
+                    println!("injecting corruption on read!");
+                    fault::corrupt_responses(responses, extents);
+                }
+
+                Message::ReadRangeResponse {
+                    upstairs_id: self.job.upstairs_connection.upstairs_id,
+                    session_id: self.job.upstairs_connection.session_id,
+                    job_id: self.job.ds_id,
+                    responses,
+                }
+            }
+            IOop::WriteUnwritten {
+                dependencies: _dependencies,
+                writes,
+            } => {
+                /*
+                 * Any error from an IO should be intercepted here and passed
+                 * back to the upstairs.
+                 */
+                let result = if let Some(e) = injected_error {
+                    println!("injecting error on writeunwritten!");
+                    Err(e)
+                } else if self.return_errors && random() && random() {
+                    println!("returning error on writeunwritten!");
+                    Err(CrucibleError::GenericError("test error".to_string()))
+                } else if !self.active {
+                    println!("Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    // The region_write will handle what happens to each block
+                    // based on if they have data or not.
+                    self.region.region_write(writes, self.job_id, true)
+                };
+
+                Message::WriteUnwrittenAck {
+                    upstairs_id: self.job.upstairs_connection.upstairs_id,
+                    session_id: self.job.upstairs_connection.session_id,
+                    job_id: self.job.ds_id,
+                    result,
+                }
+            }
+            IOop::Write {
+                dependencies: _dependencies,
+                writes,
+            } => {
+                let result = if let Some(e) = injected_error {
+                    println!("injecting error on write!");
+                    Err(e)
+                } else if self.return_errors && random() && random() {
+                    println!("returning error on write!");
+                    Err(CrucibleError::GenericError("test error".to_string()))
+                } else if !self.active {
+                    println!("Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    self.region.region_write(writes, self.job_id, false)
+                };
+
+                Message::WriteAck {
+                    upstairs_id: self.job.upstairs_connection.upstairs_id,
+                    session_id: self.job.upstairs_connection.session_id,
+                    job_id: self.job.ds_id,
+                    result,
+                }
+            }
+            IOop::Flush {
+                dependencies: _dependencies,
+                flush_number,
+                gen_number,
+                snapshot_details,
+            } => {
+                let result = if let Some(e) = injected_error {
+                    println!("injecting error on flush!");
+                    Err(e)
+                } else if self.return_errors && random() && random() {
+                    println!("returning error on flush!");
+                    Err(CrucibleError::GenericError("test error".to_string()))
+                } else if !self.active {
+                    println!("Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    self.region.region_flush(
+                        *flush_number,
+                        *gen_number,
+                        snapshot_details,
+                        self.job_id,
+                    )
+                };
+
+                Message::FlushAck {
+                    upstairs_id: self.job.upstairs_connection.upstairs_id,
+                    session_id: self.job.upstairs_connection.session_id,
+                    job_id: self.job.ds_id,
+                    result,
+                }
+            }
+            IOop::Discard {
+                dependencies: _dependencies,
+                discards,
+            } => {
+                let result = if let Some(e) = injected_error {
+                    println!("injecting error on discard!");
+                    Err(e)
+                } else if self.return_errors && random() && random() {
+                    println!("returning error on discard!");
+                    Err(CrucibleError::GenericError("test error".to_string()))
+                } else if !self.active {
+                    println!("Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    self.region.region_discard(discards, self.job_id)
+                };
+
+                Message::DiscardAck {
+                    upstairs_id: self.job.upstairs_connection.upstairs_id,
+                    session_id: self.job.upstairs_connection.session_id,
+                    job_id: self.job.ds_id,
+                    result,
+                }
+            }
+            IOop::WriteZeroes {
+                dependencies: _dependencies,
+                zeroes,
+            } => {
+                let result = if let Some(e) = injected_error {
+                    println!("injecting error on write zeroes!");
+                    Err(e)
+                } else if self.return_errors && random() && random() {
+                    println!("returning error on write zeroes!");
+                    Err(CrucibleError::GenericError("test error".to_string()))
+                } else if !self.active {
+                    println!("Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    self.region.region_write_zeroes(zeroes, self.job_id)
+                };
 
-    /*
-     * We have to keep track of all IOs that have been issued since
-     * our last flush, as that is how we make sure dependencies are
-     * respected. The last_flush is the downstairs job ID number (ds_id
-     * typically) for the most recent flush.
-     */
-    last_flush: u64,
-    completed: Vec<u64>,
-}
+                Message::WriteZeroesAck {
+                    upstairs_id: self.job.upstairs_connection.upstairs_id,
+                    session_id: self.job.upstairs_connection.session_id,
+                    job_id: self.job.ds_id,
+                    result,
+                }
+            }
+            IOop::CompareAndWrite {
+                dependencies: _dependencies,
+                writes,
+            } => {
+                let result = if let Some(e) = injected_error {
+                    println!("injecting error on compare and write!");
+                    Err(e)
+                } else if self.return_errors && random() && random() {
+                    println!("returning error on compare and write!");
+                    Err(CrucibleError::GenericError("test error".to_string()))
+                } else if !self.active {
+                    println!("Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    self.region.region_compare_and_write(writes, self.job_id)
+                };
 
-#[derive(Debug, Clone)]
-struct DownstairsWork {
-    upstairs_connection: UpstairsConnection,
-    ds_id: u64,
-    work: IOop,
-    state: WorkState,
+                Message::CompareAndWriteAck {
+                    upstairs_id: self.job.upstairs_connection.upstairs_id,
+                    session_id: self.job.upstairs_connection.session_id,
+                    job_id: self.job.ds_id,
+                    result,
+                }
+            }
+        }
+    }
 }
 
 impl Work {
@@ -2007,8 +3010,10 @@ impl Work {
      * re-aquire the Work mutex and things can change.
      */
     fn in_progress(&mut self, ds_id: u64) -> Option<(u64, UpstairsConnection)> {
+        let blocked = self.blocked_by_running(ds_id);
+
         /*
-         * Once we support multiple threads, we can obtain a ds_id that
+         * Jobs run on multiple threads, so we can obtain a ds_id that
          * looked valid when we made a list of jobs, but something
          * else moved that job along and now it no longer exists.  We
          * need to handle that case correctly.
@@ -2074,6 +3079,18 @@ impl Work {
                                     dependencies: _,
                                     requests: _,
                                 } => "Read",
+                                IOop::Discard {
+                                    dependencies: _,
+                                    discards: _,
+                                } => "Discard",
+                                IOop::WriteZeroes {
+                                    dependencies: _,
+                                    zeroes: _,
+                                } => "WriteZ",
+                                IOop::CompareAndWrite {
+                                    dependencies: _,
+                                    writes: _,
+                                } => "CmpWr",
                             },
                             job.upstairs_connection,
                             deps_outstanding.len(),
@@ -2096,8 +3113,18 @@ impl Work {
                 }
 
                 /*
-                 * We had no dependencies, or they are all completed, we
-                 * can go ahead and work on this job.
+                 * A running job is using an extent this one changes, or
+                 * changing one it uses.  Leave this job where it is, and
+                 * look again when something finishes.
+                 */
+                if blocked {
+                    return None;
+                }
+
+                /*
+                 * We had no dependencies, or they are all completed, and
+                 * nothing running is in our way, we can go ahead and work
+                 * on this job.
                  */
                 job.state = WorkState::InProgress;
 
@@ -2119,6 +3146,23 @@ impl Work {
         }
     }
 
+    /**
+     * Would the given job, if it started now, use an extent that a job
+     * already in progress is changing, or change one it is using?
+     */
+    fn blocked_by_running(&self, ds_id: u64) -> bool {
+        let job = match self.active.get(&ds_id) {
+            Some(job) => job,
+            None => return false,
+        };
+        let extents = ExtentUse::of(&job.work);
+
+        self.active.values().any(|other| {
+            other.state == WorkState::InProgress
+                && extents.conflicts(&ExtentUse::of(&other.work))
+        })
+    }
+
     // Return a job that's ready to have the work done
     async fn get_ready_job(&mut self, job_id: u64) -> Option<DownstairsWork> {
         match self.active.get(&job_id) {
@@ -2203,6 +3247,8 @@ pub fn create_region(
     extent_count: u64,
     uuid: Uuid,
     encrypted: bool,
+    metadata_backend: MetadataBackend,
+    layout: RegionLayout,
 ) -> Result<Region> {
     /*
      * Create the region options, then the region.
@@ -2213,6 +3259,8 @@ pub fn create_region(
         .set_extent_size(Block::new(extent_size, block_size.trailing_zeros()));
     region_options.set_uuid(uuid);
     region_options.set_encrypted(encrypted);
+    region_options.set_metadata_backend(metadata_backend);
+    region_options.set_layout(layout);
 
     let mut region = Region::create(&data, region_options)?;
     region.extend(extent_count as u32)?;
@@ -2225,8 +3273,12 @@ pub fn build_downstairs_for_region(
     lossy: bool,
     return_errors: bool,
     read_only: bool,
+    compression: Compression,
+    capture_dir: Option<PathBuf>,
+    io_backend: IoBackend,
 ) -> Result<Arc<Mutex<Downstairs>>> {
-    let region = Region::open(&data, Default::default(), true, read_only)?;
+    let mut region = Region::open(&data, Default::default(), true, read_only)?;
+    region.set_io_backend(io_backend)?;
 
     println!("UUID: {:?}", region.def().uuid());
     println!(
@@ -2243,6 +3295,8 @@ pub fn build_downstairs_for_region(
         return_errors,
         read_only,
         encrypted,
+        compression,
+        capture_dir,
     ))))
 }
 
@@ -2369,9 +3423,13 @@ pub async fn start_downstairs(
 #[cfg(test)]
 mod test {
     use super::*;
+    use opentelemetry::sdk::export::trace::SpanData;
+    use opentelemetry::sdk::trace::{SpanProcessor, TracerProvider};
+    use opentelemetry::trace::{TraceResult, TracerProvider as _};
     use rand_chacha::ChaCha20Rng;
     use tempfile::tempdir;
     use tokio::sync::mpsc::error::TryRecvError;
+    use tracing_subscriber::layer::SubscriberExt;
 
     fn add_work(
         work: &mut Work,
@@ -2398,10 +3456,12 @@ mod test {
                         requests: vec![ReadRequest {
                             eid: 1,
                             offset: Block::new_512(1),
+                            num_blocks: 1,
                         }],
                     }
                 },
                 state: WorkState::New,
+                trace: None,
             },
         );
     }
@@ -2422,6 +3482,35 @@ mod test {
                     writes: Vec::with_capacity(1),
                 },
                 state: WorkState::New,
+                trace: None,
+            },
+        );
+    }
+
+    fn add_work_write(
+        work: &mut Work,
+        upstairs_connection: UpstairsConnection,
+        ds_id: u64,
+        deps: Vec<u64>,
+        eid: u64,
+    ) {
+        work.add_work(
+            ds_id,
+            DownstairsWork {
+                upstairs_connection,
+                ds_id,
+                work: IOop::Write {
+                    dependencies: deps,
+                    writes: vec![crucible_protocol::Write {
+                        eid,
+                        offset: Block::new_512(1),
+                        data: Bytes::from(vec![0u8; 512]),
+                        encryption_context: None,
+                        hash: 0,
+                    }],
+                },
+                state: WorkState::New,
+                trace: None,
             },
         );
     }
@@ -2543,7 +3632,15 @@ mod test {
         region.extend(2)?;
 
         let path_dir = dir.as_ref().to_path_buf();
-        let ads = build_downstairs_for_region(&path_dir, false, false, false)?;
+        let ads = build_downstairs_for_region(
+            &path_dir,
+            false,
+            false,
+            false,
+            Compression::None,
+            None,
+            IoBackend::default(),
+        )?;
 
         // This happens in proc() function.
         let upstairs_connection = UpstairsConnection {
@@ -2565,9 +3662,10 @@ mod test {
             requests: vec![ReadRequest {
                 eid: 0,
                 offset: Block::new_512(1),
+                num_blocks: 1,
             }],
         };
-        ds.add_work(upstairs_connection, 1000, rio).await?;
+        ds.add_work(upstairs_connection, 1000, rio, None).await?;
 
         let deps = vec![1000];
         let rio = IOop::Read {
@@ -2575,9 +3673,10 @@ mod test {
             requests: vec![ReadRequest {
                 eid: 1,
                 offset: Block::new_512(1),
+                num_blocks: 1,
             }],
         };
-        ds.add_work(upstairs_connection, 1001, rio).await?;
+        ds.add_work(upstairs_connection, 1001, rio, None).await?;
 
         show_work(&mut ds).await;
 
@@ -3140,17 +4239,228 @@ mod test {
         assert_eq!(work.completed, vec![1000, 1001, 1002, 1003]);
     }
 
+    #[test]
+    fn jobs_on_different_extents_run_together() {
+        let mut work = Work::default();
+        let upstairs_connection = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 0,
+        };
+
+        add_work_write(&mut work, upstairs_connection, 1000, vec![], 0);
+        add_work_write(&mut work, upstairs_connection, 1001, vec![], 2);
+        add_work(&mut work, upstairs_connection, 1002, vec![], false);
+
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1000, 1001, 1002]);
+    }
+
+    #[test]
+    fn jobs_with_upstairs_dependencies_run_together() {
+        // Dependencies as the upstairs makes them: a job only waits for
+        // earlier jobs on the same blocks, and for the last flush.
+        let mut work = Work::default();
+        let upstairs_connection = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 0,
+        };
+
+        add_work_write(&mut work, upstairs_connection, 1000, vec![], 1);
+        add_work_write(&mut work, upstairs_connection, 1001, vec![], 3);
+        add_work(&mut work, upstairs_connection, 1002, vec![1000], false);
+        add_work(
+            &mut work,
+            upstairs_connection,
+            1003,
+            vec![1000, 1001, 1002],
+            true,
+        );
+        add_work_write(&mut work, upstairs_connection, 1004, vec![1003], 1);
+        add_work_write(&mut work, upstairs_connection, 1005, vec![1003], 3);
+
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1000, 1001]);
+
+        test_do_work(&mut work, next_jobs);
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1002]);
+
+        test_do_work(&mut work, next_jobs);
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1003]);
+
+        test_do_work(&mut work, next_jobs);
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1004, 1005]);
+    }
+
+    #[test]
+    fn jobs_on_the_same_extent_wait() {
+        let mut work = Work::default();
+        let upstairs_connection = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 0,
+        };
+
+        // A write, a read, and another write, all to extent 1
+        add_work_write(&mut work, upstairs_connection, 1000, vec![], 1);
+        add_work(&mut work, upstairs_connection, 1001, vec![], false);
+        add_work_write(&mut work, upstairs_connection, 1002, vec![], 1);
+
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1000]);
+
+        // Waiting on an extent is not waiting on a dependency.
+        assert_eq!(work.active.get(&1001).unwrap().state, WorkState::New);
+        assert_eq!(work.new_work(upstairs_connection), vec![1001, 1002]);
+
+        test_do_work(&mut work, next_jobs);
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1001]);
+
+        test_do_work(&mut work, next_jobs);
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1002]);
+
+        test_do_work(&mut work, next_jobs);
+        assert_eq!(work.completed, vec![1000, 1001, 1002]);
+    }
+
+    #[test]
+    fn flush_waits_for_writes_not_reads() {
+        let mut work = Work::default();
+        let upstairs_connection = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 0,
+        };
+
+        add_work(&mut work, upstairs_connection, 1000, vec![], false);
+        add_work_write(&mut work, upstairs_connection, 1001, vec![], 3);
+        add_work(&mut work, upstairs_connection, 1002, vec![], true);
+
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1000, 1001]);
+
+        test_do_work(&mut work, vec![1001]);
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1002]);
+
+        // Nothing that writes can start while the flush runs.
+        add_work_write(&mut work, upstairs_connection, 1003, vec![], 0);
+        add_work(&mut work, upstairs_connection, 1004, vec![], false);
+        let next_jobs = test_push_next_jobs(&mut work, upstairs_connection);
+        assert_eq!(next_jobs, vec![1004]);
+    }
+
+    #[test]
+    fn compare_and_write_is_exclusive() {
+        let read = ExtentUse::of(&IOop::Read {
+            dependencies: vec![],
+            requests: vec![ReadRequest {
+                eid: 4,
+                offset: Block::new_512(0),
+                num_blocks: 1,
+            }],
+        });
+        let cw = ExtentUse::of(&IOop::CompareAndWrite {
+            dependencies: vec![],
+            writes: vec![crucible_protocol::CompareAndWrite {
+                eid: 4,
+                offset: Block::new_512(1),
+                expected: Bytes::from(vec![0u8; 512]),
+                data: Bytes::from(vec![1u8; 512]),
+                hash: 0,
+            }],
+        });
+
+        assert!(!read.conflicts(&read));
+        assert!(cw.conflicts(&read));
+        assert!(read.conflicts(&cw));
+        assert!(cw.conflicts(&cw));
+    }
+
     #[test]
     fn import_test_basic() -> Result<()> {
         /*
          * import + export test where data matches region size
          */
-
+
+        let block_size: u64 = 512;
+        let extent_size = 10;
+
+        // create region
+
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(block_size);
+        region_options.set_extent_size(Block::new(
+            extent_size,
+            block_size.trailing_zeros(),
+        ));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(10)?;
+
+        // create random file
+
+        let total_bytes = region.def().total_size();
+        let mut random_data = Vec::with_capacity(total_bytes as usize);
+        random_data.resize(total_bytes as usize, 0);
+
+        let mut rng = ChaCha20Rng::from_entropy();
+        rng.fill_bytes(&mut random_data);
+
+        // write random_data to file
+
+        let tempdir = tempdir()?;
+        mkdir_for_file(tempdir.path())?;
+
+        let random_file_path = tempdir.path().join("random_data");
+        let mut random_file = File::create(&random_file_path)?;
+        random_file.write_all(&random_data[..])?;
+
+        // import random_data to the region
+
+        downstairs_import(&mut region, &random_file_path, false)?;
+        region.region_flush(1, 1, &None, 0)?;
+
+        // export region to another file
+
+        let export_path = tempdir.path().join("exported_data");
+        downstairs_export(
+            &mut region,
+            &export_path,
+            0,
+            total_bytes / block_size,
+            ImageFormat::Raw,
+        )?;
+
+        // compare files
+
+        let expected = std::fs::read(random_file_path)?;
+        let actual = std::fs::read(export_path)?;
+
+        assert_eq!(expected, actual);
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_test_sparse() -> Result<()> {
+        /*
+         * import + export test where blocks of zeros are left unwritten
+         */
         let block_size: u64 = 512;
         let extent_size = 10;
 
-        // create region
-
         let mut region_options: crucible_common::RegionOptions =
             Default::default();
         region_options.set_block_size(block_size);
@@ -3161,35 +4471,30 @@ mod test {
         region_options.set_uuid(Uuid::new_v4());
 
         let dir = tempdir()?;
-        mkdir_for_file(dir.path())?;
-
         let mut region = Region::create(&dir, region_options)?;
-        region.extend(10)?;
+        region.extend(3)?;
 
-        // create random file
+        // Random data, with blocks 5 to 24 zeroed
 
         let total_bytes = region.def().total_size();
-        let mut random_data = Vec::with_capacity(total_bytes as usize);
-        random_data.resize(total_bytes as usize, 0);
-
+        let mut random_data = vec![0u8; total_bytes as usize];
         let mut rng = ChaCha20Rng::from_entropy();
         rng.fill_bytes(&mut random_data);
-
-        // write random_data to file
+        for b in &mut random_data[5 * 512..25 * 512] {
+            *b = 0;
+        }
 
         let tempdir = tempdir()?;
-        mkdir_for_file(tempdir.path())?;
-
         let random_file_path = tempdir.path().join("random_data");
-        let mut random_file = File::create(&random_file_path)?;
-        random_file.write_all(&random_data[..])?;
-
-        // import random_data to the region
+        std::fs::write(&random_file_path, &random_data)?;
 
-        downstairs_import(&mut region, &random_file_path)?;
+        downstairs_import(&mut region, &random_file_path, false)?;
         region.region_flush(1, 1, &None, 0)?;
 
-        // export region to another file
+        let usage = region.space_usage()?;
+        assert_eq!(usage.allocated_blocks, vec![5, 0, 5]);
+        assert_eq!(usage.allocated_bytes, 10 * block_size);
+        assert_eq!(usage.logical_bytes, total_bytes);
 
         let export_path = tempdir.path().join("exported_data");
         downstairs_export(
@@ -3197,14 +4502,145 @@ mod test {
             &export_path,
             0,
             total_bytes / block_size,
+            ImageFormat::Raw,
         )?;
+        assert_eq!(random_data, std::fs::read(export_path)?);
 
-        // compare files
+        Ok(())
+    }
 
-        let expected = std::fs::read(random_file_path)?;
-        let actual = std::fs::read(export_path)?;
+    #[test]
+    fn import_export_image_formats() -> Result<()> {
+        /*
+         * Export to raw-sparse and qcow2, and check both hold the same
+         * disk, including when qcow2 is imported again.
+         */
+        let block_size: u64 = 512;
 
-        assert_eq!(expected, actual);
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(block_size);
+        region_options.set_extent_size(Block::new(10, 9));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, region_options.clone())?;
+        region.extend(3)?;
+
+        // Random data, with blocks 5 to 24 zeroed
+
+        let total_bytes = region.def().total_size();
+        let mut random_data = vec![0u8; total_bytes as usize];
+        let mut rng = ChaCha20Rng::from_entropy();
+        rng.fill_bytes(&mut random_data);
+        for b in &mut random_data[5 * 512..25 * 512] {
+            *b = 0;
+        }
+
+        let image_dir = tempdir()?;
+        let random_file_path = image_dir.path().join("random_data");
+        std::fs::write(&random_file_path, &random_data)?;
+
+        downstairs_import(&mut region, &random_file_path, false)?;
+        region.region_flush(1, 1, &None, 0)?;
+
+        let sparse_path = image_dir.path().join("exported.raw");
+        downstairs_export(
+            &mut region,
+            &sparse_path,
+            0,
+            0,
+            ImageFormat::RawSparse,
+        )?;
+        assert_eq!(random_data, std::fs::read(&sparse_path)?);
+
+        let qcow2_path = image_dir.path().join("exported.qcow2");
+        downstairs_export(&mut region, &qcow2_path, 0, 0, ImageFormat::Qcow2)?;
+        assert_ne!(random_data, std::fs::read(&qcow2_path)?);
+
+        // Import the qcow2 image into a new region, and export part of it.
+
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, region_options)?;
+        downstairs_import(&mut region, &qcow2_path, false)?;
+        region.region_flush(1, 1, &None, 0)?;
+        assert_eq!(region.def().extent_count(), 3);
+
+        let usage = region.space_usage()?;
+        assert_eq!(usage.allocated_blocks, vec![5, 0, 5]);
+
+        let export_path = image_dir.path().join("exported_part");
+        downstairs_export(&mut region, &export_path, 3, 20, ImageFormat::Raw)?;
+        assert_eq!(
+            random_data[3 * 512..23 * 512],
+            std::fs::read(&export_path)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_stream_and_resume() -> Result<()> {
+        let block_size: u64 = 512;
+
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(block_size);
+        region_options.set_extent_size(Block::new(10, 9));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let mut random_data = vec![0u8; 45 * 512];
+        let mut rng = ChaCha20Rng::from_entropy();
+        rng.fill_bytes(&mut random_data);
+
+        let image_dir = tempdir()?;
+        let random_file_path = image_dir.path().join("random_data");
+        std::fs::write(&random_file_path, &random_data)?;
+
+        // A stream of unknown length grows the region as it goes.
+
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, region_options.clone())?;
+        region.extend(1)?;
+        let source = ImportSource::Raw(Box::new(std::io::Cursor::new(
+            random_data.clone(),
+        )));
+        import_image(&mut region, source, None, 0)?;
+        assert_eq!(region.def().extent_count(), 5);
+        assert!(region.dirty()?.iter().all(|d| !d));
+
+        let export_path = image_dir.path().join("exported");
+        downstairs_export(&mut region, &export_path, 0, 45, ImageFormat::Raw)?;
+        assert_eq!(random_data, std::fs::read(&export_path)?);
+
+        // Nothing to resume once an import is done.
+
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, region_options.clone())?;
+        downstairs_import(&mut region, &random_file_path, false)?;
+        assert!(!import_checkpoint_path(&dir).exists());
+        assert!(
+            downstairs_import(&mut region, &random_file_path, true).is_err()
+        );
+
+        // Pretend an import stopped after two extents, and resume it.
+        // Only the rest is imported again.
+
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(2)?;
+        write_json(
+            import_checkpoint_path(&dir),
+            &ImportCheckpoint { bytes: 20 * 512 },
+            false,
+        )?;
+        downstairs_import(&mut region, &random_file_path, true)?;
+        assert!(!import_checkpoint_path(&dir).exists());
+
+        downstairs_export(&mut region, &export_path, 0, 0, ImageFormat::Raw)?;
+        let exported = std::fs::read(&export_path)?;
+        assert!(exported[..20 * 512].iter().all(|b| *b == 0));
+        assert_eq!(exported[20 * 512..45 * 512], random_data[20 * 512..]);
 
         Ok(())
     }
@@ -3254,7 +4690,7 @@ mod test {
 
         // import random_data to the region
 
-        downstairs_import(&mut region, &random_file_path)?;
+        downstairs_import(&mut region, &random_file_path, false)?;
         region.region_flush(1, 1, &None, 0)?;
 
         // export region to another file (note: 100 fewer bytes imported than
@@ -3267,6 +4703,7 @@ mod test {
             &export_path,
             0,
             region_size / block_size,
+            ImageFormat::Raw,
         )?;
 
         // compare files
@@ -3336,7 +4773,7 @@ mod test {
 
         // import random_data to the region
 
-        downstairs_import(&mut region, &random_file_path)?;
+        downstairs_import(&mut region, &random_file_path, false)?;
         region.region_flush(1, 1, &None, 0)?;
 
         // export region to another file (note: 100 more bytes will have caused
@@ -3350,6 +4787,7 @@ mod test {
             &export_path,
             0,
             total_bytes / block_size + 1,
+            ImageFormat::Raw,
         )?;
 
         // compare files
@@ -3419,7 +4857,7 @@ mod test {
 
         // import random_data to the region
 
-        downstairs_import(&mut region, &random_file_path)?;
+        downstairs_import(&mut region, &random_file_path, false)?;
         region.region_flush(1, 1, &None, 0)?;
 
         // read block by block
@@ -3430,6 +4868,7 @@ mod test {
                     &[crucible_protocol::ReadRequest {
                         eid: eid.into(),
                         offset: Block::new_512(offset),
+                        num_blocks: 1,
                     }],
                     0,
                 )?;
@@ -3437,10 +4876,10 @@ mod test {
                 assert_eq!(responses.len(), 1);
 
                 let response = &responses[0];
-                assert_eq!(response.hashes.len(), 1);
+                assert_eq!(response.blocks[0].hashes.len(), 1);
                 assert_eq!(
                     integrity_hash(&[&response.data[..]]),
-                    response.hashes[0],
+                    response.blocks[0].hashes[0],
                 );
 
                 read_data.extend_from_slice(&response.data[..]);
@@ -3476,9 +4915,13 @@ mod test {
         let path_dir = dir.as_ref().to_path_buf();
 
         build_downstairs_for_region(
-            &path_dir, false, // lossy
+            &path_dir,
+            false, // lossy
             false, // return_errors
             read_only,
+            Compression::None,
+            None,
+            IoBackend::default(),
         )
     }
 
@@ -3740,9 +5183,10 @@ mod test {
             requests: vec![ReadRequest {
                 eid: 0,
                 offset: Block::new_512(1),
+                num_blocks: 1,
             }],
         };
-        ds.add_work(upstairs_connection_1, 1000, read_1.clone())
+        ds.add_work(upstairs_connection_1, 1000, read_1.clone(), None)
             .await?;
 
         let read_2 = IOop::Read {
@@ -3750,9 +5194,10 @@ mod test {
             requests: vec![ReadRequest {
                 eid: 1,
                 offset: Block::new_512(2),
+                num_blocks: 1,
             }],
         };
-        ds.add_work(upstairs_connection_2, 1000, read_2.clone())
+        ds.add_work(upstairs_connection_2, 1000, read_2.clone(), None)
             .await?;
 
         let work_1 = ds.new_work(upstairs_connection_1).await?;
@@ -3802,7 +5247,15 @@ mod test {
         region.extend(2)?;
 
         let path_dir = dir.as_ref().to_path_buf();
-        let ads = build_downstairs_for_region(&path_dir, false, false, false)?;
+        let ads = build_downstairs_for_region(
+            &path_dir,
+            false,
+            false,
+            false,
+            Compression::None,
+            None,
+            IoBackend::default(),
+        )?;
 
         // This happens in proc() function.
         let upstairs_connection_1 = UpstairsConnection {
@@ -3824,9 +5277,10 @@ mod test {
             requests: vec![ReadRequest {
                 eid: 0,
                 offset: Block::new_512(1),
+                num_blocks: 1,
             }],
         };
-        ds.add_work(upstairs_connection_1, 1000, rio).await?;
+        ds.add_work(upstairs_connection_1, 1000, rio, None).await?;
 
         // Now we mimic what happens in the do_work_task()
         let new_work = ds.new_work(upstairs_connection_1).await.unwrap();
@@ -3888,7 +5342,15 @@ mod test {
         region.extend(2)?;
 
         let path_dir = dir.as_ref().to_path_buf();
-        let ads = build_downstairs_for_region(&path_dir, false, false, false)?;
+        let ads = build_downstairs_for_region(
+            &path_dir,
+            false,
+            false,
+            false,
+            Compression::None,
+            None,
+            IoBackend::default(),
+        )?;
 
         // This happens in proc() function.
         let upstairs_connection_1 = UpstairsConnection {
@@ -3910,9 +5372,10 @@ mod test {
             requests: vec![ReadRequest {
                 eid: 0,
                 offset: Block::new_512(1),
+                num_blocks: 1,
             }],
         };
-        ds.add_work(upstairs_connection_1, 1000, rio).await?;
+        ds.add_work(upstairs_connection_1, 1000, rio, None).await?;
 
         // Now we mimic what happens in the do_work_task()
         let new_work = ds.new_work(upstairs_connection_1).await.unwrap();
@@ -3973,7 +5436,15 @@ mod test {
         region.extend(2)?;
 
         let path_dir = dir.as_ref().to_path_buf();
-        let ads = build_downstairs_for_region(&path_dir, false, false, false)?;
+        let ads = build_downstairs_for_region(
+            &path_dir,
+            false,
+            false,
+            false,
+            Compression::None,
+            None,
+            IoBackend::default(),
+        )?;
 
         // This happens in proc() function.
         let upstairs_connection_1 = UpstairsConnection {
@@ -3995,9 +5466,10 @@ mod test {
             requests: vec![ReadRequest {
                 eid: 0,
                 offset: Block::new_512(1),
+                num_blocks: 1,
             }],
         };
-        ds.add_work(upstairs_connection_1, 1000, rio).await?;
+        ds.add_work(upstairs_connection_1, 1000, rio, None).await?;
 
         // Now we mimic what happens in the do_work_task()
         let new_work = ds.new_work(upstairs_connection_1).await.unwrap();
@@ -4032,4 +5504,299 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn extend_region_while_running() -> Result<()> {
+        // Grow a region a downstairs is serving, and check that both the
+        // region and anyone watching for it see the new size.
+        let block_size: u64 = 512;
+        let extent_size = 4;
+
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(block_size);
+        region_options.set_extent_size(Block::new(
+            extent_size,
+            block_size.trailing_zeros(),
+        ));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(2)?;
+
+        let path_dir = dir.as_ref().to_path_buf();
+        let ads = build_downstairs_for_region(
+            &path_dir,
+            false,
+            false,
+            false,
+            Compression::None,
+            None,
+            IoBackend::default(),
+        )?;
+
+        let ds = ads.lock().await;
+        let mut rx = ds.region_def_tx.subscribe();
+
+        let region_def = ds.extend_region(5).await?;
+        assert_eq!(region_def.extent_count(), 5);
+        assert_eq!(ds.region.read().await.def().extent_count(), 5);
+        assert_eq!(ds.region.read().await.extents.len(), 5);
+
+        rx.changed().await?;
+        assert_eq!(rx.borrow().extent_count(), 5);
+
+        // The region can't shrink.
+        assert!(ds.extend_region(3).await.is_err());
+        assert_eq!(ds.region.read().await.def().extent_count(), 5);
+
+        // The new extents are really there the next time the region is
+        // opened.
+        drop(ds);
+        drop(ads);
+        let region = Region::open(&path_dir, Default::default(), true, false)?;
+        assert_eq!(region.def().extent_count(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_injected_faults() -> Result<()> {
+        // Fail reads of one extent, and corrupt reads of the other.
+        let block_size: u64 = 512;
+        let extent_size = 4;
+
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(block_size);
+        region_options.set_extent_size(Block::new(
+            extent_size,
+            block_size.trailing_zeros(),
+        ));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(2)?;
+
+        let path_dir = dir.as_ref().to_path_buf();
+        let ads = build_downstairs_for_region(
+            &path_dir,
+            false,
+            false,
+            false,
+            Compression::None,
+            None,
+            IoBackend::default(),
+        )?;
+
+        let upstairs_connection = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 10,
+        };
+
+        let (_tx, mut _rx) = channel(1);
+        let tx = Arc::new(_tx);
+
+        let mut ds = ads.lock().await;
+        ds.promote_to_active(upstairs_connection, tx.clone())
+            .await?;
+
+        ds.set_faults(vec![
+            Fault {
+                ops: vec![FaultOp::Read],
+                extents: vec![1],
+                skip: 0,
+                count: Some(1),
+                action: FaultAction::Fail {
+                    error: CrucibleError::IoError("injected".to_string()),
+                },
+            },
+            Fault {
+                ops: Vec::new(),
+                extents: vec![0],
+                skip: 0,
+                count: None,
+                action: FaultAction::Corrupt,
+            },
+        ]);
+
+        for (job_id, eid) in [(1000, 0), (1001, 1), (1002, 1)] {
+            let rio = IOop::Read {
+                dependencies: Vec::new(),
+                requests: vec![ReadRequest {
+                    eid,
+                    offset: Block::new_512(1),
+                    num_blocks: 1,
+                }],
+            };
+            ds.add_work(upstairs_connection, job_id, rio, None).await?;
+        }
+
+        let mut results = Vec::new();
+        for job_id in [1000, 1001, 1002] {
+            ds.in_progress(upstairs_connection, job_id).await?.unwrap();
+            let m = ds.do_work(upstairs_connection, job_id).await?.unwrap();
+            match &m {
+                Message::ReadRangeResponse { responses, .. } => {
+                    results.push(responses.clone());
+                }
+                m => panic!("unexpected message {:?}", m),
+            }
+            ds.complete_work(upstairs_connection, job_id, m).await?;
+        }
+
+        // The corrupted block read back with a bit flipped, and the
+        // injected error only happened once.
+        assert_eq!(results[0].as_ref().unwrap()[0].data[0], 1);
+        assert_eq!(
+            results[1].as_ref().unwrap_err(),
+            &CrucibleError::IoError("injected".to_string())
+        );
+        assert_eq!(results[2].as_ref().unwrap()[0].data[0], 0);
+
+        let report = ds.fault_report();
+        assert_eq!((report[0].matched, report[0].injected), (2, 1));
+        assert_eq!((report[1].matched, report[1].injected), (1, 1));
+
+        Ok(())
+    }
+
+    /*
+     * Keeps every span that ends, so a test can look at what was traced.
+     */
+    #[derive(Debug, Clone, Default)]
+    struct KeepSpans(Arc<std::sync::Mutex<Vec<SpanData>>>);
+
+    impl SpanProcessor for KeepSpans {
+        fn on_start(
+            &self,
+            _span: &mut opentelemetry::sdk::trace::Span,
+            _cx: &opentelemetry::Context,
+        ) {
+        }
+
+        fn on_end(&self, span: SpanData) {
+            self.0.lock().unwrap().push(span);
+        }
+
+        fn force_flush(&self) -> TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_do_work_continues_trace() -> Result<()> {
+        // The work for a job is traced as a child of the span the upstairs
+        // sent along with it.
+        let ads = build_test_downstairs(false)?;
+
+        let upstairs_connection = UpstairsConnection {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 10,
+        };
+
+        let (_tx, mut _rx) = channel(1);
+        let tx = Arc::new(_tx);
+
+        let mut ds = ads.lock().await;
+        ds.promote_to_active(upstairs_connection, tx.clone())
+            .await?;
+
+        let trace = TraceContext {
+            trace_id: [0x4b; 16],
+            span_id: [0xf0; 8],
+            flags: 1,
+        };
+        let rio = IOop::Read {
+            dependencies: Vec::new(),
+            requests: vec![ReadRequest {
+                eid: 0,
+                offset: Block::new_512(1),
+                num_blocks: 1,
+            }],
+        };
+        ds.add_work(upstairs_connection, 1000, rio, Some(trace))
+            .await?;
+
+        let kept = KeepSpans::default();
+        let provider = TracerProvider::builder()
+            .with_span_processor(kept.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("test")),
+        );
+
+        {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            ds.in_progress(upstairs_connection, 1000).await?.unwrap();
+            let m = ds.do_work(upstairs_connection, 1000).await?.unwrap();
+            ds.complete_work(upstairs_connection, 1000, m).await?;
+        }
+
+        let spans = kept.0.lock().unwrap();
+        let span = spans.iter().find(|s| s.name == "do_work").unwrap();
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_bytes(trace.trace_id)
+        );
+        assert_eq!(span.parent_span_id, SpanId::from_bytes(trace.span_id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_read_response() {
+        let request = ReadRequest {
+            eid: 1,
+            offset: Block::new_512(2),
+            num_blocks: 3,
+        };
+        let m = Message::ReadRangeResponse {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1000,
+            responses: Ok(vec![ReadResponse::from_request_with_data(
+                &request,
+                &[7u8; 512 * 3],
+            )]),
+        };
+
+        match block_read_response(m) {
+            Message::ReadResponse {
+                job_id: 1000,
+                responses: Ok(responses),
+                ..
+            } => {
+                assert_eq!(responses.len(), 3);
+                for (i, response) in responses.iter().enumerate() {
+                    assert_eq!(response.eid, 1);
+                    assert_eq!(response.offset, Block::new_512(2 + i as u64));
+                    assert_eq!(response.data[..], [7u8; 512][..]);
+                    assert_eq!(response.hashes.len(), 1);
+                }
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        // Anything but a read goes through as it was.
+        let m = Message::FlushAck {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1001,
+            result: Ok(()),
+        };
+        assert_eq!(block_read_response(m.clone()), m);
+    }
 }
@@ -5,7 +5,8 @@
     feature(asm_sym)
 )]
 
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 
 use anyhow::{bail, Result};
@@ -16,8 +17,10 @@ use tracing_subscriber::util::SubscriberInitExt;
 use usdt::register_probes;
 use uuid::Uuid;
 
+use crucible_common::{MetadataBackend, RegionLayout, ADMIN_PORT_OFFSET};
 use crucible_downstairs::admin::*;
 use crucible_downstairs::*;
+use crucible_protocol::Compression;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum Mode {
@@ -54,9 +57,17 @@ enum Args {
         #[clap(long, default_value = "15", action)]
         extent_count: u64,
 
+        /// An image to import into the new region, raw or qcow2, or -
+        /// to read a raw image from stdin.
         #[clap(short, long, name = "FILE", action)]
         import_path: Option<PathBuf>,
 
+        /// Finish an import into this region that was interrupted,
+        /// starting after the last extent it flushed.  Give it the same
+        /// image again.
+        #[clap(long, requires = "FILE", action)]
+        resume: bool,
+
         #[clap(short, long, name = "UUID", action)]
         uuid: Uuid,
 
@@ -67,6 +78,19 @@ enum Args {
             action(clap::ArgAction::Set)
         )]
         encrypted: bool,
+
+        /// Where each extent keeps its hashes, encryption contexts and
+        /// flush numbers: sqlite, or sidecar for a fixed size file next
+        /// to the extent.
+        #[clap(long, default_value = "sqlite", action)]
+        metadata_backend: MetadataBackend,
+
+        /// How to lay out the region files: per-extent, for a data file
+        /// and metadata for each extent, or single-file, for one data
+        /// file holding every extent.  A single-file region needs
+        /// sidecar metadata.
+        #[clap(long, default_value = "per-extent", action)]
+        layout: RegionLayout,
     },
     /*
      * Dump region information.
@@ -103,6 +127,56 @@ enum Args {
         /// No color output
         #[clap(long, action)]
         no_color: bool,
+
+        /// Print JSON instead of tables, for other programs to read
+        #[clap(long, action)]
+        json: bool,
+    },
+    /*
+     * Check a region that is not in use for problems, such as after a
+     * crash and before a downstairs serves it again.
+     * With --repair, fix the problems that can be fixed without the other
+     * downstairs.
+     */
+    #[clap(alias = "fsck")]
+    Verify {
+        /*
+         * Directory containing a region.
+         */
+        #[clap(short, long, name = "DIRECTORY", action)]
+        data: PathBuf,
+
+        /*
+         * Fix what can be fixed.
+         */
+        #[clap(long, action)]
+        repair: bool,
+    },
+    /*
+     * Bring the three regions of a region set back into agreement, the
+     * way the upstairs does when it connects to them.  No downstairs may
+     * have any of them open.
+     * With --dry-run, only show what would be copied where.
+     */
+    Mend {
+        /*
+         * Directories containing the three regions.
+         */
+        #[clap(short, long, name = "DIRECTORY", action)]
+        data: Vec<PathBuf>,
+
+        /*
+         * Also mend extents whose metadata agree but whose blocks don't,
+         * from the regions whose blocks agree and match their hashes.
+         */
+        #[clap(long, action)]
+        hash_majority: bool,
+
+        /*
+         * Don't change anything.
+         */
+        #[clap(long, action)]
+        dry_run: bool,
     },
     Export {
         /*
@@ -119,6 +193,11 @@ enum Args {
 
         #[clap(short, long, default_value = "0", name = "SKIP", action)]
         skip: u64,
+
+        /// The format of the image to write: raw, raw-sparse, which
+        /// leaves holes for blocks of zeros, or qcow2.
+        #[clap(long, default_value = "raw", action)]
+        format: ImageFormat,
     },
     Run {
         /// Address the downstairs will listen for the upstairs on.
@@ -168,7 +247,34 @@ enum Args {
 
         #[clap(long, default_value = "rw", action)]
         mode: Mode,
+
+        /// Compress block data sent to and from the upstairs, if it
+        /// supports it: none, lz4, or zstd.
+        #[clap(long, default_value = "none", action)]
+        compression: Compression,
+
+        /// Record every connection from an upstairs to a capture file in
+        /// this directory.
+        #[clap(long, name = "CAPTURE_DIR", action)]
+        capture_dir: Option<PathBuf>,
+
+        /// How to do IO to the extent files: std, or io-uring on a Linux
+        /// downstairs built with the io_uring feature.
+        #[clap(long, default_value = "std", action)]
+        io_backend: IoBackend,
+
+        /// Check the blocks of the region against their integrity hashes
+        /// in the background, at no more than this many blocks a second.
+        /// Zero, the default, turns it off.
+        #[clap(long, default_value = "0", action)]
+        scrub_rate: u64,
+
+        /// Serve the admin API for this downstairs on loopback, at its
+        /// port plus ADMIN_PORT_OFFSET, for whatever manages the region.
+        #[clap(long, action)]
+        admin: bool,
     },
+    AdminAPI,
     RepairAPI,
     Serve {
         #[clap(short, long, action)]
@@ -196,20 +302,46 @@ async fn main() -> Result<()> {
             extent_size,
             extent_count,
             import_path,
+            resume,
             uuid,
             encrypted,
+            metadata_backend,
+            layout,
         } => {
-            let mut region = create_region(
-                block_size,
-                data,
-                extent_size,
-                extent_count,
-                uuid,
-                encrypted,
-            )?;
+            /*
+             * To resume an import, the region is already there.
+             */
+            let mut region = if resume {
+                let region = region::Region::open(
+                    &data,
+                    Default::default(),
+                    true,
+                    false,
+                )?;
+                if region.def().uuid() != uuid {
+                    bail!(
+                        "Region {:?} has UUID {}, not {}",
+                        data,
+                        region.def().uuid(),
+                        uuid
+                    );
+                }
+                region
+            } else {
+                create_region(
+                    block_size,
+                    data,
+                    extent_size,
+                    extent_count,
+                    uuid,
+                    encrypted,
+                    metadata_backend,
+                    layout,
+                )?
+            };
 
             if let Some(ref ip) = import_path {
-                downstairs_import(&mut region, ip).unwrap();
+                downstairs_import(&mut region, ip, resume).unwrap();
                 /*
                  * The region we just created should now have a flush so the
                  * new data and inital flush number is written to disk.
@@ -231,11 +363,31 @@ async fn main() -> Result<()> {
             block,
             only_show_differences,
             no_color,
+            json,
         } => {
             if data.is_empty() {
                 bail!("Need at least one data directory to dump");
             }
-            dump_region(data, extent, block, only_show_differences, no_color)?;
+            dump_region(
+                data,
+                extent,
+                block,
+                only_show_differences,
+                no_color,
+                json,
+            )?;
+            Ok(())
+        }
+        Args::Verify { data, repair } => {
+            verify_region(data, repair)?;
+            Ok(())
+        }
+        Args::Mend {
+            data,
+            hash_majority,
+            dry_run,
+        } => {
+            mend_regions(data, hash_majority, dry_run)?;
             Ok(())
         }
         Args::Export {
@@ -243,12 +395,14 @@ async fn main() -> Result<()> {
             data,
             export_path,
             skip,
+            format,
         } => {
             // Open Region read only
             region =
                 region::Region::open(&data, Default::default(), true, true)?;
 
-            downstairs_export(&mut region, export_path, skip, count).unwrap();
+            downstairs_export(&mut region, export_path, skip, count, format)
+                .unwrap();
             Ok(())
         }
         Args::Run {
@@ -263,6 +417,11 @@ async fn main() -> Result<()> {
             key_pem,
             root_cert_pem,
             mode,
+            compression,
+            capture_dir,
+            io_backend,
+            scrub_rate,
+            admin,
         } => {
             /*
              * If any of our async tasks in our runtime panic, then we should
@@ -306,8 +465,38 @@ async fn main() -> Result<()> {
                 lossy,
                 return_errors,
                 read_only,
+                compression,
+                capture_dir,
+                io_backend,
             )?;
 
+            if scrub_rate > 0 {
+                tokio::spawn(scrub_region(d.clone(), scrub_rate));
+            }
+
+            if admin {
+                let admin_port = match port.checked_add(ADMIN_PORT_OFFSET) {
+                    Some(admin_port) => admin_port,
+                    None => bail!("no admin port to go with port {}", port),
+                };
+                let admin_address =
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), admin_port);
+
+                let decorator = slog_term::TermDecorator::new().build();
+                let drain =
+                    slog_term::FullFormat::new(decorator).build().fuse();
+                let drain = slog_async::Async::new(drain).build().fuse();
+                let log = slog::Logger::root(drain, slog::o!());
+
+                let uuid = d.lock().await.region.read().await.def().uuid();
+                let mut downstairs = HashMap::new();
+                downstairs.insert(uuid, d.clone());
+                tokio::spawn(async move {
+                    let s = run_dropshot(admin_address, &log, downstairs).await;
+                    println!("Got {:?} from admin server", s);
+                });
+            }
+
             start_downstairs(
                 d,
                 address,
@@ -319,6 +508,7 @@ async fn main() -> Result<()> {
             )
             .await
         }
+        Args::AdminAPI => admin::write_openapi(&mut std::io::stdout()),
         Args::RepairAPI => repair::write_openapi(&mut std::io::stdout()),
         Args::Serve {
             trace_endpoint,
@@ -358,7 +548,7 @@ async fn main() -> Result<()> {
 
             let log = slog::Logger::root(drain, slog::o!());
 
-            run_dropshot(bind_addr, &log).await
+            run_dropshot(bind_addr, &log, HashMap::new()).await
         }
     }
 }
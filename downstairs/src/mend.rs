@@ -0,0 +1,516 @@
+// Copyright 2022 Oxide Computer Company
+/*
+ * Bring the three regions of a region set back into agreement while no
+ * downstairs has them open.  This is the offline version of what the
+ * upstairs does when it first connects to its three downstairs: pick a
+ * source for each extent that differs using the same rules, then copy
+ * the source extent's files over the others, the same way an extent
+ * repair from another downstairs does.
+ *
+ * Extents whose gen, flush and dirty bits all agree are left alone by the
+ * upstairs, even if their blocks do not.  With hash majority we also read
+ * those extents, and mend any where one region's blocks differ from the
+ * two that agree with each other and with their hashes.
+ */
+use std::fs::{rename, File};
+use std::os::unix::fs::FileExt;
+
+use crucible::{DownstairsMend, ExtentFix, RegionMetadata};
+use crucible_protocol::{ReadRequest, ReadResponseBlockMetadata};
+
+use super::*;
+use crate::metadata::SidecarMetadata;
+use crate::region::{
+    block_matches_hashes, copy_dir, extent_dir, extent_file_name, extent_path,
+    move_replacement_extent, region_data_path, region_meta_path, replace_dir,
+    sync_path, validate_repair_files, ExtentType,
+};
+
+/*
+ * How many blocks we read at once when comparing an extent's blocks.
+ */
+const MEND_CHUNK_BLOCKS: u64 = 64;
+
+/*
+ * What comparing the blocks of one extent across the regions found.
+ */
+enum BlockVote {
+    Same,
+    Fix(ExtentFix),
+    NoMajority,
+}
+
+/*
+ * Mend the three regions in region_dir.  With dry_run, only print what
+ * would be copied where.  Return an error if any extent is left that we
+ * could not decide how to mend.
+ */
+pub fn mend_regions(
+    region_dir: Vec<PathBuf>,
+    hash_majority: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if region_dir.len() != 3 {
+        bail!(
+            "Need three region directories to mend, not {}",
+            region_dir.len()
+        );
+    }
+
+    let regions = region_dir
+        .iter()
+        .map(|dir| Region::open(dir, Default::default(), false, dry_run))
+        .collect::<Result<Vec<_>>>()?;
+    let defs = regions.iter().map(|r| r.def()).collect::<Vec<_>>();
+
+    for (dir, def) in region_dir.iter().zip(defs.iter()).skip(1) {
+        if def.block_size() != defs[0].block_size()
+            || def.extent_size().value != defs[0].extent_size().value
+            || def.extent_count() != defs[0].extent_count()
+        {
+            bail!(
+                "Region {:?} has {} extents of {} blocks of {} bytes, \
+                but {:?} has {} of {} of {}",
+                dir,
+                def.extent_count(),
+                def.extent_size().value,
+                def.block_size(),
+                region_dir[0],
+                defs[0].extent_count(),
+                defs[0].extent_size().value,
+                defs[0].block_size(),
+            );
+        }
+    }
+
+    let metadata = regions
+        .iter()
+        .map(|r| {
+            Ok(RegionMetadata {
+                generation: r.gen_numbers()?,
+                flush_numbers: r.flush_numbers()?,
+                dirty: r.dirty()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut mend =
+        match DownstairsMend::new(&metadata[0], &metadata[1], &metadata[2]) {
+            Some(dsm) => dsm.mend,
+            None => HashMap::new(),
+        };
+
+    let mut no_majority = Vec::new();
+    if hash_majority {
+        for eid in 0..defs[0].extent_count() as usize {
+            if mend.contains_key(&eid) {
+                continue;
+            }
+            match vote_blocks(&regions, eid)? {
+                BlockVote::Same => {}
+                BlockVote::Fix(ef) => {
+                    println!("Extent {} has blocks that differ", eid);
+                    mend.insert(eid, ef);
+                }
+                BlockVote::NoMajority => {
+                    println!(
+                        "Extent {} has blocks that differ, and no region \
+                        agrees with another on all of them",
+                        eid
+                    );
+                    no_majority.push(eid);
+                }
+            }
+        }
+    }
+
+    let mut eids = mend.keys().copied().collect::<Vec<usize>>();
+    eids.sort_unstable();
+    for eid in eids.iter() {
+        let ef = &mend[eid];
+        println!(
+            "Extent {}: copy from {:?} to {:?}",
+            eid,
+            region_dir[ef.source as usize],
+            ef.dest
+                .iter()
+                .map(|d| &region_dir[*d as usize])
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    if dry_run {
+        println!("Dry run, {} extents not mended", eids.len());
+    } else if !eids.is_empty() {
+        /*
+         * Like the upstairs, flush each source extent with a gen and
+         * flush number higher than any the region set has, so a dirty
+         * source is clean and the others match it once they have its
+         * files.
+         */
+        let max_flush = metadata
+            .iter()
+            .filter_map(|m| m.flush_numbers.iter().max())
+            .max()
+            .map_or(0, |m| m + 1);
+        let max_gen = metadata
+            .iter()
+            .filter_map(|m| m.generation.iter().max())
+            .max()
+            .map_or(0, |m| m + 1);
+        for eid in eids.iter() {
+            let source = mend[eid].source as usize;
+            regions[source].region_flush_extent(*eid, max_flush, max_gen, 0)?;
+        }
+
+        /*
+         * Every extent must be closed before we replace its files.
+         */
+        drop(regions);
+
+        for eid in eids.iter() {
+            let ef = &mend[eid];
+            let source = ef.source as usize;
+            for dest in ef.dest.iter().map(|d| *d as usize) {
+                copy_extent(
+                    &region_dir[source],
+                    &defs[source],
+                    &region_dir[dest],
+                    &defs[dest],
+                    *eid,
+                )?;
+                move_replacement_extent(&region_dir[dest], *eid, &defs[dest])?;
+            }
+        }
+        println!("Mended {} extents", eids.len());
+    }
+
+    if !no_majority.is_empty() {
+        bail!(
+            "Extents {:?} differ with no majority to mend them from",
+            no_majority
+        );
+    }
+
+    Ok(())
+}
+
+/*
+ * Compare every block of an extent across the three regions.  A region
+ * can be the source if, for every block that differs, its copy matches
+ * its own hash and another region's copy.  The regions that can't be are
+ * the ones to mend.
+ */
+fn vote_blocks(regions: &[Region], eid: usize) -> Result<BlockVote> {
+    let (block_size, extent_size, _) = regions[0].region_def();
+    let block_size = block_size as usize;
+
+    let mut candidates = [true; 3];
+    let mut different = false;
+    let mut offset = 0;
+    while offset < extent_size.value {
+        let num_blocks = MEND_CHUNK_BLOCKS.min(extent_size.value - offset);
+        let mut responses = Vec::with_capacity(regions.len());
+        for region in regions.iter() {
+            let mut response = region.region_read(
+                &[ReadRequest {
+                    eid: eid as u64,
+                    offset: Block::new(offset, extent_size.shift),
+                    num_blocks,
+                }],
+                0,
+            )?;
+            responses.push(response.pop().unwrap());
+        }
+
+        for i in 0..num_blocks as usize {
+            let range = i * block_size..(i + 1) * block_size;
+            let copies = responses
+                .iter()
+                .map(|r| (&r.data[range.clone()], &r.blocks[i]))
+                .collect::<Vec<(&[u8], &ReadResponseBlockMetadata)>>();
+            if copies.iter().all(|c| *c == copies[0]) {
+                continue;
+            }
+            different = true;
+
+            for (c, copy) in copies.iter().enumerate() {
+                let (data, block) = copy;
+                let valid = if block.hashes.is_empty() {
+                    data.iter().all(|b| *b == 0)
+                } else {
+                    block_matches_hashes(block, data)
+                };
+                let agreed = copies
+                    .iter()
+                    .enumerate()
+                    .any(|(o, other)| o != c && other == copy);
+                candidates[c] &= valid && agreed;
+            }
+        }
+
+        offset += num_blocks;
+    }
+
+    if !different {
+        return Ok(BlockVote::Same);
+    }
+
+    /*
+     * Two regions that both agree with another on every block agree with
+     * each other, so any candidate will do, and everything that is not
+     * one needs mending.
+     */
+    match candidates.iter().position(|c| *c) {
+        Some(source) => Ok(BlockVote::Fix(ExtentFix {
+            source: source as u8,
+            dest: (0..3).filter(|c| !candidates[*c as usize]).collect(),
+        })),
+        None => Ok(BlockVote::NoMajority),
+    }
+}
+
+/*
+ * Copy the files for extent eid from the source region into the replace
+ * directory of the destination region, the same way get_extent_copy does
+ * from another downstairs.  A single file region gives the parts of its
+ * region files that the extent's files would hold.
+ */
+fn copy_extent(
+    source_dir: &Path,
+    source_def: &RegionDefinition,
+    dest_dir: &Path,
+    dest_def: &RegionDefinition,
+    eid: usize,
+) -> Result<()> {
+    let rd = replace_dir(dest_dir, eid as u32);
+    if rd.exists() {
+        bail!(
+            "Replace directory: {:?} already exists, verify {:?} with \
+            --repair first",
+            rd,
+            dest_dir
+        );
+    }
+
+    let cp = copy_dir(dest_dir, eid as u32);
+    if cp.exists() {
+        std::fs::remove_dir_all(&cp)?;
+    }
+    println!("Create copy dir {:?}", cp);
+    std::fs::create_dir_all(&cp)?;
+
+    let mut files = Vec::new();
+    if source_def.layout() == RegionLayout::SingleFile {
+        let bcount = source_def.extent_size().value;
+        for (path, extent_type, len) in [
+            (
+                region_data_path(source_dir),
+                ExtentType::Data,
+                source_def.block_size() * bcount,
+            ),
+            (
+                region_meta_path(source_dir),
+                ExtentType::Meta,
+                SidecarMetadata::size(bcount),
+            ),
+        ] {
+            let mut buf = vec![0u8; len as usize];
+            File::open(&path)?.read_exact_at(&mut buf, len * eid as u64)?;
+
+            let name = extent_file_name(eid as u32, extent_type);
+            let copy = cp.join(&name);
+            std::fs::write(&copy, &buf)?;
+            sync_path(&copy)?;
+            files.push(name);
+        }
+    } else {
+        let mut source = extent_path(source_dir, eid as u32);
+        for extent_type in [
+            ExtentType::Data,
+            ExtentType::Db,
+            ExtentType::DbShm,
+            ExtentType::DbWal,
+            ExtentType::Meta,
+        ] {
+            let name = extent_file_name(eid as u32, extent_type);
+            source.set_file_name(&name);
+            if source.exists() {
+                let copy = cp.join(&name);
+                std::fs::copy(&source, &copy)?;
+                sync_path(&copy)?;
+                files.push(name);
+            }
+        }
+    }
+
+    files.sort();
+    let problem = if !validate_repair_files(eid, &files) {
+        Some(format!("Invalid repair file list: {:?}", files))
+    } else if dest_def.layout() == RegionLayout::SingleFile
+        && !files.contains(&extent_file_name(eid as u32, ExtentType::Meta))
+    {
+        Some(format!(
+            "A {} region needs a sidecar to repair from, not {:?}",
+            dest_def.layout(),
+            files,
+        ))
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        std::fs::remove_dir_all(&cp)?;
+        bail!("Extent {}: {}", eid, problem);
+    }
+
+    println!("Repair files copied, move directory {:?} to {:?}", cp, rd);
+    rename(&cp, &rd)?;
+    sync_path(extent_dir(dest_dir, eid as u32))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    use crucible_common::{MetadataBackend, RegionOptions};
+    use std::fs::OpenOptions;
+    use tempfile::{tempdir, TempDir};
+
+    fn region_options(layout: RegionLayout) -> RegionOptions {
+        let mut region_options = RegionOptions::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+        if layout == RegionLayout::SingleFile {
+            region_options.set_metadata_backend(MetadataBackend::Sidecar);
+            region_options.set_layout(layout);
+        }
+        region_options
+    }
+
+    fn create_regions(
+        layout: RegionLayout,
+    ) -> Result<(Vec<TempDir>, Vec<Region>)> {
+        let mut dirs = Vec::new();
+        let mut regions = Vec::new();
+        for _ in 0..3 {
+            let dir = tempdir()?;
+            let mut region = Region::create(&dir, region_options(layout))?;
+            region.extend(3)?;
+            dirs.push(dir);
+            regions.push(region);
+        }
+        Ok((dirs, regions))
+    }
+
+    fn write_blocks(region: &Region, eid: u64, count: u64) -> Result<()> {
+        for block in 0..count {
+            let data = Bytes::from(vec![block as u8 + 1; 512]);
+            let hash = integrity_hash(&[&data[..]]);
+            region.region_write(
+                &[crucible_protocol::Write {
+                    eid,
+                    offset: Block::new_512(block),
+                    data,
+                    encryption_context: None,
+                    hash,
+                }],
+                block,
+                false,
+            )?;
+        }
+        region.region_flush(1, 1, &None, count)?;
+        Ok(())
+    }
+
+    fn read_extent(dir: &Path, eid: u64) -> Result<BytesMut> {
+        let region = Region::open(dir, Default::default(), false, true)?;
+        let mut responses = region.region_read(
+            &[ReadRequest {
+                eid,
+                offset: Block::new_512(0),
+                num_blocks: 10,
+            }],
+            0,
+        )?;
+        Ok(responses.pop().unwrap().data)
+    }
+
+    fn paths(dirs: &[TempDir]) -> Vec<PathBuf> {
+        dirs.iter().map(|d| d.path().to_path_buf()).collect()
+    }
+
+    #[test]
+    fn mend_by_metadata() -> Result<()> {
+        for layout in [RegionLayout::PerExtent, RegionLayout::SingleFile] {
+            let (dirs, regions) = create_regions(layout)?;
+            write_blocks(&regions[0], 1, 4)?;
+            write_blocks(&regions[1], 1, 4)?;
+            drop(regions);
+
+            mend_regions(paths(&dirs), false, true)?;
+            let region =
+                Region::open(&dirs[2], Default::default(), false, true)?;
+            assert_eq!(region.flush_numbers()?, vec![0, 0, 0]);
+            drop(region);
+
+            mend_regions(paths(&dirs), false, false)?;
+            for dir in dirs.iter() {
+                let region =
+                    Region::open(dir, Default::default(), false, true)?;
+                assert_eq!(region.flush_numbers()?, vec![0, 1, 0]);
+                assert_eq!(region.dirty()?, vec![false; 3]);
+            }
+            assert_eq!(
+                read_extent(dirs[2].path(), 1)?,
+                read_extent(dirs[0].path(), 1)?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mend_by_hash_majority() -> Result<()> {
+        for layout in [RegionLayout::PerExtent, RegionLayout::SingleFile] {
+            let (dirs, regions) = create_regions(layout)?;
+            for region in regions.iter() {
+                write_blocks(region, 2, 4)?;
+            }
+            drop(regions);
+            let good = read_extent(dirs[0].path(), 2)?;
+
+            // Rot one byte of a written block in the middle region.
+            let (path, offset) = if layout == RegionLayout::SingleFile {
+                (region_data_path(&dirs[1]), 2 * 10 * 512 + 3 * 512 + 5)
+            } else {
+                (extent_path(&dirs[1], 2), 3 * 512 + 5)
+            };
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.write_all_at(&[0xff], offset)?;
+            file.sync_all()?;
+
+            // The metadata all agree, so only the blocks give it away.
+            mend_regions(paths(&dirs), false, false)?;
+            assert_ne!(read_extent(dirs[1].path(), 2)?, good);
+
+            mend_regions(paths(&dirs), true, false)?;
+            assert_eq!(read_extent(dirs[1].path(), 2)?, good);
+
+            // With all three different, there is no telling which is right.
+            for (i, dir) in dirs.iter().enumerate() {
+                let (path, offset) = if layout == RegionLayout::SingleFile {
+                    (region_data_path(dir), 2 * 10 * 512 + 5)
+                } else {
+                    (extent_path(dir, 2), 5)
+                };
+                let file = OpenOptions::new().write(true).open(path)?;
+                file.write_all_at(&[i as u8 + 0x10], offset)?;
+                file.sync_all()?;
+            }
+            assert!(mend_regions(paths(&dirs), true, false).is_err());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1311 @@
+// Copyright 2022 Oxide Computer Company
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use crucible_protocol::EncryptionContext;
+use rusqlite::{params, Connection};
+
+/**
+ * The hash, and encryption context if the region is encrypted, for one
+ * block that is about to be written.
+ */
+#[derive(Debug)]
+pub struct BlockContext<'a> {
+    pub block: u64,
+    pub hash: u64,
+    pub encryption_context: Option<&'a EncryptionContext>,
+}
+
+/**
+ * Where an extent keeps everything about it that is not block data.
+ *
+ * For every block, we keep the hash (and encryption context) of each
+ * write since the last flush, as any one of them could be what is on disk
+ * after a crash.  A flush makes the latest one the only one.  A block with
+ * no hashes has never been written.
+ */
+pub trait ExtentMetadata: fmt::Debug + Send {
+    fn gen_number(&self) -> Result<u64>;
+    fn flush_number(&self) -> Result<u64>;
+    fn dirty(&self) -> Result<bool>;
+    fn set_dirty(&mut self) -> Result<()>;
+
+    /*
+     * The flush and generation numbers are updated at the same time, and
+     * this also clears the dirty bit.
+     */
+    fn set_flush_number(&mut self, new_flush: u64, new_gen: u64) -> Result<()>;
+
+    /*
+     * For a given block, return all encryption contexts since last flush.
+     * Order so latest is last.
+     */
+    fn get_encryption_contexts(
+        &self,
+        block: u64,
+    ) -> Result<Vec<EncryptionContext>>;
+
+    /*
+     * For a given block, return all hashes since last flush. Order so
+     * latest is last.
+     */
+    fn get_hashes(&self, block: u64) -> Result<Vec<u64>>;
+
+    /*
+     * Whether the extent data must be synced to disk before a new context
+     * can be recorded for this block.  If it returns true for any block
+     * in a write, the caller syncs the data before set_block_contexts.
+     */
+    fn needs_sync_before_write(&self, _block: u64) -> bool {
+        false
+    }
+
+    /*
+     * Durably record a new context for each block before the data for
+     * them is written.  Each block's context is recorded on its own, so
+     * after a crash some blocks may have their new context and others
+     * not.  That is safe because the contexts go down before the data,
+     * and a read accepts the data if it matches any context the block
+     * still has.
+     */
+    fn set_block_contexts(&mut self, contexts: &[BlockContext]) -> Result<()>;
+
+    /*
+     * Remove every context for num_blocks blocks starting at block,
+     * leaving them unwritten.
+     */
+    fn remove_blocks(&mut self, block: u64, num_blocks: u64) -> Result<()>;
+
+    /*
+     * How many blocks have at least one context, which is every block
+     * written and not discarded since.
+     */
+    fn written_blocks(&self) -> Result<u64>;
+
+    /*
+     * Get rid of all but the most recent context for each block.  Only
+     * call this once the extent data has been synced.
+     */
+    fn truncate_encryption_contexts_and_hashes(&mut self) -> Result<()>;
+
+    /*
+     * Get everything recorded so far into the metadata files proper, so
+     * a copy of the extent can leave out any journal.  Only call this
+     * while nothing else is using the extent.
+     */
+    fn checkpoint(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn as_any(&mut self) -> &mut dyn std::any::Any;
+}
+
+#[derive(Debug)]
+pub struct SqliteMetadata {
+    metadb: Connection,
+}
+
+/// Always open sqlite with journaling, and synchronous.
+/// Note: these pragma_updates are not durable
+fn open_sqlite_connection<P: AsRef<Path>>(path: &P) -> Result<Connection> {
+    let metadb = Connection::open(&path)?;
+
+    assert!(metadb.is_autocommit());
+    metadb.pragma_update(None, "journal_mode", &"WAL")?;
+    metadb.pragma_update(None, "synchronous", &"FULL")?;
+
+    // rusqlite provides an LRU Cache (a cache which, when full, evicts the
+    // least-recently-used value). This caches prepared statements, allowing
+    // us to nullify the cost of parsing and compiling frequently used
+    // statements.  I've changed all sqlite queries in Inner to use
+    // `prepare_cached` to take advantage of this cache. I have not done this
+    // for `prepare_cached` region creation,
+    // since it wouldn't be relevant there.
+
+    // The result is a dramatic reduction in CPU time spent parsing queries.
+    // Prior to this change, sqlite3Prepare was taking 60% of CPU time
+    // during reads and 50% during writes based on dtrace flamegraphs.
+    // Afterwards, they don't even show up on the graph. I'm seeing a minimum
+    // doubling of actual throughput with `crudd` after this change on my
+    // local hardware.
+
+    // However, this does cost some amount of memory per-extent and thus will
+    // scale linearly . This cache size I'm setting now (64 statements) is
+    // chosen somewhat arbitrarily. If this becomes a significant consumer
+    // of memory, we should reduce the size of the LRU, and stop caching the
+    // statements in the coldest paths. At present, it doesn't seem to have any
+    // meaningful impact on memory usage.
+
+    // We could instead try to pre-generate and hold onto all the statements we
+    // need to use ourselves, but I looked into doing that, and basically
+    // the code required would be a mess due to lifetimes. We shouldn't do
+    // that except as a last resort.
+
+    // Also, if you're curious, the hashing function used is
+    // https://docs.rs/ahash/latest/ahash/index.html
+    metadb.set_prepared_statement_cache_capacity(64);
+
+    Ok(metadb)
+}
+
+impl SqliteMetadata {
+    pub fn open<P: AsRef<Path>>(path: &P) -> Result<SqliteMetadata> {
+        Ok(SqliteMetadata {
+            metadb: open_sqlite_connection(path)?,
+        })
+    }
+
+    /*
+     * Instead of creating the sqlite db for every extent, create it only
+     * once, and copy from a seed db when creating other extents. This
+     * minimizes Region create time.
+     */
+    pub fn create<P: AsRef<Path>>(
+        path: &P,
+        seed: &P,
+    ) -> Result<SqliteMetadata> {
+        if seed.as_ref().exists() {
+            std::fs::copy(seed, path)?;
+
+            return SqliteMetadata::open(path);
+        }
+
+        /*
+         * Create the metadata db
+         */
+        let metadb = open_sqlite_connection(path)?;
+
+        /*
+         * Create tables and insert base data
+         */
+        metadb.execute(
+            "CREATE TABLE metadata (
+                name TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        let meta = super::region::ExtentMeta::default();
+
+        metadb.execute(
+            "INSERT INTO metadata
+            (name, value) VALUES (?1, ?2)",
+            params!["ext_version", meta.ext_version],
+        )?;
+        metadb.execute(
+            "INSERT INTO metadata
+            (name, value) VALUES (?1, ?2)",
+            params!["gen_number", meta.gen_number],
+        )?;
+        metadb.execute(
+            "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+            params!["flush_number", meta.flush_number],
+        )?;
+        metadb.execute(
+            "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+            params!["dirty", meta.dirty],
+        )?;
+
+        metadb.execute(
+            "CREATE TABLE encryption_context (
+                counter INTEGER,
+                block INTEGER,
+                nonce BLOB NOT NULL,
+                tag BLOB NOT NULL,
+                PRIMARY KEY (block, counter)
+            )",
+            [],
+        )?;
+
+        metadb.execute(
+            "CREATE TABLE integrity_hashes (
+                counter INTEGER,
+                block INTEGER,
+                hash BLOB NOT NULL,
+                PRIMARY KEY (block, counter)
+            )",
+            [],
+        )?;
+
+        // write out
+        metadb.close().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("metadb.close() failed! {}", e.1),
+            )
+        })?;
+
+        // Save it as DB seed
+        std::fs::copy(path, seed)?;
+
+        SqliteMetadata::open(path)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> SqliteMetadata {
+        SqliteMetadata {
+            metadb: Connection::open_in_memory().unwrap(),
+        }
+    }
+
+    /*
+     * Given a (block, nonce, tag), append an encryption context row.
+     *
+     * For the params, keep a list of references so that copying is
+     * minimized.
+     */
+    fn tx_set_encryption_context(
+        tx: &rusqlite::Transaction,
+        encryption_context_params: &(u64, &EncryptionContext),
+    ) -> Result<()> {
+        let (block, encryption_context) = encryption_context_params;
+
+        let stmt =
+            "INSERT INTO encryption_context (counter, block, nonce, tag) \
+             VALUES ( \
+                 (SELECT IFNULL(MAX(counter), 0) + 1 FROM encryption_context WHERE block=?1), \
+                 ?1, ?2, ?3 \
+             )";
+
+        let rows_affected = tx.prepare_cached(stmt)?.execute(params![
+            block,
+            &encryption_context.nonce,
+            &encryption_context.tag
+        ])?;
+        assert_eq!(rows_affected, 1);
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn set_encryption_context(
+        &mut self,
+        encryption_context_params: &[(u64, &EncryptionContext)],
+    ) -> Result<()> {
+        let tx = self.metadb.transaction()?;
+
+        for tuple in encryption_context_params {
+            Self::tx_set_encryption_context(&tx, tuple)?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /*
+     * Given a (block, hash), append a hash row.
+     */
+    fn tx_set_hash(
+        tx: &rusqlite::Transaction,
+        hash_params: &(u64, u64),
+    ) -> Result<()> {
+        let (block, hash) = hash_params;
+
+        let stmt =
+            "INSERT INTO integrity_hashes (counter, block, hash) \
+             VALUES ( \
+                 (SELECT IFNULL(MAX(counter), 0) + 1 FROM integrity_hashes WHERE block=?1), \
+                 ?1, ?2 \
+             )";
+
+        let rows_affected = tx
+            .prepare_cached(stmt)?
+            .execute(params![block, &hash.to_le_bytes()])?;
+        assert_eq!(rows_affected, 1);
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn set_hashes(&mut self, hash_params: &[(u64, u64)]) -> Result<()> {
+        let tx = self.metadb.transaction()?;
+
+        for tuple in hash_params {
+            Self::tx_set_hash(&tx, tuple)?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /*
+     * In order to unit test truncate_encryption_contexts_and_hashes, return
+     * blocks and counters.
+     */
+    #[cfg(test)]
+    pub fn get_blocks_and_counters_for_encryption_context(
+        &mut self,
+    ) -> Result<Vec<(u64, u64)>> {
+        let mut stmt = self
+            .metadb
+            .prepare_cached("SELECT block, counter FROM encryption_context")?;
+
+        let stmt_iter =
+            stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut results = Vec::new();
+
+        for row in stmt_iter {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    #[cfg(test)]
+    pub fn get_blocks_and_counters_for_hashes(
+        &mut self,
+    ) -> Result<Vec<(u64, u64)>> {
+        let mut stmt = self
+            .metadb
+            .prepare_cached("SELECT block, counter FROM integrity_hashes")?;
+
+        let stmt_iter =
+            stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut results = Vec::new();
+
+        for row in stmt_iter {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+}
+
+impl ExtentMetadata for SqliteMetadata {
+    fn gen_number(&self) -> Result<u64> {
+        let mut stmt = self.metadb.prepare_cached(
+            "SELECT value FROM metadata where name='gen_number'",
+        )?;
+        let gen_number_iter = stmt.query_map([], |row| row.get(0))?;
+
+        let mut gen_number_values: Vec<u64> = vec![];
+        for gen_number_value in gen_number_iter {
+            gen_number_values.push(gen_number_value?);
+        }
+
+        assert!(gen_number_values.len() == 1);
+
+        Ok(gen_number_values[0])
+    }
+
+    fn flush_number(&self) -> Result<u64> {
+        let mut stmt = self.metadb.prepare_cached(
+            "SELECT value FROM metadata where name='flush_number'",
+        )?;
+        let flush_number_iter = stmt.query_map([], |row| row.get(0))?;
+
+        let mut flush_number_values: Vec<u64> = vec![];
+        for flush_number_value in flush_number_iter {
+            flush_number_values.push(flush_number_value?);
+        }
+
+        assert!(flush_number_values.len() == 1);
+
+        Ok(flush_number_values[0])
+    }
+
+    fn set_flush_number(&mut self, new_flush: u64, new_gen: u64) -> Result<()> {
+        let mut stmt = self.metadb.prepare_cached(
+            "UPDATE metadata SET value=?1 WHERE name='flush_number'",
+        )?;
+
+        let _rows_affected = stmt.execute([new_flush])?;
+
+        let mut stmt = self.metadb.prepare_cached(
+            "UPDATE metadata SET value=?1 WHERE name='gen_number'",
+        )?;
+
+        let _rows_affected = stmt.execute([new_gen])?;
+
+        /*
+         * When we write out the new flush number, the dirty bit should be
+         * set back to false.
+         */
+        let mut stmt = self
+            .metadb
+            .prepare_cached("UPDATE metadata SET value=0 WHERE name='dirty'")?;
+        let _rows_affected = stmt.execute([])?;
+
+        Ok(())
+    }
+
+    fn dirty(&self) -> Result<bool> {
+        let mut stmt = self
+            .metadb
+            .prepare_cached("SELECT value FROM metadata where name='dirty'")?;
+        let dirty_iter = stmt.query_map([], |row| row.get(0))?;
+
+        let mut dirty_values: Vec<bool> = vec![];
+        for dirty_value in dirty_iter {
+            dirty_values.push(dirty_value?);
+        }
+
+        assert!(dirty_values.len() == 1);
+
+        Ok(dirty_values[0])
+    }
+
+    fn set_dirty(&mut self) -> Result<()> {
+        let _ = self
+            .metadb
+            .prepare_cached("UPDATE metadata SET value=1 WHERE name='dirty'")?
+            .execute([])?;
+        Ok(())
+    }
+
+    fn get_encryption_contexts(
+        &self,
+        block: u64,
+    ) -> Result<Vec<EncryptionContext>> {
+        // NOTE: "ORDER BY RANDOM()" would be a good --lossy addition here
+        let stmt = "SELECT nonce, tag FROM encryption_context where block=?1 \
+             ORDER BY counter ASC";
+        let mut stmt = self.metadb.prepare_cached(stmt)?;
+
+        let stmt_iter = stmt
+            .query_map(params![block], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut results = Vec::new();
+
+        for row in stmt_iter {
+            let (nonce, tag) = row?;
+            results.push(EncryptionContext { nonce, tag });
+        }
+
+        Ok(results)
+    }
+
+    fn get_hashes(&self, block: u64) -> Result<Vec<u64>> {
+        // NOTE: "ORDER BY RANDOM()" would be a good --lossy addition here
+        let stmt = "SELECT hash FROM integrity_hashes where block=?1 \
+             ORDER BY counter ASC";
+        let mut stmt = self.metadb.prepare_cached(stmt)?;
+
+        let stmt_iter = stmt.query_map(params![block], |row| row.get(0))?;
+
+        let mut results = Vec::new();
+
+        for row in stmt_iter {
+            let hash: Vec<u8> = row?;
+            assert_eq!(hash.len(), 8);
+
+            results.push(u64::from_le_bytes(hash[..].try_into()?));
+        }
+
+        Ok(results)
+    }
+
+    /*
+     * To minimize the performance hit of sending many transactions to
+     * sqlite, every context goes in the same one.
+     */
+    fn set_block_contexts(&mut self, contexts: &[BlockContext]) -> Result<()> {
+        let tx = self.metadb.transaction()?;
+
+        for context in contexts {
+            if let Some(encryption_context) = context.encryption_context {
+                Self::tx_set_encryption_context(
+                    &tx,
+                    &(context.block, encryption_context),
+                )?;
+            }
+
+            Self::tx_set_hash(&tx, &(context.block, context.hash))?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn remove_blocks(&mut self, block: u64, num_blocks: u64) -> Result<()> {
+        let end = block + num_blocks;
+
+        let tx = self.metadb.transaction()?;
+
+        let _rows_affected = tx
+            .prepare_cached(
+                "DELETE FROM encryption_context \
+                 WHERE block >= ?1 AND block < ?2",
+            )?
+            .execute(params![block, end])?;
+
+        let _rows_affected = tx
+            .prepare_cached(
+                "DELETE FROM integrity_hashes \
+                 WHERE block >= ?1 AND block < ?2",
+            )?
+            .execute(params![block, end])?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /*
+     * Every write records a hash, so the hashes alone tell us which
+     * blocks are written.
+     */
+    fn written_blocks(&self) -> Result<u64> {
+        let count: i64 = self
+            .metadb
+            .prepare_cached(
+                "SELECT COUNT(DISTINCT block) FROM integrity_hashes",
+            )?
+            .query_row([], |row| row.get(0))?;
+        Ok(count as u64)
+    }
+
+    fn truncate_encryption_contexts_and_hashes(&mut self) -> Result<()> {
+        let tx = self.metadb.transaction()?;
+
+        // Clear encryption context
+        let stmt = "DELETE FROM encryption_context WHERE ROWID not in \
+             (select ROWID from \
+                 (select ROWID,block,MAX(counter) \
+                  from encryption_context group by block\
+                 ) \
+             )";
+
+        let _rows_affected = tx.prepare_cached(stmt)?.execute([])?;
+
+        let _rows_affected = tx
+            .prepare_cached("UPDATE encryption_context SET counter = 0")?
+            .execute([])?;
+
+        // Clear integrity hash
+        let stmt = "DELETE FROM integrity_hashes WHERE ROWID not in \
+             (select ROWID from \
+                 (select ROWID,block,MAX(counter) \
+                  from integrity_hashes group by block \
+                 ) \
+             )";
+
+        let _rows_affected = tx.prepare_cached(stmt)?.execute([])?;
+
+        let _rows_affected = tx
+            .prepare_cached("UPDATE integrity_hashes SET counter = 0")?
+            .execute([])?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /*
+     * Move everything in the write ahead log into the database and empty
+     * the log.  SQLite gives up instead of waiting if a reader is in the
+     * way, which nothing should be.
+     */
+    fn checkpoint(&mut self) -> Result<()> {
+        let busy: i64 = self.metadb.query_row(
+            "PRAGMA wal_checkpoint(TRUNCATE)",
+            [],
+            |row| row.get(0),
+        )?;
+        if busy != 0 {
+            bail!("could not checkpoint the metadata database");
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+/*
+ * A sidecar file is a fixed size header followed by a fixed size record
+ * for every block in the extent:
+ *
+ *   header: [magic | u64 gen | u64 flush | u8 dirty | padding]
+ *   record: [u8 state | slot 0 | slot 1]
+ *   slot:   [u8 flags | u64 hash | nonce | tag]
+ *
+ * All numbers are little endian.
+ *
+ * Each block has room for two contexts.  Bit 0 of the state says which
+ * slot holds the newest one, and bit 1 says the other slot holds one that
+ * could still be what is on disk.  A write puts its context in the slot
+ * that is not the newest.  If that slot is still needed, the extent data
+ * is synced first, so the newest context becomes the only one that could
+ * be on disk and the other slot is free again.  A flush syncs the data
+ * anyway, so after one every block needs only its newest context.
+ *
+ * Every change is written in place and synced before we return, so a
+ * write costs one sync of this file and, for a block written twice
+ * between flushes, one of the extent data.
+ *
+ * A sidecar need not start at the beginning of its file, so one file can
+ * hold the sidecars for every extent in a region.
+ */
+const SIDECAR_MAGIC: &[u8; 8] = b"CRUMETA1";
+const SIDECAR_HEADER_SIZE: usize = 32;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const SLOT_SIZE: usize = 1 + 8 + NONCE_SIZE + TAG_SIZE;
+const RECORD_SIZE: usize = 1 + 2 * SLOT_SIZE;
+
+const STATE_NEWEST: u8 = 1 << 0;
+const STATE_OLDER_LIVE: u8 = 1 << 1;
+
+const SLOT_WRITTEN: u8 = 1 << 0;
+const SLOT_ENCRYPTED: u8 = 1 << 1;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct Slot {
+    flags: u8,
+    hash: u64,
+    nonce: [u8; NONCE_SIZE],
+    tag: [u8; TAG_SIZE],
+}
+
+impl Slot {
+    fn written(&self) -> bool {
+        self.flags & SLOT_WRITTEN != 0
+    }
+
+    fn encryption_context(&self) -> Option<EncryptionContext> {
+        if self.flags & SLOT_ENCRYPTED != 0 {
+            Some(EncryptionContext {
+                nonce: self.nonce.to_vec(),
+                tag: self.tag.to_vec(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn from_context(context: &BlockContext) -> Result<Slot> {
+        let mut slot = Slot {
+            flags: SLOT_WRITTEN,
+            hash: context.hash,
+            ..Default::default()
+        };
+
+        if let Some(ec) = context.encryption_context {
+            if ec.nonce.len() != NONCE_SIZE || ec.tag.len() != TAG_SIZE {
+                bail!(
+                    "block {}: encryption context of {} and {} bytes does \
+                    not fit in a sidecar",
+                    context.block,
+                    ec.nonce.len(),
+                    ec.tag.len(),
+                );
+            }
+            slot.flags |= SLOT_ENCRYPTED;
+            slot.nonce.copy_from_slice(&ec.nonce);
+            slot.tag.copy_from_slice(&ec.tag);
+        }
+
+        Ok(slot)
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.flags;
+        buf[1..9].copy_from_slice(&self.hash.to_le_bytes());
+        buf[9..9 + NONCE_SIZE].copy_from_slice(&self.nonce);
+        buf[9 + NONCE_SIZE..SLOT_SIZE].copy_from_slice(&self.tag);
+    }
+
+    fn decode(buf: &[u8]) -> Slot {
+        let mut slot = Slot {
+            flags: buf[0],
+            hash: u64::from_le_bytes(buf[1..9].try_into().unwrap()),
+            ..Default::default()
+        };
+        slot.nonce.copy_from_slice(&buf[9..9 + NONCE_SIZE]);
+        slot.tag.copy_from_slice(&buf[9 + NONCE_SIZE..SLOT_SIZE]);
+        slot
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct BlockRecord {
+    state: u8,
+    slots: [Slot; 2],
+}
+
+impl BlockRecord {
+    /*
+     * Every context that could be on disk for this block, oldest first.
+     */
+    fn live(&self) -> Vec<&Slot> {
+        let newest = (self.state & STATE_NEWEST) as usize;
+        let mut live = Vec::with_capacity(2);
+        if self.state & STATE_OLDER_LIVE != 0 {
+            live.push(&self.slots[1 - newest]);
+        }
+        live.push(&self.slots[newest]);
+        live.retain(|s| s.written());
+        live
+    }
+
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0u8; RECORD_SIZE];
+        buf[0] = self.state;
+        self.slots[0].encode(&mut buf[1..1 + SLOT_SIZE]);
+        self.slots[1].encode(&mut buf[1 + SLOT_SIZE..]);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> BlockRecord {
+        BlockRecord {
+            state: buf[0],
+            slots: [
+                Slot::decode(&buf[1..1 + SLOT_SIZE]),
+                Slot::decode(&buf[1 + SLOT_SIZE..RECORD_SIZE]),
+            ],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SidecarMetadata {
+    file: File,
+
+    /*
+     * Where in the file this sidecar starts.  A file can hold the
+     * sidecars for many extents back to back.
+     */
+    base: u64,
+
+    gen_number: u64,
+    flush_number: u64,
+    dirty: bool,
+
+    /*
+     * A copy of every record in the file, so reads never have to go to
+     * it.
+     */
+    records: Vec<BlockRecord>,
+}
+
+impl SidecarMetadata {
+    /*
+     * The number of bytes the sidecar for an extent of this many blocks
+     * takes up.
+     */
+    pub fn size(blocks: u64) -> u64 {
+        SIDECAR_HEADER_SIZE as u64 + blocks * RECORD_SIZE as u64
+    }
+
+    fn record_offset(&self, block: u64) -> u64 {
+        self.base + SIDECAR_HEADER_SIZE as u64 + block * RECORD_SIZE as u64
+    }
+
+    /*
+     * Create the sidecar for a new extent of the given number of blocks,
+     * all of them unwritten.
+     */
+    pub fn create<P: AsRef<Path>>(
+        path: &P,
+        blocks: u64,
+    ) -> Result<SidecarMetadata> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.set_len(SidecarMetadata::size(blocks))?;
+
+        SidecarMetadata::create_in(file, 0, blocks)
+    }
+
+    /*
+     * Create a sidecar at base in a file that is already big enough to
+     * hold it, and where that space is all zeros.
+     */
+    pub fn create_in(
+        file: File,
+        base: u64,
+        blocks: u64,
+    ) -> Result<SidecarMetadata> {
+        let end = base + SidecarMetadata::size(blocks);
+        let size = file.metadata()?.len();
+        if size < end {
+            bail!("sidecar at {} needs {} bytes, file has {}", base, end, size);
+        }
+
+        let mut magic = [0u8; SIDECAR_MAGIC.len()];
+        file.read_exact_at(&mut magic, base)?;
+        if &magic == SIDECAR_MAGIC {
+            bail!("there is already a sidecar at {}", base);
+        }
+
+        let meta = SidecarMetadata {
+            file,
+            base,
+            gen_number: 0,
+            flush_number: 0,
+            dirty: false,
+            records: vec![BlockRecord::default(); blocks as usize],
+        };
+        meta.write_header()?;
+        meta.file.sync_all()?;
+
+        Ok(meta)
+    }
+
+    pub fn open<P: AsRef<Path>>(
+        path: &P,
+        blocks: u64,
+        read_only: bool,
+    ) -> Result<SidecarMetadata> {
+        let file =
+            OpenOptions::new().read(true).write(!read_only).open(path)?;
+
+        let size = file.metadata()?.len();
+        if size != SidecarMetadata::size(blocks) {
+            bail!(
+                "sidecar {:?} is {} bytes, expected {} for {} blocks",
+                path.as_ref(),
+                size,
+                SidecarMetadata::size(blocks),
+                blocks,
+            );
+        }
+
+        match SidecarMetadata::open_in(file, 0, blocks) {
+            Ok(meta) => Ok(meta),
+            Err(e) => bail!("{:?}: {}", path.as_ref(), e),
+        }
+    }
+
+    /*
+     * Open the sidecar at base in a file.
+     */
+    pub fn open_in(
+        file: File,
+        base: u64,
+        blocks: u64,
+    ) -> Result<SidecarMetadata> {
+        let end = base + SidecarMetadata::size(blocks);
+        let size = file.metadata()?.len();
+        if size < end {
+            bail!("sidecar at {} needs {} bytes, file has {}", base, end, size);
+        }
+
+        let mut buf = vec![0u8; SidecarMetadata::size(blocks) as usize];
+        file.read_exact_at(&mut buf, base)?;
+
+        if &buf[0..8] != SIDECAR_MAGIC {
+            bail!("no sidecar at {}", base);
+        }
+
+        let records = buf[SIDECAR_HEADER_SIZE..]
+            .chunks_exact(RECORD_SIZE)
+            .map(BlockRecord::decode)
+            .collect();
+
+        Ok(SidecarMetadata {
+            file,
+            base,
+            gen_number: u64::from_le_bytes(buf[8..16].try_into()?),
+            flush_number: u64::from_le_bytes(buf[16..24].try_into()?),
+            dirty: buf[24] != 0,
+            records,
+        })
+    }
+
+    fn write_header(&self) -> Result<()> {
+        let mut buf = [0u8; SIDECAR_HEADER_SIZE];
+        buf[0..8].copy_from_slice(SIDECAR_MAGIC);
+        buf[8..16].copy_from_slice(&self.gen_number.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.flush_number.to_le_bytes());
+        buf[24] = self.dirty as u8;
+        self.file.write_all_at(&buf, self.base)?;
+        Ok(())
+    }
+
+    fn write_record(&self, block: u64) -> Result<()> {
+        let buf = self.records[block as usize].encode();
+        self.file.write_all_at(&buf, self.record_offset(block))?;
+        Ok(())
+    }
+
+    fn record(&self, block: u64) -> Result<&BlockRecord> {
+        match self.records.get(block as usize) {
+            Some(record) => Ok(record),
+            None => bail!(
+                "block {} is past the end of a {} block sidecar",
+                block,
+                self.records.len()
+            ),
+        }
+    }
+}
+
+impl ExtentMetadata for SidecarMetadata {
+    fn gen_number(&self) -> Result<u64> {
+        Ok(self.gen_number)
+    }
+
+    fn flush_number(&self) -> Result<u64> {
+        Ok(self.flush_number)
+    }
+
+    fn set_flush_number(&mut self, new_flush: u64, new_gen: u64) -> Result<()> {
+        self.flush_number = new_flush;
+        self.gen_number = new_gen;
+        self.dirty = false;
+        self.write_header()?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn dirty(&self) -> Result<bool> {
+        Ok(self.dirty)
+    }
+
+    fn set_dirty(&mut self) -> Result<()> {
+        if !self.dirty {
+            self.dirty = true;
+            self.write_header()?;
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn get_encryption_contexts(
+        &self,
+        block: u64,
+    ) -> Result<Vec<EncryptionContext>> {
+        Ok(self
+            .record(block)?
+            .live()
+            .iter()
+            .filter_map(|slot| slot.encryption_context())
+            .collect())
+    }
+
+    fn get_hashes(&self, block: u64) -> Result<Vec<u64>> {
+        Ok(self
+            .record(block)?
+            .live()
+            .iter()
+            .map(|slot| slot.hash)
+            .collect())
+    }
+
+    fn needs_sync_before_write(&self, block: u64) -> bool {
+        match self.records.get(block as usize) {
+            Some(record) => record.state & STATE_OLDER_LIVE != 0,
+            None => false,
+        }
+    }
+
+    fn set_block_contexts(&mut self, contexts: &[BlockContext]) -> Result<()> {
+        /*
+         * Check everything before changing anything, so a bad context
+         * leaves every record as it was.
+         */
+        let mut slots = Vec::with_capacity(contexts.len());
+        for (i, context) in contexts.iter().enumerate() {
+            self.record(context.block)?;
+            if contexts[..i].iter().any(|c| c.block == context.block) {
+                bail!("block {} written twice in one write", context.block);
+            }
+            slots.push(Slot::from_context(context)?);
+        }
+
+        for (context, slot) in contexts.iter().zip(slots) {
+            let record = &mut self.records[context.block as usize];
+            let newest = (record.state & STATE_NEWEST) as usize;
+
+            /*
+             * If the newest slot has been written, keep it around: until
+             * our data is synced, it could still be what is on disk.
+             * The caller has synced the data if the other slot was still
+             * needed, so we are free to take it.
+             */
+            let older_live = if record.slots[newest].written() {
+                STATE_OLDER_LIVE
+            } else {
+                0
+            };
+
+            record.slots[1 - newest] = slot;
+            record.state = (1 - newest) as u8 | older_live;
+
+            self.write_record(context.block)?;
+        }
+
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn remove_blocks(&mut self, block: u64, num_blocks: u64) -> Result<()> {
+        let end = block + num_blocks;
+        if end > self.records.len() as u64 {
+            bail!(
+                "blocks {}..{} are past the end of a {} block sidecar",
+                block,
+                end,
+                self.records.len()
+            );
+        }
+
+        for b in block..end {
+            self.records[b as usize] = BlockRecord::default();
+            self.write_record(b)?;
+        }
+
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn written_blocks(&self) -> Result<u64> {
+        Ok(self
+            .records
+            .iter()
+            .filter(|record| !record.live().is_empty())
+            .count() as u64)
+    }
+
+    fn truncate_encryption_contexts_and_hashes(&mut self) -> Result<()> {
+        let mut changed = false;
+
+        for b in 0..self.records.len() {
+            let record = &mut self.records[b];
+            if record.state & STATE_OLDER_LIVE == 0 {
+                continue;
+            }
+
+            record.state &= !STATE_OLDER_LIVE;
+            self.write_record(b as u64)?;
+            changed = true;
+        }
+
+        if changed {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn ctx(block: u64, hash: u64) -> BlockContext<'static> {
+        BlockContext {
+            block,
+            hash,
+            encryption_context: None,
+        }
+    }
+
+    #[test]
+    fn sidecar_starts_unwritten() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("000.meta");
+        let meta = SidecarMetadata::create(&path, 10)?;
+
+        assert_eq!(meta.gen_number()?, 0);
+        assert_eq!(meta.flush_number()?, 0);
+        assert!(!meta.dirty()?);
+        for block in 0..10 {
+            assert!(meta.get_hashes(block)?.is_empty());
+            assert!(meta.get_encryption_contexts(block)?.is_empty());
+        }
+        assert!(meta.get_hashes(10).is_err());
+
+        // Never overwrite an existing sidecar.
+        assert!(SidecarMetadata::create(&path, 10).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sidecar_keeps_contexts_until_flush() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("000.meta");
+        let mut meta = SidecarMetadata::create(&path, 10)?;
+
+        meta.set_dirty()?;
+        meta.set_block_contexts(&[ctx(0, 11), ctx(1, 21)])?;
+        assert_eq!(meta.get_hashes(0)?, vec![11]);
+        assert_eq!(meta.get_hashes(1)?, vec![21]);
+        assert!(!meta.needs_sync_before_write(0));
+
+        // A second write keeps the first, as either could be on disk.
+        meta.set_block_contexts(&[ctx(0, 12)])?;
+        assert_eq!(meta.get_hashes(0)?, vec![11, 12]);
+
+        // A third needs the data synced, after which the first is gone.
+        assert!(meta.needs_sync_before_write(0));
+        assert!(!meta.needs_sync_before_write(1));
+        meta.set_block_contexts(&[ctx(0, 13)])?;
+        assert_eq!(meta.get_hashes(0)?, vec![12, 13]);
+
+        // Everything survives a reopen.
+        drop(meta);
+        let mut meta = SidecarMetadata::open(&path, 10, false)?;
+        assert!(meta.dirty()?);
+        assert_eq!(meta.get_hashes(0)?, vec![12, 13]);
+        assert_eq!(meta.get_hashes(1)?, vec![21]);
+
+        // "Flush", so only the latest should remain.
+        meta.truncate_encryption_contexts_and_hashes()?;
+        meta.set_flush_number(3, 2)?;
+        assert_eq!(meta.get_hashes(0)?, vec![13]);
+        assert!(!meta.needs_sync_before_write(0));
+
+        drop(meta);
+        let meta = SidecarMetadata::open(&path, 10, true)?;
+        assert_eq!(meta.flush_number()?, 3);
+        assert_eq!(meta.gen_number()?, 2);
+        assert!(!meta.dirty()?);
+        assert_eq!(meta.get_hashes(0)?, vec![13]);
+        assert_eq!(meta.get_hashes(1)?, vec![21]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sidecar_encryption_contexts() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("000.meta");
+        let mut meta = SidecarMetadata::create(&path, 4)?;
+
+        let first = EncryptionContext {
+            nonce: vec![1; NONCE_SIZE],
+            tag: vec![2; TAG_SIZE],
+        };
+        let second = EncryptionContext {
+            nonce: vec![3; NONCE_SIZE],
+            tag: vec![4; TAG_SIZE],
+        };
+
+        meta.set_block_contexts(&[BlockContext {
+            block: 2,
+            hash: 7,
+            encryption_context: Some(&first),
+        }])?;
+        meta.set_block_contexts(&[BlockContext {
+            block: 2,
+            hash: 8,
+            encryption_context: Some(&second),
+        }])?;
+        assert_eq!(
+            meta.get_encryption_contexts(2)?,
+            vec![first.clone(), second.clone()]
+        );
+
+        // A context that does not fit changes nothing.
+        let big = EncryptionContext {
+            nonce: vec![5; 32],
+            tag: vec![6; 32],
+        };
+        assert!(meta
+            .set_block_contexts(&[
+                ctx(1, 9),
+                BlockContext {
+                    block: 3,
+                    hash: 10,
+                    encryption_context: Some(&big),
+                },
+            ])
+            .is_err());
+        assert!(meta.get_hashes(1)?.is_empty());
+        assert!(meta.get_hashes(3)?.is_empty());
+
+        meta.truncate_encryption_contexts_and_hashes()?;
+        assert_eq!(meta.get_encryption_contexts(2)?, vec![second]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sidecar_remove_blocks() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("000.meta");
+        let mut meta = SidecarMetadata::create(&path, 6)?;
+
+        meta.set_block_contexts(
+            &(0..6).map(|b| ctx(b, b)).collect::<Vec<_>>(),
+        )?;
+        meta.remove_blocks(2, 3)?;
+        assert!(meta.remove_blocks(5, 2).is_err());
+        assert_eq!(meta.written_blocks()?, 3);
+
+        drop(meta);
+        let meta = SidecarMetadata::open(&path, 6, true)?;
+        assert_eq!(meta.written_blocks()?, 3);
+        for block in 0..6 {
+            if (2..5).contains(&block) {
+                assert!(meta.get_hashes(block)?.is_empty());
+            } else {
+                assert_eq!(meta.get_hashes(block)?, vec![block]);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sidecar_rejects_wrong_size() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("000.meta");
+        SidecarMetadata::create(&path, 6)?;
+
+        assert!(SidecarMetadata::open(&path, 7, false).is_err());
+        assert!(SidecarMetadata::open(&path, 6, false).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sidecars_share_a_file() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("region.meta");
+        let size = SidecarMetadata::size(4);
+        let file = File::create(&path)?;
+        file.set_len(2 * size)?;
+
+        let open = || OpenOptions::new().read(true).write(true).open(&path);
+
+        let mut first = SidecarMetadata::create_in(open()?, 0, 4)?;
+        let mut second = SidecarMetadata::create_in(open()?, size, 4)?;
+        assert!(SidecarMetadata::create_in(open()?, size, 4).is_err());
+        assert!(SidecarMetadata::create_in(open()?, 2 * size, 4).is_err());
+
+        first.set_block_contexts(&[ctx(3, 1)])?;
+        first.set_flush_number(1, 1)?;
+        second.set_block_contexts(&[ctx(3, 2)])?;
+        second.set_flush_number(2, 2)?;
+        drop(first);
+        drop(second);
+
+        let first = SidecarMetadata::open_in(open()?, 0, 4)?;
+        let second = SidecarMetadata::open_in(open()?, size, 4)?;
+        assert_eq!(first.flush_number()?, 1);
+        assert_eq!(first.get_hashes(3)?, vec![1]);
+        assert_eq!(second.flush_number()?, 2);
+        assert_eq!(second.get_hashes(3)?, vec![2]);
+
+        Ok(())
+    }
+}
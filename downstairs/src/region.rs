@@ -1,25 +1,29 @@
 // Copyright 2021 Oxide Computer Company
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::TryInto;
 use std::fmt;
 use std::fs::{rename, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::SocketAddr;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
 use std::sync::{Mutex, MutexGuard};
 
 use anyhow::{bail, Result};
 use crucible_common::*;
-use crucible_protocol::{EncryptionContext, SnapshotDetails};
+use crucible_protocol::{EncryptionContext, SnapshotDetails, SpaceUsage};
 use futures::TryStreamExt;
 use repair_client::types::FileType;
 use repair_client::Client;
-use rusqlite::{params, Connection};
 use serde::{Deserialize, Serialize};
 use tokio::macros::support::Pin;
 use tracing::instrument;
 
 use super::*;
+use crate::metadata::*;
+use crate::snapshot;
+use crate::uring;
 
 #[derive(Debug)]
 pub struct Extent {
@@ -28,104 +32,153 @@ pub struct Extent {
     block_size: u64,
     extent_size: Block,
     /// Inner contains information about the actual extent file that holds
-    /// the data, and the metadata (stored in the database) about that
-    /// extent.
+    /// the data, and the metadata (stored in the database or sidecar) about
+    /// that extent.
     ///
     /// If Some(), it means the extent file and database metadata for
     /// it are opened.
     /// If None, it means the extent is currently
     /// closed (and possibly being updated out of band).
     inner: Option<Mutex<Inner>>,
+    io_backend: IoBackend,
 }
 
-#[derive(Debug)]
-pub struct Inner {
-    file: File,
-    metadb: Connection,
+/**
+ * How a downstairs does IO to its extent files.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoBackend {
+    /**
+     * Seek, read, and write on the file, holding the extent lock.
+     */
+    Std,
+    /**
+     * Submit all of a job's reads, writes, or fsyncs at once through
+     * io_uring, without holding the extent lock while they happen.  Only
+     * on Linux, in a downstairs built with the io_uring feature.
+     */
+    IoUring,
 }
 
-impl Inner {
-    pub fn gen_number(&self) -> Result<u64> {
-        let mut stmt = self.metadb.prepare_cached(
-            "SELECT value FROM metadata where name='gen_number'",
-        )?;
-        let gen_number_iter = stmt.query_map([], |row| row.get(0))?;
-
-        let mut gen_number_values: Vec<u64> = vec![];
-        for gen_number_value in gen_number_iter {
-            gen_number_values.push(gen_number_value?);
+impl IoBackend {
+    /**
+     * Can this downstairs, on this kernel, do IO this way?
+     */
+    pub fn check_available(&self) -> Result<()> {
+        if *self == IoBackend::IoUring {
+            if let Err(e) = uring::available() {
+                bail!("cannot use the {} IO backend: {}", self, e);
+            }
         }
+        Ok(())
+    }
+}
 
-        assert!(gen_number_values.len() == 1);
-
-        Ok(gen_number_values[0])
+impl Default for IoBackend {
+    fn default() -> Self {
+        IoBackend::Std
     }
+}
 
-    pub fn flush_number(&self) -> Result<u64> {
-        let mut stmt = self.metadb.prepare_cached(
-            "SELECT value FROM metadata where name='flush_number'",
-        )?;
-        let flush_number_iter = stmt.query_map([], |row| row.get(0))?;
+impl std::str::FromStr for IoBackend {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().replace('_', "-").as_str() {
+            "std" => IoBackend::Std,
+            "io-uring" => IoBackend::IoUring,
+            _ => {
+                bail!("not a valid IO backend: {}", s);
+            }
+        })
+    }
+}
 
-        let mut flush_number_values: Vec<u64> = vec![];
-        for flush_number_value in flush_number_iter {
-            flush_number_values.push(flush_number_value?);
+impl fmt::Display for IoBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoBackend::Std => write!(f, "std"),
+            IoBackend::IoUring => write!(f, "io-uring"),
         }
+    }
+}
 
-        assert!(flush_number_values.len() == 1);
+#[derive(Debug)]
+pub struct Inner {
+    file: File,
+    meta: Box<dyn ExtentMetadata>,
+
+    /*
+     * Where this extent's data starts in file.  This is only non-zero
+     * when every extent shares one data file.
+     */
+    data_offset: u64,
+}
+
+impl Inner {
+    pub fn gen_number(&self) -> Result<u64> {
+        self.meta.gen_number()
+    }
 
-        Ok(flush_number_values[0])
+    pub fn flush_number(&self) -> Result<u64> {
+        self.meta.flush_number()
     }
 
     /*
      * The flush and generation numbers will be updated at the same time.
      */
-    fn set_flush_number(&self, new_flush: u64, new_gen: u64) -> Result<()> {
-        let mut stmt = self.metadb.prepare_cached(
-            "UPDATE metadata SET value=?1 WHERE name='flush_number'",
-        )?;
-
-        let _rows_affected = stmt.execute([new_flush])?;
+    fn set_flush_number(&mut self, new_flush: u64, new_gen: u64) -> Result<()> {
+        self.meta.set_flush_number(new_flush, new_gen)
+    }
 
-        let mut stmt = self.metadb.prepare_cached(
-            "UPDATE metadata SET value=?1 WHERE name='gen_number'",
-        )?;
+    pub fn dirty(&self) -> Result<bool> {
+        self.meta.dirty()
+    }
 
-        let _rows_affected = stmt.execute([new_gen])?;
+    fn set_dirty(&mut self) -> Result<()> {
+        self.meta.set_dirty()
+    }
 
-        /*
-         * When we write out the new flush number, the dirty bit should be
-         * set back to false.
-         */
-        let mut stmt = self
-            .metadb
-            .prepare_cached("UPDATE metadata SET value=0 WHERE name='dirty'")?;
-        let _rows_affected = stmt.execute([])?;
+    pub fn written_blocks(&self) -> Result<u64> {
+        self.meta.written_blocks()
+    }
 
-        Ok(())
+    /*
+     * A block is written if it has a hash, which every write leaves and
+     * only a discard removes.
+     */
+    fn block_written(&self, block: u64) -> Result<bool> {
+        Ok(!self.meta.get_hashes(block)?.is_empty())
     }
 
-    pub fn dirty(&self) -> Result<bool> {
-        let mut stmt = self
-            .metadb
-            .prepare_cached("SELECT value FROM metadata where name='dirty'")?;
-        let dirty_iter = stmt.query_map([], |row| row.get(0))?;
+    /*
+     * Fill in the metadata for each block of a read.  A block with no
+     * hashes is unwritten, and reads as zeros whatever the file holds: a
+     * discard forgets the blocks before their data is punched out.
+     */
+    fn fill_read_response(
+        &self,
+        request: &crucible_protocol::ReadRequest,
+        response: &mut crucible_protocol::ReadResponse,
+    ) -> Result<()> {
+        let block_size = response.data.len() / request.num_blocks as usize;
+        let end = request.offset.value + request.num_blocks;
+
+        for (i, block) in (request.offset.value..end).enumerate() {
+            let hashes = self.get_hashes(block)?;
+            if hashes.is_empty() {
+                response.data[i * block_size..(i + 1) * block_size]
+                    .iter_mut()
+                    .for_each(|b| *b = 0);
+            }
 
-        let mut dirty_values: Vec<bool> = vec![];
-        for dirty_value in dirty_iter {
-            dirty_values.push(dirty_value?);
+            response.blocks.push(
+                crucible_protocol::ReadResponseBlockMetadata {
+                    encryption_contexts: self.get_encryption_contexts(block)?,
+                    hashes,
+                },
+            );
         }
 
-        assert!(dirty_values.len() == 1);
-
-        Ok(dirty_values[0])
-    }
-
-    fn set_dirty(&self) -> Result<()> {
-        let _ = self
-            .metadb
-            .prepare_cached("UPDATE metadata SET value=1 WHERE name='dirty'")?
-            .execute([])?;
         Ok(())
     }
 
@@ -137,22 +190,7 @@ impl Inner {
         &self,
         block: u64,
     ) -> Result<Vec<EncryptionContext>> {
-        // NOTE: "ORDER BY RANDOM()" would be a good --lossy addition here
-        let stmt = "SELECT nonce, tag FROM encryption_context where block=?1 \
-             ORDER BY counter ASC";
-        let mut stmt = self.metadb.prepare_cached(stmt)?;
-
-        let stmt_iter = stmt
-            .query_map(params![block], |row| Ok((row.get(0)?, row.get(1)?)))?;
-
-        let mut results = Vec::new();
-
-        for row in stmt_iter {
-            let (nonce, tag) = row?;
-            results.push(EncryptionContext { nonce, tag });
-        }
-
-        Ok(results)
+        self.meta.get_encryption_contexts(block)
     }
 
     /*
@@ -160,56 +198,22 @@ impl Inner {
      * is last.
      */
     pub fn get_hashes(&self, block: u64) -> Result<Vec<u64>> {
-        // NOTE: "ORDER BY RANDOM()" would be a good --lossy addition here
-        let stmt = "SELECT hash FROM integrity_hashes where block=?1 \
-             ORDER BY counter ASC";
-        let mut stmt = self.metadb.prepare_cached(stmt)?;
-
-        let stmt_iter = stmt.query_map(params![block], |row| row.get(0))?;
-
-        let mut results = Vec::new();
-
-        for row in stmt_iter {
-            let hash: Vec<u8> = row?;
-            assert_eq!(hash.len(), 8);
-
-            results.push(u64::from_le_bytes(hash[..].try_into()?));
-        }
+        self.meta.get_hashes(block)
+    }
 
-        Ok(results)
+    fn truncate_encryption_contexts_and_hashes(&mut self) -> Result<()> {
+        self.meta.truncate_encryption_contexts_and_hashes()
     }
 
     /*
-     * Given a (block, nonce, tag), append an encryption context row.
-     *
-     * For the params, keep a list of references so that copying is
-     * minimized.
+     * The tests below poke at the SQLite tables directly.
      */
-    pub fn tx_set_encryption_context(
-        tx: &rusqlite::Transaction,
-        encryption_context_params: &(u64, &EncryptionContext),
-    ) -> Result<()> {
-        let (block, encryption_context) = encryption_context_params;
-
-        let stmt =
-            "INSERT INTO encryption_context (counter, block, nonce, tag) \
-             VALUES ( \
-                 (SELECT IFNULL(MAX(counter), 0) + 1 FROM encryption_context WHERE block=?1), \
-                 ?1, ?2, ?3 \
-             )";
-
-        let rows_affected = tx.prepare_cached(stmt)?.execute(params![
-            block,
-            &encryption_context.nonce,
-            &encryption_context.tag
-        ])?;
-        assert_eq!(rows_affected, 1);
-
-        Ok(())
-    }
-
-    pub fn metadb_transaction(&mut self) -> Result<rusqlite::Transaction> {
-        Ok(self.metadb.transaction()?)
+    #[cfg(test)]
+    fn sqlite(&mut self) -> &mut SqliteMetadata {
+        self.meta
+            .as_any()
+            .downcast_mut::<SqliteMetadata>()
+            .expect("extent metadata is not SQLite")
     }
 
     #[cfg(test)]
@@ -217,136 +221,28 @@ impl Inner {
         &mut self,
         encryption_context_params: &[(u64, &EncryptionContext)],
     ) -> Result<()> {
-        let tx = self.metadb.transaction()?;
-
-        for tuple in encryption_context_params {
-            Self::tx_set_encryption_context(&tx, &tuple)?;
-        }
-
-        tx.commit()?;
-
-        Ok(())
-    }
-
-    /*
-     * Given a (block, hash), append a hash row.
-     */
-    pub fn tx_set_hash(
-        tx: &rusqlite::Transaction,
-        hash_params: &(u64, u64),
-    ) -> Result<()> {
-        let (block, hash) = hash_params;
-
-        let stmt =
-            "INSERT INTO integrity_hashes (counter, block, hash) \
-             VALUES ( \
-                 (SELECT IFNULL(MAX(counter), 0) + 1 FROM integrity_hashes WHERE block=?1), \
-                 ?1, ?2 \
-             )";
-
-        let rows_affected = tx
-            .prepare_cached(stmt)?
-            .execute(params![block, &hash.to_le_bytes()])?;
-        assert_eq!(rows_affected, 1);
-
-        Ok(())
+        self.sqlite()
+            .set_encryption_context(encryption_context_params)
     }
 
     #[cfg(test)]
     pub fn set_hashes(&mut self, hash_params: &[(u64, u64)]) -> Result<()> {
-        let tx = self.metadb.transaction()?;
-
-        for tuple in hash_params {
-            Self::tx_set_hash(&tx, tuple)?;
-        }
-
-        tx.commit()?;
-
-        Ok(())
-    }
-
-    /*
-     * Get rid of all but most recent encryption context and hash for each
-     * block.
-     */
-    fn truncate_encryption_contexts_and_hashes(&mut self) -> Result<()> {
-        let tx = self.metadb.transaction()?;
-
-        // Clear encryption context
-        let stmt = "DELETE FROM encryption_context WHERE ROWID not in \
-             (select ROWID from \
-                 (select ROWID,block,MAX(counter) \
-                  from encryption_context group by block\
-                 ) \
-             )";
-
-        let _rows_affected = tx.prepare_cached(stmt)?.execute([])?;
-
-        let _rows_affected = tx
-            .prepare_cached("UPDATE encryption_context SET counter = 0")?
-            .execute([])?;
-
-        // Clear integrity hash
-        let stmt = "DELETE FROM integrity_hashes WHERE ROWID not in \
-             (select ROWID from \
-                 (select ROWID,block,MAX(counter) \
-                  from integrity_hashes group by block \
-                 ) \
-             )";
-
-        let _rows_affected = tx.prepare_cached(stmt)?.execute([])?;
-
-        let _rows_affected = tx
-            .prepare_cached("UPDATE integrity_hashes SET counter = 0")?
-            .execute([])?;
-
-        tx.commit()?;
-
-        Ok(())
+        self.sqlite().set_hashes(hash_params)
     }
 
-    /*
-     * In order to unit test truncate_encryption_contexts_and_hashes, return
-     * blocks and counters.
-     */
     #[cfg(test)]
     fn get_blocks_and_counters_for_encryption_context(
         &mut self,
     ) -> Result<Vec<(u64, u64)>> {
-        let mut stmt = self
-            .metadb
-            .prepare_cached("SELECT block, counter FROM encryption_context")?;
-
-        let stmt_iter =
-            stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
-
-        let mut results = Vec::new();
-
-        for row in stmt_iter {
-            results.push(row?);
-        }
-
-        Ok(results)
+        self.sqlite()
+            .get_blocks_and_counters_for_encryption_context()
     }
 
     #[cfg(test)]
     fn get_blocks_and_counters_for_hashes(
         &mut self,
     ) -> Result<Vec<(u64, u64)>> {
-        let mut stmt = self
-            .metadb
-            .prepare_cached("SELECT block, counter FROM integrity_hashes")?;
-
-        let stmt_iter =
-            stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
-
-        let mut results = Vec::new();
-
-        for row in stmt_iter {
-            results.push(row?);
-        }
-
-        Ok(results)
+        self.sqlite().get_blocks_and_counters_for_hashes()
     }
 }
 
@@ -393,6 +289,7 @@ pub enum ExtentType {
     Db,
     DbShm,
     DbWal,
+    Meta,
 }
 
 impl fmt::Display for ExtentType {
@@ -402,6 +299,7 @@ impl fmt::Display for ExtentType {
             ExtentType::Db => write!(f, "db"),
             ExtentType::DbShm => write!(f, "db-shm"),
             ExtentType::DbWal => write!(f, "db-wal"),
+            ExtentType::Meta => write!(f, "meta"),
         }
     }
 }
@@ -417,6 +315,7 @@ impl ExtentType {
             ExtentType::Db => FileType::Db,
             ExtentType::DbShm => FileType::DbShm,
             ExtentType::DbWal => FileType::DbWal,
+            ExtentType::Meta => FileType::Meta,
         }
     }
 }
@@ -429,7 +328,10 @@ pub fn extent_file_name(number: u32, extent_type: ExtentType) -> String {
         ExtentType::Data => {
             format!("{:03X}", number & 0xFFF)
         }
-        ExtentType::Db | ExtentType::DbShm | ExtentType::DbWal => {
+        ExtentType::Db
+        | ExtentType::DbShm
+        | ExtentType::DbWal
+        | ExtentType::Meta => {
             format!("{:03X}.{}", number & 0xFFF, extent_type)
         }
     }
@@ -498,12 +400,69 @@ pub fn completed_dir<P: AsRef<Path>>(dir: P, number: u32) -> PathBuf {
     out
 }
 
-fn config_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+pub fn config_path<P: AsRef<Path>>(dir: P) -> PathBuf {
     let mut out = dir.as_ref().to_path_buf();
     out.push("region.json");
     out
 }
 
+/**
+ * What region.json holds: the region definition the upstairs is told
+ * about, plus how this downstairs stores the region, which stays off the
+ * wire.  Regions created before these were choices all use SQLite and a
+ * file per extent.
+ */
+#[derive(Deserialize, Serialize)]
+struct RegionConfig {
+    #[serde(flatten)]
+    def: RegionDefinition,
+    #[serde(default)]
+    metadata_backend: MetadataBackend,
+    #[serde(default)]
+    layout: RegionLayout,
+}
+
+pub fn read_region_config<P: AsRef<Path>>(dir: P) -> Result<RegionDefinition> {
+    let config: RegionConfig = read_json(config_path(dir))?;
+    let mut def = config.def;
+    def.set_metadata_backend(config.metadata_backend);
+    def.set_layout(config.layout);
+    Ok(def)
+}
+
+pub fn write_region_config<P: AsRef<Path>>(
+    dir: P,
+    def: &RegionDefinition,
+    clobber: bool,
+) -> Result<()> {
+    let config = RegionConfig {
+        def: *def,
+        metadata_backend: def.metadata_backend(),
+        layout: def.layout(),
+    };
+    write_json(config_path(dir), &config, clobber)
+}
+
+/**
+ * Produce a PathBuf that refers to the data file holding every extent in
+ * a single file region, anchored under "dir".
+ */
+pub fn region_data_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+    let mut out = dir.as_ref().to_path_buf();
+    out.push("region.data");
+    out
+}
+
+/**
+ * Produce a PathBuf that refers to the file holding the sidecar for every
+ * extent in a single file region, anchored under "dir".
+ */
+pub fn region_meta_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+    let mut out = dir.as_ref().to_path_buf();
+    out.push("region.meta");
+    out
+}
+
 /**
  * Remove directories associated with repair except for the replace
  * directory. Replace is handled specifically during extent open.
@@ -523,8 +482,9 @@ pub fn remove_copy_cleanup_dir<P: AsRef<Path>>(dir: P, eid: u32) -> Result<()> {
 
 /**
  * Validate a list of sorted repair files.
- * There are either two or four files we expect to find, any more or less
- * and we have a bad list.  No duplicates.
+ * An extent with SQLite metadata has either two or four files we expect
+ * to find, and one with a sidecar has exactly two.  Any more or less and
+ * we have a bad list.  No duplicates.
  */
 pub fn validate_repair_files(eid: usize, files: &[String]) -> bool {
     println!("validate {} with {:?}", eid, files);
@@ -541,51 +501,13 @@ pub fn validate_repair_files(eid: usize, files: &[String]) -> bool {
         extent_file_name(eid, ExtentType::DbWal),
     ]);
 
-    // Either we have some or all.
-    files == some || files == all
-}
+    let sidecar = vec![
+        extent_file_name(eid, ExtentType::Data),
+        extent_file_name(eid, ExtentType::Meta),
+    ];
 
-/// Always open sqlite with journaling, and synchronous.
-/// Note: these pragma_updates are not durable
-fn open_sqlite_connection<P: AsRef<Path>>(path: &P) -> Result<Connection> {
-    let metadb = Connection::open(&path)?;
-
-    assert!(metadb.is_autocommit());
-    metadb.pragma_update(None, "journal_mode", &"WAL")?;
-    metadb.pragma_update(None, "synchronous", &"FULL")?;
-
-    // rusqlite provides an LRU Cache (a cache which, when full, evicts the
-    // least-recently-used value). This caches prepared statements, allowing
-    // us to nullify the cost of parsing and compiling frequently used
-    // statements.  I've changed all sqlite queries in Inner to use
-    // `prepare_cached` to take advantage of this cache. I have not done this
-    // for `prepare_cached` region creation,
-    // since it wouldn't be relevant there.
-
-    // The result is a dramatic reduction in CPU time spent parsing queries.
-    // Prior to this change, sqlite3Prepare was taking 60% of CPU time
-    // during reads and 50% during writes based on dtrace flamegraphs.
-    // Afterwards, they don't even show up on the graph. I'm seeing a minimum
-    // doubling of actual throughput with `crudd` after this change on my
-    // local hardware.
-
-    // However, this does cost some amount of memory per-extent and thus will
-    // scale linearly . This cache size I'm setting now (64 statements) is
-    // chosen somewhat arbitrarily. If this becomes a significant consumer
-    // of memory, we should reduce the size of the LRU, and stop caching the
-    // statements in the coldest paths. At present, it doesn't seem to have any
-    // meaningful impact on memory usage.
-
-    // We could instead try to pre-generate and hold onto all the statements we
-    // need to use ourselves, but I looked into doing that, and basically
-    // the code required would be a mess due to lifetimes. We shouldn't do
-    // that except as a last resort.
-
-    // Also, if you're curious, the hashing function used is
-    // https://docs.rs/ahash/latest/ahash/index.html
-    metadb.set_prepared_statement_cache_capacity(64);
-
-    Ok(metadb)
+    // Either we have some or all, or the sidecar pair.
+    files == some || files == all || files == sidecar
 }
 
 impl Extent {
@@ -608,7 +530,9 @@ impl Extent {
         let bcount = def.extent_size().value;
         let size = def.block_size().checked_mul(bcount).unwrap();
 
-        remove_copy_cleanup_dir(&dir, number)?;
+        if !read_only {
+            remove_copy_cleanup_dir(&dir, number)?;
+        }
 
         // If the replace directory exists for this extent, then it means
         // a repair was interrupted before it could finish.  We will continue
@@ -619,7 +543,13 @@ impl Extent {
                 "Extent {} found replacement dir, finishing replacement",
                 number
             );
-            move_replacement_extent(&dir, number as usize)?;
+            move_replacement_extent(&dir, number as usize, def)?;
+        }
+
+        if def.layout() == RegionLayout::SingleFile {
+            return Extent::open_in_region_file(
+                &dir, def, number, read_only, false,
+            );
         }
 
         /*
@@ -653,17 +583,27 @@ impl Extent {
             };
 
         /*
-         * Open a connection to the metadata db
+         * Open the metadata.  Go by what is on disk rather than what the
+         * region definition says, so a region keeps working with
+         * whatever backend its extents were created with.
          */
-        path.set_extension("db");
-        let metadb = match open_sqlite_connection(&path) {
+        path.set_extension("meta");
+        let meta: Result<Box<dyn ExtentMetadata>> = if path.exists() {
+            SidecarMetadata::open(&path, bcount, read_only)
+                .map(|m| Box::new(m) as Box<dyn ExtentMetadata>)
+        } else {
+            path.set_extension("db");
+            SqliteMetadata::open(&path)
+                .map(|m| Box::new(m) as Box<dyn ExtentMetadata>)
+        };
+        let meta = match meta {
             Err(e) => {
                 println!(
-                    "Error: Open of db file {:?} for extent#{} returned: {}",
+                    "Error: Open of metadata {:?} for extent#{} returned: {}",
                     path, number, e
                 );
                 bail!(
-                    "Open of db file {:?} for extent#{} returned: {}",
+                    "Open of metadata {:?} for extent#{} returned: {}",
                     path,
                     number,
                     e,
@@ -679,7 +619,90 @@ impl Extent {
             read_only,
             block_size: def.block_size(),
             extent_size: def.extent_size(),
-            inner: Some(Mutex::new(Inner { file, metadb })),
+            inner: Some(Mutex::new(Inner {
+                file,
+                meta,
+                data_offset: 0,
+            })),
+            io_backend: IoBackend::default(),
+        })
+    }
+
+    /**
+     * Open, or create, an extent in a region where every extent shares
+     * one data file and one metadata file.  Both files must already be
+     * big enough to hold it.
+     */
+    fn open_in_region_file<P: AsRef<Path>>(
+        dir: P,
+        def: &RegionDefinition,
+        number: u32,
+        read_only: bool,
+        create: bool,
+    ) -> Result<Extent> {
+        let bcount = def.extent_size().value;
+        let size = def.block_size().checked_mul(bcount).unwrap();
+        let data_offset = size.checked_mul(number as u64).unwrap();
+
+        let path = region_data_path(&dir);
+        let file =
+            match OpenOptions::new().read(true).write(!read_only).open(&path) {
+                Err(e) => {
+                    bail!(
+                        "Open of {:?} for extent#{} returned: {}",
+                        path,
+                        number,
+                        e,
+                    );
+                }
+                Ok(f) => {
+                    let cur_size = f.metadata()?.len();
+                    if cur_size < data_offset + size {
+                        bail!(
+                            "File size {:?} too small for extent#{}, need {:?}",
+                            cur_size,
+                            number,
+                            data_offset + size,
+                        );
+                    }
+                    f
+                }
+            };
+
+        let path = region_meta_path(&dir);
+        let meta_file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(&path)?;
+        let base = SidecarMetadata::size(bcount) * number as u64;
+        let meta = if create {
+            SidecarMetadata::create_in(meta_file, base, bcount)
+        } else {
+            SidecarMetadata::open_in(meta_file, base, bcount)
+        };
+        let meta = match meta {
+            Err(e) => {
+                bail!(
+                    "Open of metadata {:?} for extent#{} returned: {}",
+                    path,
+                    number,
+                    e,
+                );
+            }
+            Ok(m) => m,
+        };
+
+        Ok(Extent {
+            number,
+            read_only,
+            block_size: def.block_size(),
+            extent_size: def.extent_size(),
+            inner: Some(Mutex::new(Inner {
+                file,
+                meta: Box::new(meta),
+                data_offset,
+            })),
+            io_backend: IoBackend::default(),
         })
     }
 
@@ -704,6 +727,11 @@ impl Extent {
         def: &RegionDefinition,
         number: u32,
     ) -> Result<Extent> {
+        if def.layout() == RegionLayout::SingleFile {
+            remove_copy_cleanup_dir(&dir, number)?;
+            return Extent::open_in_region_file(&dir, def, number, false, true);
+        }
+
         /*
          * Store extent data in files within a directory hierarchy so that
          * there are not too many files in any level of that hierarchy.
@@ -731,89 +759,20 @@ impl Extent {
         file.set_len(size)?;
         file.seek(SeekFrom::Start(0))?;
 
-        let mut seed = dir.as_ref().to_path_buf();
-        seed.push("seed");
-        seed.set_extension("db");
-        path.set_extension("db");
-
-        // Instead of creating the sqlite db for every extent, create it only
-        // once, and copy from a seed db when creating other extents. This
-        // minimizes Region create time.
-        let metadb = if Path::new(&seed).exists() {
-            std::fs::copy(&seed, &path)?;
-
-            open_sqlite_connection(&path)?
-        } else {
-            /*
-             * Create the metadata db
-             */
-            let metadb = open_sqlite_connection(&path)?;
-
-            /*
-             * Create tables and insert base data
-             */
-            metadb.execute(
-                "CREATE TABLE metadata (
-                    name TEXT PRIMARY KEY,
-                    value INTEGER NOT NULL
-                )",
-                [],
-            )?;
-
-            let meta = ExtentMeta::default();
-
-            metadb.execute(
-                "INSERT INTO metadata
-                (name, value) VALUES (?1, ?2)",
-                params!["ext_version", meta.ext_version],
-            )?;
-            metadb.execute(
-                "INSERT INTO metadata
-                (name, value) VALUES (?1, ?2)",
-                params!["gen_number", meta.gen_number],
-            )?;
-            metadb.execute(
-                "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
-                params!["flush_number", meta.flush_number],
-            )?;
-            metadb.execute(
-                "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
-                params!["dirty", meta.dirty],
-            )?;
-
-            metadb.execute(
-                "CREATE TABLE encryption_context (
-                    counter INTEGER,
-                    block INTEGER,
-                    nonce BLOB NOT NULL,
-                    tag BLOB NOT NULL,
-                    PRIMARY KEY (block, counter)
-                )",
-                [],
-            )?;
-
-            metadb.execute(
-                "CREATE TABLE integrity_hashes (
-                    counter INTEGER,
-                    block INTEGER,
-                    hash BLOB NOT NULL,
-                    PRIMARY KEY (block, counter)
-                )",
-                [],
-            )?;
-
-            // write out
-            metadb.close().map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("metadb.close() failed! {}", e.1),
-                )
-            })?;
+        let meta: Box<dyn ExtentMetadata> = match def.metadata_backend() {
+            MetadataBackend::Sqlite => {
+                let mut seed = dir.as_ref().to_path_buf();
+                seed.push("seed");
+                seed.set_extension("db");
+                path.set_extension("db");
 
-            // Save it as DB seed
-            std::fs::copy(&path, &seed)?;
+                Box::new(SqliteMetadata::create(&path, &seed)?)
+            }
+            MetadataBackend::Sidecar => {
+                path.set_extension("meta");
 
-            open_sqlite_connection(&path)?
+                Box::new(SidecarMetadata::create(&path, bcount)?)
+            }
         };
 
         /*
@@ -824,7 +783,12 @@ impl Extent {
             read_only: false,
             block_size: def.block_size(),
             extent_size: def.extent_size(),
-            inner: Some(Mutex::new(Inner { file, metadb })),
+            inner: Some(Mutex::new(Inner {
+                file,
+                meta,
+                data_offset: 0,
+            })),
+            io_backend: IoBackend::default(),
         })
     }
 
@@ -887,27 +851,103 @@ impl Extent {
         self.number
     }
 
-    #[instrument]
-    pub fn read(
-        &self,
-        requests: &[&crucible_protocol::ReadRequest],
-        responses: &mut Vec<crucible_protocol::ReadResponse>,
-    ) -> Result<(), CrucibleError> {
-        let mut inner = self.inner();
+    /*
+     * Get everything the metadata has recorded into its files proper,
+     * ahead of copying them for a snapshot.
+     */
+    pub fn checkpoint(&self) -> Result<(), CrucibleError> {
+        match &self.inner {
+            Some(inner) => Ok(inner.lock().unwrap().meta.checkpoint()?),
+            None => {
+                crucible_bail!(
+                    GenericError,
+                    "extent {} is closed",
+                    self.number
+                );
+            }
+        }
+    }
+
+    /*
+     * How many blocks of this extent are written.  A closed extent is
+     * being repaired, and we can't say until it is open again.
+     */
+    pub fn written_blocks(&self) -> Result<u64, CrucibleError> {
+        match &self.inner {
+            Some(inner) => Ok(inner.lock().unwrap().written_blocks()?),
+            None => {
+                crucible_bail!(
+                    GenericError,
+                    "extent {} is closed",
+                    self.number
+                );
+            }
+        }
+    }
+
+    /*
+     * For each of num_blocks blocks from offset, whether it is written,
+     * going only by the metadata.
+     */
+    pub fn blocks_written(
+        &self,
+        offset: Block,
+        num_blocks: u64,
+    ) -> Result<Vec<bool>, CrucibleError> {
+        self.check_input_range(offset, num_blocks)?;
+        let inner = match &self.inner {
+            Some(inner) => inner.lock().unwrap(),
+            None => {
+                crucible_bail!(
+                    GenericError,
+                    "extent {} is closed",
+                    self.number
+                );
+            }
+        };
+
+        (offset.value..offset.value + num_blocks)
+            .map(|block| Ok(inner.block_written(block)?))
+            .collect()
+    }
+
+    #[instrument]
+    pub fn read(
+        &self,
+        requests: &[&crucible_protocol::ReadRequest],
+        responses: &mut Vec<crucible_protocol::ReadResponse>,
+    ) -> Result<(), CrucibleError> {
+        if self.io_backend == IoBackend::IoUring {
+            return self.read_uring(requests, responses);
+        }
+
+        let mut inner = self.inner();
+
+        for request in requests {
+            if request.num_blocks == 0 {
+                crucible_bail!(
+                    InvalidNumberOfBlocks,
+                    "read of 0 blocks at {}:{}",
+                    request.eid,
+                    request.offset.value,
+                );
+            }
 
-        for request in requests {
             let mut response = crucible_protocol::ReadResponse::from_request(
                 request,
                 self.block_size as usize,
             );
 
-            self.check_input(request.offset, &response.data)?;
+            self.check_input_range(request.offset, request.num_blocks)?;
 
-            let byte_offset = request.offset.value * self.block_size;
+            let byte_offset =
+                inner.data_offset + request.offset.value * self.block_size;
 
             inner.file.seek(SeekFrom::Start(byte_offset))?;
 
             /*
+             * One read for the whole range.
+             *
              * XXX This read_exact only works because we have filled our
              * buffer with data ahead of time.  If we want to use
              * an uninitialized buffer, then we need a different
@@ -915,10 +955,59 @@ impl Extent {
              */
             inner.file.read_exact(&mut response.data)?;
 
-            response.encryption_contexts =
-                inner.get_encryption_contexts(request.offset.value)?;
+            inner.fill_read_response(request, &mut response)?;
+
+            responses.push(response);
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Read the data for every request in one batch through io_uring, and
+     * only hold the extent lock to find out where the data is and to get
+     * the metadata for it.
+     */
+    fn read_uring(
+        &self,
+        requests: &[&crucible_protocol::ReadRequest],
+        responses: &mut Vec<crucible_protocol::ReadResponse>,
+    ) -> Result<(), CrucibleError> {
+        let mut batch = Vec::with_capacity(requests.len());
+        for request in requests {
+            if request.num_blocks == 0 {
+                crucible_bail!(
+                    InvalidNumberOfBlocks,
+                    "read of 0 blocks at {}:{}",
+                    request.eid,
+                    request.offset.value,
+                );
+            }
+
+            self.check_input_range(request.offset, request.num_blocks)?;
+
+            batch.push(crucible_protocol::ReadResponse::from_request(
+                request,
+                self.block_size as usize,
+            ));
+        }
+
+        let (fd, data_offset) = self.raw_fd();
+        let mut ops: Vec<uring::Op> = requests
+            .iter()
+            .zip(batch.iter_mut())
+            .map(|(request, response)| {
+                let byte_offset =
+                    data_offset + request.offset.value * self.block_size;
+                uring::Op::read(fd, byte_offset, &mut response.data)
+            })
+            .collect();
+        uring::submit(&mut ops)?;
+        drop(ops);
 
-            response.hashes = inner.get_hashes(request.offset.value)?;
+        let inner = self.inner();
+        for (request, mut response) in requests.iter().zip(batch) {
+            inner.fill_read_response(request, &mut response)?;
 
             responses.push(response);
         }
@@ -926,6 +1015,40 @@ impl Extent {
         Ok(())
     }
 
+    /*
+     * The descriptor for this extent's data file, and where in it the
+     * extent starts.  The file stays open for as long as we are borrowed,
+     * as closing or replacing an extent needs it mutably, so this can be
+     * used without holding the extent lock.
+     */
+    fn raw_fd(&self) -> (RawFd, u64) {
+        let inner = self.inner();
+        (inner.file.as_raw_fd(), inner.data_offset)
+    }
+
+    /**
+     * Verify that num_blocks blocks starting at offset fit within the
+     * extent.
+     */
+    fn check_input_range(
+        &self,
+        offset: Block,
+        num_blocks: u64,
+    ) -> Result<(), CrucibleError> {
+        if offset.block_size_in_bytes() != self.block_size as u32 {
+            crucible_bail!(BlockSizeMismatch);
+        }
+
+        if offset.shift != self.extent_size.shift {
+            crucible_bail!(BlockSizeMismatch);
+        }
+
+        match offset.value.checked_add(num_blocks) {
+            Some(end) if end <= self.extent_size.value => Ok(()),
+            _ => crucible_bail!(OffsetInvalid),
+        }
+    }
+
     /**
      * Verify that the requested block offset and size of the buffer
      * will fit within the extent.
@@ -1036,7 +1159,7 @@ impl Extent {
         // We know we have at least one block to write.
         inner.set_dirty()?;
 
-        let tx = inner.metadb_transaction()?;
+        let mut contexts = Vec::with_capacity(writes.len());
         for write in writes {
             // Since we only add to writes_to_skip if only_write_unwritten,
             // this will be empty if only_write_unwritten is false, so we
@@ -1046,23 +1169,59 @@ impl Extent {
                 assert!(only_write_unwritten);
                 continue;
             }
-            if let Some(encryption_context) = &write.encryption_context {
-                Inner::tx_set_encryption_context(
-                    &tx,
-                    &(write.offset.value, encryption_context),
-                )?;
+            contexts.push(BlockContext {
+                block: write.offset.value,
+                hash: write.hash,
+                encryption_context: write.encryption_context.as_ref(),
+            });
+        }
+
+        /*
+         * Some metadata backends only have room for so many contexts per
+         * block, and need what we wrote before on disk before they can
+         * let go of an older one.
+         */
+        if contexts
+            .iter()
+            .any(|c| inner.meta.needs_sync_before_write(c.block))
+        {
+            if let Err(e) = inner.file.sync_data() {
+                crucible_bail!(
+                    IoError,
+                    "extent {}: fsync before write failure: {:?}",
+                    self.number,
+                    e
+                );
             }
+        }
+
+        inner.meta.set_block_contexts(&contexts)?;
 
-            Inner::tx_set_hash(&tx, &(write.offset.value, write.hash))?;
+        if self.io_backend == IoBackend::IoUring {
+            drop(inner);
+
+            let (fd, data_offset) = self.raw_fd();
+            let mut ops: Vec<uring::Op> = writes
+                .iter()
+                .filter(|w| !writes_to_skip.contains(&w.offset.value))
+                .map(|w| {
+                    let byte_offset =
+                        data_offset + w.offset.value * self.block_size;
+                    uring::Op::write(fd, byte_offset, &w.data)
+                })
+                .collect();
+            uring::submit(&mut ops)?;
+
+            return Ok(());
         }
-        tx.commit()?;
 
         for write in writes {
             if writes_to_skip.contains(&write.offset.value) {
                 assert!(only_write_unwritten);
                 continue;
             }
-            let byte_offset = write.offset.value * self.block_size;
+            let byte_offset =
+                inner.data_offset + write.offset.value * self.block_size;
 
             inner.file.seek(SeekFrom::Start(byte_offset))?;
             inner.file.write_all(&write.data)?;
@@ -1071,6 +1230,133 @@ impl Extent {
         Ok(())
     }
 
+    /*
+     * Forget the contents of num_blocks blocks starting at offset.  After
+     * this, those blocks have no hashes or encryption contexts, which is
+     * what makes a block unwritten, and their data is all zeros.
+     */
+    #[instrument]
+    pub fn discard(
+        &self,
+        offset: Block,
+        num_blocks: u64,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        if num_blocks == 0 {
+            crucible_bail!(InvalidNumberOfBlocks, "cannot discard 0 blocks");
+        }
+
+        self.check_input_range(offset, num_blocks)?;
+
+        let mut inner = self.inner();
+
+        /*
+         * The metadata goes first, and once it has no hashes for a block
+         * that block reads as zeros, whatever is still in the file.  So
+         * a crash before the hole is punched, or before it reaches the
+         * disk, can't leave a block with data that fails its hashes.
+         * As with a write, the next flush makes the data durable.
+         */
+        inner.set_dirty()?;
+        inner.meta.remove_blocks(offset.value, num_blocks)?;
+
+        self.zero_blocks(&mut inner, offset, num_blocks)?;
+
+        Ok(())
+    }
+
+    /*
+     * Write zeros to num_blocks blocks starting at offset.  Unlike a
+     * discard, the blocks stay written: each gets the hash of a block of
+     * zeros, the same as if a buffer of zeros came through write, so a
+     * later write_unwritten leaves them alone.
+     *
+     * Encrypted blocks would need an encryption context we have no key
+     * to make, so the upstairs sends those as a regular write instead.
+     */
+    #[instrument]
+    pub fn write_zeroes(
+        &self,
+        offset: Block,
+        num_blocks: u64,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        if num_blocks == 0 {
+            crucible_bail!(InvalidNumberOfBlocks, "cannot zero 0 blocks");
+        }
+
+        self.check_input_range(offset, num_blocks)?;
+
+        let mut inner = self.inner();
+
+        /*
+         * This is the same order as a write: the new hashes go down
+         * before the data they describe.
+         */
+        inner.set_dirty()?;
+
+        let hash = integrity_hash(&[&vec![0u8; self.block_size as usize]]);
+        let contexts: Vec<BlockContext> = (offset.value
+            ..offset.value + num_blocks)
+            .map(|block| BlockContext {
+                block,
+                hash,
+                encryption_context: None,
+            })
+            .collect();
+
+        if contexts
+            .iter()
+            .any(|c| inner.meta.needs_sync_before_write(c.block))
+        {
+            if let Err(e) = inner.file.sync_data() {
+                crucible_bail!(
+                    IoError,
+                    "extent {}: fsync before write zeroes failure: {:?}",
+                    self.number,
+                    e
+                );
+            }
+        }
+
+        inner.meta.set_block_contexts(&contexts)?;
+
+        self.zero_blocks(&mut inner, offset, num_blocks)
+    }
+
+    /*
+     * Make num_blocks blocks starting at offset read back as zeros.  We
+     * punch a hole there so the space is freed, and only when the file
+     * system will not do that do we write zeros over them.
+     */
+    fn zero_blocks(
+        &self,
+        inner: &mut Inner,
+        offset: Block,
+        num_blocks: u64,
+    ) -> Result<(), CrucibleError> {
+        let byte_offset = inner.data_offset + offset.value * self.block_size;
+        if punch_hole(&inner.file, byte_offset, num_blocks * self.block_size)
+            .is_ok()
+        {
+            return Ok(());
+        }
+
+        let zeros = vec![0u8; self.block_size as usize];
+        inner.file.seek(SeekFrom::Start(byte_offset))?;
+        for _ in 0..num_blocks {
+            inner.file.write_all(&zeros)?;
+        }
+
+        Ok(())
+    }
+
     #[instrument]
     pub fn flush_block(
         &self,
@@ -1080,21 +1366,10 @@ impl Extent {
     ) -> Result<(), CrucibleError> {
         let mut inner = self.inner();
 
-        if !inner.dirty()? {
-            /*
-             * If we have made no writes to this extent since the last flush,
-             * we do not need to update the extent on disk
-             */
+        if !self.needs_flush(&inner)? {
             return Ok(());
         }
 
-        // Read only extents should never have the dirty bit set. If they do,
-        // bail
-        if self.read_only {
-            eprintln!("read-only extent {} has dirty bit set!", self.number);
-            crucible_bail!(ModifyingReadOnlyRegion);
-        }
-
         /*
          * We must first fsync to get any outstanding data written to disk.
          * This must be done before we update the flush number.
@@ -1111,6 +1386,37 @@ impl Extent {
             );
         }
 
+        self.finish_flush(&mut inner, new_flush, new_gen)
+    }
+
+    fn needs_flush(&self, inner: &Inner) -> Result<bool, CrucibleError> {
+        if !inner.dirty()? {
+            /*
+             * If we have made no writes to this extent since the last flush,
+             * we do not need to update the extent on disk
+             */
+            return Ok(false);
+        }
+
+        // Read only extents should never have the dirty bit set. If they do,
+        // bail
+        if self.read_only {
+            eprintln!("read-only extent {} has dirty bit set!", self.number);
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        Ok(true)
+    }
+
+    /*
+     * The part of a flush that comes after the data is on disk.
+     */
+    fn finish_flush(
+        &self,
+        inner: &mut Inner,
+        new_flush: u64,
+        new_gen: u64,
+    ) -> Result<(), CrucibleError> {
         /*
          * Clear old encryption contexts and hashes. In order to be crash
          * consistent, only perform this after the extent fsync is done.
@@ -1134,6 +1440,15 @@ pub struct Region {
     def: RegionDefinition,
     pub extents: Vec<Extent>,
     read_only: bool,
+
+    /*
+     * Extents written to since their last flush, so a flush only has to
+     * visit those.  An extent not in here has nothing to flush and keeps
+     * the flush number it already has.
+     */
+    dirty_extents: Mutex<BTreeSet<usize>>,
+
+    io_backend: IoBackend,
 }
 
 impl Region {
@@ -1157,7 +1472,22 @@ impl Region {
         mkdir_for_file(&cp)?;
 
         let def = RegionDefinition::from_options(&options).unwrap();
-        write_json(&cp, &def, false)?;
+
+        /*
+         * A single file region starts with empty files, and extending it
+         * makes room for the new extents.
+         */
+        if def.layout() == RegionLayout::SingleFile {
+            for path in &[region_data_path(&dir), region_meta_path(&dir)] {
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(path)?;
+            }
+        }
+
+        write_region_config(dir.as_ref(), &def, false)?;
         println!("Created new region file {:?}", cp);
 
         /*
@@ -1168,6 +1498,8 @@ impl Region {
             def,
             extents: Vec::new(),
             read_only: false,
+            dirty_extents: Mutex::new(BTreeSet::new()),
+            io_backend: IoBackend::default(),
         };
 
         region.open_extents(true)?;
@@ -1191,7 +1523,7 @@ impl Region {
          * We are expecting to find a region config file and extent files.
          * If we do not, then report error and exit.
          */
-        let def = match read_json(&cp) {
+        let def = match read_region_config(dir.as_ref()) {
             Ok(def) => def,
             Err(e) => bail!("Error {:?} opening region config {:?}", e, cp),
         };
@@ -1208,6 +1540,8 @@ impl Region {
             def,
             extents: Vec::new(),
             read_only,
+            dirty_extents: Mutex::new(BTreeSet::new()),
+            io_backend: IoBackend::default(),
         };
 
         region.open_extents(false)?;
@@ -1219,6 +1553,20 @@ impl Region {
         self.def.get_encrypted()
     }
 
+    /**
+     * Do IO to the extents of this region the given way from now on.
+     */
+    pub fn set_io_backend(&mut self, io_backend: IoBackend) -> Result<()> {
+        io_backend.check_available()?;
+
+        self.io_backend = io_backend;
+        for extent in self.extents.iter_mut() {
+            extent.io_backend = io_backend;
+        }
+
+        Ok(())
+    }
+
     /**
      * If our extent_count is higher than the number of populated entries
      * we have in our extents Vec, then open all the new extent files and
@@ -1248,6 +1596,8 @@ impl Region {
 
         for eid in next_eid..self.def.extent_count() {
             assert_eq!(self.extents[eid as usize].number, eid);
+            self.extents[eid as usize].io_backend = self.io_backend;
+            self.track_dirty(eid as usize)?;
         }
         assert_eq!(self.def.extent_count() as usize, self.extents.len());
 
@@ -1288,9 +1638,53 @@ impl Region {
         assert_eq!(self.extents[eid].number, eid as u32);
         assert!(!self.read_only);
 
-        let new_extent =
+        let mut new_extent =
             Extent::open(&self.dir, &self.def, eid as u32, self.read_only)?;
+        new_extent.io_backend = self.io_backend;
         self.extents[eid] = new_extent;
+        self.track_dirty(eid)?;
+        Ok(())
+    }
+
+    /**
+     * Bring our set of dirty extents in line with what the just opened
+     * extent has on disk.  An extent left dirty by a crash, or replaced
+     * by a repair, needs flushing even though we never wrote to it.
+     */
+    fn track_dirty(&self, eid: usize) -> Result<(), CrucibleError> {
+        let dirty = self.extents[eid].inner().dirty()?;
+        let mut dirty_extents = self.dirty_extents.lock().unwrap();
+        if dirty {
+            dirty_extents.insert(eid);
+        } else {
+            dirty_extents.remove(&eid);
+        }
+        Ok(())
+    }
+
+    fn mark_dirty(&self, eid: usize) {
+        self.dirty_extents.lock().unwrap().insert(eid);
+    }
+
+    /**
+     * Flush one extent, taking it out of the dirty set first so a write
+     * that lands while we are flushing puts it back.
+     */
+    fn flush_dirty_extent(
+        &self,
+        eid: usize,
+        flush_number: u64,
+        gen_number: u64,
+        job_id: u64,
+    ) -> Result<(), CrucibleError> {
+        self.dirty_extents.lock().unwrap().remove(&eid);
+
+        let extent = &self.extents[eid];
+        if let Err(e) = extent.flush_block(flush_number, gen_number, job_id) {
+            self.mark_dirty(eid);
+            return Err(e);
+        }
+
         Ok(())
     }
 
@@ -1343,7 +1737,7 @@ impl Region {
         // Returning from get_extent_copy means we have copied all our
         // files and moved the copy directory to replace directory.
         // Now, replace the current extent files with the replacement ones.
-        move_replacement_extent(&self.dir, eid)?;
+        move_replacement_extent(&self.dir, eid, &self.def)?;
 
         Ok(())
     }
@@ -1398,9 +1792,10 @@ impl Region {
         println!("Found repair files: {:?}", repair_files);
 
         // The repair file list should always contain the extent data
-        // file itself, and the .db file (metadata) for that extent.
-        // Missing these means the repair will not succeed.
-        // Optionally, there could be both .db-shm and .db-wal.
+        // file itself, and either the .db file or the .meta file
+        // (metadata) for that extent.  Missing these means the repair
+        // will not succeed.
+        // With a .db, there could optionally be both .db-shm and .db-wal.
         if !validate_repair_files(eid, &repair_files) {
             crucible_bail!(
                 RepairFilesInvalid,
@@ -1409,6 +1804,20 @@ impl Region {
             );
         }
 
+        // A single file region has nowhere to put a SQLite database, so
+        // it can only be repaired from an extent with a sidecar.
+        if self.def.layout() == RegionLayout::SingleFile
+            && !repair_files
+                .contains(&extent_file_name(eid as u32, ExtentType::Meta))
+        {
+            crucible_bail!(
+                RepairFilesInvalid,
+                "A {} region needs a sidecar to repair from, not {:?}",
+                self.def.layout(),
+                repair_files,
+            );
+        }
+
         // First, copy the main extent data file.
         let extent_copy = extent.create_copy_file(copy_dir.clone(), None)?;
         let repair_stream = match repair_server
@@ -1427,31 +1836,18 @@ impl Region {
         };
         save_stream_to_file(extent_copy, repair_stream.into_inner()).await?;
 
-        // The .db file is also required to exist for any valid extent.
-        let extent_db =
-            extent.create_copy_file(copy_dir.clone(), Some(ExtentType::Db))?;
-        let repair_stream = match repair_server
-            .get_extent_file(eid as u32, FileType::Db)
-            .await
-        {
-            Ok(rs) => rs,
-            Err(e) => {
-                crucible_bail!(
-                    RepairRequestError,
-                    "Failed to get extent {} db file: {:?}",
-                    eid,
-                    e,
-                );
-            }
-        };
-        save_stream_to_file(extent_db, repair_stream.into_inner()).await?;
-
-        // These next two are optional.
-        for opt_file in &[ExtentType::DbShm, ExtentType::DbWal] {
+        // The rest are whichever metadata files the source has, which
+        // validate_repair_files has already checked make sense together.
+        for opt_file in &[
+            ExtentType::Db,
+            ExtentType::DbShm,
+            ExtentType::DbWal,
+            ExtentType::Meta,
+        ] {
             let filename = extent_file_name(eid as u32, opt_file.clone());
 
             if repair_files.contains(&filename) {
-                let extent_shm = extent.create_copy_file(
+                let extent_meta = extent.create_copy_file(
                     copy_dir.clone(),
                     Some(opt_file.clone()),
                 )?;
@@ -1470,7 +1866,7 @@ impl Region {
                         );
                     }
                 };
-                save_stream_to_file(extent_shm, repair_stream.into_inner())
+                save_stream_to_file(extent_meta, repair_stream.into_inner())
                     .await?;
             }
         }
@@ -1510,13 +1906,41 @@ impl Region {
         }
 
         if newsize > self.def.extent_count() {
+            if self.def.layout() == RegionLayout::SingleFile {
+                self.grow_region_files(newsize)?;
+            }
             self.def.set_extent_count(newsize);
-            write_json(config_path(&self.dir), &self.def, true)?;
+            write_region_config(&self.dir, &self.def, true)?;
             self.open_extents(true)?;
         }
         Ok(())
     }
 
+    /**
+     * Make the data and metadata files of a single file region big enough
+     * for extent_count extents.  Space for the new extents is all zeros,
+     * so their blocks are unwritten and their sidecars not yet created.
+     */
+    fn grow_region_files(&self, extent_count: u32) -> Result<()> {
+        let bcount = self.def.extent_size().value;
+        let sizes = [
+            (region_data_path(&self.dir), self.def.block_size() * bcount),
+            (region_meta_path(&self.dir), SidecarMetadata::size(bcount)),
+        ];
+
+        for (path, extent_bytes) in sizes.iter() {
+            let file = OpenOptions::new().write(true).open(path)?;
+            let size = extent_bytes * extent_count as u64;
+            if file.metadata()?.len() < size {
+                file.set_len(size)?;
+            }
+            file.sync_all()?;
+        }
+        sync_path(&self.dir)?;
+
+        Ok(())
+    }
+
     pub fn region_def(&self) -> (u64, Block, u32) {
         (
             self.def.block_size(),
@@ -1560,6 +1984,87 @@ impl Region {
             .collect::<Result<Vec<_>>>()
     }
 
+    /**
+     * How much of this region has been written, and how much space its
+     * files take up on disk.
+     */
+    pub fn space_usage(&self) -> Result<SpaceUsage, CrucibleError> {
+        let allocated_blocks = self
+            .extents
+            .iter()
+            .map(|e| e.written_blocks())
+            .collect::<Result<Vec<_>, _>>()?;
+        let allocated: u64 = allocated_blocks.iter().sum();
+
+        Ok(SpaceUsage {
+            logical_bytes: self.def.total_size(),
+            allocated_bytes: allocated * self.def.block_size(),
+            /*
+             * Snapshots live under the region directory, but they are
+             * not the region.
+             */
+            physical_bytes: disk_usage(
+                &self.dir,
+                Some(&snapshot::snapshot_dir(&self.dir)),
+            )?,
+            allocated_blocks,
+        })
+    }
+
+    /*
+     * Which of num_blocks blocks of an extent from offset are written, from
+     * the metadata alone, without reading any block data.
+     */
+    pub fn blocks_written(
+        &self,
+        eid: u32,
+        offset: Block,
+        num_blocks: u64,
+    ) -> Result<Vec<bool>, CrucibleError> {
+        match self.extents.get(eid as usize) {
+            Some(extent) => extent.blocks_written(offset, num_blocks),
+            None => crucible_bail!(InvalidExtent),
+        }
+    }
+
+    /*
+     * Read num_blocks blocks of an extent from offset, and check each one
+     * against the integrity hashes stored for it.  Return the offsets of
+     * the blocks that match none of them.  A block that was never written
+     * has no hashes and nothing to check.
+     */
+    pub fn scrub_blocks(
+        &self,
+        eid: u32,
+        offset: Block,
+        num_blocks: u64,
+    ) -> Result<Vec<u64>, CrucibleError> {
+        let responses = self.region_read(
+            &[crucible_protocol::ReadRequest {
+                eid: eid as u64,
+                offset,
+                num_blocks,
+            }],
+            0,
+        )?;
+        let response = &responses[0];
+        let block_size = self.def.block_size() as usize;
+
+        let mut corrupt = Vec::new();
+        for (i, block) in response.blocks.iter().enumerate() {
+            if block.hashes.is_empty() {
+                continue;
+            }
+
+            let data = &response.data[i * block_size..(i + 1) * block_size];
+            if !block_matches_hashes(block, data) {
+                corrupt.push(offset.value + i as u64);
+            }
+        }
+
+        Ok(corrupt)
+    }
+
     pub fn validate_hashes(
         &self,
         writes: &[crucible_protocol::Write],
@@ -1623,6 +2128,7 @@ impl Region {
         for eid in batched_writes.keys() {
             let extent = &self.extents[*eid];
             let writes = batched_writes.get(eid).unwrap();
+            self.mark_dirty(*eid);
             extent.write(&writes[..], only_write_unwritten)?;
         }
         if only_write_unwritten {
@@ -1683,6 +2189,197 @@ impl Region {
         Ok(responses)
     }
 
+    #[instrument]
+    pub fn region_discard(
+        &self,
+        discards: &[crucible_protocol::Discard],
+        job_id: u64,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        cdt::os__discard__start!(|| job_id);
+        for discard in discards {
+            let extent = match self.extents.get(discard.eid as usize) {
+                Some(extent) => extent,
+                None => crucible_bail!(InvalidExtent),
+            };
+            self.mark_dirty(discard.eid as usize);
+            extent.discard(discard.offset, discard.num_blocks)?;
+        }
+        cdt::os__discard__done!(|| job_id);
+
+        Ok(())
+    }
+
+    /*
+     * Zero the given ranges without writing a buffer of zeros through the
+     * write path.  The blocks are left written, as the guest asked for
+     * zeros there, and a read only parent must not fill them in later.
+     */
+    #[instrument]
+    pub fn region_write_zeroes(
+        &self,
+        zeroes: &[crucible_protocol::WriteZeroes],
+        job_id: u64,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        if self.encrypted() {
+            crucible_bail!(Unsupported, "write zeroes on an encrypted region");
+        }
+
+        cdt::os__writezeroes__start!(|| job_id);
+        for zero in zeroes {
+            let extent = match self.extents.get(zero.eid as usize) {
+                Some(extent) => extent,
+                None => crucible_bail!(InvalidExtent),
+            };
+            self.mark_dirty(zero.eid as usize);
+            extent.write_zeroes(zero.offset, zero.num_blocks)?;
+        }
+        cdt::os__writezeroes__done!(|| job_id);
+
+        Ok(())
+    }
+
+    /*
+     * Write every block only if every block currently holds what the
+     * upstairs expects, otherwise write nothing and return CompareMismatch.
+     * A block that was never written holds zeros.
+     *
+     * The compare and the write happen within the one job, so no other IO
+     * can land on these blocks in between.
+     */
+    #[instrument]
+    pub fn region_compare_and_write(
+        &self,
+        writes: &[crucible_protocol::CompareAndWrite],
+        job_id: u64,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        if self.encrypted() {
+            crucible_bail!(
+                Unsupported,
+                "compare and write on an encrypted region"
+            );
+        }
+
+        let bs = self.def.block_size() as usize;
+        let mut requests = Vec::with_capacity(writes.len());
+        for write in writes {
+            if write.expected.len() != bs || write.data.len() != bs {
+                crucible_bail!(DataLenUnaligned);
+            }
+            if self.extents.get(write.eid as usize).is_none() {
+                crucible_bail!(InvalidExtent);
+            }
+            requests.push(crucible_protocol::ReadRequest {
+                eid: write.eid,
+                offset: write.offset,
+                num_blocks: 1,
+            });
+        }
+
+        cdt::os__compareandwrite__start!(|| job_id);
+        let responses = self.region_read(&requests, job_id)?;
+        for (write, response) in writes.iter().zip(responses.iter()) {
+            if write.expected[..] != response.data[..] {
+                return Err(CrucibleError::CompareMismatch);
+            }
+        }
+
+        let writes: Vec<crucible_protocol::Write> = writes
+            .iter()
+            .map(|w| crucible_protocol::Write {
+                eid: w.eid,
+                offset: w.offset,
+                data: w.data.clone(),
+                encryption_context: None,
+                hash: w.hash,
+            })
+            .collect();
+        self.region_write(&writes, job_id, false)?;
+        cdt::os__compareandwrite__done!(|| job_id);
+
+        Ok(())
+    }
+
+    /*
+     * Flush the given extents with one batch of fsyncs through io_uring,
+     * then update the metadata of each.  A flush has every extent to
+     * itself while it runs, so nothing writes to them in between.
+     */
+    fn flush_extents_uring(
+        &self,
+        eids: &[usize],
+        flush_number: u64,
+        gen_number: u64,
+    ) -> Result<(), CrucibleError> {
+        {
+            let mut dirty_extents = self.dirty_extents.lock().unwrap();
+            for eid in eids {
+                dirty_extents.remove(eid);
+            }
+        }
+
+        let result = self.sync_and_finish_flush(eids, flush_number, gen_number);
+        if result.is_err() {
+            let mut dirty_extents = self.dirty_extents.lock().unwrap();
+            dirty_extents.extend(eids.iter().copied());
+        }
+
+        result
+    }
+
+    fn sync_and_finish_flush(
+        &self,
+        eids: &[usize],
+        flush_number: u64,
+        gen_number: u64,
+    ) -> Result<(), CrucibleError> {
+        let mut to_flush = Vec::with_capacity(eids.len());
+        for eid in eids {
+            let extent = &self.extents[*eid];
+            if extent.needs_flush(&extent.inner())? {
+                to_flush.push(extent);
+            }
+        }
+
+        /*
+         * Extents in a single file region all share the one file, which
+         * only needs syncing once.
+         */
+        let fds: BTreeSet<RawFd> =
+            to_flush.iter().map(|extent| extent.raw_fd().0).collect();
+        let mut ops: Vec<uring::Op> =
+            fds.iter().map(|fd| uring::Op::fsync(*fd)).collect();
+        if let Err(e) = uring::submit(&mut ops) {
+            crucible_bail!(
+                IoError,
+                "fsync of {} extents failure: {:?}",
+                to_flush.len(),
+                e
+            );
+        }
+
+        for extent in to_flush {
+            extent.finish_flush(
+                &mut extent.inner(),
+                flush_number,
+                gen_number,
+            )?;
+        }
+
+        Ok(())
+    }
+
     /*
      * Send a flush to just the given extent. The provided flush number is
      * what an extent should use if a flush is required.
@@ -1700,8 +2397,7 @@ impl Region {
             eid, flush_number, gen_number
         );
 
-        let extent = &self.extents[eid];
-        extent.flush_block(flush_number, gen_number, 0)?;
+        self.flush_dirty_extent(eid, flush_number, gen_number, 0)?;
 
         Ok(())
     }
@@ -1725,78 +2421,182 @@ impl Region {
             crucible_bail!(ModifyingReadOnlyRegion);
         }
 
-        // XXX How to we convert between usize and u32 correctly?
+        /*
+         * Only extents written since their last flush have anything to
+         * sync.  The rest keep the flush number they have, which is what
+         * flush_block would leave them with anyway.
+         */
         cdt::os__flush__start!(|| job_id);
-        for eid in 0..self.def.extent_count() {
-            let extent = &self.extents[eid as usize];
-            extent.flush_block(flush_number, gen_number, job_id)?;
+        let dirty_extents: Vec<usize> =
+            self.dirty_extents.lock().unwrap().iter().copied().collect();
+        if self.io_backend == IoBackend::IoUring {
+            self.flush_extents_uring(&dirty_extents, flush_number, gen_number)?;
+        } else {
+            for eid in dirty_extents {
+                self.flush_dirty_extent(eid, flush_number, gen_number, job_id)?;
+            }
         }
         cdt::os__flush__done!(|| job_id);
 
-        // snapshots currently only work with ZFS
-        if cfg!(feature = "zfs_snapshot") {
-            if let Some(snapshot_details) = snapshot_details {
-                // Check if the path exists, return an error if it does
-                let test_path = format!(
-                    "{}/.zfs/snapshot/{}",
-                    self.dir.clone().into_os_string().into_string().unwrap(),
-                    snapshot_details.snapshot_name,
-                );
-
-                if std::path::Path::new(&test_path).is_dir() {
-                    crucible_bail!(
-                        SnapshotExistsAlready,
-                        "{}",
-                        snapshot_details.snapshot_name,
-                    );
+        if let Some(snapshot_details) = snapshot_details {
+            /*
+             * Without ZFS, a snapshot is a copy of the region's files.
+             */
+            if cfg!(feature = "zfs_snapshot") {
+                self.zfs_snapshot(snapshot_details)?;
+            } else {
+                let name = &snapshot_details.snapshot_name;
+                for extent in &self.extents {
+                    if let Err(e) = extent.checkpoint() {
+                        crucible_bail!(SnapshotFailed, "{}: {}", name, e);
+                    }
                 }
+                snapshot::take_snapshot(&self.dir, &self.def, name)?;
+            }
+        }
 
-                // Look up dataset name for path (this works with any path, and
-                // will return the parent dataset).
-                let path =
-                    self.dir.clone().into_os_string().into_string().unwrap();
-
-                let dataset_name = std::process::Command::new("zfs")
-                    .args(["list", "-pH", "-o", "name", &path])
-                    .output()
-                    .map_err(|e| {
-                        CrucibleError::SnapshotFailed(e.to_string())
-                    })?;
-
-                let dataset_name = std::str::from_utf8(&dataset_name.stdout)
-                    .map_err(|e| CrucibleError::SnapshotFailed(e.to_string()))?
-                    .trim_end(); // remove '\n' from end
-
-                let output = std::process::Command::new("zfs")
-                    .args([
-                        "snapshot",
-                        format!(
-                            "{}@{}",
-                            dataset_name, snapshot_details.snapshot_name
-                        )
-                        .as_str(),
-                    ])
-                    .output()
-                    .map_err(|e| {
-                        CrucibleError::SnapshotFailed(e.to_string())
-                    })?;
+        Ok(())
+    }
 
-                if !output.status.success() {
-                    crucible_bail!(
-                        SnapshotFailed,
-                        "{}",
-                        std::str::from_utf8(&output.stderr).map_err(|e| {
-                            CrucibleError::GenericError(e.to_string())
-                        })?,
-                    );
-                }
-            }
+    /*
+     * Take a ZFS snapshot of the dataset the region is in.
+     */
+    fn zfs_snapshot(
+        &self,
+        snapshot_details: &SnapshotDetails,
+    ) -> Result<(), CrucibleError> {
+        // Check if the path exists, return an error if it does
+        let test_path = format!(
+            "{}/.zfs/snapshot/{}",
+            self.dir.clone().into_os_string().into_string().unwrap(),
+            snapshot_details.snapshot_name,
+        );
+
+        if std::path::Path::new(&test_path).is_dir() {
+            crucible_bail!(
+                SnapshotExistsAlready,
+                "{}",
+                snapshot_details.snapshot_name,
+            );
+        }
+
+        // Look up dataset name for path (this works with any path, and
+        // will return the parent dataset).
+        let path = self.dir.clone().into_os_string().into_string().unwrap();
+
+        let dataset_name = std::process::Command::new("zfs")
+            .args(["list", "-pH", "-o", "name", &path])
+            .output()
+            .map_err(|e| CrucibleError::SnapshotFailed(e.to_string()))?;
+
+        let dataset_name = std::str::from_utf8(&dataset_name.stdout)
+            .map_err(|e| CrucibleError::SnapshotFailed(e.to_string()))?
+            .trim_end(); // remove '\n' from end
+
+        let output = std::process::Command::new("zfs")
+            .args([
+                "snapshot",
+                format!("{}@{}", dataset_name, snapshot_details.snapshot_name)
+                    .as_str(),
+            ])
+            .output()
+            .map_err(|e| CrucibleError::SnapshotFailed(e.to_string()))?;
+
+        if !output.status.success() {
+            crucible_bail!(
+                SnapshotFailed,
+                "{}",
+                std::str::from_utf8(&output.stderr).map_err(|e| {
+                    CrucibleError::GenericError(e.to_string())
+                })?,
+            );
         }
 
         Ok(())
     }
 }
 
+/*
+ * The space everything under path, other than skip, takes up on disk.  For
+ * a sparse file that is only the parts of it that have been written to.
+ */
+fn disk_usage<P: AsRef<Path>>(path: P, skip: Option<&Path>) -> Result<u64> {
+    if skip == Some(path.as_ref()) {
+        return Ok(0);
+    }
+
+    let metadata = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        /*
+         * Repairs and SQLite come and go with files of their own, so
+         * something we saw in a directory can be gone by the time we look
+         * at it.
+         */
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    /*
+     * st_blocks counts 512 byte units, whatever the file system's block
+     * size is.
+     */
+    let mut bytes = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(&path)? {
+            bytes += disk_usage(entry?.path(), skip)?;
+        }
+    }
+
+    Ok(bytes)
+}
+
+/*
+ * Free len bytes of file starting at offset, without changing the size of
+ * the file.  The range then reads back as zeros, and no longer takes up
+ * space on disk.
+ */
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    let r = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "illumos")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    /*
+     * A zero l_len would free everything to the end of the file, which
+     * nobody calling this wants.
+     */
+    assert!(len > 0);
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = offset as libc::off_t;
+    fl.l_len = len as libc::off_t;
+    let r = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_FREESP, &fl) };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "illumos")))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "no way to punch holes here",
+    ))
+}
+
 /**
  * Given a path to a directory or file, open it, then fsync it.
  * If the file is already open, then just fsync it yourself.
@@ -1818,13 +2618,33 @@ pub fn sync_path<P: AsRef<Path> + std::fmt::Debug>(
     Ok(())
 }
 
+/**
+ * Does a block's data match one of the integrity hashes stored for it?
+ * For an encrypted block, the hash covers the encryption context too.
+ */
+pub fn block_matches_hashes(
+    block: &crucible_protocol::ReadResponseBlockMetadata,
+    data: &[u8],
+) -> bool {
+    if block.encryption_contexts.is_empty() {
+        block.hashes.contains(&integrity_hash(&[data]))
+    } else {
+        block.encryption_contexts.iter().any(|ctx| {
+            let hash = integrity_hash(&[&ctx.nonce, &ctx.tag, data]);
+            block.hashes.contains(&hash)
+        })
+    }
+}
+
 /**
  * Copy the contents of the replacement directory on to the extent
- * files in the extent directory.
+ * files in the extent directory, or into the region files if this is a
+ * single file region.
  */
 pub fn move_replacement_extent<P: AsRef<Path>>(
     region_dir: P,
     eid: usize,
+    def: &RegionDefinition,
 ) -> Result<(), CrucibleError> {
     let destination_dir = extent_dir(&region_dir, eid as u32);
     let extent_file_name = extent_file_name(eid as u32, ExtentType::Data);
@@ -1836,94 +2656,145 @@ pub fn move_replacement_extent<P: AsRef<Path>>(
 
     println!("Copy files from {:?} in {:?}", replace_dir, destination_dir,);
 
-    // Setup the original and replacement file names.
+    // Setup the replacement file name.
     let mut new_file = replace_dir.clone();
-    new_file.push(extent_file_name.clone());
-
-    let mut original_file = destination_dir.clone();
-    original_file.push(extent_file_name);
+    new_file.push(extent_file_name);
 
-    // Copy the new file (the one we copied from the source side) on top
-    // of the original file.
-    if let Err(e) = std::fs::copy(new_file.clone(), original_file.clone()) {
-        crucible_bail!(
-            IoError,
-            "copy of {:?} to {:?} got: {:?}",
-            new_file,
-            original_file,
-            e
-        );
+    if def.layout() == RegionLayout::SingleFile {
+        move_replacement_into_region_files(&region_dir, eid, def, new_file)?;
+    } else {
+        move_replacement_extent_files(&destination_dir, new_file)?;
     }
-    sync_path(&original_file)?;
 
-    new_file.set_extension("db");
-    original_file.set_extension("db");
-    if let Err(e) = std::fs::copy(new_file.clone(), original_file.clone()) {
-        crucible_bail!(
-            IoError,
-            "copy {:?} to {:?} got: {:?}",
-            new_file,
-            original_file,
-            e
-        );
-    }
-    sync_path(&original_file)?;
+    // After we have all files: move the copy dir.
+    println!("Move directory  {:?} to {:?}", replace_dir, completed_dir);
+    rename(replace_dir, &completed_dir)?;
 
-    // The .db-shm and .db-wal files may or may not exist.  If they don't
-    // exist on the source side, then be sure to remove them locally to
-    // avoid database corruption from a mismatch between old and new.
-    new_file.set_extension("db-shm");
-    original_file.set_extension("db-shm");
-    if new_file.exists() {
-        if let Err(e) = std::fs::copy(new_file.clone(), original_file.clone()) {
-            crucible_bail!(
-                IoError,
-                "copy {:?} to {:?} got: {:?}",
-                new_file,
-                original_file,
-                e
-            );
-        }
-        sync_path(&original_file)?;
-    } else if original_file.exists() {
-        println!(
-            "Remove old file {:?} as there is no replacement",
-            original_file.clone()
-        );
-        std::fs::remove_file(&original_file)?;
-    }
+    sync_path(&destination_dir)?;
+
+    std::fs::remove_dir_all(&completed_dir)?;
+
+    sync_path(&destination_dir)?;
+    Ok(())
+}
+
+/**
+ * Write the replacement data and sidecar files over this extent's part
+ * of the region data and metadata files.  Doing it again after an
+ * interruption gets the same result, so this is safe to replay.
+ */
+fn move_replacement_into_region_files<P: AsRef<Path>>(
+    region_dir: P,
+    eid: usize,
+    def: &RegionDefinition,
+    mut new_file: PathBuf,
+) -> Result<(), CrucibleError> {
+    let bcount = def.extent_size().value;
+    let data_size = def.block_size() * bcount;
+    let meta_size = SidecarMetadata::size(bcount);
+
+    let copies = [
+        (region_data_path(&region_dir), None, data_size),
+        (region_meta_path(&region_dir), Some("meta"), meta_size),
+    ];
+
+    for (destination, extension, size) in copies.iter() {
+        if let Some(extension) = extension {
+            new_file.set_extension(extension);
+        }
+        println!("Copy {:?} into {:?}", new_file, destination);
+
+        let buf = std::fs::read(&new_file)?;
+        if buf.len() as u64 != *size {
+            crucible_bail!(
+                IoError,
+                "{:?} is {} bytes, expected {}",
+                new_file,
+                buf.len(),
+                size
+            );
+        }
 
-    new_file.set_extension("db-wal");
-    original_file.set_extension("db-wal");
-    if new_file.exists() {
-        if let Err(e) = std::fs::copy(new_file.clone(), original_file.clone()) {
+        let file = OpenOptions::new().write(true).open(destination)?;
+        if let Err(e) = file.write_all_at(&buf, size * eid as u64) {
             crucible_bail!(
                 IoError,
                 "copy {:?} to {:?} got: {:?}",
                 new_file,
-                original_file,
+                destination,
+                e
+            );
+        }
+        if let Err(e) = file.sync_data() {
+            crucible_bail!(
+                IoError,
+                "{:?}: fsync failure: {:?}",
+                destination,
                 e
             );
         }
-        sync_path(&original_file)?;
-    } else if original_file.exists() {
-        println!(
-            "Remove old file {:?} as there is no replacement",
-            original_file.clone()
-        );
-        std::fs::remove_file(&original_file)?;
     }
-    sync_path(&destination_dir)?;
 
-    // After we have all files: move the copy dir.
-    println!("Move directory  {:?} to {:?}", replace_dir, completed_dir);
-    rename(replace_dir, &completed_dir)?;
+    Ok(())
+}
 
-    sync_path(&destination_dir)?;
+/**
+ * Copy the replacement files over the extent's own files.
+ */
+fn move_replacement_extent_files(
+    destination_dir: &Path,
+    mut new_file: PathBuf,
+) -> Result<(), CrucibleError> {
+    let extent_file_name = new_file.file_name().unwrap().to_owned();
 
-    std::fs::remove_dir_all(&completed_dir)?;
+    let mut original_file = destination_dir.to_path_buf();
+    original_file.push(extent_file_name);
+
+    // Copy the new file (the one we copied from the source side) on top
+    // of the original file.
+    if let Err(e) = std::fs::copy(new_file.clone(), original_file.clone()) {
+        crucible_bail!(
+            IoError,
+            "copy of {:?} to {:?} got: {:?}",
+            new_file,
+            original_file,
+            e
+        );
+    }
+    sync_path(&original_file)?;
+
+    // Which metadata files there are depends on how the source extent
+    // keeps its metadata, and that need not be the same as how ours did.
+    // Copy over each one the source has, and be sure to remove any others
+    // locally, both so the extent opens with the right backend and to
+    // avoid database corruption from a mismatch between old and new
+    // .db-shm and .db-wal files.
+    for ext in &["db", "db-shm", "db-wal", "meta"] {
+        new_file.set_extension(ext);
+        original_file.set_extension(ext);
+        if new_file.exists() {
+            if let Err(e) =
+                std::fs::copy(new_file.clone(), original_file.clone())
+            {
+                crucible_bail!(
+                    IoError,
+                    "copy {:?} to {:?} got: {:?}",
+                    new_file,
+                    original_file,
+                    e
+                );
+            }
+            sync_path(&original_file)?;
+        } else if original_file.exists() {
+            println!(
+                "Remove old file {:?} as there is no replacement",
+                original_file.clone()
+            );
+            std::fs::remove_file(&original_file)?;
+        }
+    }
+    sync_path(destination_dir)?;
 
-    sync_path(&destination_dir)?;
     Ok(())
 }
 
@@ -1971,7 +2842,7 @@ pub async fn save_stream_to_file(
 mod test {
     use super::*;
     use crate::dump::dump_region;
-    use bytes::{BufMut, BytesMut};
+    use bytes::{BufMut, Bytes, BytesMut};
     use rand::{Rng, RngCore};
     use std::fs::rename;
     use std::path::PathBuf;
@@ -1987,7 +2858,8 @@ mod test {
 
         let inn = Inner {
             file: ff,
-            metadb: Connection::open_in_memory().unwrap(),
+            meta: Box::new(SqliteMetadata::in_memory()),
+            data_offset: 0,
         };
 
         /*
@@ -2000,6 +2872,7 @@ mod test {
             block_size: 512,
             extent_size: Block::new_512(100),
             inner: Some(Mutex::new(inn)),
+            io_backend: IoBackend::default(),
         }
     }
 
@@ -2009,6 +2882,22 @@ mod test {
         TEST_UUID_STR.parse().unwrap()
     }
 
+    /*
+     * Run a region test on each IO backend we can use here.  io_uring is
+     * skipped if this downstairs was built without it, or the kernel will
+     * not give us a ring.
+     */
+    fn on_each_io_backend(test: fn(IoBackend) -> Result<()>) -> Result<()> {
+        for io_backend in [IoBackend::Std, IoBackend::IoUring] {
+            if let Err(e) = io_backend.check_available() {
+                println!("skipping: {}", e);
+                continue;
+            }
+            test(io_backend)?;
+        }
+        Ok(())
+    }
+
     fn new_region_options() -> crucible_common::RegionOptions {
         let mut region_options: crucible_common::RegionOptions =
             Default::default();
@@ -2441,6 +3330,25 @@ mod test {
         assert_eq!(validate_repair_files(1, &good_files), false);
     }
 
+    #[test]
+    fn validate_repair_files_sidecar() {
+        // An extent with a sidecar has just the data and .meta files
+        let good_files: Vec<String> =
+            vec!["001".to_string(), "001.meta".to_string()];
+        assert!(validate_repair_files(1, &good_files));
+    }
+
+    #[test]
+    fn validate_repair_files_sidecar_and_db() {
+        // An extent keeps its metadata one way or the other, not both
+        let good_files: Vec<String> = vec![
+            "001".to_string(),
+            "001.db".to_string(),
+            "001.meta".to_string(),
+        ];
+        assert_eq!(validate_repair_files(1, &good_files), false);
+    }
+
     #[test]
     fn reopen_all_extents() -> Result<()> {
         // Create the region, make three extents
@@ -2681,7 +3589,7 @@ mod test {
         /*
          * Dump the region
          */
-        dump_region(dvec, None, None, false, false)?;
+        dump_region(dvec, None, None, false, false, false)?;
 
         Ok(())
     }
@@ -2713,7 +3621,7 @@ mod test {
         /*
          * Dump the region
          */
-        dump_region(dvec, None, None, false, false)?;
+        dump_region(dvec, None, None, false, false, false)?;
 
         Ok(())
     }
@@ -2746,7 +3654,7 @@ mod test {
         /*
          * Dump the region
          */
-        dump_region(dvec, Some(2), None, false, false)?;
+        dump_region(dvec, Some(2), None, false, false, false)?;
 
         Ok(())
     }
@@ -2971,8 +3879,13 @@ mod test {
 
     #[test]
     fn test_big_write() -> Result<()> {
+        on_each_io_backend(test_big_write_on)
+    }
+
+    fn test_big_write_on(io_backend: IoBackend) -> Result<()> {
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(3)?;
 
         let ddef = region.def();
@@ -3033,7 +3946,11 @@ mod test {
             let offset: Block =
                 Block::new_512((i as u64) % ddef.extent_size().value);
 
-            requests.push(crucible_protocol::ReadRequest { eid, offset });
+            requests.push(crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            });
         }
 
         let responses = region.region_read(&requests, 0)?;
@@ -3051,8 +3968,13 @@ mod test {
 
     #[test]
     fn test_ok_hash_ok() -> Result<()> {
+        on_each_io_backend(test_ok_hash_ok_on)
+    }
+
+    fn test_ok_hash_ok_on(io_backend: IoBackend) -> Result<()> {
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(1)?;
 
         let data = BytesMut::from(&[1u8; 512][..]);
@@ -3078,10 +4000,15 @@ mod test {
 
     #[test]
     fn test_write_unwritten_when_empty() -> Result<()> {
+        on_each_io_backend(test_write_unwritten_when_empty_on)
+    }
+
+    fn test_write_unwritten_when_empty_on(io_backend: IoBackend) -> Result<()> {
         // Verify that a read fill does write to a block when there is
         // no data written yet.
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(1)?;
 
         // Fill a buffer with "9"'s (random)
@@ -3113,12 +4040,16 @@ mod test {
 
         // Now read back that block, make sure it is updated.
         let responses = region.region_read(
-            &[crucible_protocol::ReadRequest { eid, offset }],
+            &[crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            }],
             0,
         )?;
 
         assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].hashes.len(), 1);
+        assert_eq!(responses[0].blocks[0].hashes.len(), 1);
         assert_eq!(responses[0].data[..], [9u8; 512][..]);
 
         Ok(())
@@ -3126,10 +4057,17 @@ mod test {
 
     #[test]
     fn test_write_unwritten_when_written() -> Result<()> {
+        on_each_io_backend(test_write_unwritten_when_written_on)
+    }
+
+    fn test_write_unwritten_when_written_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
         // Verify that a read fill does not write to the block when
         // there is data written already.
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(1)?;
 
         // Fill a buffer with "9"'s (random)
@@ -3174,14 +4112,18 @@ mod test {
 
         // Now read back that block, make sure it has the first write
         let responses = region.region_read(
-            &[crucible_protocol::ReadRequest { eid, offset }],
+            &[crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            }],
             2,
         )?;
 
         // We should still have one response.
         assert_eq!(responses.len(), 1);
         // Hash should be just 1
-        assert_eq!(responses[0].hashes.len(), 1);
+        assert_eq!(responses[0].blocks[0].hashes.len(), 1);
         // Data should match first write
         assert_eq!(responses[0].data[..], [9u8; 512][..]);
 
@@ -3190,11 +4132,18 @@ mod test {
 
     #[test]
     fn test_write_unwritten_when_written_flush() -> Result<()> {
+        on_each_io_backend(test_write_unwritten_when_written_flush_on)
+    }
+
+    fn test_write_unwritten_when_written_flush_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
         // Verify that a read fill does not write to the block when
         // there is data written already.  This time run a flush after the
         // first write.  Verify correct state of dirty bit as well.
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(1)?;
 
         // Fill a buffer with "9"'s
@@ -3260,14 +4209,18 @@ mod test {
 
         // Read back our block, make sure it has the first write data
         let responses = region.region_read(
-            &[crucible_protocol::ReadRequest { eid, offset }],
+            &[crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            }],
             2,
         )?;
 
         // We should still have one response.
         assert_eq!(responses.len(), 1);
         // Hash should be just 1
-        assert_eq!(responses[0].hashes.len(), 1);
+        assert_eq!(responses[0].blocks[0].hashes.len(), 1);
         // Data should match first write
         assert_eq!(responses[0].data[..], [9u8; 512][..]);
 
@@ -3276,10 +4229,15 @@ mod test {
 
     #[test]
     fn test_write_unwritten_big_write() -> Result<()> {
+        on_each_io_backend(test_write_unwritten_big_write_on)
+    }
+
+    fn test_write_unwritten_big_write_on(io_backend: IoBackend) -> Result<()> {
         // Do a multi block write where all blocks start new (unwritten)
         // Verify only empty blocks have data.
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(3)?;
 
         let ddef = region.def();
@@ -3338,7 +4296,11 @@ mod test {
             let offset: Block =
                 Block::new_512((i as u64) % ddef.extent_size().value);
 
-            requests.push(crucible_protocol::ReadRequest { eid, offset });
+            requests.push(crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            });
         }
 
         let responses = region.region_read(&requests, 0)?;
@@ -3356,11 +4318,18 @@ mod test {
 
     #[test]
     fn test_write_unwritten_big_write_partial_0() -> Result<()> {
+        on_each_io_backend(test_write_unwritten_big_write_partial_0_on)
+    }
+
+    fn test_write_unwritten_big_write_partial_0_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
         // Do a write to block zero, then do a multi block write with
         // only_write_unwritten set. Verify block zero is the first write, and
         // the remaining blocks have the contents from the multi block fill.
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(3)?;
 
         let ddef = region.def();
@@ -3449,7 +4418,11 @@ mod test {
             let offset: Block =
                 Block::new_512((i as u64) % ddef.extent_size().value);
 
-            requests.push(crucible_protocol::ReadRequest { eid, offset });
+            requests.push(crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            });
         }
 
         let responses = region.region_read(&requests, 0)?;
@@ -3467,12 +4440,19 @@ mod test {
 
     #[test]
     fn test_write_unwritten_big_write_partial_1() -> Result<()> {
+        on_each_io_backend(test_write_unwritten_big_write_partial_1_on)
+    }
+
+    fn test_write_unwritten_big_write_partial_1_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
         // Write to the second block, then do a multi block fill.
         // Verify the second block has the original data we wrote, and all
         // the other blocks have the data from the multi block fill.
 
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(3)?;
 
         let ddef = region.def();
@@ -3561,7 +4541,11 @@ mod test {
             let offset: Block =
                 Block::new_512((i as u64) % ddef.extent_size().value);
 
-            requests.push(crucible_protocol::ReadRequest { eid, offset });
+            requests.push(crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            });
         }
 
         let responses = region.region_read(&requests, 0)?;
@@ -3579,6 +4563,12 @@ mod test {
 
     #[test]
     fn test_write_unwritten_big_write_partial_final() -> Result<()> {
+        on_each_io_backend(test_write_unwritten_big_write_partial_final_on)
+    }
+
+    fn test_write_unwritten_big_write_partial_final_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
         // Do a write to the fourth block, then do a multi block read fill
         // where the last block of the read fill is what we wrote to in
         // our first write.
@@ -3587,6 +4577,7 @@ mod test {
 
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(5)?;
 
         let ddef = region.def();
@@ -3666,7 +4657,11 @@ mod test {
                 Block::new_512((i as u64) % ddef.extent_size().value);
 
             println!("Read eid: {}, {} offset: {:?}", eid, i, offset);
-            requests.push(crucible_protocol::ReadRequest { eid, offset });
+            requests.push(crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            });
         }
 
         let responses = region.region_read(&requests, 0)?;
@@ -3685,10 +4680,17 @@ mod test {
 
     #[test]
     fn test_write_unwritten_big_write_partial_sparse() -> Result<()> {
+        on_each_io_backend(test_write_unwritten_big_write_partial_sparse_on)
+    }
+
+    fn test_write_unwritten_big_write_partial_sparse_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
         // Do a multi block write_unwritten where a few different blocks have
         // data. Verify only unwritten blocks get the data.
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(4)?;
 
         let ddef = region.def();
@@ -3773,7 +4775,11 @@ mod test {
             let offset: Block =
                 Block::new_512((i as u64) % ddef.extent_size().value);
 
-            requests.push(crucible_protocol::ReadRequest { eid, offset });
+            requests.push(crucible_protocol::ReadRequest {
+                eid,
+                offset,
+                num_blocks: 1,
+            });
         }
 
         let responses = region.region_read(&requests, 0)?;
@@ -3791,8 +4797,13 @@ mod test {
 
     #[test]
     fn test_bad_hash_bad() -> Result<()> {
+        on_each_io_backend(test_bad_hash_bad_on)
+    }
+
+    fn test_bad_hash_bad_on(io_backend: IoBackend) -> Result<()> {
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(1)?;
 
         let data = BytesMut::from(&[1u8; 512][..]);
@@ -3829,22 +4840,1108 @@ mod test {
 
     #[test]
     fn test_blank_block_read_ok() -> Result<()> {
+        on_each_io_backend(test_blank_block_read_ok_on)
+    }
+
+    fn test_blank_block_read_ok_on(io_backend: IoBackend) -> Result<()> {
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
         region.extend(1)?;
 
         let responses = region.region_read(
             &[crucible_protocol::ReadRequest {
                 eid: 0,
                 offset: Block::new_512(0),
+                num_blocks: 1,
             }],
             0,
         )?;
 
         assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].hashes.len(), 0);
+        assert_eq!(responses[0].blocks[0].hashes.len(), 0);
         assert_eq!(responses[0].data[..], [0u8; 512][..]);
 
         Ok(())
     }
+
+    #[test]
+    fn test_discard_makes_blocks_unwritten() -> Result<()> {
+        on_each_io_backend(test_discard_makes_blocks_unwritten_on)
+    }
+
+    fn test_discard_makes_blocks_unwritten_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(2)?;
+
+        // Write 9s to the first four blocks of both extents
+        let data = Bytes::from(&[9u8; 512][..]);
+        let hash = integrity_hash(&[&data[..]]);
+        let mut writes: Vec<crucible_protocol::Write> = Vec::new();
+        for eid in 0..2 {
+            for i in 0..4 {
+                writes.push(crucible_protocol::Write {
+                    eid,
+                    offset: Block::new_512(i),
+                    data: data.clone(),
+                    encryption_context: None,
+                    hash,
+                });
+            }
+        }
+        region.region_write(&writes, 0, false)?;
+        region.region_flush(1, 2, &None, 1)?;
+
+        // Discard the middle two blocks of extent 0, and all of extent 1
+        let discards = vec![
+            crucible_protocol::Discard {
+                eid: 0,
+                offset: Block::new_512(1),
+                num_blocks: 2,
+            },
+            crucible_protocol::Discard {
+                eid: 1,
+                offset: Block::new_512(0),
+                num_blocks: 10,
+            },
+        ];
+        region.region_discard(&discards, 2)?;
+
+        let dirty = region.dirty()?;
+        assert!(dirty[0]);
+        assert!(dirty[1]);
+
+        let mut requests: Vec<crucible_protocol::ReadRequest> = Vec::new();
+        for eid in 0..2 {
+            for i in 0..4 {
+                requests.push(crucible_protocol::ReadRequest {
+                    eid,
+                    offset: Block::new_512(i),
+                    num_blocks: 1,
+                });
+            }
+        }
+        let responses = region.region_read(&requests, 3)?;
+
+        for (i, response) in responses.iter().enumerate() {
+            if i == 0 || i == 3 {
+                assert_eq!(response.blocks[0].hashes, vec![hash]);
+                assert_eq!(response.data[..], [9u8; 512][..]);
+            } else {
+                assert!(response.blocks[0].hashes.is_empty());
+                assert!(response.blocks[0].encryption_contexts.is_empty());
+                assert_eq!(response.data[..], [0u8; 512][..]);
+            }
+        }
+
+        // The metadata alone says the same, without reading anything.
+        assert_eq!(
+            region.blocks_written(0, Block::new_512(0), 5)?,
+            vec![true, false, false, true, false]
+        );
+        assert_eq!(
+            region.blocks_written(1, Block::new_512(0), 4)?,
+            vec![false; 4]
+        );
+        assert!(region.blocks_written(0, Block::new_512(8), 3).is_err());
+        assert!(region.blocks_written(2, Block::new_512(0), 1).is_err());
+
+        // A discarded block is unwritten, so write_unwritten lands there.
+        let data = Bytes::from(&[1u8; 512][..]);
+        let writes = vec![crucible_protocol::Write {
+            eid: 0,
+            offset: Block::new_512(1),
+            data: data.clone(),
+            encryption_context: None,
+            hash: integrity_hash(&[&data[..]]),
+        }];
+        region.region_write(&writes, 4, true)?;
+
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(1),
+                num_blocks: 1,
+            }],
+            5,
+        )?;
+        assert_eq!(responses[0].data[..], [1u8; 512][..]);
+
+        Ok(())
+    }
+
+    fn write_block(
+        region: &Region,
+        block: u64,
+        fill: u8,
+        job_id: u64,
+    ) -> Result<u64> {
+        let data = Bytes::from(vec![fill; 512]);
+        let hash = integrity_hash(&[&data[..]]);
+        region.region_write(
+            &[crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(block),
+                data,
+                encryption_context: None,
+                hash,
+            }],
+            job_id,
+            false,
+        )?;
+        Ok(hash)
+    }
+
+    fn block_hashes(region: &Region, block: u64) -> Result<Vec<u64>> {
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(block),
+                num_blocks: 1,
+            }],
+            0,
+        )?;
+        Ok(responses[0].blocks[0].hashes.clone())
+    }
+
+    #[test]
+    fn sidecar_region() -> Result<()> {
+        on_each_io_backend(sidecar_region_on)
+    }
+
+    fn sidecar_region_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_metadata_backend(MetadataBackend::Sidecar);
+        let mut region = Region::create(&dir, region_options)?;
+        region.set_io_backend(io_backend)?;
+        region.extend(1)?;
+
+        let mut path = extent_path(&dir, 0);
+        path.set_extension("meta");
+        assert!(path.exists());
+        path.set_extension("db");
+        assert!(!path.exists());
+
+        // Every write since the last flush could be what is on disk,
+        // as far as the sidecar knows, until it has room for no more.
+        let h1 = write_block(&region, 0, 1, 1)?;
+        assert_eq!(block_hashes(&region, 0)?, vec![h1]);
+        let h2 = write_block(&region, 0, 2, 2)?;
+        assert_eq!(block_hashes(&region, 0)?, vec![h1, h2]);
+        let h3 = write_block(&region, 0, 3, 3)?;
+        assert_eq!(block_hashes(&region, 0)?, vec![h2, h3]);
+        write_block(&region, 1, 4, 4)?;
+        assert!(region.dirty()?[0]);
+
+        region.region_flush(7, 2, &None, 5)?;
+        assert_eq!(block_hashes(&region, 0)?, vec![h3]);
+        assert!(!region.dirty()?[0]);
+
+        region.region_discard(
+            &[crucible_protocol::Discard {
+                eid: 0,
+                offset: Block::new_512(1),
+                num_blocks: 1,
+            }],
+            6,
+        )?;
+        assert!(block_hashes(&region, 1)?.is_empty());
+        drop(region);
+
+        // The region definition is not what picks the backend when an
+        // extent is opened, the files on disk are.
+        let mut region = Region::open(&dir, new_region_options(), false, true)?;
+        region.set_io_backend(io_backend)?;
+        assert_eq!(region.def().metadata_backend(), MetadataBackend::Sidecar);
+        // The upstairs is never told how the region is stored.
+        let sent = serde_json::to_value(region.def())?;
+        assert!(sent.get("metadata_backend").is_none());
+        assert_eq!(region.flush_numbers()?, vec![7]);
+        assert_eq!(region.gen_numbers()?, vec![2]);
+        assert_eq!(region.dirty()?, vec![true]);
+        assert_eq!(block_hashes(&region, 0)?, vec![h3]);
+        assert!(block_hashes(&region, 1)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_extent_replay_sidecar() -> Result<()> {
+        on_each_io_backend(reopen_extent_replay_sidecar_on)
+    }
+
+    fn reopen_extent_replay_sidecar_on(io_backend: IoBackend) -> Result<()> {
+        // Repair an extent that uses SQLite with the files from one that
+        // uses a sidecar, and make sure it comes back with a sidecar.
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(2)?;
+
+        let source_dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_metadata_backend(MetadataBackend::Sidecar);
+        let mut source = Region::create(&source_dir, region_options)?;
+        source.set_io_backend(io_backend)?;
+        source.extend(2)?;
+        let hash = write_block(&source, 0, 9, 1)?;
+        source.region_flush(1, 1, &None, 2)?;
+
+        let ext_zero = &mut region.extents[0];
+        ext_zero.close()?;
+        let cp = ext_zero.create_copy_dir(&dir)?;
+
+        let mut source_path = extent_path(&source_dir, 0);
+        let mut dest_path = cp.clone();
+        dest_path.push(extent_file_name(0, ExtentType::Data));
+        std::fs::copy(&source_path, &dest_path)?;
+        source_path.set_extension("meta");
+        dest_path.set_extension("meta");
+        std::fs::copy(&source_path, &dest_path)?;
+
+        let rd = replace_dir(&dir, 0);
+        rename(cp, rd)?;
+
+        region.reopen_extent(0)?;
+
+        let mut path = extent_path(&dir, 0);
+        for ext in &["db", "db-shm", "db-wal"] {
+            path.set_extension(ext);
+            assert!(!path.exists());
+        }
+        path.set_extension("meta");
+        assert!(path.exists());
+
+        assert_eq!(block_hashes(&region, 0)?, vec![hash]);
+        assert_eq!(region.flush_numbers()?, vec![1, 0]);
+
+        Ok(())
+    }
+
+    fn single_file_region_options() -> crucible_common::RegionOptions {
+        let mut region_options = new_region_options();
+        region_options.set_metadata_backend(MetadataBackend::Sidecar);
+        region_options.set_layout(RegionLayout::SingleFile);
+        region_options
+    }
+
+    #[test]
+    fn single_file_region_needs_sidecar() {
+        let dir = tempdir().unwrap();
+        let mut region_options = single_file_region_options();
+        region_options.set_metadata_backend(MetadataBackend::Sqlite);
+        assert!(Region::create(&dir, region_options).is_err());
+    }
+
+    #[test]
+    fn single_file_region() -> Result<()> {
+        on_each_io_backend(single_file_region_on)
+    }
+
+    fn single_file_region_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, single_file_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(2)?;
+
+        // Each extent gets its own data and sidecar in the region files.
+        let data = Bytes::from(vec![7u8; 512]);
+        let hash = integrity_hash(&[&data[..]]);
+        let writes: Vec<crucible_protocol::Write> = (0..2)
+            .map(|eid| crucible_protocol::Write {
+                eid,
+                offset: Block::new_512(9),
+                data: data.clone(),
+                encryption_context: None,
+                hash,
+            })
+            .collect();
+        region.region_write(&writes, 1, false)?;
+        region.region_flush(1, 1, &None, 2)?;
+
+        // Growing the region makes room without touching what is there.
+        region.extend(3)?;
+        let data_len = std::fs::metadata(region_data_path(&dir))?.len();
+        assert_eq!(data_len, 3 * 10 * 512);
+        let meta_len = std::fs::metadata(region_meta_path(&dir))?.len();
+        assert_eq!(meta_len, 3 * SidecarMetadata::size(10));
+        drop(region);
+
+        // Nothing but the region files.
+        let mut names: Vec<String> = std::fs::read_dir(&dir)?
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["region.data", "region.json", "region.meta"]);
+
+        let mut region = Region::open(&dir, new_region_options(), false, true)?;
+        region.set_io_backend(io_backend)?;
+        assert_eq!(region.def().layout(), RegionLayout::SingleFile);
+        let sent = serde_json::to_value(region.def())?;
+        assert!(sent.get("layout").is_none());
+        assert_eq!(region.flush_numbers()?, vec![1, 1, 0]);
+
+        let requests: Vec<crucible_protocol::ReadRequest> = (0..3)
+            .map(|eid| crucible_protocol::ReadRequest {
+                eid,
+                offset: Block::new_512(9),
+                num_blocks: 1,
+            })
+            .collect();
+        let responses = region.region_read(&requests, 3)?;
+        for response in &responses[0..2] {
+            assert_eq!(response.data[..], data[..]);
+            assert_eq!(response.blocks[0].hashes, vec![hash]);
+        }
+        assert_eq!(responses[2].data[..], [0u8; 512][..]);
+        assert!(responses[2].blocks[0].hashes.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_extent_replay_single_file() -> Result<()> {
+        on_each_io_backend(reopen_extent_replay_single_file_on)
+    }
+
+    fn reopen_extent_replay_single_file_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
+        // Repair an extent of a single file region with the files of an
+        // extent that has its own, and make sure only that extent's part
+        // of the region files changes.
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, single_file_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(3)?;
+        let old_hash = write_block(&region, 0, 1, 1)?;
+        let data = Bytes::from(vec![1u8; 512]);
+        region.region_write(
+            &[crucible_protocol::Write {
+                eid: 2,
+                offset: Block::new_512(0),
+                data,
+                encryption_context: None,
+                hash: old_hash,
+            }],
+            2,
+            false,
+        )?;
+        region.region_flush(1, 1, &None, 3)?;
+
+        let source_dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_metadata_backend(MetadataBackend::Sidecar);
+        let mut source = Region::create(&source_dir, region_options)?;
+        source.set_io_backend(io_backend)?;
+        source.extend(2)?;
+        let new_hash = write_block(&source, 0, 9, 1)?;
+        source.region_flush(4, 4, &None, 2)?;
+
+        let ext_one = &mut region.extents[1];
+        ext_one.close()?;
+        let cp = ext_one.create_copy_dir(&dir)?;
+
+        let mut source_path = extent_path(&source_dir, 0);
+        let mut dest_path = cp.clone();
+        dest_path.push(extent_file_name(1, ExtentType::Data));
+        std::fs::copy(&source_path, &dest_path)?;
+        source_path.set_extension("meta");
+        dest_path.set_extension("meta");
+        std::fs::copy(&source_path, &dest_path)?;
+
+        rename(cp, replace_dir(&dir, 1))?;
+
+        region.reopen_extent(1)?;
+        assert!(!replace_dir(&dir, 1).exists());
+
+        assert_eq!(region.flush_numbers()?, vec![1, 4, 1]);
+        let requests: Vec<crucible_protocol::ReadRequest> = (0..3)
+            .map(|eid| crucible_protocol::ReadRequest {
+                eid,
+                offset: Block::new_512(0),
+                num_blocks: 1,
+            })
+            .collect();
+        let responses = region.region_read(&requests, 4)?;
+        assert_eq!(responses[0].blocks[0].hashes, vec![old_hash]);
+        assert_eq!(responses[1].blocks[0].hashes, vec![new_hash]);
+        assert_eq!(responses[1].data[..], [9u8; 512][..]);
+        assert_eq!(responses[2].blocks[0].hashes, vec![old_hash]);
+        assert_eq!(responses[2].data[..], [1u8; 512][..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_range() -> Result<()> {
+        on_each_io_backend(test_read_range_on)
+    }
+
+    fn test_read_range_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(1)?;
+
+        // Write blocks 2, 3 and 4, each with its own data
+        let writes: Vec<crucible_protocol::Write> = (2..5)
+            .map(|i| {
+                let data = Bytes::from(vec![i as u8; 512]);
+                let hash = integrity_hash(&[&data[..]]);
+                crucible_protocol::Write {
+                    eid: 0,
+                    offset: Block::new_512(i),
+                    data,
+                    encryption_context: None,
+                    hash,
+                }
+            })
+            .collect();
+        region.region_write(&writes, 0, false)?;
+
+        // Read blocks 1 through 5 in one request
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(1),
+                num_blocks: 5,
+            }],
+            1,
+        )?;
+        assert_eq!(responses.len(), 1);
+
+        let response = &responses[0];
+        assert_eq!(response.data.len(), 512 * 5);
+        assert_eq!(response.blocks.len(), 5);
+
+        for (i, (data, block)) in response.iter_blocks().enumerate() {
+            let i = i as u64 + 1;
+            if (2..5).contains(&i) {
+                assert_eq!(data, &[i as u8; 512][..]);
+                assert_eq!(block.hashes, vec![integrity_hash(&[data])]);
+            } else {
+                assert_eq!(data, &[0u8; 512][..]);
+                assert!(block.hashes.is_empty());
+            }
+        }
+
+        // A range that runs off the end of the extent is refused
+        assert_eq!(
+            region.region_read(
+                &[crucible_protocol::ReadRequest {
+                    eid: 0,
+                    offset: Block::new_512(8),
+                    num_blocks: 3,
+                }],
+                2,
+            ),
+            Err(CrucibleError::OffsetInvalid)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unwritten_block_reads_as_zeros() -> Result<()> {
+        on_each_io_backend(test_unwritten_block_reads_as_zeros_on)
+    }
+
+    fn test_unwritten_block_reads_as_zeros_on(
+        io_backend: IoBackend,
+    ) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(1)?;
+
+        write_block(&region, 1, 9, 1)?;
+        write_block(&region, 2, 9, 2)?;
+        region.region_flush(1, 2, &None, 3)?;
+
+        /*
+         * Where a discard stops if we crash after it drops the metadata
+         * for block 1, and before it punches out the data.
+         */
+        region.extents[0].inner().meta.remove_blocks(1, 1)?;
+
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(0),
+                num_blocks: 3,
+            }],
+            4,
+        )?;
+        let response = &responses[0];
+
+        assert!(response.blocks[1].hashes.is_empty());
+        assert_eq!(response.data[..1024], [0u8; 1024][..]);
+        assert_eq!(response.blocks[2].hashes.len(), 1);
+        assert_eq!(response.data[1024..], [9u8; 512][..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_discard_past_extent_end() -> Result<()> {
+        on_each_io_backend(test_discard_past_extent_end_on)
+    }
+
+    fn test_discard_past_extent_end_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(1)?;
+
+        let discards = vec![crucible_protocol::Discard {
+            eid: 0,
+            offset: Block::new_512(8),
+            num_blocks: 3,
+        }];
+        assert_eq!(
+            region.region_discard(&discards, 0),
+            Err(CrucibleError::OffsetInvalid)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "illumos"))]
+    fn test_discard_frees_space() -> Result<()> {
+        for mut region_options in
+            [new_region_options(), single_file_region_options()]
+        {
+            region_options.set_block_size(4096);
+            region_options.set_extent_size(Block::new(256, 12));
+
+            let dir = tempdir()?;
+            let mut region = Region::create(&dir, region_options)?;
+            region.extend(1)?;
+
+            let data = Bytes::from(vec![9u8; 4096]);
+            let hash = integrity_hash(&[&data[..]]);
+            let writes: Vec<crucible_protocol::Write> = (0..256)
+                .map(|i| crucible_protocol::Write {
+                    eid: 0,
+                    offset: Block::new_4096(i),
+                    data: data.clone(),
+                    encryption_context: None,
+                    hash,
+                })
+                .collect();
+            region.region_write(&writes, 0, false)?;
+            region.region_flush(1, 1, &None, 1)?;
+            let written = region.space_usage()?.physical_bytes;
+
+            region.region_discard(
+                &[crucible_protocol::Discard {
+                    eid: 0,
+                    offset: Block::new_4096(0),
+                    num_blocks: 256,
+                }],
+                2,
+            )?;
+            region.region_flush(2, 1, &None, 3)?;
+
+            // Most of that megabyte of data should be gone from the disk.
+            let discarded = region.space_usage()?.physical_bytes;
+            assert!(discarded + 512 * 1024 < written);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_zeroes() -> Result<()> {
+        on_each_io_backend(test_write_zeroes_on)
+    }
+
+    fn test_write_zeroes_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(1)?;
+
+        let data = Bytes::from(&[9u8; 512][..]);
+        let hash = integrity_hash(&[&data[..]]);
+        let writes: Vec<crucible_protocol::Write> = (0..3)
+            .map(|i| crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(i),
+                data: data.clone(),
+                encryption_context: None,
+                hash,
+            })
+            .collect();
+        region.region_write(&writes, 0, false)?;
+
+        let zeroes = vec![crucible_protocol::WriteZeroes {
+            eid: 0,
+            offset: Block::new_512(1),
+            num_blocks: 2,
+        }];
+        region.region_write_zeroes(&zeroes, 1)?;
+
+        let requests: Vec<crucible_protocol::ReadRequest> = (0..3)
+            .map(|i| crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(i),
+                num_blocks: 1,
+            })
+            .collect();
+        let responses = region.region_read(&requests, 2)?;
+
+        assert_eq!(responses[0].data[..], [9u8; 512][..]);
+        assert_eq!(responses[1].data[..], [0u8; 512][..]);
+        assert_eq!(responses[2].data[..], [0u8; 512][..]);
+
+        // The zeroed blocks are still written, so a fill from a read only
+        // parent does not land on them.
+        let zero_hash = integrity_hash(&[&[0u8; 512][..]]);
+        assert_eq!(responses[1].blocks[0].hashes.last(), Some(&zero_hash));
+        assert_eq!(responses[2].blocks[0].hashes.last(), Some(&zero_hash));
+
+        let ones = Bytes::from(&[1u8; 512][..]);
+        let writes = vec![crucible_protocol::Write {
+            eid: 0,
+            offset: Block::new_512(1),
+            data: ones.clone(),
+            encryption_context: None,
+            hash: integrity_hash(&[&ones[..]]),
+        }];
+        region.region_write(&writes, 3, true)?;
+        let responses = region.region_read(&requests, 4)?;
+        assert_eq!(responses[1].data[..], [0u8; 512][..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_zeroes_encrypted() -> Result<()> {
+        on_each_io_backend(test_write_zeroes_encrypted_on)
+    }
+
+    fn test_write_zeroes_encrypted_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_encrypted(true);
+        let mut region = Region::create(&dir, region_options)?;
+        region.set_io_backend(io_backend)?;
+        region.extend(1)?;
+
+        let zeroes = vec![crucible_protocol::WriteZeroes {
+            eid: 0,
+            offset: Block::new_512(0),
+            num_blocks: 1,
+        }];
+        assert!(matches!(
+            region.region_write_zeroes(&zeroes, 1),
+            Err(CrucibleError::Unsupported(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_write() -> Result<()> {
+        on_each_io_backend(test_compare_and_write_on)
+    }
+
+    fn test_compare_and_write_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(1)?;
+
+        let zeros = Bytes::from(&[0u8; 512][..]);
+        let ones = Bytes::from(&[1u8; 512][..]);
+        let twos = Bytes::from(&[2u8; 512][..]);
+
+        let cw = |block: u64, expected: &Bytes, data: &Bytes| {
+            crucible_protocol::CompareAndWrite {
+                eid: 0,
+                offset: Block::new_512(block),
+                expected: expected.clone(),
+                data: data.clone(),
+                hash: integrity_hash(&[&data[..]]),
+            }
+        };
+        let read = |region: &Region| -> Result<Vec<BytesMut>> {
+            let requests = vec![crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(0),
+                num_blocks: 2,
+            }];
+            let responses = region.region_read(&requests, 0)?;
+            Ok(responses.into_iter().map(|r| r.data).collect())
+        };
+
+        // Unwritten blocks compare equal to zeros.
+        region.region_compare_and_write(
+            &[cw(0, &zeros, &ones), cw(1, &zeros, &ones)],
+            1,
+        )?;
+        assert_eq!(read(&region)?[0][..], [1u8; 1024][..]);
+
+        // If one block does not match, neither is written.
+        assert_eq!(
+            region.region_compare_and_write(
+                &[cw(0, &ones, &twos), cw(1, &zeros, &twos)],
+                2,
+            ),
+            Err(CrucibleError::CompareMismatch)
+        );
+        assert_eq!(read(&region)?[0][..], [1u8; 1024][..]);
+
+        region.region_compare_and_write(
+            &[cw(0, &ones, &twos), cw(1, &ones, &twos)],
+            3,
+        )?;
+        assert_eq!(read(&region)?[0][..], [2u8; 1024][..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_only_dirty_extents() -> Result<()> {
+        on_each_io_backend(flush_only_dirty_extents_on)
+    }
+
+    fn flush_only_dirty_extents_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(3)?;
+        assert!(region.dirty_extents.lock().unwrap().is_empty());
+
+        write_block(&region, 0, 1, 1)?;
+        region.region_discard(
+            &[crucible_protocol::Discard {
+                eid: 2,
+                offset: Block::new_512(0),
+                num_blocks: 1,
+            }],
+            2,
+        )?;
+        assert_eq!(
+            *region.dirty_extents.lock().unwrap(),
+            [0, 2].iter().copied().collect()
+        );
+
+        // Extent 1 was never touched, so it keeps its flush number.
+        region.region_flush(3, 1, &None, 3)?;
+        assert!(region.dirty_extents.lock().unwrap().is_empty());
+        assert_eq!(region.flush_numbers()?, vec![3, 0, 3]);
+        assert_eq!(region.dirty()?, vec![false, false, false]);
+
+        write_block(&region, 1, 2, 4)?;
+        region.region_flush(4, 1, &None, 5)?;
+        assert_eq!(region.flush_numbers()?, vec![4, 0, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_finds_dirty_extents() -> Result<()> {
+        on_each_io_backend(reopen_finds_dirty_extents_on)
+    }
+
+    fn reopen_finds_dirty_extents_on(io_backend: IoBackend) -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.set_io_backend(io_backend)?;
+        region.extend(2)?;
+
+        // Leave extent 0 dirty on disk, as a crash before a flush would.
+        write_block(&region, 0, 1, 1)?;
+        drop(region);
+
+        let mut region =
+            Region::open(&dir, new_region_options(), false, false)?;
+        region.set_io_backend(io_backend)?;
+        assert_eq!(
+            *region.dirty_extents.lock().unwrap(),
+            [0].iter().copied().collect()
+        );
+
+        region.region_flush(2, 1, &None, 2)?;
+        assert_eq!(region.flush_numbers()?, vec![2, 0]);
+        assert_eq!(region.dirty()?, vec![false, false]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn space_usage() -> Result<()> {
+        on_each_io_backend(space_usage_on)
+    }
+
+    fn space_usage_on(io_backend: IoBackend) -> Result<()> {
+        for region_options in
+            [new_region_options(), single_file_region_options()]
+        {
+            let dir = tempdir()?;
+            let mut region = Region::create(&dir, region_options)?;
+            region.set_io_backend(io_backend)?;
+            region.extend(3)?;
+
+            let usage = region.space_usage()?;
+            assert_eq!(usage.logical_bytes, region.def().total_size());
+            assert_eq!(usage.allocated_bytes, 0);
+            assert_eq!(usage.allocated_blocks, vec![0, 0, 0]);
+
+            write_block(&region, 0, 1, 1)?;
+            write_block(&region, 0, 2, 2)?;
+            write_block(&region, 3, 3, 3)?;
+            region.region_flush(4, 1, &None, 4)?;
+
+            let usage = region.space_usage()?;
+            assert_eq!(usage.allocated_bytes, 2 * 512);
+            assert_eq!(usage.allocated_blocks, vec![2, 0, 0]);
+            assert!(usage.physical_bytes > 0);
+
+            region.region_discard(
+                &[crucible_protocol::Discard {
+                    eid: 0,
+                    offset: Block::new_512(0),
+                    num_blocks: 1,
+                }],
+                5,
+            )?;
+            assert_eq!(region.space_usage()?.allocated_blocks, vec![1, 0, 0]);
+
+            // Nothing can be said about an extent in the middle of repair.
+            region.extents[1].close()?;
+            assert!(region.space_usage().is_err());
+        }
+
+        Ok(())
+    }
+
+    fn read_block(region: &Region, block: u64) -> Result<Vec<u8>> {
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(block),
+                num_blocks: 1,
+            }],
+            0,
+        )?;
+        Ok(responses[0].data.to_vec())
+    }
+
+    /*
+     * Whether the file system tests run on can reflink, which a snapshot
+     * without ZFS needs.
+     */
+    #[cfg(not(feature = "zfs_snapshot"))]
+    fn reflink_works() -> Result<bool> {
+        let dir = tempdir()?;
+        let from = File::create(dir.path().join("from"))?;
+        let to = File::create(dir.path().join("to"))?;
+        Ok(snapshot::reflink(&from, &to).is_ok())
+    }
+
+    #[test]
+    #[cfg(not(feature = "zfs_snapshot"))]
+    fn snapshot_without_zfs() -> Result<()> {
+        let name = "first".to_string();
+        let reflink_works = reflink_works()?;
+        for region_options in
+            [new_region_options(), single_file_region_options()]
+        {
+            let dir = tempdir()?;
+            let mut region = Region::create(&dir, region_options)?;
+            region.extend(3)?;
+
+            write_block(&region, 1, 1, 1)?;
+            let snapshot = Some(SnapshotDetails {
+                snapshot_name: name.clone(),
+            });
+
+            // Without reflink there is no snapshot, and nothing of one is
+            // left behind.
+            if !reflink_works {
+                match region.region_flush(2, 1, &snapshot, 2) {
+                    Err(CrucibleError::SnapshotFailed(_)) => {}
+                    x => panic!("snapshot taken without reflink: {:?}", x),
+                }
+                let snapshots = snapshot::snapshot_dir(&dir);
+                assert_eq!(std::fs::read_dir(snapshots)?.count(), 0);
+                continue;
+            }
+
+            region.region_flush(2, 1, &snapshot, 2)?;
+
+            // The same name can't be used twice.
+            write_block(&region, 1, 2, 3)?;
+            match region.region_flush(4, 1, &snapshot, 4) {
+                Err(CrucibleError::SnapshotExistsAlready(_)) => {}
+                x => panic!("snapshot taken twice: {:?}", x),
+            }
+
+            // The snapshot is a region of its own, and has what the region
+            // had when it was taken, without any SQLite journal.
+            let path = snapshot::snapshot_dir(&dir).join(&name);
+            assert!(!extent_dir(&path, 0)
+                .join(extent_file_name(0, ExtentType::DbWal))
+                .exists());
+            let snap = Region::open(&path, Default::default(), false, true)?;
+            assert_eq!(snap.def(), region.def());
+            assert_eq!(snap.flush_numbers()?, vec![2, 0, 0]);
+            assert_eq!(read_block(&snap, 1)?, vec![1; 512]);
+            assert_eq!(read_block(&region, 1)?, vec![2; 512]);
+
+            // A read only region can't take one.
+            let snapshot = Some(SnapshotDetails {
+                snapshot_name: "second".to_string(),
+            });
+            assert!(snap.region_flush(4, 1, &snapshot, 4).is_err());
+
+            // Nor can there be one with a name that is not a file name.
+            let snapshot = Some(SnapshotDetails {
+                snapshot_name: "../second".to_string(),
+            });
+            match region.region_flush(5, 1, &snapshot, 5) {
+                Err(CrucibleError::SnapshotFailed(_)) => {}
+                x => panic!("bad snapshot name taken: {:?}", x),
+            }
+
+            // Snapshots aren't part of the space the region uses.
+            let usage = region.space_usage()?;
+            assert!(usage.physical_bytes < disk_usage(&dir, None)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_empties_the_sqlite_journal() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(1)?;
+
+        let hash = write_block(&region, 1, 1, 1)?;
+        let wal =
+            extent_dir(&dir, 0).join(extent_file_name(0, ExtentType::DbWal));
+        assert!(std::fs::metadata(&wal)?.len() > 0);
+
+        region.extents[0].checkpoint()?;
+        assert_eq!(std::fs::metadata(&wal)?.len(), 0);
+
+        // The database alone has everything now.
+        let copy = tempdir()?;
+        let db = copy.path().join("db");
+        std::fs::copy(
+            extent_dir(&dir, 0).join(extent_file_name(0, ExtentType::Db)),
+            &db,
+        )?;
+        let mut meta = SqliteMetadata::open(&db)?;
+        assert_eq!(meta.get_hashes(1)?, vec![hash]);
+        assert!(meta.dirty()?);
+        meta.checkpoint()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn scrub_finds_corrupt_blocks() -> Result<()> {
+        on_each_io_backend(scrub_finds_corrupt_blocks_on)
+    }
+
+    fn scrub_finds_corrupt_blocks_on(io_backend: IoBackend) -> Result<()> {
+        for region_options in
+            [new_region_options(), single_file_region_options()]
+        {
+            let dir = tempdir()?;
+            let mut region = Region::create(&dir, region_options)?;
+            region.set_io_backend(io_backend)?;
+            region.extend(2)?;
+
+            for block in 0..4 {
+                write_block(&region, block, block as u8 + 1, block)?;
+            }
+            region.region_flush(5, 1, &None, 5)?;
+            assert!(region.scrub_blocks(0, Block::new_512(0), 10)?.is_empty());
+
+            // Rot one byte of block 2 behind the region's back.
+            let path = if region.def().layout() == RegionLayout::SingleFile {
+                region_data_path(&dir)
+            } else {
+                extent_path(&dir, 0)
+            };
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.write_all_at(&[0xff], 2 * 512 + 7)?;
+            file.sync_all()?;
+
+            assert_eq!(region.scrub_blocks(0, Block::new_512(0), 10)?, vec![2]);
+            assert_eq!(region.scrub_blocks(0, Block::new_512(2), 1)?, vec![2]);
+            assert!(region.scrub_blocks(1, Block::new_512(0), 10)?.is_empty());
+
+            // Once the block is written again, it is fine.
+            write_block(&region, 2, 9, 6)?;
+            assert!(region.scrub_blocks(0, Block::new_512(0), 10)?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn io_backend_from_str() {
+        for io_backend in [IoBackend::Std, IoBackend::IoUring] {
+            let parsed: IoBackend = io_backend.to_string().parse().unwrap();
+            assert_eq!(parsed, io_backend);
+        }
+        assert_eq!(
+            "io_uring".parse::<IoBackend>().unwrap(),
+            IoBackend::IoUring
+        );
+        assert!("aio".parse::<IoBackend>().is_err());
+    }
+
+    #[test]
+    fn switch_io_backend() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(2)?;
+
+        region.set_io_backend(IoBackend::Std)?;
+        let hash = write_block(&region, 0, 1, 1)?;
+        region.region_flush(2, 1, &None, 2)?;
+
+        /*
+         * Whatever the region ends up using, it must see what the other
+         * backend wrote.
+         */
+        let uring = region.set_io_backend(IoBackend::IoUring);
+        if IoBackend::IoUring.check_available().is_ok() {
+            uring?;
+            assert!(region
+                .extents
+                .iter()
+                .all(|extent| { extent.io_backend == IoBackend::IoUring }));
+        } else {
+            assert!(uring.is_err());
+            assert!(region
+                .extents
+                .iter()
+                .all(|extent| { extent.io_backend == IoBackend::Std }));
+        }
+        assert_eq!(block_hashes(&region, 0)?, vec![hash]);
+
+        let hash = write_block(&region, 1, 2, 3)?;
+        region.region_flush(4, 1, &None, 4)?;
+        region.set_io_backend(IoBackend::Std)?;
+        assert_eq!(block_hashes(&region, 1)?, vec![hash]);
+        assert_eq!(region.flush_numbers()?, vec![4, 0]);
+
+        Ok(())
+    }
 }
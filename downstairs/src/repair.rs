@@ -1,4 +1,5 @@
 // Copyright 2022 Oxide Computer Company
+use std::os::unix::fs::FileExt;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -17,13 +18,27 @@ use schemars::JsonSchema;
 use serde::Deserialize;
 
 use super::*;
-use crate::region::{extent_dir, extent_file_name, extent_path, ExtentType};
+use crate::metadata::SidecarMetadata;
+use crate::region::{
+    extent_dir, extent_file_name, extent_path, region_data_path,
+    region_meta_path, ExtentType,
+};
+use crucible_common::{RegionDefinition, RegionLayout};
 
 /**
- * Our context is the root of the region we want to serve.
+ * Our context is the root of the region we want to serve.  The region
+ * definition changes if the region grows, so we keep up with it rather
+ * than take a copy.
  */
 pub struct FileServerContext {
     region_dir: PathBuf,
+    region_def: watch::Receiver<RegionDefinition>,
+}
+
+impl FileServerContext {
+    fn region_def(&self) -> RegionDefinition {
+        *self.region_def.borrow()
+    }
 }
 
 pub fn write_openapi<W: Write>(f: &mut W) -> Result<()> {
@@ -74,10 +89,16 @@ pub async fn repair_main(
      * files live.
      */
     let ds = ds.lock().await;
-    let region_dir = ds.region.dir.clone();
+    let region = ds.region.read().await;
+    let region_dir = region.dir.clone();
+    let region_def = ds.region_def_tx.subscribe();
+    drop(region);
     drop(ds);
 
-    let context = FileServerContext { region_dir };
+    let context = FileServerContext {
+        region_dir,
+        region_def,
+    };
 
     println!("Repair listens on {}", addr);
     /*
@@ -111,6 +132,8 @@ pub enum FileType {
     DatabaseSharedMemory,
     #[serde(rename = "db_wal")]
     DatabaseLog,
+    #[serde(rename = "meta")]
+    Metadata,
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -130,6 +153,17 @@ async fn get_extent_file(
     let fs = path.into_inner();
     let eid = fs.eid;
 
+    let region_def = rqctx.context().region_def();
+    if region_def.layout() == RegionLayout::SingleFile {
+        let (path, offset, len) = region_file_range(
+            rqctx.context().region_dir.clone(),
+            &region_def,
+            eid,
+            fs.file_type,
+        )?;
+        return get_a_range(path, offset, len).await;
+    }
+
     let mut extent_path = extent_path(rqctx.context().region_dir.clone(), eid);
     match fs.file_type {
         FileType::Database => {
@@ -141,6 +175,9 @@ async fn get_extent_file(
         FileType::DatabaseLog => {
             extent_path.set_extension("db-shm");
         }
+        FileType::Metadata => {
+            extent_path.set_extension("meta");
+        }
         FileType::Data => (),
     };
 
@@ -187,6 +224,68 @@ async fn get_a_file(path: PathBuf) -> Result<Response<Body>, HttpError> {
     }
 }
 
+/**
+ * Find the part of a single file region's files that holds what the
+ * given extent file would, if the extent had its own.
+ */
+fn region_file_range(
+    region_dir: PathBuf,
+    region_def: &RegionDefinition,
+    eid: u32,
+    file_type: FileType,
+) -> Result<(PathBuf, u64, u64), HttpError> {
+    if eid >= region_def.extent_count() {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("No extent {}", eid),
+        ));
+    }
+
+    let bcount = region_def.extent_size().value;
+    let (path, len) = match file_type {
+        FileType::Data => (
+            region_data_path(region_dir),
+            region_def.block_size() * bcount,
+        ),
+        FileType::Metadata => {
+            (region_meta_path(region_dir), SidecarMetadata::size(bcount))
+        }
+        _ => {
+            return Err(HttpError::for_bad_request(
+                None,
+                "A single file region has no database".to_string(),
+            ));
+        }
+    };
+
+    Ok((path, len * eid as u64, len))
+}
+
+async fn get_a_range(
+    path: PathBuf,
+    offset: u64,
+    len: u64,
+) -> Result<Response<Body>, HttpError> {
+    println!("Request for {} bytes at {} of {:?}", len, offset, path);
+
+    let mut buf = vec![0u8; len as usize];
+    let read = std::fs::File::open(&path)
+        .and_then(|file| file.read_exact_at(&mut buf, offset));
+    if let Err(e) = read {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("file {:?}: {:#}", path, e),
+        ));
+    }
+
+    let content_type = "application/octet-stream".to_string();
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(Body::from(buf))?)
+}
+
 /**
  * Get the list of files related to an extent.
  *
@@ -202,6 +301,20 @@ async fn get_files_for_extent(
     path: Path<Eid>,
 ) -> Result<HttpResponseOk<Vec<String>>, HttpError> {
     let eid = path.into_inner().eid;
+
+    let region_def = rqctx.context().region_def();
+    if region_def.layout() == RegionLayout::SingleFile {
+        /*
+         * Every extent has the same two files, which are really parts of
+         * the region files.
+         */
+        region_file_range(PathBuf::new(), &region_def, eid, FileType::Data)?;
+        return Ok(HttpResponseOk(vec![
+            extent_file_name(eid, ExtentType::Data),
+            extent_file_name(eid, ExtentType::Meta),
+        ]));
+    }
+
     let extent_dir = extent_dir(rqctx.context().region_dir.clone(), eid);
 
     // Some sanity checking on the extent path
@@ -230,7 +343,8 @@ async fn get_files_for_extent(
 /**
  * Return the list of extent files we have in our region directory
  * that correspond to the given extent.  Return an error if any
- * of the required files are missing.
+ * of the required files are missing, or if there is no metadata for
+ * the extent in either of the places it could be.
  */
 async fn extent_file_list(
     extent_dir: PathBuf,
@@ -239,9 +353,10 @@ async fn extent_file_list(
     let mut files = Vec::new();
     let possible_files = vec![
         (extent_file_name(eid, ExtentType::Data), true),
-        (extent_file_name(eid, ExtentType::Db), true),
+        (extent_file_name(eid, ExtentType::Db), false),
         (extent_file_name(eid, ExtentType::DbShm), false),
         (extent_file_name(eid, ExtentType::DbWal), false),
+        (extent_file_name(eid, ExtentType::Meta), false),
     ];
 
     for (file, required) in possible_files.into_iter() {
@@ -255,6 +370,13 @@ async fn extent_file_list(
         }
     }
 
+    let db = extent_file_name(eid, ExtentType::Db);
+    let meta = extent_file_name(eid, ExtentType::Meta);
+    if !files.contains(&db) && !files.contains(&meta) {
+        println!("Needed file {} or {} is missing", db, meta);
+        return Err(HttpError::for_bad_request(None, "EBADF".to_string()));
+    }
+
     Ok(files)
 }
 
@@ -403,6 +525,63 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn extent_expected_files_sidecar() -> Result<()> {
+        // An extent that keeps its metadata in a sidecar has just the
+        // data and .meta files, and no database.
+        let dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_metadata_backend(MetadataBackend::Sidecar);
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(3)?;
+
+        let ed = extent_dir(&dir, 1);
+        let ex_files = extent_file_list(ed.clone(), 1).await.unwrap();
+        assert_eq!(ex_files, vec!["001", "001.meta"]);
+
+        // Without the sidecar there is no metadata at all.
+        let mut rm_file = ed.clone();
+        rm_file.push(extent_file_name(1, ExtentType::Meta));
+        std::fs::remove_file(&rm_file).unwrap();
+
+        assert!(extent_file_list(ed, 1).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_file_region_ranges() -> Result<()> {
+        // Each extent of a single file region is served from its part of
+        // the region files.
+        let dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_metadata_backend(MetadataBackend::Sidecar);
+        region_options.set_layout(RegionLayout::SingleFile);
+        let mut region = Region::create(&dir, region_options)?;
+        region.extend(3)?;
+        let def = region.def();
+        let rd = dir.path().to_path_buf();
+
+        let (path, offset, len) =
+            region_file_range(rd.clone(), &def, 2, FileType::Data).unwrap();
+        assert_eq!(path, region_data_path(&dir));
+        assert_eq!((offset, len), (2 * 512 * 10, 512 * 10));
+
+        let (path, offset, len) =
+            region_file_range(rd.clone(), &def, 1, FileType::Metadata).unwrap();
+        assert_eq!(path, region_meta_path(&dir));
+        let size = SidecarMetadata::size(10);
+        assert_eq!((offset, len), (size, size));
+
+        // There is no database, and no extent past the end.
+        assert!(
+            region_file_range(rd.clone(), &def, 1, FileType::Database).is_err()
+        );
+        assert!(region_file_range(rd, &def, 3, FileType::Data).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_crucible_repair_openapi() {
         let mut raw = Vec::new();
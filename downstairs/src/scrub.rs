@@ -0,0 +1,211 @@
+// Copyright 2022 Oxide Computer Company
+/*
+ * The scrubber walks every block of the region in the background, no
+ * faster than it is told to, and checks the data against the integrity
+ * hashes stored when it was written.  Otherwise a block that rots on
+ * disk is only found when a guest happens to read it.
+ *
+ * A block that fails the check is looked at again with the region held
+ * for writing, so no job can be part way through changing it.  Only if
+ * it fails then too is it reported, both here and in the stats.
+ */
+use std::collections::BTreeSet;
+use std::sync::Mutex as SyncMutex;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::*;
+
+/*
+ * How many blocks we read at once.  The region is only held for reading
+ * while we do, and jobs that want it for writing wait for us.
+ */
+const SCRUB_CHUNK_BLOCKS: u64 = 64;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ScrubReport {
+    /// Passes over the whole region finished since the downstairs started.
+    pub passes: u64,
+    /// Blocks checked, over every pass.
+    pub blocks_checked: u64,
+    /// Blocks that did not match their integrity hash the last time they
+    /// were checked.
+    pub corrupt_blocks: BTreeSet<CorruptBlock>,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+)]
+pub struct CorruptBlock {
+    pub eid: u32,
+    /// The block's offset in its extent.
+    pub block: u64,
+}
+
+/*
+ * Scrub the region forever, checking no more than blocks_per_sec blocks
+ * a second.
+ */
+pub async fn scrub_region(ads: Arc<Mutex<Downstairs>>, blocks_per_sec: u64) {
+    assert!(blocks_per_sec > 0);
+
+    let (region, report, mut dss) = {
+        let ds = ads.lock().await;
+        (ds.region.clone(), ds.scrub_report.clone(), ds.dss.clone())
+    };
+    println!("Scrubbing region at {} blocks a second", blocks_per_sec);
+
+    loop {
+        /*
+         * The region can grow, so look again at how big it is each pass.
+         */
+        let (_, extent_size, extent_count) = region.read().await.region_def();
+
+        for eid in 0..extent_count {
+            let mut offset = 0;
+            while offset < extent_size.value {
+                let started = Instant::now();
+                let num_blocks =
+                    SCRUB_CHUNK_BLOCKS.min(extent_size.value - offset);
+                let block = Block::new(offset, extent_size.shift);
+
+                /*
+                 * Reading and hashing the blocks is slow, blocking work,
+                 * so it happens off the async threads, like job IO.
+                 */
+                let held = region.clone().read_owned().await;
+                let corrupt = tokio::task::spawn_blocking(move || {
+                    held.scrub_blocks(eid, block, num_blocks)
+                })
+                .await
+                .expect("scrub task panicked");
+                let mut corrupt = match corrupt {
+                    Ok(corrupt) => corrupt,
+                    Err(e) => {
+                        /*
+                         * The extent is closed for repair, which will
+                         * leave it with all new blocks anyway.
+                         */
+                        println!("Scrub skipping extent {}: {}", eid, e);
+                        break;
+                    }
+                };
+
+                if !corrupt.is_empty() {
+                    let held = region.clone().write_owned().await;
+                    corrupt = tokio::task::spawn_blocking(move || {
+                        corrupt.retain(|b| {
+                            let block = Block::new(*b, extent_size.shift);
+                            match held.scrub_blocks(eid, block, 1) {
+                                Ok(still) => !still.is_empty(),
+                                Err(_) => false,
+                            }
+                        });
+                        corrupt
+                    })
+                    .await
+                    .expect("scrub task panicked");
+                }
+
+                let found = record_chunk(
+                    &report,
+                    eid,
+                    offset..offset + num_blocks,
+                    &corrupt,
+                );
+                dss.add_scrub(num_blocks, found).await;
+
+                offset += num_blocks;
+
+                let pause = Duration::from_secs_f64(
+                    num_blocks as f64 / blocks_per_sec as f64,
+                );
+                sleep_until(started + pause).await;
+            }
+        }
+
+        {
+            let mut r = report.lock().unwrap();
+            r.passes += 1;
+            println!(
+                "Scrub pass {} done, {} corrupt blocks",
+                r.passes,
+                r.corrupt_blocks.len()
+            );
+        }
+    }
+}
+
+/*
+ * Update the report with what we found checking a range of blocks of an
+ * extent, and return how many corrupt blocks we did not already know
+ * about.  A block that was corrupt and is no longer has been rewritten
+ * or repaired since.
+ */
+fn record_chunk(
+    report: &SyncMutex<ScrubReport>,
+    eid: u32,
+    blocks: std::ops::Range<u64>,
+    corrupt: &[u64],
+) -> u64 {
+    let mut report = report.lock().unwrap();
+    report.blocks_checked += blocks.end - blocks.start;
+
+    let in_chunk = |c: &CorruptBlock| c.eid == eid && blocks.contains(&c.block);
+    let known: BTreeSet<u64> = report
+        .corrupt_blocks
+        .iter()
+        .filter(|c| in_chunk(c))
+        .map(|c| c.block)
+        .collect();
+    report.corrupt_blocks.retain(|c| !in_chunk(c));
+
+    let mut found = 0;
+    for block in corrupt {
+        if !known.contains(block) {
+            println!("Scrub found extent {} block {} corrupt", eid, block);
+            found += 1;
+        }
+        report
+            .corrupt_blocks
+            .insert(CorruptBlock { eid, block: *block });
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_chunk_keeps_what_is_corrupt_now() {
+        let report = SyncMutex::new(ScrubReport::default());
+
+        assert_eq!(record_chunk(&report, 0, 0..64, &[3, 9]), 2);
+        assert_eq!(record_chunk(&report, 1, 0..64, &[3]), 1);
+
+        // Finding the same block again is not news, and a block that is
+        // no longer corrupt is dropped, but only from the chunk checked.
+        assert_eq!(record_chunk(&report, 0, 0..64, &[9, 10]), 1);
+
+        let report = report.into_inner().unwrap();
+        assert_eq!(report.blocks_checked, 192);
+        let corrupt: Vec<(u32, u64)> = report
+            .corrupt_blocks
+            .iter()
+            .map(|c| (c.eid, c.block))
+            .collect();
+        assert_eq!(corrupt, vec![(0, 9), (0, 10), (1, 3)]);
+    }
+}
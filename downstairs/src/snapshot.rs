@@ -0,0 +1,187 @@
+// Copyright 2022 Oxide Computer Company
+/*
+ * Snapshots of a region on a file system without ZFS.
+ *
+ * A snapshot is a copy of the region's files, made right after a flush,
+ * in snapshots/<name> under the region directory.  That copy is a region
+ * of its own, which a read only downstairs can serve.  The copy is a
+ * reflink, which shares blocks with the region until one side writes to
+ * them and takes no time to make no matter how big the region is.
+ *
+ * The copy is made inside the flush, while nothing can write to the
+ * region, so a file system that can't reflink gets no snapshots at all.
+ * Copying every file in full there would hold up every other job for as
+ * long as that takes.
+ *
+ * Nothing that walks the region directory should count what is under
+ * snapshots/ as part of the region.
+ */
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crucible_common::{crucible_bail, CrucibleError};
+use crucible_common::{RegionDefinition, RegionLayout};
+
+use crate::region::{
+    config_path, extent_dir, extent_file_name, region_data_path,
+    region_meta_path, ExtentType,
+};
+
+pub fn snapshot_dir<P: AsRef<Path>>(region_dir: P) -> PathBuf {
+    let mut out = region_dir.as_ref().to_path_buf();
+    out.push("snapshots");
+    out
+}
+
+/*
+ * Copy the region in region_dir to a snapshot with the given name.  The
+ * caller has just flushed and checkpointed every extent's metadata, and
+ * makes sure nothing writes to the region until we return.
+ */
+pub fn take_snapshot(
+    region_dir: &Path,
+    def: &RegionDefinition,
+    name: &str,
+) -> Result<(), CrucibleError> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        crucible_bail!(SnapshotFailed, "bad snapshot name {:?}", name);
+    }
+
+    let snapshots = snapshot_dir(region_dir);
+    let snapshot = snapshots.join(name);
+    if snapshot.exists() {
+        crucible_bail!(SnapshotExistsAlready, "{}", name);
+    }
+
+    /*
+     * Build the copy off to the side and rename it into place when it is
+     * all there, so a snapshot that exists is always a whole one.  What
+     * is off to the side can only be left over from a crash part way
+     * through an earlier attempt.
+     */
+    let partial = snapshots.join(format!(".{}.partial", name));
+    let result = remove_partial(&partial)
+        .and_then(|_| copy_region(region_dir, def, &partial))
+        .and_then(|_| fs::rename(&partial, &snapshot))
+        .and_then(|_| File::open(&snapshots)?.sync_all());
+
+    if let Err(e) = result {
+        let _ = remove_partial(&partial);
+        crucible_bail!(SnapshotFailed, "{}: {}", name, e);
+    }
+
+    println!("Snapshot {} of {:?} taken", name, region_dir);
+    Ok(())
+}
+
+fn remove_partial(partial: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(partial) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/*
+ * Copy every file of the region to the same place under dest.
+ */
+fn copy_region(
+    region_dir: &Path,
+    def: &RegionDefinition,
+    dest: &Path,
+) -> io::Result<()> {
+    /*
+     * Each file, and if it has to be there.  An extent can be without any
+     * of the files for the metadata backend it does not use.  SQLite's
+     * journal files are left behind, as the checkpoint has put everything
+     * in them into the database, and a copy of the live -shm file would
+     * not be one SQLite could trust.
+     */
+    let mut files = vec![(config_path(region_dir), true)];
+    if def.layout() == RegionLayout::SingleFile {
+        files.push((region_data_path(region_dir), true));
+        files.push((region_meta_path(region_dir), true));
+    } else {
+        for eid in 0..def.extent_count() {
+            let dir = extent_dir(region_dir, eid);
+            files.push((
+                dir.join(extent_file_name(eid, ExtentType::Data)),
+                true,
+            ));
+            for extent_type in [ExtentType::Db, ExtentType::Meta] {
+                files.push((
+                    dir.join(extent_file_name(eid, extent_type)),
+                    false,
+                ));
+            }
+        }
+    }
+
+    let mut dirs = BTreeSet::new();
+    dirs.insert(dest.to_path_buf());
+    for (src, required) in files {
+        if !required && !src.exists() {
+            continue;
+        }
+
+        let dst = dest.join(src.strip_prefix(region_dir).unwrap());
+        let parent = dst.parent().unwrap();
+        fs::create_dir_all(parent)?;
+        for dir in parent.ancestors().take_while(|d| d.starts_with(dest)) {
+            dirs.insert(dir.to_path_buf());
+        }
+
+        clone_file(&src, &dst)?;
+    }
+
+    /*
+     * The files are synced as they are copied, but the directories that
+     * name them have to be as well.
+     */
+    for dir in dirs {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+fn clone_file(src: &Path, dst: &Path) -> io::Result<()> {
+    let from = File::open(src)?;
+    let to = OpenOptions::new().write(true).create_new(true).open(dst)?;
+
+    if let Err(e) = reflink(&from, &to) {
+        return Err(io::Error::new(
+            e.kind(),
+            format!("can not reflink {:?}: {}", src, e),
+        ));
+    }
+
+    to.sync_all()
+}
+
+/*
+ * Make to share all of from's blocks, if the file system can.
+ */
+#[cfg(target_os = "linux")]
+pub(crate) fn reflink(from: &File, to: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    /*
+     * _IOW(0x94, 9, int), from linux/fs.h.
+     */
+    const FICLONE: u64 = 0x4004_9409;
+
+    let rc =
+        unsafe { libc::ioctl(to.as_raw_fd(), FICLONE as _, from.as_raw_fd()) };
+    if rc == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn reflink(_from: &File, _to: &File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
@@ -39,6 +39,18 @@ pub struct Flush {
     #[datum]
     pub count: Cumulative<i64>,
 }
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct Scrub {
+    // Count of blocks the scrubber has checked
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct Corrupt {
+    // Count of blocks the scrubber has found not to match their hash
+    #[datum]
+    pub count: Cumulative<i64>,
+}
 
 // All the counter stats in one struct.
 #[derive(Clone, Debug)]
@@ -48,6 +60,8 @@ pub struct DsCountStat {
     write_count: Write,
     read_count: Read,
     flush_count: Flush,
+    scrub_count: Scrub,
+    corrupt_count: Corrupt,
 }
 
 impl DsCountStat {
@@ -58,6 +72,8 @@ impl DsCountStat {
             write_count: Default::default(),
             read_count: Default::default(),
             flush_count: Default::default(),
+            scrub_count: Default::default(),
+            corrupt_count: Default::default(),
         }
     }
 }
@@ -95,6 +111,13 @@ impl DsStatOuter {
         let datum = dss.flush_count.datum_mut();
         *datum += 1;
     }
+    pub async fn add_scrub(&mut self, blocks: u64, corrupt: u64) {
+        let mut dss = self.ds_stat_wrap.lock().await;
+        let datum = dss.scrub_count.datum_mut();
+        *datum += blocks as i64;
+        let datum = dss.corrupt_count.datum_mut();
+        *datum += corrupt as i64;
+    }
 }
 
 // This trait is what is called to update the data to send to Oximeter.
@@ -108,13 +131,15 @@ impl Producer for DsStatOuter {
     ) -> Result<Box<dyn Iterator<Item = Sample> + 'static>, MetricsError> {
         let dss = executor::block_on(self.ds_stat_wrap.lock());
 
-        let mut data = Vec::with_capacity(4);
+        let mut data = Vec::with_capacity(6);
         let name = dss.stat_name;
 
         data.push(Sample::new(&name, &dss.up_connect_count));
         data.push(Sample::new(&name, &dss.flush_count));
         data.push(Sample::new(&name, &dss.write_count));
         data.push(Sample::new(&name, &dss.read_count));
+        data.push(Sample::new(&name, &dss.scrub_count));
+        data.push(Sample::new(&name, &dss.corrupt_count));
 
         // Yield the available samples.
         Ok(Box::new(data.into_iter()))
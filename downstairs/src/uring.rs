@@ -0,0 +1,307 @@
+// Copyright 2022 Oxide Computer Company
+/*
+ * Positioned reads, writes, and fsyncs of extent files through io_uring.
+ *
+ * A job hands us every operation it needs at once, and they go to the
+ * kernel in as few submissions as the ring allows.  Each thread doing IO
+ * has its own ring, so jobs running on different threads never wait on
+ * one another for it.
+ *
+ * Without the io_uring feature, or off Linux, the same functions are
+ * here but return an error, and IoBackend::IoUring is never selected.
+ */
+pub use imp::*;
+
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+mod imp {
+    use std::cell::RefCell;
+    use std::io;
+    use std::marker::PhantomData;
+    use std::os::unix::io::RawFd;
+
+    use io_uring::{opcode, squeue, types, IoUring};
+
+    /*
+     * How many operations we have in flight on a ring at once.  A job
+     * with more than this has them submitted in batches.
+     */
+    const RING_ENTRIES: u32 = 64;
+
+    /*
+     * IORING_ENTER_GETEVENTS, which the io-uring crate keeps to itself.
+     */
+    const ENTER_GETEVENTS: u32 = 1;
+
+    thread_local! {
+        static RING: RefCell<Option<IoUring>> = RefCell::new(None);
+    }
+
+    enum Kind {
+        Read(*mut u8),
+        Write(*const u8),
+        Fsync,
+    }
+
+    /*
+     * One operation on a file.  A read or write remembers where in its
+     * buffer it is up to, as the kernel is free to do less than we ask.
+     */
+    pub struct Op<'a> {
+        fd: RawFd,
+        offset: u64,
+        kind: Kind,
+        len: usize,
+        _buf: PhantomData<&'a mut [u8]>,
+    }
+
+    impl<'a> Op<'a> {
+        pub fn read(fd: RawFd, offset: u64, buf: &'a mut [u8]) -> Op<'a> {
+            Op {
+                fd,
+                offset,
+                kind: Kind::Read(buf.as_mut_ptr()),
+                len: buf.len(),
+                _buf: PhantomData,
+            }
+        }
+
+        pub fn write(fd: RawFd, offset: u64, buf: &'a [u8]) -> Op<'a> {
+            Op {
+                fd,
+                offset,
+                kind: Kind::Write(buf.as_ptr()),
+                len: buf.len(),
+                _buf: PhantomData,
+            }
+        }
+
+        pub fn fsync(fd: RawFd) -> Op<'a> {
+            Op {
+                fd,
+                offset: 0,
+                kind: Kind::Fsync,
+                len: 0,
+                _buf: PhantomData,
+            }
+        }
+
+        fn entry(&self) -> squeue::Entry {
+            let fd = types::Fd(self.fd);
+            let len = self.len.min(u32::MAX as usize) as u32;
+            match self.kind {
+                Kind::Read(buf) => opcode::Read::new(fd, buf, len)
+                    .offset(self.offset as _)
+                    .build(),
+                Kind::Write(buf) => opcode::Write::new(fd, buf, len)
+                    .offset(self.offset as _)
+                    .build(),
+                Kind::Fsync => opcode::Fsync::new(fd).build(),
+            }
+        }
+
+        /*
+         * Account for the kernel doing n bytes of this operation, and
+         * return true if there is nothing left to do.
+         */
+        fn advance(&mut self, n: usize) -> io::Result<bool> {
+            match &mut self.kind {
+                Kind::Fsync => return Ok(true),
+                Kind::Read(_) if n == 0 => {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Kind::Write(_) if n == 0 => {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                /*
+                 * Safety: n is no more than what we asked for, which is
+                 * no more than what is left of the buffer.
+                 */
+                Kind::Read(buf) => *buf = unsafe { buf.add(n) },
+                Kind::Write(buf) => *buf = unsafe { buf.add(n) },
+            }
+            self.offset += n as u64;
+            self.len -= n;
+            Ok(self.len == 0)
+        }
+    }
+
+    /*
+     * Make sure this thread can have a ring, so asking for the io_uring
+     * backend fails up front on a kernel that does not support it.
+     */
+    pub fn available() -> io::Result<()> {
+        with_ring(|_| Ok(()))
+    }
+
+    fn with_ring<T>(
+        f: impl FnOnce(&mut IoUring) -> io::Result<T>,
+    ) -> io::Result<T> {
+        RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            if ring.is_none() {
+                *ring = Some(IoUring::new(RING_ENTRIES)?);
+            }
+            let result = f(ring.as_mut().unwrap());
+            if result.is_err() {
+                /*
+                 * Don't trust a ring that something went wrong with to
+                 * have nothing of ours left on it.  The next user gets a
+                 * new one.
+                 */
+                *ring = None;
+            }
+            result
+        })
+    }
+
+    /*
+     * Do all the given operations, and return once every one is done.
+     * They can happen in any order, so a caller that needs one done
+     * before another has to submit them separately.
+     */
+    pub fn submit(ops: &mut [Op]) -> io::Result<()> {
+        with_ring(|ring| {
+            let mut pending: Vec<usize> = (0..ops.len()).collect();
+            let mut error = None;
+
+            while !pending.is_empty() && error.is_none() {
+                let count = pending.len().min(RING_ENTRIES as usize);
+                let batch: Vec<usize> = pending.drain(..count).collect();
+
+                for i in batch.iter() {
+                    let entry = ops[*i].entry().user_data(*i as u64);
+                    /*
+                     * Safety: the buffer behind this entry is borrowed
+                     * for as long as ops is, and we wait below for the
+                     * kernel to finish with every entry we push.
+                     */
+                    unsafe {
+                        ring.submission()
+                            .push(&entry)
+                            .expect("io_uring submission queue is full");
+                    }
+                }
+
+                let mut done = 0;
+                while done < batch.len() {
+                    match ring.submit_and_wait(batch.len() - done) {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                            continue;
+                        }
+                        Err(e) => {
+                            /*
+                             * Whatever the kernel took from the queue
+                             * still points at our buffers, so it all has
+                             * to finish before we can hand them back.
+                             */
+                            let outstanding =
+                                batch.len() - done - ring.submission().len();
+                            drain(ring, outstanding);
+                            return Err(e);
+                        }
+                    }
+
+                    for cqe in ring.completion() {
+                        done += 1;
+                        let i = cqe.user_data() as usize;
+                        if cqe.result() < 0 {
+                            let e = io::Error::from_raw_os_error(-cqe.result());
+                            match e.kind() {
+                                io::ErrorKind::Interrupted
+                                | io::ErrorKind::WouldBlock => pending.push(i),
+                                _ => {
+                                    error.get_or_insert(e);
+                                }
+                            }
+                            continue;
+                        }
+                        match ops[i].advance(cqe.result() as usize) {
+                            Ok(true) => {}
+                            Ok(false) => pending.push(i),
+                            Err(e) => {
+                                error.get_or_insert(e);
+                            }
+                        }
+                    }
+                }
+            }
+
+            match error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /*
+     * Reap completions, throwing them away, until n have come back.
+     */
+    fn drain(ring: &mut IoUring, mut n: usize) {
+        loop {
+            n -= ring.completion().count().min(n);
+            if n == 0 {
+                return;
+            }
+            /*
+             * Safety: we only wait here, with nothing to submit and no
+             * argument for the kernel to read.
+             */
+            let waited = unsafe {
+                ring.submitter()
+                    .enter::<()>(0, n as u32, ENTER_GETEVENTS, None)
+            };
+            match waited {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    /*
+                     * We can't return while the kernel may still write
+                     * into a buffer the caller is about to reuse.
+                     */
+                    panic!("cannot wait for io_uring completions: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io_uring")))]
+mod imp {
+    use std::io;
+    use std::marker::PhantomData;
+    use std::os::unix::io::RawFd;
+
+    pub struct Op<'a> {
+        _buf: PhantomData<&'a mut [u8]>,
+    }
+
+    impl<'a> Op<'a> {
+        pub fn read(_fd: RawFd, _offset: u64, _buf: &'a mut [u8]) -> Op<'a> {
+            Op { _buf: PhantomData }
+        }
+
+        pub fn write(_fd: RawFd, _offset: u64, _buf: &'a [u8]) -> Op<'a> {
+            Op { _buf: PhantomData }
+        }
+
+        pub fn fsync(_fd: RawFd) -> Op<'a> {
+            Op { _buf: PhantomData }
+        }
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "this downstairs was built without io_uring support",
+        )
+    }
+
+    pub fn available() -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn submit(_ops: &mut [Op]) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
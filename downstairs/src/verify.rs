@@ -0,0 +1,613 @@
+// Copyright 2022 Oxide Computer Company
+/*
+ * Check a region that no downstairs has open for anything that would
+ * stop one from opening it, or have it hand out bad data once it had.
+ *
+ * With repair, we also fix what can be fixed without the other
+ * downstairs: we finish or clean up after an extent repair that was cut
+ * short, and give a data file that is too short its missing blocks back
+ * as zeros.  A block that had data there is then found not to match its
+ * hash, like any other corrupt block.  Everything else is left for the
+ * upstairs to repair from the other downstairs, or for an operator.
+ */
+use std::fs::OpenOptions;
+
+use crucible_common::RegionOptions;
+use crucible_protocol::ReadRequest;
+use rusqlite::{Connection, OpenFlags};
+
+use super::*;
+use crate::metadata::SidecarMetadata;
+use crate::region::{
+    block_matches_hashes, completed_dir, config_path, copy_dir, extent_path,
+    move_replacement_extent, read_region_config, region_data_path,
+    region_meta_path, replace_dir, validate_repair_files, ExtentMeta,
+};
+
+/*
+ * How many blocks we read at once when checking them against their
+ * hashes.
+ */
+const VERIFY_CHUNK_BLOCKS: u64 = 64;
+
+struct Report {
+    repair: bool,
+    problems: u64,
+    fixed: u64,
+}
+
+impl Report {
+    fn problem(&mut self, what: String) {
+        println!("Problem: {}", what);
+        self.problems += 1;
+    }
+
+    /*
+     * Report a problem, and fix it if we were asked to.  Return true if
+     * the problem is gone.
+     */
+    fn fixable<F>(&mut self, what: String, fix: F) -> bool
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        if !self.repair {
+            self.problem(what);
+            return false;
+        }
+
+        match fix() {
+            Ok(()) => {
+                println!("Fixed: {}", what);
+                self.fixed += 1;
+                true
+            }
+            Err(e) => {
+                self.problem(format!("{} (repair failed: {})", what, e));
+                false
+            }
+        }
+    }
+}
+
+/*
+ * Verify the region in region_dir, and return an error if it has any
+ * problems left when we are done.
+ */
+pub fn verify_region(region_dir: PathBuf, repair: bool) -> Result<()> {
+    let mut report = Report {
+        repair,
+        problems: 0,
+        fixed: 0,
+    };
+
+    /*
+     * Without a good region config we don't know what else should be
+     * there, so there is no going on.
+     */
+    let def = check_config(&region_dir)?;
+    println!(
+        "Verifying region {:?}: {} extents of {} blocks of {} bytes",
+        region_dir,
+        def.extent_count(),
+        def.extent_size().value,
+        def.block_size(),
+    );
+
+    /*
+     * Opening an extent cleans up after an interrupted repair, so do this
+     * before we open anything.
+     */
+    for eid in 0..def.extent_count() {
+        check_repair_dirs(&mut report, &region_dir, &def, eid);
+    }
+
+    let mut files_ok = true;
+    if def.layout() == RegionLayout::SingleFile {
+        files_ok = check_region_files(&mut report, &region_dir, &def);
+    } else {
+        for eid in 0..def.extent_count() {
+            files_ok &= check_extent_files(&mut report, &region_dir, &def, eid);
+        }
+    }
+
+    /*
+     * We can only open the region to read its blocks once every file it
+     * needs is there.
+     */
+    if files_ok {
+        if let Err(e) = check_blocks(&mut report, &region_dir) {
+            report.problem(format!("reading blocks failed: {}", e));
+        }
+    } else {
+        println!("Not checking blocks until the region files are fixed");
+    }
+
+    if report.fixed > 0 {
+        println!("Fixed {} problems", report.fixed);
+    }
+    if report.problems > 0 {
+        bail!("Region {:?} has {} problems", region_dir, report.problems);
+    }
+
+    println!("Region {:?} is good", region_dir);
+    Ok(())
+}
+
+fn check_config(region_dir: &Path) -> Result<RegionDefinition> {
+    let cp = config_path(region_dir);
+    let def = match read_region_config(region_dir) {
+        Ok(def) => def,
+        Err(e) => bail!("Error {:?} reading region config {:?}", e, cp),
+    };
+
+    let mut options = RegionOptions::default();
+    options.set_block_size(def.block_size());
+    options.set_extent_size(def.extent_size());
+    options.set_uuid(def.uuid());
+    options.set_encrypted(def.get_encrypted());
+    options.set_metadata_backend(def.metadata_backend());
+    options.set_layout(def.layout());
+    if let Err(e) = options.validate() {
+        bail!("Region config {:?} is not valid: {}", cp, e);
+    }
+
+    if def.extent_size().block_size_in_bytes() as u64 != def.block_size() {
+        bail!(
+            "Region config {:?} has {} byte blocks, but extent size in {}",
+            cp,
+            def.block_size(),
+            def.extent_size().block_size_in_bytes(),
+        );
+    }
+
+    Ok(def)
+}
+
+/*
+ * A repair copies an extent's new files into its copy directory, renames
+ * that to the replace directory once they are all there, copies them
+ * over the extent's own files, and renames the replace directory to the
+ * completed directory before removing it.
+ */
+fn check_repair_dirs(
+    report: &mut Report,
+    region_dir: &Path,
+    def: &RegionDefinition,
+    eid: u32,
+) {
+    /*
+     * A repair that did not get as far as a replace directory never
+     * touched the extent, and one that got past it is done.
+     */
+    for dir in [copy_dir(region_dir, eid), completed_dir(region_dir, eid)] {
+        if dir.exists() {
+            report.fixable(
+                format!("extent {} has leftover repair dir {:?}", eid, dir),
+                || Ok(std::fs::remove_dir_all(&dir)?),
+            );
+        }
+    }
+
+    /*
+     * One that stopped with a replace directory may have been part way
+     * through copying, so the extent could be neither old nor new.
+     * Copying it all again finishes the job.
+     */
+    let rd = replace_dir(region_dir, eid);
+    if rd.exists() {
+        report.fixable(
+            format!("extent {} has an unfinished repair in {:?}", eid, rd),
+            || {
+                let cd = completed_dir(region_dir, eid);
+                if cd.exists() {
+                    bail!("{:?} is in the way", cd);
+                }
+
+                let mut files = Vec::new();
+                for entry in std::fs::read_dir(&rd)? {
+                    files.push(entry?.file_name().to_string_lossy().into());
+                }
+                files.sort();
+                if !validate_repair_files(eid as usize, &files) {
+                    bail!("{:?} does not hold a whole extent", rd);
+                }
+
+                move_replacement_extent(region_dir, eid as usize, def)?;
+                Ok(())
+            },
+        );
+    }
+}
+
+/*
+ * Check the data file and metadata of one extent in a region with a file
+ * per extent.  Return true if they are good enough to open.
+ */
+fn check_extent_files(
+    report: &mut Report,
+    region_dir: &Path,
+    def: &RegionDefinition,
+    eid: u32,
+) -> bool {
+    let blocks = def.extent_size().value;
+    let path = extent_path(region_dir, eid);
+
+    let mut ok = check_data_file(
+        report,
+        &format!("extent {}", eid),
+        &path,
+        def.block_size() * blocks,
+        true,
+    );
+
+    /*
+     * Go by the metadata that is there, like opening the extent does.
+     */
+    let mut meta = path.clone();
+    meta.set_extension("meta");
+    let mut db = path;
+    db.set_extension("db");
+    if meta.exists() {
+        if let Err(e) = SidecarMetadata::open(&meta, blocks, true) {
+            report.problem(format!("extent {} metadata: {}", eid, e));
+            ok = false;
+        }
+    } else if db.exists() {
+        let problems = match sqlite_problems(&db, blocks) {
+            Ok(problems) => problems,
+            Err(e) => vec![e.to_string()],
+        };
+        for p in problems.iter() {
+            report.problem(format!("extent {} metadata {:?}: {}", eid, db, p));
+        }
+        ok &= problems.is_empty();
+    } else {
+        report.problem(format!("extent {} has no metadata", eid));
+        ok = false;
+    }
+
+    ok
+}
+
+/*
+ * Check the data and metadata files that hold every extent of a single
+ * file region.  Return true if they are good enough to open.
+ */
+fn check_region_files(
+    report: &mut Report,
+    region_dir: &Path,
+    def: &RegionDefinition,
+) -> bool {
+    let blocks = def.extent_size().value;
+    let count = def.extent_count() as u64;
+
+    /*
+     * Growing the region makes the files bigger before the region config
+     * says it has the new extents, so bigger files are fine.
+     */
+    let mut ok = check_data_file(
+        report,
+        "region",
+        &region_data_path(region_dir),
+        def.block_size() * blocks * count,
+        false,
+    );
+
+    let path = region_meta_path(region_dir);
+    let file = match OpenOptions::new().read(true).open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            report.problem(format!("region metadata {:?}: {}", path, e));
+            return false;
+        }
+    };
+
+    for eid in 0..def.extent_count() {
+        let base = SidecarMetadata::size(blocks) * eid as u64;
+        let meta = file
+            .try_clone()
+            .map_err(anyhow::Error::from)
+            .and_then(|file| SidecarMetadata::open_in(file, base, blocks));
+        if let Err(e) = meta {
+            report.problem(format!("extent {} metadata: {}", eid, e));
+            ok = false;
+        }
+    }
+
+    ok
+}
+
+/*
+ * Check a data file is there and the size it should be.  One that is
+ * short can be fixed by putting zeros where the missing blocks were.
+ */
+fn check_data_file(
+    report: &mut Report,
+    what: &str,
+    path: &Path,
+    size: u64,
+    exact: bool,
+) -> bool {
+    let len = match std::fs::metadata(path) {
+        Ok(m) => m.len(),
+        Err(e) => {
+            report.problem(format!("{} data file {:?}: {}", what, path, e));
+            return false;
+        }
+    };
+
+    if len < size {
+        report.fixable(
+            format!(
+                "{} data file {:?} is {} bytes, short of {}",
+                what, path, len, size
+            ),
+            || {
+                let file = OpenOptions::new().write(true).open(path)?;
+                file.set_len(size)?;
+                file.sync_all()?;
+                Ok(())
+            },
+        )
+    } else if exact && len > size {
+        report.problem(format!(
+            "{} data file {:?} is {} bytes, more than {}",
+            what, path, len, size
+        ));
+        false
+    } else {
+        true
+    }
+}
+
+/*
+ * Look over an extent's SQLite metadata, and return what is wrong with
+ * it.
+ */
+fn sqlite_problems(path: &Path, blocks: u64) -> Result<Vec<String>> {
+    /*
+     * Opening without create, so a missing file is an error and not a
+     * new empty database.
+     */
+    let db =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let mut problems = Vec::new();
+
+    let check: String =
+        db.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+    if check != "ok" {
+        problems.push(format!("quick_check: {}", check));
+    }
+
+    for table in ["metadata", "encryption_context", "integrity_hashes"] {
+        let found: u32 = db.query_row(
+            "SELECT count(*) FROM sqlite_master
+            WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get(0),
+        )?;
+        if found == 0 {
+            problems.push(format!("no {} table", table));
+        }
+    }
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+
+    for name in ["ext_version", "gen_number", "flush_number", "dirty"] {
+        let values: Vec<i64> = db
+            .prepare("SELECT value FROM metadata WHERE name = ?1")?
+            .query_map([name], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        match values[..] {
+            [] => problems.push(format!("no {} in metadata", name)),
+            [value] if name == "ext_version" => {
+                let expected = ExtentMeta::default().ext_version as i64;
+                if value != expected {
+                    problems.push(format!(
+                        "ext_version is {}, expected {}",
+                        value, expected
+                    ));
+                }
+            }
+            [value] if value < 0 => {
+                problems.push(format!("{} is {}", name, value));
+            }
+            [_] => {}
+            _ => problems.push(format!("{} rows for {}", values.len(), name)),
+        }
+    }
+
+    for table in ["encryption_context", "integrity_hashes"] {
+        let outside: u64 = db.query_row(
+            &format!(
+                "SELECT count(*) FROM {} WHERE block < 0 OR block >= ?1",
+                table
+            ),
+            [blocks],
+            |row| row.get(0),
+        )?;
+        if outside > 0 {
+            problems.push(format!(
+                "{} {} rows for blocks past the end of the extent",
+                outside, table
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+/*
+ * Read every block of the region, and check it against the hashes
+ * stored for it.  A block with no hashes has never been written, or was
+ * discarded, and should read back as zeros.
+ */
+fn check_blocks(report: &mut Report, region_dir: &Path) -> Result<()> {
+    let region = Region::open(region_dir, Default::default(), false, true)?;
+    let (block_size, extent_size, extent_count) = region.region_def();
+    let block_size = block_size as usize;
+
+    let mut written = 0;
+    for eid in 0..extent_count {
+        let mut offset = 0;
+        while offset < extent_size.value {
+            let num_blocks =
+                VERIFY_CHUNK_BLOCKS.min(extent_size.value - offset);
+            let responses = region.region_read(
+                &[ReadRequest {
+                    eid: eid as u64,
+                    offset: Block::new(offset, extent_size.shift),
+                    num_blocks,
+                }],
+                0,
+            )?;
+            let response = &responses[0];
+
+            for (i, block) in response.blocks.iter().enumerate() {
+                let data = &response.data[i * block_size..(i + 1) * block_size];
+                let n = offset + i as u64;
+
+                if block.hashes.is_empty() {
+                    if data.iter().any(|b| *b != 0) {
+                        report.problem(format!(
+                            "extent {} block {} has data but no hash",
+                            eid, n
+                        ));
+                    }
+                    continue;
+                }
+
+                written += 1;
+                if region.encrypted() && block.encryption_contexts.is_empty() {
+                    report.problem(format!(
+                        "extent {} block {} has no encryption context",
+                        eid, n
+                    ));
+                } else if !block_matches_hashes(block, data) {
+                    report.problem(format!(
+                        "extent {} block {} does not match its hash",
+                        eid, n
+                    ));
+                }
+            }
+
+            offset += num_blocks;
+        }
+    }
+
+    println!(
+        "Checked {} blocks, {} of them written",
+        extent_count as u64 * extent_size.value,
+        written
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    use crucible_common::MetadataBackend;
+    use std::os::unix::fs::FileExt;
+    use tempfile::tempdir;
+
+    fn region_options(layout: RegionLayout) -> RegionOptions {
+        let mut region_options = RegionOptions::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(10));
+        region_options.set_uuid(Uuid::new_v4());
+        if layout == RegionLayout::SingleFile {
+            region_options.set_metadata_backend(MetadataBackend::Sidecar);
+            region_options.set_layout(layout);
+        }
+        region_options
+    }
+
+    fn write_blocks(region: &Region, eid: u64, count: u64) -> Result<()> {
+        for block in 0..count {
+            let data = Bytes::from(vec![block as u8 + 1; 512]);
+            let hash = integrity_hash(&[&data[..]]);
+            region.region_write(
+                &[crucible_protocol::Write {
+                    eid,
+                    offset: Block::new_512(block),
+                    data,
+                    encryption_context: None,
+                    hash,
+                }],
+                block,
+                false,
+            )?;
+        }
+        region.region_flush(1, 1, &None, count)?;
+        Ok(())
+    }
+
+    #[test]
+    fn verify_good_region() -> Result<()> {
+        for layout in [RegionLayout::PerExtent, RegionLayout::SingleFile] {
+            let dir = tempdir()?;
+            let mut region = Region::create(&dir, region_options(layout))?;
+            region.extend(3)?;
+            write_blocks(&region, 1, 4)?;
+            drop(region);
+
+            verify_region(dir.path().to_path_buf(), false)?;
+
+            // Rot one byte of a written block behind the region's back.
+            let (path, offset) = if layout == RegionLayout::SingleFile {
+                (region_data_path(&dir), 10 * 512 + 2 * 512 + 7)
+            } else {
+                (extent_path(&dir, 1), 2 * 512 + 7)
+            };
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.write_all_at(&[0xff], offset)?;
+            file.sync_all()?;
+
+            assert!(verify_region(dir.path().to_path_buf(), false).is_err());
+            assert!(verify_region(dir.path().to_path_buf(), true).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_repairs_what_it_can() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region =
+            Region::create(&dir, region_options(RegionLayout::PerExtent))?;
+        region.extend(3)?;
+        write_blocks(&region, 0, 4)?;
+        drop(region);
+
+        // A repair of extent 1 that never got going, and an extent 2 data
+        // file that has lost its unwritten tail.
+        let cp = copy_dir(&dir, 1);
+        std::fs::create_dir_all(&cp)?;
+        let path = extent_path(&dir, 2);
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(5 * 512)?;
+
+        // Without repair we only look.
+        assert!(verify_region(dir.path().to_path_buf(), false).is_err());
+        assert!(cp.exists());
+        assert_eq!(std::fs::metadata(&path)?.len(), 5 * 512);
+
+        verify_region(dir.path().to_path_buf(), true)?;
+        assert!(!cp.exists());
+        assert_eq!(std::fs::metadata(&path)?.len(), 10 * 512);
+
+        verify_region(dir.path().to_path_buf(), false)?;
+
+        // Missing metadata is beyond repair here.
+        let mut db = extent_path(&dir, 1);
+        db.set_extension("db");
+        std::fs::remove_file(&db)?;
+        assert!(verify_region(dir.path().to_path_buf(), true).is_err());
+
+        Ok(())
+    }
+}
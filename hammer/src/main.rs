@@ -90,6 +90,7 @@ fn main() -> Result<()> {
         root_cert_pem: opt.root_cert_pem,
         control: opt.control,
         read_only: false,
+        capture_dir: None,
     };
     let mut generation_number = opt.gen;
 
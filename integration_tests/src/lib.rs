@@ -38,6 +38,8 @@ mod test {
                 2, /* extent_count */
                 Uuid::new_v4(),
                 encrypted,
+                MetadataBackend::Sqlite,
+                RegionLayout::PerExtent,
             )?;
 
             let downstairs = build_downstairs_for_region(
@@ -45,6 +47,9 @@ mod test {
                 false, /* lossy */
                 false, /* return_errors */
                 read_only,
+                Compression::None,
+                None, /* capture_dir */
+                IoBackend::Std,
             )?;
 
             let adownstairs = downstairs.clone();
@@ -103,6 +108,7 @@ mod test {
             root_cert_pem: None,
             control: None,
             read_only,
+            capture_dir: None,
         };
         Ok(co)
     }
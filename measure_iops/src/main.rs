@@ -81,6 +81,7 @@ fn main() -> Result<()> {
         root_cert_pem: opt.root_cert_pem,
         control: None,
         read_only: false,
+        capture_dir: None,
     };
 
     /*
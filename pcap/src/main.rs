@@ -0,0 +1,442 @@
+// Copyright 2022 Oxide Computer Company
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use clap::Parser;
+use tokio_util::codec::Decoder;
+
+use crucible_common::CrucibleError;
+use crucible_protocol::*;
+
+mod pcap;
+mod tcp;
+
+use pcap::PacketReader;
+use tcp::{parse_segment, Reassembler, StreamEvent};
+
+#[derive(Debug, Parser)]
+#[clap(about = "decode crucible messages from a pcap or pcapng file")]
+pub struct Opt {
+    #[clap(name = "FILE", action)]
+    file: PathBuf,
+
+    /// Downstairs ports to decode traffic on.  Specify this option
+    /// multiple times for more than one.
+    #[clap(short, long, default_value = "9000", action)]
+    port: Vec<u16>,
+
+    /// Print messages in full, block data and all.
+    #[clap(long, action)]
+    full: bool,
+}
+
+fn deps(dependencies: &[u64]) -> String {
+    format!("{:?}", dependencies)
+}
+
+fn result<T>(result: &Result<T, CrucibleError>) -> String {
+    match result {
+        Ok(_) => "ok".to_string(),
+        Err(e) => format!("err {:?}", e),
+    }
+}
+
+fn read_responses(
+    name: &str,
+    job_id: u64,
+    responses: &Result<Vec<ReadResponse>, CrucibleError>,
+) -> String {
+    match responses {
+        Ok(responses) => {
+            let responses: Vec<String> = responses
+                .iter()
+                .map(|r| {
+                    let hashes: Vec<String> = r
+                        .blocks
+                        .iter()
+                        .map(|b| {
+                            let h: Vec<String> = b
+                                .hashes
+                                .iter()
+                                .map(|h| format!("{:016x}", h))
+                                .collect();
+                            format!("[{}]", h.join(" "))
+                        })
+                        .collect();
+                    format!(
+                        "eid {} block {} len {} hashes {}",
+                        r.eid,
+                        r.offset.value,
+                        r.data.len(),
+                        hashes.join(""),
+                    )
+                })
+                .collect();
+            format!("{} job {} [{}]", name, job_id, responses.join(", "))
+        }
+        Err(e) => format!("{} job {} err {:?}", name, job_id, e),
+    }
+}
+
+/*
+ * One line for a message, with what you'd want to know about the IO in
+ * it and none of the block data.
+ */
+fn summarize(m: &Message) -> String {
+    match m {
+        Message::Write {
+            job_id,
+            dependencies,
+            writes,
+            ..
+        }
+        | Message::WriteUnwritten {
+            job_id,
+            dependencies,
+            writes,
+            ..
+        } => {
+            let name = if matches!(m, Message::Write { .. }) {
+                "Write"
+            } else {
+                "WriteUnwritten"
+            };
+            let writes: Vec<String> = writes
+                .iter()
+                .map(|w| {
+                    format!(
+                        "eid {} block {} len {} hash {:016x}{}",
+                        w.eid,
+                        w.offset.value,
+                        w.data.len(),
+                        w.hash,
+                        if w.encryption_context.is_some() {
+                            " encrypted"
+                        } else {
+                            ""
+                        },
+                    )
+                })
+                .collect();
+            format!(
+                "{} job {} deps {} [{}]",
+                name,
+                job_id,
+                deps(dependencies),
+                writes.join(", ")
+            )
+        }
+        Message::ReadRequest {
+            job_id,
+            dependencies,
+            requests,
+            ..
+        } => {
+            let requests: Vec<String> = requests
+                .iter()
+                .map(|r| format!("eid {} block {}", r.eid, r.offset.value))
+                .collect();
+            format!(
+                "ReadRequest job {} deps {} [{}]",
+                job_id,
+                deps(dependencies),
+                requests.join(", ")
+            )
+        }
+        Message::ReadRangeRequest {
+            job_id,
+            dependencies,
+            requests,
+            ..
+        } => {
+            let requests: Vec<String> = requests
+                .iter()
+                .map(|r| {
+                    format!(
+                        "eid {} block {} count {}",
+                        r.eid, r.offset.value, r.num_blocks
+                    )
+                })
+                .collect();
+            format!(
+                "ReadRangeRequest job {} deps {} [{}]",
+                job_id,
+                deps(dependencies),
+                requests.join(", ")
+            )
+        }
+        Message::ReadResponse {
+            job_id, responses, ..
+        } => read_responses(
+            "ReadResponse",
+            *job_id,
+            &responses.clone().map(|responses| {
+                responses.into_iter().map(ReadResponse::from).collect()
+            }),
+        ),
+        Message::ReadRangeResponse {
+            job_id, responses, ..
+        } => read_responses("ReadRangeResponse", *job_id, responses),
+        Message::Flush {
+            job_id,
+            dependencies,
+            flush_number,
+            gen_number,
+            snapshot_details,
+            ..
+        } => format!(
+            "Flush job {} deps {} flush {} gen {}{}",
+            job_id,
+            deps(dependencies),
+            flush_number,
+            gen_number,
+            match snapshot_details {
+                Some(s) => format!(" snapshot {}", s.snapshot_name),
+                None => String::new(),
+            }
+        ),
+        Message::Discard {
+            job_id,
+            dependencies,
+            discards,
+            ..
+        } => {
+            let discards: Vec<String> = discards
+                .iter()
+                .map(|d| {
+                    format!(
+                        "eid {} block {} count {}",
+                        d.eid, d.offset.value, d.num_blocks
+                    )
+                })
+                .collect();
+            format!(
+                "Discard job {} deps {} [{}]",
+                job_id,
+                deps(dependencies),
+                discards.join(", ")
+            )
+        }
+        Message::WriteZeroes {
+            job_id,
+            dependencies,
+            zeroes,
+            ..
+        } => {
+            let zeroes: Vec<String> = zeroes
+                .iter()
+                .map(|z| {
+                    format!(
+                        "eid {} block {} count {}",
+                        z.eid, z.offset.value, z.num_blocks
+                    )
+                })
+                .collect();
+            format!(
+                "WriteZeroes job {} deps {} [{}]",
+                job_id,
+                deps(dependencies),
+                zeroes.join(", ")
+            )
+        }
+        Message::CompareAndWrite {
+            job_id,
+            dependencies,
+            writes,
+            ..
+        } => {
+            let writes: Vec<String> = writes
+                .iter()
+                .map(|w| {
+                    format!(
+                        "eid {} block {} len {} hash {:016x}",
+                        w.eid,
+                        w.offset.value,
+                        w.data.len(),
+                        w.hash,
+                    )
+                })
+                .collect();
+            format!(
+                "CompareAndWrite job {} deps {} [{}]",
+                job_id,
+                deps(dependencies),
+                writes.join(", ")
+            )
+        }
+        Message::WriteAck {
+            job_id, result: r, ..
+        } => {
+            format!("WriteAck job {} {}", job_id, result(r))
+        }
+        Message::WriteUnwrittenAck {
+            job_id, result: r, ..
+        } => {
+            format!("WriteUnwrittenAck job {} {}", job_id, result(r))
+        }
+        Message::FlushAck {
+            job_id, result: r, ..
+        } => {
+            format!("FlushAck job {} {}", job_id, result(r))
+        }
+        Message::DiscardAck {
+            job_id, result: r, ..
+        } => {
+            format!("DiscardAck job {} {}", job_id, result(r))
+        }
+        Message::WriteZeroesAck {
+            job_id, result: r, ..
+        } => {
+            format!("WriteZeroesAck job {} {}", job_id, result(r))
+        }
+        Message::CompareAndWriteAck {
+            job_id, result: r, ..
+        } => {
+            format!("CompareAndWriteAck job {} {}", job_id, result(r))
+        }
+        Message::ExtentVersions {
+            gen_numbers,
+            flush_numbers,
+            dirty_bits,
+        } => format!(
+            "ExtentVersions {} extents gen {:?} flush {:?} dirty {:?}",
+            gen_numbers.len(),
+            gen_numbers,
+            flush_numbers,
+            dirty_bits
+        ),
+        Message::SpaceUsage { result: Ok(usage) } => format!(
+            "SpaceUsage logical {} allocated {} physical {}",
+            usage.logical_bytes, usage.allocated_bytes, usage.physical_bytes
+        ),
+        Message::Unknown(len, _) => format!("Unknown {} bytes", len),
+        _ => format!("{:?}", m),
+    }
+}
+
+/*
+ * Decoding state for one direction of one connection.
+ */
+struct Stream {
+    buf: BytesMut,
+    decoder: CrucibleDecoder,
+
+    /*
+     * Once a frame fails to decode, we no longer know where the next one
+     * starts, so stop trying.
+     */
+    lost: bool,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::parse();
+
+    let reader = PacketReader::new(BufReader::new(File::open(&opt.file)?))?;
+    let mut reassembler = Reassembler::new();
+    let mut streams: HashMap<(SocketAddr, SocketAddr), Stream> = HashMap::new();
+    let mut first = None;
+
+    for packet in reader {
+        let packet = packet?;
+        let segment = match parse_segment(&packet) {
+            Some(segment) => segment,
+            None => continue,
+        };
+
+        if !opt.port.contains(&segment.src.port())
+            && !opt.port.contains(&segment.dst.port())
+        {
+            continue;
+        }
+
+        let start = *first.get_or_insert(packet.time);
+        let time = packet.time.saturating_sub(start) as f64 / 1e9;
+        let key = (segment.src, segment.dst);
+        let direction = if opt.port.contains(&segment.dst.port()) {
+            "up->ds"
+        } else {
+            "ds->up"
+        };
+
+        for event in reassembler.add(&segment) {
+            match event {
+                StreamEvent::Start { from_syn } => {
+                    if !from_syn {
+                        println!(
+                            "{:>12.6} {} -> {} joined part way through, \
+                            may not find the first frame",
+                            time, segment.src, segment.dst
+                        );
+                    }
+                    streams.insert(
+                        key,
+                        Stream {
+                            buf: BytesMut::new(),
+                            decoder: CrucibleDecoder::new(),
+                            lost: false,
+                        },
+                    );
+                }
+                StreamEvent::Data(data) => {
+                    let stream = streams.get_mut(&key).unwrap();
+                    if stream.lost {
+                        continue;
+                    }
+                    stream.buf.extend_from_slice(&data);
+
+                    loop {
+                        match stream.decoder.decode(&mut stream.buf) {
+                            Ok(Some(m)) => {
+                                println!(
+                                    "{:>12.6} {} -> {} {} {}",
+                                    time,
+                                    segment.src,
+                                    segment.dst,
+                                    direction,
+                                    if opt.full {
+                                        format!("{:?}", m)
+                                    } else {
+                                        summarize(&m)
+                                    },
+                                );
+                            }
+                            Ok(None) => break,
+                            Err(e) => {
+                                println!(
+                                    "{:>12.6} {} -> {} can't decode, \
+                                    giving up on this stream: {:?}",
+                                    time, segment.src, segment.dst, e
+                                );
+                                stream.lost = true;
+                                stream.buf.clear();
+                                break;
+                            }
+                        }
+                    }
+                }
+                StreamEvent::End => {
+                    if let Some(stream) = streams.remove(&key) {
+                        if !stream.buf.is_empty() && !stream.lost {
+                            println!(
+                                "{:>12.6} {} -> {} closed with {} bytes of \
+                                a frame left over",
+                                time,
+                                segment.src,
+                                segment.dst,
+                                stream.buf.len()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
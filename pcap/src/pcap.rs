@@ -0,0 +1,449 @@
+// Copyright 2022 Oxide Computer Company
+use std::io::{ErrorKind, Read};
+
+use anyhow::{bail, Result};
+
+/*
+ * Just enough of the pcap and pcapng file formats to get at the packets
+ * inside, along with when they were seen and what link layer they start
+ * with.
+ */
+pub const LINKTYPE_NULL: u32 = 0;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+pub const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const PCAP_MAGIC_USEC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b23c4d;
+const PCAPNG_SHB: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const PCAPNG_IDB: u32 = 0x00000001;
+const PCAPNG_SPB: u32 = 0x00000003;
+const PCAPNG_EPB: u32 = 0x00000006;
+
+/*
+ * Nothing we want comes anywhere near this big.  Anything bigger means the
+ * file is damaged, not that we should allocate that much.
+ */
+const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    /// Nanoseconds since the UNIX epoch.
+    pub time: u64,
+    pub linktype: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    linktype: u32,
+    /// Timestamp units per second.
+    resolution: u64,
+}
+
+enum Format {
+    Pcap {
+        big_endian: bool,
+        resolution: u64,
+        linktype: u32,
+    },
+    PcapNg {
+        big_endian: bool,
+        interfaces: Vec<Interface>,
+    },
+}
+
+pub struct PacketReader<R: Read> {
+    src: R,
+    format: Format,
+}
+
+fn u16_at(b: &[u8], big_endian: bool) -> u16 {
+    let v = [b[0], b[1]];
+    if big_endian {
+        u16::from_be_bytes(v)
+    } else {
+        u16::from_le_bytes(v)
+    }
+}
+
+fn u32_at(b: &[u8], big_endian: bool) -> u32 {
+    let v = [b[0], b[1], b[2], b[3]];
+    if big_endian {
+        u32::from_be_bytes(v)
+    } else {
+        u32::from_le_bytes(v)
+    }
+}
+
+fn to_nanos(ts: u64, resolution: u64) -> u64 {
+    let secs = ts / resolution;
+    let frac = ts % resolution;
+    secs * 1_000_000_000 + frac * 1_000_000_000 / resolution
+}
+
+/*
+ * Fill buf, or return false if the file ends cleanly before the first
+ * byte of it.
+ */
+fn read_or_eof<R: Read>(src: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut got = 0;
+    while got < buf.len() {
+        match src.read(&mut buf[got..]) {
+            Ok(0) if got == 0 => return Ok(false),
+            Ok(0) => bail!("file ends part way through a record"),
+            Ok(n) => got += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn read_body<R: Read>(src: &mut R, len: usize) -> Result<Vec<u8>> {
+    if len > MAX_RECORD_LEN {
+        bail!("record of {} bytes, the file must be damaged", len);
+    }
+    let mut body = vec![0u8; len];
+    src.read_exact(&mut body)?;
+    Ok(body)
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(mut src: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        src.read_exact(&mut magic)?;
+
+        let format = if u32::from_le_bytes(magic) == PCAPNG_SHB {
+            PacketReader::read_shb(&mut src)?
+        } else {
+            let (big_endian, resolution) =
+                match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                    (PCAP_MAGIC_USEC, _) => (false, 1_000_000),
+                    (PCAP_MAGIC_NSEC, _) => (false, 1_000_000_000),
+                    (_, PCAP_MAGIC_USEC) => (true, 1_000_000),
+                    (_, PCAP_MAGIC_NSEC) => (true, 1_000_000_000),
+                    _ => bail!("not a pcap or pcapng file: {:x?}", magic),
+                };
+
+            let mut header = [0u8; 20];
+            src.read_exact(&mut header)?;
+            Format::Pcap {
+                big_endian,
+                resolution,
+                linktype: u32_at(&header[16..], big_endian) & 0x0fff_ffff,
+            }
+        };
+
+        Ok(PacketReader { src, format })
+    }
+
+    /*
+     * Read the rest of a section header block, whose type we have already
+     * read.  The type reads the same in either byte order, the magic after
+     * the length is what tells us which one this section uses.  A new
+     * section starts over with its own interfaces.
+     */
+    fn read_shb(src: &mut R) -> Result<Format> {
+        let mut head = [0u8; 8];
+        src.read_exact(&mut head)?;
+
+        let big_endian = match (
+            u32::from_le_bytes([head[4], head[5], head[6], head[7]]),
+            u32::from_be_bytes([head[4], head[5], head[6], head[7]]),
+        ) {
+            (PCAPNG_BYTE_ORDER_MAGIC, _) => false,
+            (_, PCAPNG_BYTE_ORDER_MAGIC) => true,
+            _ => bail!("bad pcapng byte order magic {:x?}", &head[4..]),
+        };
+
+        let len = u32_at(&head[0..], big_endian) as usize;
+        if len < 28 || len % 4 != 0 {
+            bail!("pcapng section header of {} bytes", len);
+        }
+        read_body(src, len - 12)?;
+
+        Ok(Format::PcapNg {
+            big_endian,
+            interfaces: Vec::new(),
+        })
+    }
+
+    /*
+     * The next packet, or None at the end of the file.
+     */
+    pub fn next_packet(&mut self) -> Result<Option<Packet>> {
+        match &mut self.format {
+            Format::Pcap {
+                big_endian,
+                resolution,
+                linktype,
+            } => {
+                let mut head = [0u8; 16];
+                if !read_or_eof(&mut self.src, &mut head)? {
+                    return Ok(None);
+                }
+
+                let secs = u32_at(&head[0..], *big_endian) as u64;
+                let frac = u32_at(&head[4..], *big_endian) as u64;
+                let caplen = u32_at(&head[8..], *big_endian) as usize;
+                let data = read_body(&mut self.src, caplen)?;
+
+                Ok(Some(Packet {
+                    time: to_nanos(secs * *resolution + frac, *resolution),
+                    linktype: *linktype,
+                    data,
+                }))
+            }
+            Format::PcapNg { .. } => loop {
+                if let Some(packet) = self.next_pcapng_block()? {
+                    return Ok(packet);
+                }
+            },
+        }
+    }
+
+    /*
+     * Read one pcapng block.  Returns None for a block that isn't a
+     * packet, and Some(None) at the end of the file.
+     */
+    fn next_pcapng_block(&mut self) -> Result<Option<Option<Packet>>> {
+        let mut block_type = [0u8; 4];
+        if !read_or_eof(&mut self.src, &mut block_type)? {
+            return Ok(Some(None));
+        }
+
+        if u32::from_le_bytes(block_type) == PCAPNG_SHB {
+            self.format = PacketReader::read_shb(&mut self.src)?;
+            return Ok(None);
+        }
+
+        let mut block_len = [0u8; 4];
+        self.src.read_exact(&mut block_len)?;
+
+        let (big_endian, interfaces) = match &mut self.format {
+            Format::PcapNg {
+                big_endian,
+                interfaces,
+            } => (*big_endian, interfaces),
+            Format::Pcap { .. } => unreachable!(),
+        };
+
+        let block_type = u32_at(&block_type, big_endian);
+        let len = u32_at(&block_len, big_endian) as usize;
+        if len < 12 || len % 4 != 0 {
+            bail!("pcapng block of {} bytes", len);
+        }
+
+        /*
+         * The body, followed by the repeated block length.
+         */
+        let body = read_body(&mut self.src, len - 8)?;
+        let body = &body[..len - 12];
+
+        match block_type {
+            PCAPNG_IDB => {
+                if body.len() < 8 {
+                    bail!("pcapng interface block is too short");
+                }
+                let linktype = u16_at(&body[0..], big_endian) as u32;
+                let resolution = idb_resolution(&body[8..], big_endian)?;
+                interfaces.push(Interface {
+                    linktype,
+                    resolution,
+                });
+                Ok(None)
+            }
+            PCAPNG_EPB => {
+                if body.len() < 20 {
+                    bail!("pcapng packet block is too short");
+                }
+                let id = u32_at(&body[0..], big_endian) as usize;
+                let ts_high = u32_at(&body[4..], big_endian) as u64;
+                let ts_low = u32_at(&body[8..], big_endian) as u64;
+                let caplen = u32_at(&body[12..], big_endian) as usize;
+                if 20 + caplen > body.len() {
+                    bail!("pcapng packet of {} bytes overruns block", caplen);
+                }
+                let interface = match interfaces.get(id) {
+                    Some(interface) => *interface,
+                    None => bail!("packet on undescribed interface {}", id),
+                };
+
+                Ok(Some(Some(Packet {
+                    time: to_nanos(
+                        (ts_high << 32) | ts_low,
+                        interface.resolution,
+                    ),
+                    linktype: interface.linktype,
+                    data: body[20..20 + caplen].to_vec(),
+                })))
+            }
+            PCAPNG_SPB => {
+                if body.len() < 4 {
+                    bail!("pcapng simple packet block is too short");
+                }
+                let interface = match interfaces.first() {
+                    Some(interface) => *interface,
+                    None => bail!("simple packet with no interface"),
+                };
+                let origlen = u32_at(&body[0..], big_endian) as usize;
+                let caplen = origlen.min(body.len() - 4);
+
+                Ok(Some(Some(Packet {
+                    time: 0,
+                    linktype: interface.linktype,
+                    data: body[4..4 + caplen].to_vec(),
+                })))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/*
+ * Timestamps are in microseconds unless the interface says otherwise with
+ * an if_tsresol option.
+ */
+fn idb_resolution(mut options: &[u8], big_endian: bool) -> Result<u64> {
+    const OPT_ENDOFOPT: u16 = 0;
+    const OPT_IF_TSRESOL: u16 = 9;
+
+    while options.len() >= 4 {
+        let code = u16_at(&options[0..], big_endian);
+        let len = u16_at(&options[2..], big_endian) as usize;
+        let padded = (len + 3) & !3;
+        if code == OPT_ENDOFOPT {
+            break;
+        }
+        if 4 + padded > options.len() {
+            bail!("pcapng interface option overruns block");
+        }
+
+        if code == OPT_IF_TSRESOL && len == 1 {
+            let v = options[4];
+            let resolution = if v & 0x80 == 0 {
+                10u64.checked_pow(v as u32)
+            } else {
+                2u64.checked_pow((v & 0x7f) as u32)
+            };
+            match resolution {
+                Some(r) if r > 0 => return Ok(r),
+                _ => bail!("unsupported timestamp resolution {:#x}", v),
+            }
+        }
+
+        options = &options[4 + padded..];
+    }
+
+    Ok(1_000_000)
+}
+
+impl<R: Read> Iterator for PacketReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_pcap() -> Result<()> {
+        for big_endian in [false, true] {
+            let put32 = |v: u32| {
+                if big_endian {
+                    v.to_be_bytes()
+                } else {
+                    v.to_le_bytes()
+                }
+            };
+
+            let mut file = Vec::new();
+            file.extend(put32(PCAP_MAGIC_NSEC));
+            file.extend([0u8; 12]);
+            file.extend(put32(65535));
+            file.extend(put32(LINKTYPE_RAW));
+
+            file.extend(put32(1_600_000_000));
+            file.extend(put32(123));
+            file.extend(put32(3));
+            file.extend(put32(3));
+            file.extend([1u8, 2, 3]);
+
+            let packets = PacketReader::new(&file[..])?
+                .collect::<Result<Vec<Packet>>>()?;
+            assert_eq!(
+                packets,
+                vec![Packet {
+                    time: 1_600_000_000_000_000_123,
+                    linktype: LINKTYPE_RAW,
+                    data: vec![1, 2, 3],
+                }]
+            );
+        }
+        Ok(())
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = (12 + body.len()) as u32;
+        let mut b = Vec::new();
+        b.extend(block_type.to_le_bytes());
+        b.extend(len.to_le_bytes());
+        b.extend(body);
+        b.extend(len.to_le_bytes());
+        b
+    }
+
+    #[test]
+    fn read_pcapng() -> Result<()> {
+        let mut shb = Vec::new();
+        shb.extend(PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend([1u8, 0, 0, 0]);
+        shb.extend(u64::MAX.to_le_bytes());
+
+        // Ethernet, with timestamps in milliseconds.
+        let mut idb = Vec::new();
+        idb.extend(1u16.to_le_bytes());
+        idb.extend([0u8; 6]);
+        idb.extend(9u16.to_le_bytes());
+        idb.extend(1u16.to_le_bytes());
+        idb.extend([3u8, 0, 0, 0]);
+        idb.extend([0u8; 4]);
+
+        let mut epb = Vec::new();
+        epb.extend(0u32.to_le_bytes());
+        epb.extend(0u32.to_le_bytes());
+        epb.extend(1_500u32.to_le_bytes());
+        epb.extend(5u32.to_le_bytes());
+        epb.extend(5u32.to_le_bytes());
+        epb.extend([9u8, 8, 7, 6, 5, 0, 0, 0]);
+
+        let mut file = block(PCAPNG_SHB, &shb);
+        file.extend(block(PCAPNG_IDB, &idb));
+        file.extend(block(0x0bad, &[0u8; 4]));
+        file.extend(block(PCAPNG_EPB, &epb));
+
+        let packets =
+            PacketReader::new(&file[..])?.collect::<Result<Vec<Packet>>>()?;
+        assert_eq!(
+            packets,
+            vec![Packet {
+                time: 1_500_000_000,
+                linktype: LINKTYPE_ETHERNET,
+                data: vec![9, 8, 7, 6, 5],
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn not_a_pcap() {
+        assert!(PacketReader::new(&b"CRUCAP01"[..]).is_err());
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2022 Oxide Computer Company
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::pcap::*;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IPPROTO_TCP: u8 = 6;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub seq: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub payload: Vec<u8>,
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/*
+ * Skip any VLAN tags, and return what follows if it is IP.
+ */
+fn ethertype_payload(mut ethertype: u16, mut rest: &[u8]) -> Option<&[u8]> {
+    while ethertype == ETHERTYPE_VLAN {
+        if rest.len() < 4 {
+            return None;
+        }
+        ethertype = be16(&rest[2..]);
+        rest = &rest[4..];
+    }
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(rest),
+        _ => None,
+    }
+}
+
+/*
+ * Find the IP packet inside a link layer frame.  Anything that isn't IP
+ * (ARP, LLDP and the like) is None.
+ */
+fn link_payload(linktype: u32, data: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        LINKTYPE_ETHERNET if data.len() >= 14 => {
+            ethertype_payload(be16(&data[12..]), &data[14..])
+        }
+        LINKTYPE_LINUX_SLL if data.len() >= 16 => {
+            ethertype_payload(be16(&data[14..]), &data[16..])
+        }
+        LINKTYPE_LINUX_SLL2 if data.len() >= 20 => {
+            ethertype_payload(be16(&data[0..]), &data[20..])
+        }
+        /*
+         * The loopback header is the address family in the byte order of
+         * whatever wrote it, so go by the IP version instead.
+         */
+        LINKTYPE_NULL if data.len() >= 4 => Some(&data[4..]),
+        LINKTYPE_RAW => Some(data),
+        _ => None,
+    }
+}
+
+/*
+ * Pull the TCP segment out of a captured packet, if there is one.
+ */
+pub fn parse_segment(packet: &Packet) -> Option<Segment> {
+    let ip = link_payload(packet.linktype, &packet.data)?;
+    if ip.is_empty() {
+        return None;
+    }
+
+    let (src_ip, dst_ip, tcp) = match ip[0] >> 4 {
+        4 => {
+            if ip.len() < 20 {
+                return None;
+            }
+            let ihl = ((ip[0] & 0x0f) as usize) * 4;
+            let total = be16(&ip[2..]) as usize;
+            let fragment = be16(&ip[6..]);
+
+            /*
+             * A fragment doesn't have the whole segment in it, and
+             * Crucible's traffic shouldn't be fragmented anyway.
+             */
+            if ip[9] != IPPROTO_TCP
+                || (fragment & 0x3fff) != 0
+                || ihl < 20
+                || total < ihl
+                || total > ip.len()
+            {
+                return None;
+            }
+
+            let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            (IpAddr::V4(src), IpAddr::V4(dst), &ip[ihl..total])
+        }
+        6 => {
+            if ip.len() < 40 {
+                return None;
+            }
+            let total = 40 + be16(&ip[4..]) as usize;
+            if total > ip.len() {
+                return None;
+            }
+
+            let mut src = [0u8; 16];
+            src.copy_from_slice(&ip[8..24]);
+            let mut dst = [0u8; 16];
+            dst.copy_from_slice(&ip[24..40]);
+
+            /*
+             * Skip the extension headers that may come before TCP.
+             */
+            let mut next = ip[6];
+            let mut rest = &ip[40..total];
+            while matches!(next, 0 | 43 | 60) {
+                if rest.len() < 8 {
+                    return None;
+                }
+                let len = (rest[1] as usize + 1) * 8;
+                if len > rest.len() {
+                    return None;
+                }
+                next = rest[0];
+                rest = &rest[len..];
+            }
+            if next != IPPROTO_TCP {
+                return None;
+            }
+
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                rest,
+            )
+        }
+        _ => return None,
+    };
+
+    if tcp.len() < 20 {
+        return None;
+    }
+    let data_offset = ((tcp[12] >> 4) as usize) * 4;
+    if data_offset < 20 || data_offset > tcp.len() {
+        return None;
+    }
+    let flags = tcp[13];
+
+    Some(Segment {
+        src: SocketAddr::new(src_ip, be16(&tcp[0..])),
+        dst: SocketAddr::new(dst_ip, be16(&tcp[2..])),
+        seq: be32(&tcp[4..]),
+        syn: flags & TCP_SYN != 0,
+        fin: flags & TCP_FIN != 0,
+        rst: flags & TCP_RST != 0,
+        payload: tcp[data_offset..].to_vec(),
+    })
+}
+
+/*
+ * One direction of a TCP connection, put back in order.
+ */
+#[derive(Debug)]
+struct HalfStream {
+    /*
+     * The sequence number of the next byte we expect.
+     */
+    next_seq: u32,
+
+    /*
+     * How many bytes have been handed on so far, and anything that arrived
+     * ahead of the bytes before it, keyed by the same count.
+     */
+    delivered: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl HalfStream {
+    fn new(next_seq: u32) -> Self {
+        HalfStream {
+            next_seq,
+            delivered: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /*
+     * Take in one segment's data, and return whatever can now be handed
+     * on in order.  Retransmitted bytes are dropped.
+     */
+    fn add(&mut self, seq: u32, payload: &[u8]) -> Vec<u8> {
+        if payload.is_empty() {
+            return Vec::new();
+        }
+
+        let delta = seq.wrapping_sub(self.next_seq) as i32;
+        if delta > 0 {
+            self.pending
+                .entry(self.delivered + delta as u64)
+                .or_insert_with(|| payload.to_vec());
+            return Vec::new();
+        }
+
+        let mut out = Vec::new();
+        let skip = (-(delta as i64)) as usize;
+        if skip < payload.len() {
+            out.extend_from_slice(&payload[skip..]);
+        }
+        self.advance(out.len());
+
+        /*
+         * See if what just arrived fills the gap in front of anything
+         * that came early.
+         */
+        while let Some((&at, _)) = self.pending.iter().next() {
+            if at > self.delivered {
+                break;
+            }
+            let data = self.pending.remove(&at).unwrap();
+            let skip = (self.delivered - at) as usize;
+            if skip < data.len() {
+                out.extend_from_slice(&data[skip..]);
+                self.advance(data.len() - skip);
+            }
+        }
+
+        out
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.delivered += len as u64;
+        self.next_seq = self.next_seq.wrapping_add(len as u32);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// The first we saw of this direction of a connection.  If there was
+    /// no SYN, we joined part way through and may not be at the start of
+    /// a frame.
+    Start {
+        from_syn: bool,
+    },
+    Data(Vec<u8>),
+    End,
+}
+
+/*
+ * Follows every TCP connection in a capture, and hands back the bytes of
+ * each direction in order.
+ */
+#[derive(Default)]
+pub struct Reassembler {
+    streams: HashMap<(SocketAddr, SocketAddr), HalfStream>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler {
+            streams: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, segment: &Segment) -> Vec<StreamEvent> {
+        let key = (segment.src, segment.dst);
+        let mut events = Vec::new();
+
+        if segment.rst {
+            if self.streams.remove(&key).is_some() {
+                events.push(StreamEvent::End);
+            }
+            return events;
+        }
+
+        if segment.syn {
+            /*
+             * A SYN starts a new connection, even on a pair of addresses
+             * we have seen before.
+             */
+            if self.streams.remove(&key).is_some() {
+                events.push(StreamEvent::End);
+            }
+            self.streams
+                .insert(key, HalfStream::new(segment.seq.wrapping_add(1)));
+            events.push(StreamEvent::Start { from_syn: true });
+        }
+
+        let stream = match self.streams.get_mut(&key) {
+            Some(stream) => stream,
+            None => {
+                events.push(StreamEvent::Start { from_syn: false });
+                self.streams
+                    .entry(key)
+                    .or_insert_with(|| HalfStream::new(segment.seq))
+            }
+        };
+
+        /*
+         * Data on a SYN starts after the SYN's own sequence number.
+         */
+        let seq = if segment.syn {
+            segment.seq.wrapping_add(1)
+        } else {
+            segment.seq
+        };
+        let data = stream.add(seq, &segment.payload);
+        if !data.is_empty() {
+            events.push(StreamEvent::Data(data));
+        }
+
+        if segment.fin && stream.pending.is_empty() {
+            self.streams.remove(&key);
+            events.push(StreamEvent::End);
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn segment(seq: u32, payload: &[u8]) -> Segment {
+        Segment {
+            src: "10.0.0.1:40000".parse().unwrap(),
+            dst: "10.0.0.2:9000".parse().unwrap(),
+            seq,
+            syn: false,
+            fin: false,
+            rst: false,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn data(events: Vec<StreamEvent>) -> Vec<u8> {
+        events
+            .into_iter()
+            .filter_map(|e| match e {
+                StreamEvent::Data(d) => Some(d),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn reassemble_in_order() {
+        let mut r = Reassembler::new();
+
+        let mut syn = segment(99, &[]);
+        syn.syn = true;
+        assert_eq!(r.add(&syn), vec![StreamEvent::Start { from_syn: true }]);
+
+        assert_eq!(data(r.add(&segment(100, b"abc"))), b"abc");
+        assert_eq!(data(r.add(&segment(103, b"def"))), b"def");
+    }
+
+    #[test]
+    fn reassemble_out_of_order_and_retransmitted() {
+        let mut r = Reassembler::new();
+
+        let mut syn = segment(u32::MAX - 1, &[]);
+        syn.syn = true;
+        r.add(&syn);
+
+        // Across sequence number wrap, arriving backwards.
+        assert!(data(r.add(&segment(4, b"fgh"))).is_empty());
+        assert!(data(r.add(&segment(2, b"de"))).is_empty());
+        assert_eq!(data(r.add(&segment(u32::MAX, b"abc"))), b"abcdefgh");
+
+        // Retransmissions that overlap what we have.
+        assert_eq!(data(r.add(&segment(6, b"hijk"))), b"ijk");
+        assert!(data(r.add(&segment(0, b"bcd"))).is_empty());
+    }
+
+    #[test]
+    fn joined_part_way_through() {
+        let mut r = Reassembler::new();
+
+        let events = r.add(&segment(5000, b"xyz"));
+        assert_eq!(events[0], StreamEvent::Start { from_syn: false });
+        assert_eq!(data(events), b"xyz");
+
+        let mut fin = segment(5003, b"!");
+        fin.fin = true;
+        let events = r.add(&fin);
+        assert_eq!(events.last(), Some(&StreamEvent::End));
+    }
+
+    #[test]
+    fn parse_ethernet_ipv4_tcp() {
+        let mut frame = vec![0u8; 14];
+        frame[12] = 0x08; // IPv4
+
+        let payload = b"hello";
+        let mut ip = vec![0u8; 20];
+        ip[0] = 0x45;
+        ip[2..4]
+            .copy_from_slice(&((20 + 20 + payload.len()) as u16).to_be_bytes());
+        ip[9] = IPPROTO_TCP;
+        ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ip[16..20].copy_from_slice(&[10, 0, 0, 2]);
+
+        let mut tcp = vec![0u8; 20];
+        tcp[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&9000u16.to_be_bytes());
+        tcp[4..8].copy_from_slice(&1234u32.to_be_bytes());
+        tcp[12] = 5 << 4;
+        tcp[13] = TCP_FIN;
+
+        frame.extend(ip);
+        frame.extend(tcp);
+        frame.extend_from_slice(payload);
+        // Ethernet pads short frames, which must not end up as data.
+        frame.extend_from_slice(&[0, 0, 0]);
+
+        let packet = Packet {
+            time: 0,
+            linktype: LINKTYPE_ETHERNET,
+            data: frame,
+        };
+        let mut expected = segment(1234, payload);
+        expected.fin = true;
+        assert_eq!(parse_segment(&packet), Some(expected));
+    }
+}
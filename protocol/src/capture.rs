@@ -0,0 +1,360 @@
+// Copyright 2022 Oxide Computer Company
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{CrucibleDecoder, CrucibleEncoder, Message};
+
+/*
+ * A capture holds every Message that crossed one connection between an
+ * upstairs and a downstairs, in the order this side saw them.
+ *
+ * The file starts with CAPTURE_MAGIC, followed by one record per message:
+ *
+ *   [u64 ns since the epoch | u8 direction | frame]
+ *
+ * where frame is the message exactly as a CrucibleEncoder with no
+ * compression or checksum writes it.  Whatever the connection negotiated,
+ * a capture always holds plain frames.
+ */
+pub const CAPTURE_MAGIC: &[u8; 8] = b"CRUCAP01";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    ToDownstairs,
+    ToUpstairs,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::ToDownstairs => 0,
+            Direction::ToUpstairs => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Direction> {
+        match b {
+            0 => Ok(Direction::ToDownstairs),
+            1 => Ok(Direction::ToUpstairs),
+            _ => bail!("unknown capture direction {}", b),
+        }
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::ToDownstairs => write!(f, "up->ds"),
+            Direction::ToUpstairs => write!(f, "ds->up"),
+        }
+    }
+}
+
+/*
+ * Which end of the connection a recorder sits on.  This is what turns
+ * "sent" and "received" into a direction.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Side {
+    Upstairs,
+    Downstairs,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureRecord {
+    /// Nanoseconds since the UNIX epoch.
+    pub time: u64,
+    pub direction: Direction,
+    pub message: Message,
+}
+
+/**
+ * Writes every message a connection sends or receives to a capture file.
+ *
+ * Hand one of these to both the CrucibleEncoder and the CrucibleDecoder
+ * of a connection.  Messages are encoded where they are recorded, but the
+ * file is written by a thread of its own so a slow disk never blocks the
+ * connection.  Each record is written with a single write, so a capture
+ * from a process that died is complete up to its last message.
+ */
+#[derive(Debug)]
+pub struct Recorder {
+    side: Side,
+    path: PathBuf,
+    tx: Mutex<Option<Sender<BytesMut>>>,
+    failed: Arc<AtomicBool>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P, side: Side) -> Result<Recorder> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("create capture {:?}", path))?;
+        file.write_all(CAPTURE_MAGIC)?;
+
+        let (tx, rx) = channel::<BytesMut>();
+        let failed = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let failed = failed.clone();
+            let path = path.clone();
+            std::thread::Builder::new()
+                .name("capture".to_string())
+                .spawn(move || {
+                    for buf in rx {
+                        if let Err(e) = file.write_all(&buf) {
+                            println!("capture {:?} write failed: {}", path, e);
+                            failed.store(true, Ordering::SeqCst);
+                            return;
+                        }
+                    }
+                })?
+        };
+
+        Ok(Recorder {
+            side,
+            path,
+            tx: Mutex::new(Some(tx)),
+            failed,
+            writer: Some(writer),
+        })
+    }
+
+    /*
+     * Create a new capture in dir, named for what it records and when it
+     * was started.
+     */
+    pub fn create_in<P: AsRef<Path>>(
+        dir: P,
+        name: &str,
+        side: Side,
+    ) -> Result<Recorder> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        Recorder::create(
+            dir.as_ref().join(format!("{}-{}.cap", name, now)),
+            side,
+        )
+    }
+
+    pub fn record(&self, sent: bool, m: &Message) -> Result<()> {
+        let direction = match (self.side, sent) {
+            (Side::Upstairs, true) | (Side::Downstairs, false) => {
+                Direction::ToDownstairs
+            }
+            (Side::Upstairs, false) | (Side::Downstairs, true) => {
+                Direction::ToUpstairs
+            }
+        };
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        let mut buf = BytesMut::new();
+        buf.put_u64_le(time);
+        buf.put_u8(direction.to_byte());
+        CrucibleEncoder::new().encode(m, &mut buf)?;
+
+        /*
+         * Once the writer has given up, the capture is missing records
+         * and nothing more should go in it.
+         */
+        if self.failed.load(Ordering::SeqCst) {
+            bail!("capture {:?} stopped after a failed write", self.path);
+        }
+        match self.tx.lock().unwrap().as_ref() {
+            Some(tx) if tx.send(buf).is_ok() => Ok(()),
+            _ => bail!("capture {:?} is no longer being written", self.path),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    /*
+     * Let the writer finish whatever was recorded before the file closes.
+     */
+    fn drop(&mut self) {
+        self.tx.lock().unwrap().take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/**
+ * Reads the records back out of a capture.
+ */
+pub struct CaptureReader<R: Read> {
+    src: R,
+    decoder: CrucibleDecoder,
+}
+
+impl CaptureReader<File> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("open capture {:?}", path))?;
+        CaptureReader::new(file)
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut src: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        src.read_exact(&mut magic)?;
+        if &magic != CAPTURE_MAGIC {
+            bail!("not a capture, starts with {:x?}", magic);
+        }
+
+        Ok(CaptureReader {
+            src,
+            decoder: CrucibleDecoder::new(),
+        })
+    }
+
+    /*
+     * The next record, or None at the end of the capture.
+     */
+    pub fn next_record(&mut self) -> Result<Option<CaptureRecord>> {
+        /*
+         * Running out exactly between records is the end of the capture,
+         * anywhere else means it was cut short.
+         */
+        let mut head = [0u8; 13];
+        let mut got = 0;
+        while got < head.len() {
+            match self.src.read(&mut head[got..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => bail!("capture ends part way through a record"),
+                Ok(n) => got += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut time_bytes = [0u8; 8];
+        time_bytes.copy_from_slice(&head[0..8]);
+        let time = u64::from_le_bytes(time_bytes);
+        let direction = Direction::from_byte(head[8])?;
+
+        let mut length_bytes = [0u8; 4];
+        length_bytes.copy_from_slice(&head[9..13]);
+        let len = u32::from_le_bytes(length_bytes) as usize;
+        if !(4..=super::MAX_FRM_LEN).contains(&len) {
+            bail!("capture record at {} has bad frame length {}", time, len);
+        }
+
+        let mut frame = BytesMut::with_capacity(len);
+        frame.extend_from_slice(&head[9..13]);
+        frame.resize(len, 0);
+        self.src
+            .read_exact(&mut frame[4..])
+            .context("capture ends part way through a record")?;
+
+        match self.decoder.decode(&mut frame)? {
+            Some(message) => Ok(Some(CaptureRecord {
+                time,
+                direction,
+                message,
+            })),
+            None => bail!("capture record at {} is incomplete", time),
+        }
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Features;
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    #[test]
+    fn record_and_read_back() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("ds.cap");
+
+        let here_i_am = Message::HereIAm {
+            version: 3,
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            gen: 1,
+            read_only: false,
+            encrypted: false,
+            features: Features::supported(),
+        };
+        let yes_its_me = Message::YesItsMe {
+            version: 3,
+            features: Features::DISCARD,
+        };
+
+        let recorder = Recorder::create(&path, Side::Downstairs)?;
+        recorder.record(false, &here_i_am)?;
+        recorder.record(true, &yes_its_me)?;
+        drop(recorder);
+
+        // Never overwrite an earlier capture.
+        assert!(Recorder::create(&path, Side::Downstairs).is_err());
+
+        let records = CaptureReader::open(&path)?
+            .collect::<Result<Vec<CaptureRecord>>>()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::ToDownstairs);
+        assert_eq!(records[0].message, here_i_am);
+        assert_eq!(records[1].direction, Direction::ToUpstairs);
+        assert_eq!(records[1].message, yes_its_me);
+        assert!(records[0].time <= records[1].time);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_capture_is_an_error() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("up.cap");
+
+        let recorder = Recorder::create(&path, Side::Upstairs)?;
+        recorder.record(true, &Message::Ruok)?;
+        recorder.record(false, &Message::Imok)?;
+        drop(recorder);
+
+        let len = std::fs::metadata(&path)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(len - 1)?;
+
+        let mut reader = CaptureReader::open(&path)?;
+        let first = reader.next_record()?.unwrap();
+        assert_eq!(first.direction, Direction::ToDownstairs);
+        assert_eq!(first.message, Message::Ruok);
+        assert!(reader.next_record().is_err());
+
+        Ok(())
+    }
+}
@@ -1,17 +1,30 @@
 // Copyright 2021 Oxide Computer Company
 use std::cmp::Ordering;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::bail;
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 use uuid::Uuid;
 
 const MAX_FRM_LEN: usize = 100 * 1024 * 1024; // 100M
 
+/*
+ * The version of the message format.  This needs to change when an
+ * existing message changes in an incompatible way.  Anything optional
+ * should instead be a Features bit, agreed on during negotiation.
+ */
+pub const CRUCIBLE_MESSAGE_VERSION: u32 = 2;
+
 use crucible_common::{Block, CrucibleError, RegionDefinition};
 
+mod capture;
+pub use capture::{
+    CaptureReader, CaptureRecord, Direction, Recorder, Side, CAPTURE_MAGIC,
+};
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Write {
     pub eid: u64,
@@ -39,10 +52,15 @@ pub struct Write {
     pub hash: u64,
 }
 
+/*
+ * Read num_blocks contiguous blocks starting at offset, all within the one
+ * extent.
+ */
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ReadRequest {
     pub eid: u64,
     pub offset: Block,
+    pub num_blocks: u64,
 }
 
 // Note: if you change this, you may have to add to the dump commands that show
@@ -52,17 +70,146 @@ pub struct ReadResponse {
     pub eid: u64,
     pub offset: Block,
 
+    /*
+     * The data for every block in the request, back to back in one buffer.
+     */
+    pub data: bytes::BytesMut,
+
+    /*
+     * One entry for each block in data, in the same order.
+     */
+    pub blocks: Vec<ReadResponseBlockMetadata>,
+}
+
+/*
+ * The encryption contexts and hashes stored for a single block of a
+ * ReadResponse.  Both are empty for a block that has never been written.
+ */
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
+pub struct ReadResponseBlockMetadata {
+    pub encryption_contexts: Vec<EncryptionContext>,
+    pub hashes: Vec<u64>,
+}
+
+/*
+ * A read of the one block at offset, and what comes back for it.  These
+ * are what Message::ReadRequest and Message::ReadResponse carry, for peers
+ * that did not negotiate Features::READ_RANGES.
+ */
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BlockReadRequest {
+    pub eid: u64,
+    pub offset: Block,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BlockReadResponse {
+    pub eid: u64,
+    pub offset: Block,
+
     pub data: bytes::BytesMut,
     pub encryption_contexts: Vec<EncryptionContext>,
     pub hashes: Vec<u64>,
 }
 
+/*
+ * A discard covers num_blocks contiguous blocks starting at offset, all
+ * within the one extent.  Discarded blocks read back as unwritten (zeros,
+ * with no hashes or encryption contexts) until they are written again.
+ */
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Discard {
+    pub eid: u64,
+    pub offset: Block,
+    pub num_blocks: u64,
+}
+
+/*
+ * Zero num_blocks contiguous blocks starting at offset, all within the one
+ * extent.  Unlike a Write, no data crosses the wire.  The zeroed blocks
+ * read back as unwritten blocks do.
+ */
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct WriteZeroes {
+    pub eid: u64,
+    pub offset: Block,
+    pub num_blocks: u64,
+}
+
+/*
+ * Write data to one block only if that block currently holds expected.
+ * Both are plaintext, so this only works on an unencrypted region.  hash is
+ * the integrity hash of data, as for an unencrypted Write.
+ */
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CompareAndWrite {
+    pub eid: u64,
+    pub offset: Block,
+    pub expected: bytes::Bytes,
+    pub data: bytes::Bytes,
+    pub hash: u64,
+}
+
+/*
+ * How much of a region has been written, and what that costs on disk.
+ * logical_bytes is the size of the region as the upstairs sees it, and
+ * allocated_bytes the part of that holding written blocks.  Unwritten
+ * blocks are never allocated, so physical_bytes, all the space the
+ * region's files take up with their metadata, can be far less than
+ * logical_bytes.
+ */
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SpaceUsage {
+    pub logical_bytes: u64,
+    pub allocated_bytes: u64,
+    pub physical_bytes: u64,
+    /*
+     * The number of written blocks in each extent.
+     */
+    pub allocated_blocks: Vec<u64>,
+}
+
+/*
+ * The span an IO was submitted under in the upstairs, in the form of a
+ * W3C traceparent, sent in a Message::JobTrace.  A downstairs that is
+ * exporting traces parents the work it does for the IO on this, so one
+ * trace follows an IO through all three downstairs.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub flags: u8,
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct EncryptionContext {
     pub nonce: Vec<u8>,
     pub tag: Vec<u8>,
 }
 
+impl ReadRequest {
+    /*
+     * Split this into a read of each block it covers.
+     */
+    pub fn blocks(&self) -> impl Iterator<Item = BlockReadRequest> + '_ {
+        (0..self.num_blocks).map(move |i| BlockReadRequest {
+            eid: self.eid,
+            offset: Block::new(self.offset.value + i, self.offset.shift),
+        })
+    }
+}
+
+impl From<BlockReadRequest> for ReadRequest {
+    fn from(request: BlockReadRequest) -> ReadRequest {
+        ReadRequest {
+            eid: request.eid,
+            offset: request.offset,
+            num_blocks: 1,
+        }
+    }
+}
+
 impl ReadResponse {
     pub fn from_request(request: &ReadRequest, bs: usize) -> ReadResponse {
         /*
@@ -71,7 +218,7 @@ impl ReadResponse {
          * Also, we (I) need to figure out how to read data into an
          * uninitialized buffer. Until then, we have this workaround.
          */
-        let sz = bs;
+        let sz = bs * request.num_blocks as usize;
         let mut data = BytesMut::with_capacity(sz);
         data.resize(sz, 1);
 
@@ -79,23 +226,287 @@ impl ReadResponse {
             eid: request.eid,
             offset: request.offset,
             data,
-            encryption_contexts: vec![],
-            hashes: vec![],
+            blocks: Vec::with_capacity(request.num_blocks as usize),
         }
     }
 
+    /*
+     * Build a response holding data, hashing each block sized piece of it
+     * as its own block.
+     */
     pub fn from_request_with_data(
         request: &ReadRequest,
         data: &[u8],
     ) -> ReadResponse {
+        let bs = request.offset.block_size_in_bytes() as usize;
         ReadResponse {
             eid: request.eid,
             offset: request.offset,
             data: BytesMut::from(data),
-            encryption_contexts: vec![],
-            hashes: vec![crucible_common::integrity_hash(&[data])],
+            blocks: data
+                .chunks(bs)
+                .map(|block| ReadResponseBlockMetadata {
+                    encryption_contexts: vec![],
+                    hashes: vec![crucible_common::integrity_hash(&[block])],
+                })
+                .collect(),
+        }
+    }
+
+    /*
+     * Iterate over each block of the response along with its metadata.
+     */
+    pub fn iter_blocks(
+        &self,
+    ) -> impl Iterator<Item = (&[u8], &ReadResponseBlockMetadata)> {
+        let bs = self.offset.block_size_in_bytes() as usize;
+        self.data.chunks(bs).zip(self.blocks.iter())
+    }
+
+    /*
+     * Split this into a response for each block it covers.
+     */
+    pub fn into_blocks(self) -> Vec<BlockReadResponse> {
+        let bs = self.offset.block_size_in_bytes() as usize;
+        let mut data = self.data;
+        let mut responses = Vec::with_capacity(self.blocks.len());
+        for (i, block) in self.blocks.into_iter().enumerate() {
+            let len = std::cmp::min(bs, data.len());
+            responses.push(BlockReadResponse {
+                eid: self.eid,
+                offset: Block::new(
+                    self.offset.value + i as u64,
+                    self.offset.shift,
+                ),
+                data: data.split_to(len),
+                encryption_contexts: block.encryption_contexts,
+                hashes: block.hashes,
+            });
+        }
+        responses
+    }
+}
+
+impl From<BlockReadResponse> for ReadResponse {
+    fn from(response: BlockReadResponse) -> ReadResponse {
+        ReadResponse {
+            eid: response.eid,
+            offset: response.offset,
+            data: response.data,
+            blocks: vec![ReadResponseBlockMetadata {
+                encryption_contexts: response.encryption_contexts,
+                hashes: response.hashes,
+            }],
+        }
+    }
+}
+
+/**
+ * The set of optional protocol features a side is willing to use.
+ *
+ * The Upstairs sends everything it supports in HereIAm, and the Downstairs
+ * answers in YesItsMe with the subset that it also supports.  That answer is
+ * the negotiated set for the connection, and neither side may use a feature
+ * outside of it.  Features are bits in a u64 so that a peer that knows about
+ * a feature we don't can still talk to us, the unknown bits are just never
+ * part of the negotiated set.
+ */
+#[derive(
+    Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+pub struct Features(u64);
+
+impl Features {
+    /*
+     * The Downstairs understands Message::Discard.
+     */
+    pub const DISCARD: Features = Features(1 << 0);
+
+    /*
+     * The Downstairs understands Message::WriteZeroes.
+     */
+    pub const WRITE_ZEROES: Features = Features(1 << 1);
+
+    /*
+     * Frames carrying block data may be compressed with lz4, or with zstd.
+     * At most one of these is ever part of a negotiated set.
+     */
+    pub const COMPRESS_LZ4: Features = Features(1 << 2);
+    pub const COMPRESS_ZSTD: Features = Features(1 << 3);
+
+    /*
+     * Every frame ends with a CRC32C of the rest of the frame.
+     */
+    pub const FRAME_CHECKSUM: Features = Features(1 << 4);
+
+    /*
+     * The Downstairs understands Message::CompareAndWrite.
+     */
+    pub const COMPARE_AND_WRITE: Features = Features(1 << 5);
+
+    /*
+     * The Downstairs answers Message::SpaceUsagePlease.
+     */
+    pub const SPACE_USAGE: Features = Features(1 << 6);
+
+    /*
+     * The Upstairs understands Message::RegionGrown.
+     */
+    pub const REGION_GROWTH: Features = Features(1 << 7);
+
+    /*
+     * Reads go as Message::ReadRangeRequest, each request covering a range
+     * of blocks, and are answered with Message::ReadRangeResponse.
+     */
+    pub const READ_RANGES: Features = Features(1 << 8);
+
+    /*
+     * The Downstairs understands Message::JobTrace.
+     */
+    pub const TRACE_CONTEXT: Features = Features(1 << 9);
+
+    pub const fn empty() -> Features {
+        Features(0)
+    }
+
+    /*
+     * Every feature this build of Crucible knows how to use.
+     */
+    pub const fn supported() -> Features {
+        Features(
+            Features::DISCARD.0
+                | Features::WRITE_ZEROES.0
+                | Features::COMPRESS_LZ4.0
+                | Features::COMPRESS_ZSTD.0
+                | Features::FRAME_CHECKSUM.0
+                | Features::COMPARE_AND_WRITE.0
+                | Features::SPACE_USAGE.0
+                | Features::REGION_GROWTH.0
+                | Features::READ_RANGES.0
+                | Features::TRACE_CONTEXT.0,
+        )
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u64) -> Features {
+        Features(bits)
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /*
+     * True if every feature in other is also in self.
+     */
+    pub const fn contains(&self, other: Features) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    pub const fn intersection(&self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    pub fn insert(&mut self, other: Features) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Features) {
+        self.0 &= !other.0;
+    }
+}
+
+/**
+ * How frames that carry block data are compressed on a connection.
+ *
+ * Only the frame on the wire is compressed.  The messages inside, and so
+ * the integrity hashes in them, are exactly what they would be without
+ * compression.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn feature(&self) -> Features {
+        match self {
+            Compression::None => Features::empty(),
+            Compression::Lz4 => Features::COMPRESS_LZ4,
+            Compression::Zstd => Features::COMPRESS_ZSTD,
+        }
+    }
+
+    /*
+     * The compression a negotiated set of features calls for.
+     */
+    pub fn from_features(
+        features: Features,
+    ) -> Result<Compression, anyhow::Error> {
+        match (
+            features.contains(Features::COMPRESS_LZ4),
+            features.contains(Features::COMPRESS_ZSTD),
+        ) {
+            (false, false) => Ok(Compression::None),
+            (true, false) => Ok(Compression::Lz4),
+            (false, true) => Ok(Compression::Zstd),
+            (true, true) => {
+                bail!("features {} select more than one compression", features)
+            }
         }
     }
+
+    fn frame_bits(&self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_frame_bits(bits: u32) -> Result<Compression, anyhow::Error> {
+        match bits {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => bail!("unknown frame compression {}", bits),
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "none" => Compression::None,
+            "lz4" => Compression::Lz4,
+            "zstd" => Compression::Zstd,
+            _ => {
+                bail!("not a valid compression: {}", s);
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl std::fmt::Display for Features {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
 }
 
 /**
@@ -118,9 +529,11 @@ pub enum Message {
         gen: u64,
         read_only: bool,
         encrypted: bool,
+        features: Features,
     },
     YesItsMe {
         version: u32,
+        features: Features,
     },
 
     // Reasons to reject the initial negotiation
@@ -276,13 +689,13 @@ pub enum Message {
         session_id: Uuid,
         job_id: u64,
         dependencies: Vec<u64>,
-        requests: Vec<ReadRequest>,
+        requests: Vec<BlockReadRequest>,
     },
     ReadResponse {
         upstairs_id: Uuid,
         session_id: Uuid,
         job_id: u64,
-        responses: Result<Vec<ReadResponse>, CrucibleError>,
+        responses: Result<Vec<BlockReadResponse>, CrucibleError>,
     },
 
     WriteUnwritten {
@@ -299,6 +712,107 @@ pub enum Message {
         result: Result<(), CrucibleError>,
     },
 
+    /*
+     * Only sent if Features::DISCARD was negotiated.
+     */
+    Discard {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        dependencies: Vec<u64>,
+        discards: Vec<Discard>,
+    },
+    DiscardAck {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        result: Result<(), CrucibleError>,
+    },
+
+    /*
+     * Only sent if Features::WRITE_ZEROES was negotiated, and never for
+     * an encrypted region.
+     */
+    WriteZeroes {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        dependencies: Vec<u64>,
+        zeroes: Vec<WriteZeroes>,
+    },
+    WriteZeroesAck {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        result: Result<(), CrucibleError>,
+    },
+
+    /*
+     * Only sent if Features::COMPARE_AND_WRITE was negotiated.  Either
+     * every block held what was expected and all were written, or the ack
+     * is CrucibleError::CompareMismatch and nothing was written.
+     */
+    CompareAndWrite {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        dependencies: Vec<u64>,
+        writes: Vec<CompareAndWrite>,
+    },
+    CompareAndWriteAck {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        result: Result<(), CrucibleError>,
+    },
+
+    /*
+     * Only sent if Features::SPACE_USAGE was negotiated.  This is not IO,
+     * so it is answered straight away without waiting on any jobs.
+     */
+    SpaceUsagePlease,
+    SpaceUsage {
+        result: Result<SpaceUsage, CrucibleError>,
+    },
+
+    /*
+     * Only sent if Features::REGION_GROWTH was negotiated.  The downstairs
+     * added extents to its region while we were connected, and this is
+     * what it looks like now.  Nothing else about the region changes.
+     */
+    RegionGrown {
+        region_def: RegionDefinition,
+    },
+
+    /*
+     * Only sent if Features::READ_RANGES was negotiated.  Otherwise a read
+     * goes as a ReadRequest with a BlockReadRequest for every block.
+     */
+    ReadRangeRequest {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        dependencies: Vec<u64>,
+        requests: Vec<ReadRequest>,
+    },
+    ReadRangeResponse {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        responses: Result<Vec<ReadResponse>, CrucibleError>,
+    },
+
+    /*
+     * Only sent if Features::TRACE_CONTEXT was negotiated, and then just
+     * ahead of the job it is for.
+     */
+    JobTrace {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        trace: TraceContext,
+    },
+
     /*
      * Misc
      */
@@ -306,18 +820,57 @@ pub enum Message {
 }
 
 #[derive(Debug)]
-pub struct CrucibleEncoder {}
+pub struct CrucibleEncoder {
+    compression: Compression,
+    checksum: bool,
+    recorder: Option<Arc<Recorder>>,
+}
 
 impl CrucibleEncoder {
     pub fn new() -> Self {
-        CrucibleEncoder {}
+        CrucibleEncoder {
+            compression: Compression::None,
+            checksum: false,
+            recorder: None,
+        }
     }
 
-    fn serialized_size<T: serde::Serialize>(
-        m: T,
-    ) -> Result<usize, anyhow::Error> {
-        let serialized_len: usize = bincode::serialized_size(&m)? as usize;
-        let len = serialized_len + 4;
+    /*
+     * Write every message sent from here on to a capture.
+     */
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+
+    /*
+     * Compress frames carrying block data from here on.  Only call this
+     * once the other side has agreed to it in negotiation.
+     */
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /*
+     * Add a checksum trailer to every frame from here on.  Only call this
+     * once the other side has agreed to it in negotiation.
+     */
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    fn serialized_size<T: serde::Serialize>(
+        m: T,
+    ) -> Result<usize, anyhow::Error> {
+        let serialized_len: usize = bincode::serialized_size(&m)? as usize;
+        let len = serialized_len + 4;
 
         Ok(len)
     }
@@ -432,8 +985,13 @@ impl CrucibleEncoder {
                     .collect(),
             };
 
+            /*
+             * Leave room for a checksum trailer, whether or not this
+             * connection ends up using one.
+             */
             let mid_size =
-                CrucibleEncoder::serialized_size(&mid_size_write_message)?;
+                CrucibleEncoder::serialized_size(&mid_size_write_message)?
+                    + FRM_CHECKSUM_LEN;
 
             match mid_size.cmp(&MAX_FRM_LEN) {
                 Ordering::Greater => {
@@ -460,49 +1018,179 @@ impl Default for CrucibleEncoder {
 
 /*
  * A frame is [len | serialized message].
+ *
+ * A compressed frame is [len | uncompressed len | compressed message], with
+ * the compression used stored in the top bits of len.  Frames are never
+ * anywhere near big enough to need those bits, and leaving them zero for an
+ * uncompressed frame means it looks exactly like it did before compression
+ * existed.
+ *
+ * Either kind of frame may end with a CRC32C of everything before it in the
+ * frame, len included.  The top bit of len says the trailer is there, and
+ * len counts it.
  */
+const FRM_COMPRESSION_SHIFT: u32 = 28;
+const FRM_COMPRESSION_MASK: u32 = 0x7;
+const FRM_CHECKSUM_FLAG: u32 = 1 << 31;
+const FRM_LEN_MASK: u32 = (1 << FRM_COMPRESSION_SHIFT) - 1;
+const FRM_CHECKSUM_LEN: usize = 4;
 
-impl Encoder<Message> for CrucibleEncoder {
-    type Error = anyhow::Error;
+impl CrucibleEncoder {
+    /*
+     * Only the messages that carry block data are worth compressing.
+     */
+    fn compressible(m: &Message) -> bool {
+        matches!(
+            m,
+            Message::Write { .. }
+                | Message::WriteUnwritten { .. }
+                | Message::CompareAndWrite { .. }
+                | Message::ReadResponse { .. }
+                | Message::ReadRangeResponse { .. }
+        )
+    }
 
-    fn encode(
+    fn encode_message(
         &mut self,
-        m: Message,
+        m: &Message,
         dst: &mut BytesMut,
-    ) -> Result<(), Self::Error> {
-        let len = CrucibleEncoder::serialized_size(&m)?;
+    ) -> Result<(), anyhow::Error> {
+        if let Some(recorder) = &self.recorder {
+            if let Err(e) = recorder.record(true, m) {
+                /*
+                 * A capture is a debugging aid, losing it is no reason
+                 * to drop the connection.
+                 */
+                println!("no longer capturing this connection: {:?}", e);
+                self.recorder = None;
+            }
+        }
+
+        let start = dst.len();
+
+        self.encode_frame(m, dst)?;
+
+        if self.checksum {
+            /*
+             * Flag the trailer and count it in the length, then checksum
+             * the frame as it will be sent.
+             */
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&dst[start..start + 4]);
+            let len_bits = (u32::from_le_bytes(length_bytes)
+                + FRM_CHECKSUM_LEN as u32)
+                | FRM_CHECKSUM_FLAG;
+            dst[start..start + 4].copy_from_slice(&len_bits.to_le_bytes());
+
+            let crc = crc32c::crc32c(&dst[start..]);
+            dst.put_u32_le(crc);
+        }
+
+        Ok(())
+    }
+
+    fn encode_frame(
+        &mut self,
+        m: &Message,
+        dst: &mut BytesMut,
+    ) -> Result<(), anyhow::Error> {
+        if self.compression != Compression::None
+            && CrucibleEncoder::compressible(m)
+        {
+            let raw = bincode::serialize(m)?;
+
+            let compressed = match self.compression {
+                Compression::None => unreachable!(),
+                Compression::Lz4 => lz4::block::compress(&raw, None, false)?,
+                Compression::Zstd => zstd::bulk::compress(&raw, 0)?,
+            };
+
+            /*
+             * Encrypted data doesn't compress.  Don't make the frame
+             * bigger trying.
+             */
+            if compressed.len() + 4 < raw.len() {
+                let len = compressed.len() + 8;
+
+                dst.reserve(len);
+                dst.put_u32_le(
+                    len as u32
+                        | (self.compression.frame_bits()
+                            << FRM_COMPRESSION_SHIFT),
+                );
+                dst.put_u32_le(raw.len() as u32);
+                dst.extend_from_slice(&compressed);
+            } else {
+                let len = raw.len() + 4;
+
+                dst.reserve(len);
+                dst.put_u32_le(len as u32);
+                dst.extend_from_slice(&raw);
+            }
+
+            return Ok(());
+        }
+
+        let len = CrucibleEncoder::serialized_size(m)?;
 
         dst.reserve(len);
         dst.put_u32_le(len as u32);
-        bincode::serialize_into(dst.writer(), &m)?;
+        bincode::serialize_into(dst.writer(), m)?;
 
         Ok(())
     }
 }
 
-impl Encoder<&Message> for CrucibleEncoder {
+impl Encoder<Message> for CrucibleEncoder {
     type Error = anyhow::Error;
 
     fn encode(
         &mut self,
-        m: &Message,
+        m: Message,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        let len = CrucibleEncoder::serialized_size(&m)?;
+        self.encode_message(&m, dst)
+    }
+}
 
-        dst.reserve(len);
-        dst.put_u32_le(len as u32);
-        bincode::serialize_into(dst.writer(), &m)?;
+impl Encoder<&Message> for CrucibleEncoder {
+    type Error = anyhow::Error;
 
-        Ok(())
+    fn encode(
+        &mut self,
+        m: &Message,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        self.encode_message(m, dst)
     }
 }
 
-pub struct CrucibleDecoder {}
+pub struct CrucibleDecoder {
+    require_checksum: bool,
+    recorder: Option<Arc<Recorder>>,
+}
 
 impl CrucibleDecoder {
     pub fn new() -> Self {
-        CrucibleDecoder {}
+        CrucibleDecoder {
+            require_checksum: false,
+            recorder: None,
+        }
+    }
+
+    /*
+     * Write every message received from here on to a capture.
+     */
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+
+    /*
+     * Once both sides have agreed to checksum frames, one without a
+     * checksum means its length was damaged.
+     */
+    pub fn set_require_checksum(&mut self, require_checksum: bool) {
+        self.require_checksum = require_checksum;
     }
 }
 
@@ -520,6 +1208,24 @@ impl Decoder for CrucibleDecoder {
         &mut self,
         src: &mut BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
+        let message = self.decode_frame(src)?;
+
+        if let (Some(recorder), Some(m)) = (&self.recorder, &message) {
+            if let Err(e) = recorder.record(false, m) {
+                println!("no longer capturing this connection: {:?}", e);
+                self.recorder = None;
+            }
+        }
+
+        Ok(message)
+    }
+}
+
+impl CrucibleDecoder {
+    fn decode_frame(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<Message>, anyhow::Error> {
         if src.len() < 4 {
             /*
              * Wait for the u32 length prefix.
@@ -532,7 +1238,13 @@ impl Decoder for CrucibleDecoder {
          */
         let mut length_bytes = [0u8; 4];
         length_bytes.copy_from_slice(&src[0..4]);
-        let len = u32::from_le_bytes(length_bytes) as usize;
+        let len_bits = u32::from_le_bytes(length_bytes);
+        let len = (len_bits & FRM_LEN_MASK) as usize;
+        let has_checksum = (len_bits & FRM_CHECKSUM_FLAG) != 0;
+
+        if self.require_checksum && !has_checksum {
+            bail!("frame has no checksum, length bits {:#x}", len_bits);
+        }
 
         if len > MAX_FRM_LEN {
             bail!("frame is {} bytes, more than maximum {}", len, MAX_FRM_LEN);
@@ -546,11 +1258,80 @@ impl Decoder for CrucibleDecoder {
             return Ok(None);
         }
 
-        src.advance(4);
+        let frame = src.split_to(len);
+
+        let body_end = if has_checksum {
+            if len < 4 + FRM_CHECKSUM_LEN {
+                bail!("checksummed frame is only {} bytes", len);
+            }
+
+            let body_end = len - FRM_CHECKSUM_LEN;
+            let mut crc_bytes = [0u8; 4];
+            crc_bytes.copy_from_slice(&frame[body_end..]);
+            let expected = u32::from_le_bytes(crc_bytes);
+            let actual = crc32c::crc32c(&frame[..body_end]);
+
+            if actual != expected {
+                bail!(
+                    "frame checksum {:#010x} does not match {:#010x}",
+                    actual,
+                    expected
+                );
+            }
+
+            body_end
+        } else {
+            len
+        };
+
+        /*
+         * Only look at the compression bits once the checksum says they
+         * can be trusted.
+         */
+        let compression = Compression::from_frame_bits(
+            (len_bits >> FRM_COMPRESSION_SHIFT) & FRM_COMPRESSION_MASK,
+        )?;
+
+        if compression == Compression::None {
+            return Ok(Some(bincode::deserialize(&frame[4..body_end])?));
+        }
+
+        if body_end < 8 {
+            bail!("compressed frame is only {} bytes", body_end);
+        }
+
+        let mut raw_len_bytes = [0u8; 4];
+        raw_len_bytes.copy_from_slice(&frame[4..8]);
+        let raw_len = u32::from_le_bytes(raw_len_bytes) as usize;
 
-        let message = bincode::deserialize_from(src.reader());
+        if raw_len > MAX_FRM_LEN {
+            bail!(
+                "frame uncompresses to {} bytes, more than maximum {}",
+                raw_len,
+                MAX_FRM_LEN
+            );
+        }
+
+        let raw = match compression {
+            Compression::None => unreachable!(),
+            Compression::Lz4 => lz4::block::decompress(
+                &frame[8..body_end],
+                Some(raw_len as i32),
+            )?,
+            Compression::Zstd => {
+                zstd::bulk::decompress(&frame[8..body_end], raw_len)?
+            }
+        };
+
+        if raw.len() != raw_len {
+            bail!(
+                "frame uncompressed to {} bytes, expected {}",
+                raw.len(),
+                raw_len
+            );
+        }
 
-        Ok(Some(message?))
+        Ok(Some(bincode::deserialize(&raw)?))
     }
 }
 
@@ -582,6 +1363,7 @@ mod tests {
             gen: 123,
             read_only: false,
             encrypted: true,
+            features: Features::supported(),
         };
         assert_eq!(input, round_trip(&input)?);
         Ok(())
@@ -589,11 +1371,507 @@ mod tests {
 
     #[test]
     fn rt_yes_its_me() -> Result<()> {
-        let input = Message::YesItsMe { version: 20000 };
+        let input = Message::YesItsMe {
+            version: 20000,
+            features: Features::from_bits(u64::MAX),
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn features_unknown_bits_are_not_negotiated() {
+        // A peer from the future may offer bits we have never heard of.
+        let offered = Features::from_bits(u64::MAX);
+        let negotiated = offered.intersection(Features::supported());
+        assert_eq!(negotiated, Features::supported());
+        assert!(offered.contains(negotiated));
+
+        let none = Features::empty().intersection(Features::supported());
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn rt_discard() -> Result<()> {
+        let input = Message::Discard {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1000,
+            dependencies: vec![998, 999],
+            discards: vec![
+                Discard {
+                    eid: 0,
+                    offset: Block::new_512(3),
+                    num_blocks: 7,
+                },
+                Discard {
+                    eid: 1,
+                    offset: Block::new_512(0),
+                    num_blocks: 1,
+                },
+            ],
+        };
+        assert_eq!(input, round_trip(&input)?);
+
+        let input = Message::DiscardAck {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1000,
+            result: Err(CrucibleError::ModifyingReadOnlyRegion),
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_write_zeroes() -> Result<()> {
+        let input = Message::WriteZeroes {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1001,
+            dependencies: vec![1000],
+            zeroes: vec![WriteZeroes {
+                eid: 4,
+                offset: Block::new_4096(12),
+                num_blocks: 100,
+            }],
+        };
+        assert_eq!(input, round_trip(&input)?);
+
+        let input = Message::WriteZeroesAck {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1001,
+            result: Ok(()),
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_compare_and_write() -> Result<()> {
+        let input = Message::CompareAndWrite {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1002,
+            dependencies: vec![1000, 1001],
+            writes: vec![CompareAndWrite {
+                eid: 2,
+                offset: Block::new_512(7),
+                expected: bytes::Bytes::from(vec![0u8; 512]),
+                data: bytes::Bytes::from(vec![1u8; 512]),
+                hash: 10,
+            }],
+        };
+        assert_eq!(input, round_trip(&input)?);
+
+        let input = Message::CompareAndWriteAck {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1002,
+            result: Err(CrucibleError::CompareMismatch),
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_block_read_request() -> Result<()> {
+        let input = Message::ReadRequest {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1002,
+            dependencies: vec![1001],
+            requests: vec![BlockReadRequest {
+                eid: 2,
+                offset: Block::new_512(96),
+            }],
+        };
         assert_eq!(input, round_trip(&input)?);
         Ok(())
     }
 
+    #[test]
+    fn rt_block_read_response() -> Result<()> {
+        let data = vec![1u8; 512];
+        let input = Message::ReadResponse {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1002,
+            responses: Ok(vec![BlockReadResponse {
+                eid: 1,
+                offset: Block::new_512(10),
+                data: BytesMut::from(&data[..]),
+                encryption_contexts: vec![],
+                hashes: vec![crucible_common::integrity_hash(&[&data])],
+            }]),
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_read_request() -> Result<()> {
+        let input = Message::ReadRangeRequest {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1002,
+            dependencies: vec![1001],
+            requests: vec![
+                ReadRequest {
+                    eid: 2,
+                    offset: Block::new_512(96),
+                    num_blocks: 4,
+                },
+                ReadRequest {
+                    eid: 3,
+                    offset: Block::new_512(0),
+                    num_blocks: 2,
+                },
+            ],
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_job_trace() -> Result<()> {
+        let input = Message::JobTrace {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1002,
+            trace: TraceContext {
+                trace_id: [0x4b; 16],
+                span_id: [0xf0; 8],
+                flags: 1,
+            },
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_read_response() -> Result<()> {
+        let request = ReadRequest {
+            eid: 1,
+            offset: Block::new_512(10),
+            num_blocks: 2,
+        };
+        let mut data = vec![1u8; 512];
+        data.extend(vec![2u8; 512]);
+
+        let input = Message::ReadRangeResponse {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1002,
+            responses: Ok(vec![ReadResponse::from_request_with_data(
+                &request, &data,
+            )]),
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    fn round_trip_compressed(
+        input: &Message,
+        compression: Compression,
+    ) -> Result<(Message, usize)> {
+        let mut enc = CrucibleEncoder::new();
+        enc.set_compression(compression);
+        let mut buf = BytesMut::new();
+        enc.encode(input, &mut buf)?;
+        let len = buf.len();
+
+        let mut dec = CrucibleDecoder::new();
+        if let Some(output) = dec.decode(&mut buf)? {
+            assert!(buf.is_empty());
+            Ok((output, len))
+        } else {
+            bail!("expected message, got None");
+        }
+    }
+
+    fn a_write_message(data: &[u8]) -> Message {
+        let write = Write {
+            eid: 0,
+            offset: Block::new_512(0),
+            data: bytes::Bytes::from(data.to_vec()),
+            encryption_context: None,
+            hash: crucible_common::integrity_hash(&[data]),
+        };
+        Message::Write {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1003,
+            dependencies: vec![],
+            writes: vec![write],
+        }
+    }
+
+    #[test]
+    fn rt_write_compressed() -> Result<()> {
+        let input = a_write_message(&[7u8; 4096]);
+        let plain_len = CrucibleEncoder::serialized_size(&input)?;
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let (output, len) = round_trip_compressed(&input, compression)?;
+            assert_eq!(input, output);
+            assert!(len < plain_len);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rt_read_response_compressed() -> Result<()> {
+        let request = ReadRequest {
+            eid: 1,
+            offset: Block::new_512(10),
+            num_blocks: 4,
+        };
+        let data = vec![0u8; 2048];
+
+        let input = Message::ReadRangeResponse {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1004,
+            responses: Ok(vec![ReadResponse::from_request_with_data(
+                &request, &data,
+            )]),
+        };
+        let plain_len = CrucibleEncoder::serialized_size(&input)?;
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let (output, len) = round_trip_compressed(&input, compression)?;
+            assert_eq!(input, output);
+            assert!(len < plain_len);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn incompressible_frame_does_not_grow() -> Result<()> {
+        /*
+         * Stand in for ciphertext with bytes that won't compress.
+         */
+        let mut x = 0x2545f4914f6cdd1du64;
+        let data: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let input = a_write_message(&data);
+        let plain_len = CrucibleEncoder::serialized_size(&input)?;
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let (output, len) = round_trip_compressed(&input, compression)?;
+            assert_eq!(input, output);
+            assert!(len <= plain_len);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn control_messages_are_not_compressed() -> Result<()> {
+        let input = Message::Flush {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1005,
+            dependencies: vec![0; 64],
+            flush_number: 3,
+            gen_number: 1,
+            snapshot_details: None,
+        };
+        let plain_len = CrucibleEncoder::serialized_size(&input)?;
+
+        let (output, len) = round_trip_compressed(&input, Compression::Zstd)?;
+        assert_eq!(input, output);
+        assert_eq!(len, plain_len);
+        Ok(())
+    }
+
+    fn checksummed(
+        input: &Message,
+        compression: Compression,
+    ) -> Result<BytesMut> {
+        let mut enc = CrucibleEncoder::new();
+        enc.set_compression(compression);
+        enc.set_checksum(true);
+        let mut buf = BytesMut::new();
+        enc.encode(input, &mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn rt_checksummed() -> Result<()> {
+        let inputs = [
+            Message::Flush {
+                upstairs_id: Uuid::new_v4(),
+                session_id: Uuid::new_v4(),
+                job_id: 1006,
+                dependencies: vec![1001, 1002],
+                flush_number: 3,
+                gen_number: 1,
+                snapshot_details: None,
+            },
+            a_write_message(&[7u8; 4096]),
+        ];
+
+        for input in inputs.iter() {
+            for compression in
+                [Compression::None, Compression::Lz4, Compression::Zstd]
+            {
+                let mut buf = checksummed(input, compression)?;
+
+                let mut dec = CrucibleDecoder::new();
+                dec.set_require_checksum(true);
+                assert_eq!(dec.decode(&mut buf)?.as_ref(), Some(input));
+                assert!(buf.is_empty());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_detects_flipped_bits() -> Result<()> {
+        let input = Message::Flush {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1007,
+            dependencies: vec![1001, 1002],
+            flush_number: 3,
+            gen_number: 1,
+            snapshot_details: None,
+        };
+        let good = checksummed(&input, Compression::None)?;
+
+        /*
+         * Flip each bit after the length in turn.  A damaged length is
+         * covered by the test below.
+         */
+        for i in 4..good.len() {
+            for bit in 0..8 {
+                let mut buf = good.clone();
+                buf[i] ^= 1 << bit;
+
+                let mut dec = CrucibleDecoder::new();
+                assert!(dec.decode(&mut buf).is_err());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn required_checksum_rejects_plain_frame() -> Result<()> {
+        let input = Message::Ruok;
+
+        let mut enc = CrucibleEncoder::new();
+        let mut buf = BytesMut::new();
+        enc.encode(&input, &mut buf)?;
+
+        let mut dec = CrucibleDecoder::new();
+        dec.set_require_checksum(true);
+        assert!(dec.decode(&mut buf).is_err());
+
+        /*
+         * A damaged length can make a checksummed frame look shorter than
+         * it is, in which case the checksum lands on the wrong bytes.
+         */
+        let mut buf = checksummed(&input, Compression::None)?;
+        buf[0] -= 1;
+
+        let mut dec = CrucibleDecoder::new();
+        dec.set_require_checksum(true);
+        assert!(dec.decode(&mut buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compression_from_features() -> Result<()> {
+        for compression in
+            [Compression::None, Compression::Lz4, Compression::Zstd]
+        {
+            let mut features = Features::DISCARD;
+            features.insert(compression.feature());
+            assert_eq!(Compression::from_features(features)?, compression);
+            assert_eq!(
+                compression.to_string().parse::<Compression>()?,
+                compression
+            );
+        }
+
+        let mut both = Features::COMPRESS_LZ4;
+        both.insert(Features::COMPRESS_ZSTD);
+        assert!(Compression::from_features(both).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_response_blocks() {
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(0),
+            num_blocks: 3,
+        };
+        let mut data = vec![1u8; 512];
+        data.extend(vec![2u8; 512]);
+        data.extend(vec![3u8; 512]);
+
+        let response = ReadResponse::from_request_with_data(&request, &data);
+        assert_eq!(response.blocks.len(), 3);
+
+        for (i, (block, meta)) in response.iter_blocks().enumerate() {
+            assert_eq!(block, &[i as u8 + 1; 512][..]);
+            assert_eq!(
+                meta.hashes,
+                vec![crucible_common::integrity_hash(&[block])]
+            );
+        }
+    }
+
+    #[test]
+    fn read_ranges_as_blocks() {
+        let request = ReadRequest {
+            eid: 4,
+            offset: Block::new_512(6),
+            num_blocks: 2,
+        };
+        let blocks: Vec<BlockReadRequest> = request.blocks().collect();
+        assert_eq!(
+            blocks,
+            vec![
+                BlockReadRequest {
+                    eid: 4,
+                    offset: Block::new_512(6),
+                },
+                BlockReadRequest {
+                    eid: 4,
+                    offset: Block::new_512(7),
+                },
+            ]
+        );
+
+        let mut data = vec![1u8; 512];
+        data.extend(vec![2u8; 512]);
+        let response = ReadResponse::from_request_with_data(&request, &data);
+        let blocks = response.clone().into_blocks();
+        assert_eq!(blocks.len(), 2);
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(block.offset, Block::new_512(6 + i as u64));
+            assert_eq!(block.data[..], [i as u8 + 1; 512][..]);
+            assert_eq!(block.hashes, response.blocks[i].hashes);
+        }
+
+        /*
+         * A block on its own is a range of one.
+         */
+        let one = ReadResponse::from(blocks[1].clone());
+        assert_eq!(one.offset, Block::new_512(7));
+        assert_eq!(one.blocks, vec![response.blocks[1].clone()]);
+    }
+
     #[test]
     fn rt_ruok() -> Result<()> {
         let input = Message::Ruok;
@@ -637,6 +1915,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rt_sup() -> Result<()> {
+        let input = Message::SpaceUsagePlease;
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_su() -> Result<()> {
+        let input = Message::SpaceUsage {
+            result: Ok(SpaceUsage {
+                logical_bytes: 3 * 512 * 100,
+                allocated_bytes: 512 * 7,
+                physical_bytes: 8192,
+                allocated_blocks: vec![7, 0, 0],
+            }),
+        };
+        assert_eq!(input, round_trip(&input)?);
+
+        let input = Message::SpaceUsage {
+            result: Err(CrucibleError::InvalidExtent),
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_rg() -> Result<()> {
+        let mut region_def = RegionDefinition::default();
+        region_def.set_extent_count(30);
+        let input = Message::RegionGrown { region_def };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
     #[test]
     fn correctly_detect_truncated_message() -> Result<()> {
         let mut encoder = CrucibleEncoder::new();
@@ -649,6 +1962,7 @@ mod tests {
             gen: 23849183,
             read_only: true,
             encrypted: false,
+            features: Features::empty(),
         };
         let mut buffer = BytesMut::new();
 
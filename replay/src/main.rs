@@ -0,0 +1,274 @@
+// Copyright 2022 Oxide Computer Company
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crucible_protocol::*;
+
+/*
+ * Captures are written by an upstairs or a downstairs started with a
+ * capture directory.  Either side's capture holds the whole conversation,
+ * so either can be replayed.
+ */
+#[derive(Debug, Parser)]
+#[clap(about = "show or replay a capture of an upstairs/downstairs session")]
+enum Args {
+    /// Print every message in a capture.
+    Show {
+        #[clap(name = "CAPTURE", action)]
+        capture: PathBuf,
+
+        /// Print messages in full, block data and all.
+        #[clap(long, action)]
+        full: bool,
+    },
+    /// Send the upstairs half of a capture to a downstairs, and report
+    /// where its answers differ from the ones in the capture.
+    Replay {
+        #[clap(name = "CAPTURE", action)]
+        capture: PathBuf,
+
+        /// The downstairs to replay against.
+        #[clap(short, long, default_value = "127.0.0.1:9000", action)]
+        target: SocketAddr,
+
+        /// Seconds to wait for each answer from the downstairs.
+        #[clap(long, default_value = "10", action)]
+        timeout: u64,
+
+        /// Print differing messages in full, block data and all.
+        #[clap(long, action)]
+        full: bool,
+    },
+}
+
+/*
+ * Messages carrying block data are too big to read when printed whole.
+ */
+const SUMMARY_LEN: usize = 160;
+
+fn describe(m: &Message, full: bool) -> String {
+    let s = format!("{:?}", m);
+    if full || s.len() <= SUMMARY_LEN {
+        s
+    } else {
+        let mut end = SUMMARY_LEN;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}...", &s[..end])
+    }
+}
+
+fn show(capture: PathBuf, full: bool) -> Result<()> {
+    let mut first = None;
+
+    for record in CaptureReader::open(&capture)? {
+        let record = record?;
+        let start = *first.get_or_insert(record.time);
+
+        println!(
+            "{:>14} {} {}",
+            record.time.saturating_sub(start) / 1000,
+            record.direction,
+            describe(&record.message, full),
+        );
+    }
+
+    Ok(())
+}
+
+/*
+ * Whether a liveness check is answered depends only on timing, not on
+ * anything the downstairs did, so leave them out of a replay.
+ */
+fn is_ping(m: &Message) -> bool {
+    matches!(m, Message::Ruok | Message::Imok)
+}
+
+/*
+ * The job an answer from the downstairs belongs to, if it belongs to one.
+ */
+fn answer_job_id(m: &Message) -> Option<u64> {
+    match m {
+        Message::WriteAck { job_id, .. }
+        | Message::FlushAck { job_id, .. }
+        | Message::ReadResponse { job_id, .. }
+        | Message::ReadRangeResponse { job_id, .. }
+        | Message::WriteUnwrittenAck { job_id, .. }
+        | Message::DiscardAck { job_id, .. }
+        | Message::WriteZeroesAck { job_id, .. }
+        | Message::CompareAndWriteAck { job_id, .. } => Some(*job_id),
+        _ => None,
+    }
+}
+
+/*
+ * What the replayed downstairs has sent us but we have not yet compared
+ * with the capture.
+ */
+#[derive(Default)]
+struct Answers {
+    jobs: HashMap<u64, Message>,
+    other: VecDeque<Message>,
+}
+
+impl Answers {
+    fn take(&mut self, expected: &Message) -> Option<Message> {
+        match answer_job_id(expected) {
+            Some(job_id) => self.jobs.remove(&job_id),
+            None => self.other.pop_front(),
+        }
+    }
+
+    fn put(&mut self, m: Message) {
+        match answer_job_id(&m) {
+            Some(job_id) => {
+                self.jobs.insert(job_id, m);
+            }
+            None => self.other.push_back(m),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.jobs.len() + self.other.len()
+    }
+}
+
+async fn replay(
+    capture: PathBuf,
+    target: SocketAddr,
+    timeout: Duration,
+    full: bool,
+) -> Result<()> {
+    let reader = CaptureReader::open(&capture)?;
+
+    let sock = TcpStream::connect(target).await?;
+    let (read, write) = sock.into_split();
+    let mut fr = FramedRead::new(read, CrucibleDecoder::new());
+    let mut fw = FramedWrite::new(write, CrucibleEncoder::new());
+
+    let mut sent = 0;
+    let mut answers = 0;
+    let mut differ = 0;
+    let mut received = Answers::default();
+
+    /*
+     * Go through the capture in order, sending what the upstairs sent and
+     * waiting for an answer wherever the downstairs answered.  Jobs that
+     * do not depend on each other may be answered in any order, so an
+     * answer to a job is matched with the captured answer to the same
+     * job, holding on to any that arrive early.  Everything else is
+     * answered in the order it was asked.
+     */
+    for record in reader {
+        let record = record?;
+        if is_ping(&record.message) {
+            continue;
+        }
+
+        match record.direction {
+            Direction::ToDownstairs => {
+                fw.send(&record.message).await?;
+                sent += 1;
+            }
+            Direction::ToUpstairs => {
+                let actual = loop {
+                    if let Some(m) = received.take(&record.message) {
+                        break m;
+                    }
+
+                    let m = match tokio::time::timeout(timeout, fr.next()).await
+                    {
+                        Err(_) => {
+                            bail!(
+                                "timed out after {} sent, waiting for {}",
+                                sent,
+                                describe(&record.message, full),
+                            );
+                        }
+                        Ok(None) => {
+                            bail!(
+                                "downstairs hung up after {} sent, we \
+                                expected {}",
+                                sent,
+                                describe(&record.message, full),
+                            );
+                        }
+                        Ok(Some(m)) => m?,
+                    };
+
+                    if is_ping(&m) {
+                        continue;
+                    }
+
+                    /*
+                     * Talk the way this downstairs agreed to, which need
+                     * not be the way the captured one did.
+                     */
+                    if let Message::YesItsMe { features, .. } = &m {
+                        let checksum =
+                            features.contains(Features::FRAME_CHECKSUM);
+                        fw.encoder_mut().set_compression(
+                            Compression::from_features(*features)?,
+                        );
+                        fw.encoder_mut().set_checksum(checksum);
+                        fr.decoder_mut().set_require_checksum(checksum);
+                    }
+
+                    received.put(m);
+                };
+
+                answers += 1;
+                if actual != record.message {
+                    differ += 1;
+                    println!("answer {} differs", answers);
+                    println!("  captured: {}", describe(&record.message, full));
+                    println!("  replayed: {}", describe(&actual, full));
+                }
+            }
+        }
+    }
+
+    println!(
+        "sent {} messages, {} of {} answers matched",
+        sent,
+        answers - differ,
+        answers
+    );
+
+    if received.len() > 0 {
+        println!("{} answers were not in the capture:", received.len());
+        for m in received.jobs.values().chain(received.other.iter()) {
+            println!("  replayed: {}", describe(m, full));
+        }
+        differ += received.len();
+    }
+
+    if differ > 0 {
+        bail!("{} answers differ from the capture", differ);
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Args::parse() {
+        Args::Show { capture, full } => show(capture, full),
+        Args::Replay {
+            capture,
+            target,
+            timeout,
+            full,
+        } => replay(capture, target, Duration::from_secs(timeout), full).await,
+    }
+}
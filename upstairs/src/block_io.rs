@@ -111,6 +111,26 @@ impl BlockIO for FileBlockIO {
         BlockReqWaiter::immediate()
     }
 
+    fn discard(
+        &self,
+        _offset: Block,
+        _len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        crucible_bail!(Unsupported, "discard unsupported for FileBlockIO")
+    }
+
+    fn compare_and_write(
+        &self,
+        _offset: Block,
+        _expected: Bytes,
+        _data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        crucible_bail!(
+            Unsupported,
+            "compare and write unsupported for FileBlockIO"
+        )
+    }
+
     fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         Ok(WQCounts {
             up_count: 0,
@@ -275,6 +295,26 @@ impl BlockIO for ReqwestBlockIO {
         BlockReqWaiter::immediate()
     }
 
+    fn discard(
+        &self,
+        _offset: Block,
+        _len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        crucible_bail!(Unsupported, "discard unsupported for ReqwestBlockIO")
+    }
+
+    fn compare_and_write(
+        &self,
+        _offset: Block,
+        _expected: Bytes,
+        _data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        crucible_bail!(
+            Unsupported,
+            "compare and write unsupported for ReqwestBlockIO"
+        )
+    }
+
     fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         Ok(WQCounts {
             up_count: 0,
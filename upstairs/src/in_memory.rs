@@ -18,6 +18,18 @@ impl InMemoryBlockIO {
             owned: Mutex::new(vec![false; total_size]),
         }
     }
+
+    /*
+     * Grow to total_size bytes, the way a region can while in use.  The
+     * new space is zero and unwritten.
+     */
+    pub fn extend(&self, total_size: usize) {
+        let mut bytes = self.bytes.lock().unwrap();
+        let mut owned = self.owned.lock().unwrap();
+        assert!(total_size >= bytes.len());
+        bytes.resize(total_size, 0);
+        owned.resize(total_size, false);
+    }
 }
 
 impl BlockIO for InMemoryBlockIO {
@@ -107,6 +119,45 @@ impl BlockIO for InMemoryBlockIO {
         BlockReqWaiter::immediate()
     }
 
+    fn discard(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        let mut bytes = self.bytes.lock().unwrap();
+        let mut owned = self.owned.lock().unwrap();
+
+        let start = offset.value as usize * self.block_size as usize;
+        let end = start + len.value as usize * self.block_size as usize;
+
+        bytes[start..end].fill(0);
+        owned[start..end].fill(false);
+
+        BlockReqWaiter::immediate()
+    }
+
+    fn compare_and_write(
+        &self,
+        offset: Block,
+        expected: Bytes,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        let mut bytes = self.bytes.lock().unwrap();
+        let mut owned = self.owned.lock().unwrap();
+
+        let start = offset.value as usize * self.block_size as usize;
+        let end = start + data.len();
+
+        if bytes[start..end] != expected[..] {
+            crucible_bail!(CompareMismatch);
+        }
+
+        bytes[start..end].copy_from_slice(&data);
+        owned[start..end].fill(true);
+
+        BlockReqWaiter::immediate()
+    }
+
     fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         Ok(WQCounts {
             up_count: 0,
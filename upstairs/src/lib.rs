@@ -13,6 +13,7 @@ use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::io::{Read, Result as IOResult, Seek, SeekFrom, Write};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::mpsc as std_mpsc;
 use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::time::Duration;
@@ -24,6 +25,7 @@ pub use crucible_protocol::*;
 use anyhow::{anyhow, bail, Result};
 pub use bytes::{Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
+use opentelemetry::trace::TraceContextExt;
 use oximeter::types::ProducerRegistry;
 use rand::prelude::*;
 use ringbuffer::{AllocRingBuffer, RingBufferExt, RingBufferWrite};
@@ -34,6 +36,7 @@ use tokio::sync::{mpsc, watch, Notify};
 use tokio::time::{sleep_until, Instant};
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{instrument, span, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use usdt::register_probes;
 use uuid::Uuid;
 
@@ -96,10 +99,51 @@ pub trait BlockIO {
         snapshot_details: Option<SnapshotDetails>,
     ) -> Result<BlockReqWaiter, CrucibleError>;
 
+    /*
+     * Tell the storage that len blocks starting at offset are no longer
+     * needed.  Once complete, those blocks read back as zeros.
+     */
+    fn discard(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError>;
+
+    /*
+     * Write data starting at offset only if the blocks there currently
+     * hold expected, with no other IO landing in between.  If they do not,
+     * nothing is written and the request fails with CompareMismatch.
+     * Blocks that were never written compare equal to zeros.
+     *
+     * Only unencrypted regions can do this, as the compare has to be done
+     * against plaintext where the blocks are stored.
+     */
+    fn compare_and_write(
+        &self,
+        offset: Block,
+        expected: Bytes,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError>;
+
     fn show_work(&self) -> Result<WQCounts, CrucibleError>;
 
     // Common methods
 
+    /*
+     * Write zeros to len blocks starting at offset.  This default sends a
+     * buffer of zeros through write, implementations that can zero blocks
+     * without moving that buffer around should override it.
+     */
+    fn write_zeroes(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        let bs = self.get_block_size()?;
+        let data = Bytes::from(vec![0; (len.value * bs) as usize]);
+        self.write(offset, data)
+    }
+
     fn byte_offset_to_block(
         &self,
         offset: u64,
@@ -181,36 +225,60 @@ mod cdt {
     fn volume__write__start(_: u32, _: Uuid) {}
     fn volume__writeunwritten__start(_: u32, _: Uuid) {}
     fn volume__flush__start(_: u32, _: Uuid) {}
+    fn volume__discard__start(_: u32, _: Uuid) {}
+    fn volume__writezeroes__start(_: u32, _: Uuid) {}
+    fn volume__compareandwrite__start(_: u32, _: Uuid) {}
     fn gw__read__start(_: u64) {}
     fn gw__write__start(_: u64) {}
     fn gw__write__unwritten__start(_: u64) {}
     fn gw__flush__start(_: u64) {}
+    fn gw__discard__start(_: u64) {}
+    fn gw__write__zeroes__start(_: u64) {}
+    fn gw__compare__and__write__start(_: u64) {}
     fn up__to__ds__read__start(_: u64) {}
     fn up__to__ds__write__start(_: u64) {}
     fn up__to__ds__write__unwritten__start(_: u64) {}
     fn up__to__ds__flush__start(_: u64) {}
+    fn up__to__ds__discard__start(_: u64) {}
+    fn up__to__ds__write__zeroes__start(_: u64) {}
+    fn up__to__ds__compare__and__write__start(_: u64) {}
     fn ds__read__io__start(_: u64, _: u64) {}
     fn ds__write__io__start(_: u64, _: u64) {}
     fn ds__write__unwritten__io__start(_: u64, _: u64) {}
     fn ds__flush__io__start(_: u64, _: u64) {}
+    fn ds__discard__io__start(_: u64, _: u64) {}
+    fn ds__write__zeroes__io__start(_: u64, _: u64) {}
+    fn ds__compare__and__write__io__start(_: u64, _: u64) {}
     fn ds__read__io__done(_: u64, _: u64) {}
     fn ds__write__io__done(_: u64, _: u64) {}
     fn ds__write__unwritten__io__done(_: u64, _: u64) {}
     fn ds__flush__io__done(_: u64, _: u64) {}
+    fn ds__discard__io__done(_: u64, _: u64) {}
+    fn ds__write__zeroes__io__done(_: u64, _: u64) {}
+    fn ds__compare__and__write__io__done(_: u64, _: u64) {}
     fn up__to__ds__read__done(_: u64) {}
     fn up__to__ds__write__done(_: u64) {}
     fn up__to__ds__write__unwritten__done(_: u64) {}
     fn up__to__ds__flush__done(_: u64) {}
+    fn up__to__ds__discard__done(_: u64) {}
+    fn up__to__ds__write__zeroes__done(_: u64) {}
+    fn up__to__ds__compare__and__write__done(_: u64) {}
     fn gw__read__done(_: u64) {}
     fn gw__write__done(_: u64) {}
     fn gw__write__unwritten__done(_: u64) {}
     fn gw__flush__done(_: u64) {}
+    fn gw__discard__done(_: u64) {}
+    fn gw__write__zeroes__done(_: u64) {}
+    fn gw__compare__and__write__done(_: u64) {}
     fn reqwest__read__start(_: u32, _: Uuid) {}
     fn reqwest__read__done(_: u32, _: Uuid) {}
     fn volume__read__done(_: u32, _: Uuid) {}
     fn volume__write__done(_: u32, _: Uuid) {}
     fn volume__writeunwritten__done(_: u32, _: Uuid) {}
     fn volume__flush__done(_: u32, _: Uuid) {}
+    fn volume__discard__done(_: u32, _: Uuid) {}
+    fn volume__writezeroes__done(_: u32, _: Uuid) {}
+    fn volume__compareandwrite__done(_: u32, _: Uuid) {}
 }
 
 pub fn deadline_secs(secs: u64) -> Instant {
@@ -272,7 +340,7 @@ async fn process_message(
                 result.clone().map(|_| Vec::new()),
             )
         }
-        Message::ReadResponse {
+        Message::ReadRangeResponse {
             upstairs_id,
             session_id,
             job_id,
@@ -281,6 +349,75 @@ async fn process_message(
             cdt::ds__read__io__done!(|| (job_id, up_coms.client_id as u64));
             (*upstairs_id, *session_id, *job_id, responses.clone())
         }
+        Message::ReadResponse {
+            upstairs_id,
+            session_id,
+            job_id,
+            responses,
+        } => {
+            /*
+             * Without Features::READ_RANGES every block comes back on its
+             * own, in the order we asked for them, and each is a range of
+             * one block from here on.
+             */
+            cdt::ds__read__io__done!(|| (job_id, up_coms.client_id as u64));
+            (
+                *upstairs_id,
+                *session_id,
+                *job_id,
+                responses.clone().map(|responses| {
+                    responses.into_iter().map(ReadResponse::from).collect()
+                }),
+            )
+        }
+        Message::DiscardAck {
+            upstairs_id,
+            session_id,
+            job_id,
+            result,
+        } => {
+            cdt::ds__discard__io__done!(|| (job_id, up_coms.client_id as u64));
+            (
+                *upstairs_id,
+                *session_id,
+                *job_id,
+                result.clone().map(|_| Vec::new()),
+            )
+        }
+        Message::WriteZeroesAck {
+            upstairs_id,
+            session_id,
+            job_id,
+            result,
+        } => {
+            cdt::ds__write__zeroes__io__done!(|| (
+                job_id,
+                up_coms.client_id as u64
+            ));
+            (
+                *upstairs_id,
+                *session_id,
+                *job_id,
+                result.clone().map(|_| Vec::new()),
+            )
+        }
+        Message::CompareAndWriteAck {
+            upstairs_id,
+            session_id,
+            job_id,
+            result,
+        } => {
+            cdt::ds__compare__and__write__io__done!(|| (
+                job_id,
+                up_coms.client_id as u64
+            ));
+            (
+                *upstairs_id,
+                *session_id,
+                *job_id,
+                result.clone().map(|_| Vec::new()),
+            )
+        }
         /*
          * For this case, we will (TODO) want to log an error to someone, but
          * I don't think there is anything else we can do.
@@ -382,6 +519,35 @@ pub fn extent_from_offset(
     Ok(result)
 }
 
+/*
+ * Like extent_from_offset, but collapse the blocks into one contiguous
+ * range per extent:
+ *
+ *     (Extent number (EID), Block offset, Number of blocks)
+ *
+ * For operations like discard that carry no data, there is no reason to
+ * send the downstairs each block on its own.
+ */
+pub fn extent_ranges_from_offset(
+    ddef: RegionDefinition,
+    offset: Block,
+    num_blocks: Block,
+) -> Result<Vec<(u64, Block, u64)>> {
+    let mut result: Vec<(u64, Block, u64)> = Vec::new();
+
+    for (eid, bo) in extent_from_offset(ddef, offset, num_blocks)? {
+        if let Some(last) = result.last_mut() {
+            if last.0 == eid && last.1.value + last.2 == bo.value {
+                last.2 += 1;
+                continue;
+            }
+        }
+        result.push((eid, bo, 1));
+    }
+
+    Ok(result)
+}
+
 /*
  * This function is called by a worker task after the main task has added
  * work to the hashmap and notified the worker tasks that new work is ready
@@ -455,12 +621,35 @@ where
          * If in_progress returns None, it means that this client should
          * be skipped.
          */
-        let job = u.downstairs.lock().unwrap().in_progress(*new_id, client_id);
+        let (job, trace) = {
+            let mut ds = u.downstairs.lock().unwrap();
+            (
+                ds.in_progress(*new_id, client_id),
+                ds.trace_context(*new_id),
+            )
+        };
         if job.is_none() {
             continue;
         }
 
         active_count += 1;
+
+        /*
+         * The trace goes just ahead of the job it is for, and only to a
+         * downstairs that knows what to do with it.
+         */
+        if let Some(trace) = trace {
+            if u.ds_features(client_id).contains(Features::TRACE_CONTEXT) {
+                fw.send(Message::JobTrace {
+                    upstairs_id: u.uuid,
+                    session_id: u.session_id,
+                    job_id: *new_id,
+                    trace,
+                })
+                .await?;
+            }
+        }
+
         match job.unwrap() {
             IOop::Write {
                 dependencies,
@@ -516,12 +705,168 @@ where
                 requests,
             } => {
                 cdt::ds__read__io__start!(|| (*new_id, client_id as u64));
-                fw.send(Message::ReadRequest {
+                if u.ds_features(client_id).contains(Features::READ_RANGES) {
+                    fw.send(Message::ReadRangeRequest {
+                        upstairs_id: u.uuid,
+                        session_id: u.session_id,
+                        job_id: *new_id,
+                        dependencies: dependencies.clone(),
+                        requests,
+                    })
+                    .await?
+                } else {
+                    fw.send(Message::ReadRequest {
+                        upstairs_id: u.uuid,
+                        session_id: u.session_id,
+                        job_id: *new_id,
+                        dependencies: dependencies.clone(),
+                        requests: requests
+                            .iter()
+                            .flat_map(ReadRequest::blocks)
+                            .collect(),
+                    })
+                    .await?
+                }
+            }
+            IOop::Discard {
+                dependencies,
+                discards,
+            } => {
+                /*
+                 * Discard jobs are only created when every downstairs
+                 * negotiated the feature, but one may have been replaced
+                 * by an older downstairs since then.  That one gets the
+                 * blocks zeroed instead, which reads back the same.  As
+                 * for any write zeroes, an encrypted region needs them
+                 * written with an encryption context.
+                 */
+                let features = u.ds_features(client_id);
+                if features.contains(Features::DISCARD) {
+                    cdt::ds__discard__io__start!(|| (
+                        *new_id,
+                        client_id as u64
+                    ));
+                    fw.send(Message::Discard {
+                        upstairs_id: u.uuid,
+                        session_id: u.session_id,
+                        job_id: *new_id,
+                        dependencies: dependencies.clone(),
+                        discards,
+                    })
+                    .await?
+                } else if features.contains(Features::WRITE_ZEROES)
+                    && u.encryption_context.is_none()
+                {
+                    cdt::ds__write__zeroes__io__start!(|| (
+                        *new_id,
+                        client_id as u64
+                    ));
+                    fw.send(Message::WriteZeroes {
+                        upstairs_id: u.uuid,
+                        session_id: u.session_id,
+                        job_id: *new_id,
+                        dependencies: dependencies.clone(),
+                        zeroes: discards
+                            .iter()
+                            .map(|d| crucible_protocol::WriteZeroes {
+                                eid: d.eid,
+                                offset: d.offset,
+                                num_blocks: d.num_blocks,
+                            })
+                            .collect(),
+                    })
+                    .await?
+                } else {
+                    let mut writes = Vec::new();
+                    for d in discards.iter() {
+                        writes.extend(u.zero_writes(
+                            d.eid,
+                            d.offset,
+                            d.num_blocks,
+                        )?);
+                    }
+                    cdt::ds__write__io__start!(|| (*new_id, client_id as u64));
+                    fw.send(Message::Write {
+                        upstairs_id: u.uuid,
+                        session_id: u.session_id,
+                        job_id: *new_id,
+                        dependencies: dependencies.clone(),
+                        writes,
+                    })
+                    .await?
+                }
+            }
+            IOop::WriteZeroes {
+                dependencies,
+                zeroes,
+            } => {
+                /*
+                 * Same as for a discard, see above, but the fallback is
+                 * a write of zeros.
+                 */
+                if u.ds_features(client_id).contains(Features::WRITE_ZEROES) {
+                    cdt::ds__write__zeroes__io__start!(|| (
+                        *new_id,
+                        client_id as u64
+                    ));
+                    fw.send(Message::WriteZeroes {
+                        upstairs_id: u.uuid,
+                        session_id: u.session_id,
+                        job_id: *new_id,
+                        dependencies: dependencies.clone(),
+                        zeroes,
+                    })
+                    .await?
+                } else {
+                    let mut writes = Vec::new();
+                    for z in zeroes.iter() {
+                        writes.extend(u.zero_writes(
+                            z.eid,
+                            z.offset,
+                            z.num_blocks,
+                        )?);
+                    }
+                    cdt::ds__write__io__start!(|| (*new_id, client_id as u64));
+                    fw.send(Message::Write {
+                        upstairs_id: u.uuid,
+                        session_id: u.session_id,
+                        job_id: *new_id,
+                        dependencies: dependencies.clone(),
+                        writes,
+                    })
+                    .await?
+                }
+            }
+            IOop::CompareAndWrite {
+                dependencies,
+                writes,
+            } => {
+                /*
+                 * As for a discard, this downstairs may have replaced one
+                 * that negotiated the feature, but nothing else does what
+                 * a compare and write does.
+                 */
+                if !u
+                    .ds_features(client_id)
+                    .contains(Features::COMPARE_AND_WRITE)
+                {
+                    bail!(
+                        "[{}] job {} is a compare and write, but that was \
+                        not negotiated with this downstairs",
+                        client_id,
+                        new_id,
+                    );
+                }
+                cdt::ds__compare__and__write__io__start!(|| (
+                    *new_id,
+                    client_id as u64
+                ));
+                fw.send(Message::CompareAndWrite {
                     upstairs_id: u.uuid,
                     session_id: u.session_id,
                     job_id: *new_id,
                     dependencies: dependencies.clone(),
-                    requests,
+                    writes,
                 })
                 .await?
             }
@@ -537,20 +882,44 @@ async fn proc_stream(
     connected: &mut bool,
     up_coms: &mut UpComs,
 ) -> Result<()> {
+    let mut decoder = CrucibleDecoder::new();
+    let mut encoder = CrucibleEncoder::new();
+
+    if let Some(capture_dir) = &up.capture_dir {
+        let name = format!("upstairs-{}-{}", up.uuid, up_coms.client_id);
+        match Recorder::create_in(capture_dir, &name, Side::Upstairs) {
+            Ok(recorder) => {
+                let recorder = Arc::new(recorder);
+                decoder.set_recorder(recorder.clone());
+                encoder.set_recorder(recorder);
+            }
+            Err(e) => {
+                /*
+                 * A capture is a debugging aid, not worth refusing to
+                 * talk to the downstairs over.
+                 */
+                println!(
+                    "[{}] not capturing this connection: {:?}",
+                    up_coms.client_id, e
+                );
+            }
+        }
+    }
+
     match stream {
         WrappedStream::Http(sock) => {
             let (read, write) = sock.into_split();
 
-            let fr = FramedRead::new(read, CrucibleDecoder::new());
-            let fw = FramedWrite::new(write, CrucibleEncoder::new());
+            let fr = FramedRead::new(read, decoder);
+            let fw = FramedWrite::new(write, encoder);
 
             proc(target, up, fr, fw, connected, up_coms).await
         }
         WrappedStream::Https(stream) => {
             let (read, write) = tokio::io::split(stream);
 
-            let fr = FramedRead::new(read, CrucibleDecoder::new());
-            let fw = FramedWrite::new(write, CrucibleEncoder::new());
+            let fr = FramedRead::new(read, decoder);
+            let fw = FramedWrite::new(write, encoder);
 
             proc(target, up, fr, fw, connected, up_coms).await
         }
@@ -620,12 +989,13 @@ where
      * As the "client", we must begin the negotiation.
      */
     let m = Message::HereIAm {
-        version: 1,
+        version: CRUCIBLE_MESSAGE_VERSION,
         upstairs_id: up.uuid,
         session_id: up.session_id,
         gen: up.get_generation(),
         read_only: up.read_only,
         encrypted: up.encrypted(),
+        features: Features::supported(),
     };
     fw.send(m).await?;
 
@@ -786,7 +1156,7 @@ where
                             up.encrypted(),
                         );
                     }
-                    Some(Message::YesItsMe { version }) => {
+                    Some(Message::YesItsMe { version, features }) => {
                         if negotiated != 0 {
                             bail!("Got version already!");
                         }
@@ -796,13 +1166,55 @@ where
                          * from main task. In the future we will also have
                          * to handle a version mismatch.
                          */
-                        if version != 1 {
+                        if version != CRUCIBLE_MESSAGE_VERSION {
+                            up.ds_transition(
+                                up_coms.client_id,
+                                DsState::BadVersion
+                            );
+                            bail!(
+                                "expected version {}, got {}",
+                                CRUCIBLE_MESSAGE_VERSION,
+                                version
+                            );
+                        }
+
+                        /*
+                         * The downstairs answers with the features it will
+                         * use, which must be a subset of what we offered.
+                         */
+                        if !Features::supported().contains(features) {
                             up.ds_transition(
                                 up_coms.client_id,
                                 DsState::BadVersion
                             );
-                            bail!("expected version 1, got {}", version);
+                            bail!(
+                                "downstairs features {} not a subset of {}",
+                                features,
+                                Features::supported(),
+                            );
                         }
+                        up.set_ds_features(up_coms.client_id, features);
+
+                        /*
+                         * The downstairs compresses what it sends us from
+                         * here on, so we do the same.  Hashes are checked
+                         * after the frame is uncompressed, so compression
+                         * doesn't change what they cover.
+                         */
+                        fw.encoder_mut().set_compression(
+                            Compression::from_features(features)?
+                        );
+
+                        /*
+                         * Likewise every frame after YesItsMe, in both
+                         * directions, carries a checksum if agreed to.
+                         * A frame that fails it ends this connection, and
+                         * the reconnect replays whatever was in flight.
+                         */
+                        let checksum =
+                            features.contains(Features::FRAME_CHECKSUM);
+                        fw.encoder_mut().set_checksum(checksum);
+                        fr.decoder_mut().set_require_checksum(checksum);
                         negotiated = 1;
                         /*
                          * We only set guest_io_ready after all three downstairs
@@ -1383,6 +1795,9 @@ where
                             up_coms.client_id, expected_id
                         );
                     }
+                    Some(Message::RegionGrown { region_def }) => {
+                        up.ds_region_grown(up_coms.client_id, region_def)?;
+                    }
                     Some(m) => {
                         tx.send(m).await?;
                     }
@@ -1604,6 +2019,9 @@ where
                     Some(Message::Imok) => {
                         println!("[{}] Received Imok", up_coms.client_id);
                     }
+                    Some(Message::RegionGrown { region_def }) => {
+                        up.ds_region_grown(up_coms.client_id, region_def)?;
+                    }
                     Some(Message::ExtentError {
                         repair_id,
                         extent_id,
@@ -2034,6 +2452,12 @@ struct Downstairs {
      * UUID for each downstairs, index by client ID
      */
     ds_uuid: HashMap<u8, Uuid>,
+    /*
+     * How many extents each downstairs region has, index by client ID.
+     * A region can grow, and only the extents every downstairs has are
+     * ours to use.
+     */
+    ds_extent_count: HashMap<u8, u32>,
     /*
      * The IP:Port for repair when contacting the downstairs, hashed by
      * the client index the upstairs gives it..
@@ -2051,6 +2475,11 @@ struct Downstairs {
      * The last flush ID that each downstairs has acked.
      */
     ds_last_flush: Vec<u64>,
+    /*
+     * The protocol features negotiated with each downstairs, based on
+     * client ID.  This is replaced each time a downstairs reconnects.
+     */
+    ds_features: Vec<Features>,
     downstairs_errors: HashMap<u8, u64>, // client id -> errors
     active: HashMap<u64, DownstairsIO>,
     next_id: u64,
@@ -2102,9 +2531,11 @@ impl Downstairs {
 
         Self {
             ds_uuid: HashMap::new(),
+            ds_extent_count: HashMap::new(),
             ds_repair,
             ds_state: vec![DsState::New; 3],
             ds_last_flush: vec![0; 3],
+            ds_features: vec![Features::empty(); 3],
             downstairs_errors: HashMap::new(),
             active: HashMap::new(),
             completed: AllocRingBuffer::with_capacity(2048),
@@ -2153,6 +2584,13 @@ impl Downstairs {
         }
     }
 
+    /*
+     * The trace context to send to the downstairs along with a job.
+     */
+    fn trace_context(&self, ds_id: u64) -> Option<TraceContext> {
+        self.active.get(&ds_id).and_then(|job| job.trace)
+    }
+
     /**
      * Verify this Downstairs region set is still in a state where
      * reconciliation can continue.
@@ -2505,6 +2943,49 @@ impl Downstairs {
         ackable
     }
 
+    /**
+     * The jobs a new IO using the given blocks has to wait for.  Every IO
+     * waits for the newest active flush, which has itself waited for
+     * everything before it.  After that flush, an IO only waits for jobs
+     * that use some of the same blocks, and a read never waits for
+     * another read.  That leaves the downstairs free to do the rest at
+     * the same time.
+     */
+    fn dependencies<I>(&self, blocks: I, is_read: bool) -> Vec<u64>
+    where
+        I: IntoIterator<Item = ExtentBlocks>,
+    {
+        let blocks = ExtentBlocks::merge(blocks);
+
+        let last_flush = self
+            .active
+            .iter()
+            .filter(|(_, job)| matches!(job.work, IOop::Flush { .. }))
+            .map(|(ds_id, _)| *ds_id)
+            .max();
+
+        let mut dep: Vec<u64> = self
+            .active
+            .iter()
+            .filter(|(ds_id, job)| {
+                if last_flush.map_or(false, |flush| **ds_id <= flush) {
+                    return false;
+                }
+                if is_read && matches!(job.work, IOop::Read { .. }) {
+                    return false;
+                }
+                job.work
+                    .blocks()
+                    .iter()
+                    .any(|other| blocks.iter().any(|b| b.overlaps(other)))
+            })
+            .map(|(ds_id, _)| *ds_id)
+            .chain(last_flush)
+            .collect();
+        dep.sort_unstable();
+        dep
+    }
+
     /**
      * Enqueue a new downstairs request.
      */
@@ -2585,6 +3066,38 @@ impl Downstairs {
                 gen_number: _gen_number,
                 snapshot_details: _,
             } => wc.error >= 2,
+            IOop::Discard {
+                dependencies: _dependencies,
+                discards: _,
+            } => wc.error >= 2,
+            IOop::WriteZeroes {
+                dependencies: _dependencies,
+                zeroes: _,
+            } => wc.error >= 2,
+            IOop::CompareAndWrite {
+                dependencies: _dependencies,
+                writes: _,
+            } => {
+                /*
+                 * A compare and write only takes effect where two
+                 * downstairs wrote it.  See compare_and_write_diverged
+                 * for what happens to the odd one out.
+                 */
+                let mismatches = job.compare_mismatches();
+                if wc.done >= 2 {
+                    return Ok(());
+                } else if mismatches >= 2 {
+                    return Err(CrucibleError::CompareMismatch);
+                } else {
+                    return Err(CrucibleError::IoError(format!(
+                        "no two downstairs agree on compare and write, \
+                        {} wrote, {} did not match, {} failed",
+                        wc.done,
+                        mismatches,
+                        wc.error + wc.skipped - mismatches,
+                    )));
+                }
+            }
         };
 
         if bad_job {
@@ -2646,21 +3159,66 @@ impl Downstairs {
                 cdt::gw__flush__done!(|| (gw_id));
                 stats.add_flush();
             }
+            IOop::Discard {
+                dependencies: _,
+                discards: _,
+            } => {
+                cdt::gw__discard__done!(|| (gw_id));
+                // Discards move no data, so they are not in the metrics
+                // for this guest.
+            }
+            IOop::WriteZeroes {
+                dependencies: _,
+                zeroes: _,
+            } => {
+                cdt::gw__write__zeroes__done!(|| (gw_id));
+                stats.add_write(io_size as i64);
+            }
+            IOop::CompareAndWrite {
+                dependencies: _,
+                writes: _,
+            } => {
+                cdt::gw__compare__and__write__done!(|| (gw_id));
+                stats.add_write(io_size as i64);
+            }
+        }
+    }
+
+    /*
+     * A read response carries one entry in blocks for each block of data,
+     * make sure a downstairs did not send back a mismatched set.
+     */
+    fn check_read_response_blocks(
+        response: &ReadResponse,
+    ) -> Result<(), CrucibleError> {
+        let bs = response.offset.block_size_in_bytes() as usize;
+        let data_blocks = (response.data.len() + bs - 1) / bs;
+        if data_blocks != response.blocks.len() {
+            crucible_bail!(
+                InvalidNumberOfBlocks,
+                "read response for {}:{} has {} blocks of data, {} of metadata",
+                response.eid,
+                response.offset.value,
+                data_blocks,
+                response.blocks.len(),
+            );
         }
+        Ok(())
     }
 
     fn validate_unencrypted_read_response(
-        response: &mut ReadResponse,
+        data: &mut [u8],
+        block: &ReadResponseBlockMetadata,
     ) -> Result<Option<u64>, CrucibleError> {
         // check integrity hashes - make sure at least one is correct.
         let mut vh = None;
-        if !response.hashes.is_empty() {
+        if !block.hashes.is_empty() {
             let mut successful_hash = false;
 
-            let computed_hash = integrity_hash(&[&response.data[..]]);
+            let computed_hash = integrity_hash(&[&data[..]]);
 
             // The most recent hash is probably going to be the right one.
-            for hash in response.hashes.iter().rev() {
+            for hash in block.hashes.iter().rev() {
                 if computed_hash == *hash {
                     successful_hash = true;
                     vh = Some(*hash);
@@ -2671,10 +3229,10 @@ impl Downstairs {
             if !successful_hash {
                 // No integrity hash was correct for this response
                 println!("No match computed hash:0x{:x}", computed_hash,);
-                for hash in response.hashes.iter().rev() {
+                for hash in block.hashes.iter().rev() {
                     println!("No match          hash:0x{:x}", hash);
                 }
-                println!("Data from hash: {:?}", response.data);
+                println!("Data from hash: {:?}", data);
 
                 return Err(CrucibleError::HashMismatch);
             }
@@ -2685,14 +3243,15 @@ impl Downstairs {
             // removed the hashes from the db.
             //
             // XXX if it's not a blank block, we may be under attack?
-            assert!(response.data[..].iter().all(|&x| x == 0));
+            assert!(data[..].iter().all(|&x| x == 0));
         }
 
         Ok(vh)
     }
 
     fn validate_encrypted_read_response(
-        response: &mut ReadResponse,
+        data: &mut [u8],
+        block: &ReadResponseBlockMetadata,
         encryption_context: &Arc<EncryptionContext>,
     ) -> Result<Option<u64>, CrucibleError> {
         // XXX because we don't have block generation numbers, an attacker
@@ -2703,7 +3262,7 @@ impl Downstairs {
         //
         // check for response encryption contexts here
         let mut vh = None;
-        if !response.encryption_contexts.is_empty() {
+        if !block.encryption_contexts.is_empty() {
             let mut successful_decryption = false;
             let mut successful_hash = false;
 
@@ -2711,25 +3270,19 @@ impl Downstairs {
             // do not work. The most recent encryption context will most likely
             // be the correct one so start there.
             let encryption_context_iter =
-                response.encryption_contexts.iter().enumerate().rev();
+                block.encryption_contexts.iter().enumerate().rev();
 
             // Hashes and encryption contexts are written out at the same time
             // (in the same transaction) therefore there should be the same
             // number of them.
-            assert_eq!(
-                response.encryption_contexts.len(),
-                response.hashes.len(),
-            );
+            assert_eq!(block.encryption_contexts.len(), block.hashes.len(),);
 
             for (i, ctx) in encryption_context_iter {
                 // Validate integrity hash before decryption
-                let computed_hash = integrity_hash(&[
-                    &ctx.nonce[..],
-                    &ctx.tag[..],
-                    &response.data[..],
-                ]);
+                let computed_hash =
+                    integrity_hash(&[&ctx.nonce[..], &ctx.tag[..], &data[..]]);
 
-                if computed_hash == response.hashes[i] {
+                if computed_hash == block.hashes[i] {
                     successful_hash = true;
                     vh = Some(computed_hash);
 
@@ -2741,7 +3294,7 @@ impl Downstairs {
                     // unit test to validate this behaviour.
                     let decryption_result = encryption_context
                         .decrypt_in_place(
-                            &mut response.data[..],
+                            &mut data[..],
                             Nonce::from_slice(&ctx.nonce[..]),
                             Tag::from_slice(&ctx.tag[..]),
                         );
@@ -2768,16 +3321,15 @@ impl Downstairs {
 
             if !successful_hash {
                 println!("No match for encrypted computed hash");
-                for (i, ctx) in response.encryption_contexts.iter().enumerate()
-                {
+                for (i, ctx) in block.encryption_contexts.iter().enumerate() {
                     let computed_hash = integrity_hash(&[
                         &ctx.nonce[..],
                         &ctx.tag[..],
-                        &response.data[..],
+                        &data[..],
                     ]);
                     println!(
                         "Expected: 0x{:x} != Computed: 0x{:x}",
-                        response.hashes[i], computed_hash
+                        block.hashes[i], computed_hash
                     );
                 }
                 // no hash was correct
@@ -2796,7 +3348,7 @@ impl Downstairs {
             // removed the encryption contexts from the db.
             //
             // XXX if it's not a blank block, we may be under attack?
-            assert!(response.data[..].iter().all(|&x| x == 0));
+            assert!(data[..].iter().all(|&x| x == 0));
         }
 
         Ok(vh)
@@ -2851,11 +3403,17 @@ impl Downstairs {
                 if let Ok(mut responses) = responses {
                     let result: Result<(), CrucibleError> =
                         responses.iter_mut().try_for_each(|x| {
-                            let mh =
+                            Downstairs::check_read_response_blocks(x)?;
+                            let bs = x.offset.block_size_in_bytes() as usize;
+                            for (data, block) in
+                                x.data.chunks_mut(bs).zip(x.blocks.iter())
+                            {
+                                let mh =
                                 Downstairs::validate_encrypted_read_response(
-                                    x, context,
+                                    data, block, context,
                                 )?;
-                            read_response_hashes.push(mh);
+                                read_response_hashes.push(mh);
+                            }
                             Ok(())
                         });
 
@@ -2878,11 +3436,17 @@ impl Downstairs {
                 if let Ok(mut responses) = responses {
                     let result: Result<(), CrucibleError> =
                         responses.iter_mut().try_for_each(|x| {
-                            let mh =
+                            Downstairs::check_read_response_blocks(x)?;
+                            let bs = x.offset.block_size_in_bytes() as usize;
+                            for (data, block) in
+                                x.data.chunks_mut(bs).zip(x.blocks.iter())
+                            {
+                                let mh =
                                 Downstairs::validate_unencrypted_read_response(
-                                    x,
+                                    data, block,
                                 )?;
-                            read_response_hashes.push(mh);
+                                read_response_hashes.push(mh);
+                            }
                             Ok(())
                         });
 
@@ -2936,6 +3500,20 @@ impl Downstairs {
                 CrucibleError::SnapshotExistsAlready(_) => {
                     // pass
                 }
+                CrucibleError::CompareMismatch => {
+                    // This downstairs did what was asked of it, the blocks
+                    // just did not hold what the guest expected.  Once two
+                    // agree on that, the guest can hear about it.
+                    if job.ack_status == AckStatus::NotAcked
+                        && job.compare_mismatches() == 2
+                    {
+                        notify_guest = true;
+                        job.ack_status = AckStatus::AckReady;
+                        cdt::up__to__ds__compare__and__write__done!(
+                            || job.guest_id
+                        );
+                    }
+                }
                 _ => {
                     // Mark this downstairs as bad if this was a write or flush
                     // XXX: reconcilation, retries?
@@ -2954,6 +3532,18 @@ impl Downstairs {
                             flush_number: _,
                             gen_number: _,
                             snapshot_details: _,
+                        }
+                        | IOop::Discard {
+                            dependencies: _,
+                            discards: _,
+                        }
+                        | IOop::WriteZeroes {
+                            dependencies: _,
+                            zeroes: _,
+                        }
+                        | IOop::CompareAndWrite {
+                            dependencies: _,
+                            writes: _,
                         } => {
                             let errors: u64 =
                                 match self.downstairs_errors.get(&client_id) {
@@ -3048,7 +3638,12 @@ impl Downstairs {
                         }
                     }
                 }
-                _ => { /* Write and WriteUnwritten IOs have no action here */ }
+                _ => {
+                    /*
+                     * Write, WriteUnwritten, Discard, WriteZeroes, and
+                     * CompareAndWrite IOs have no action here
+                     */
+                }
             }
         } else {
             assert_eq!(newstate, IOState::Done);
@@ -3123,7 +3718,42 @@ impl Downstairs {
                         );
                     }
                 }
-                IOop::Flush {
+                IOop::Discard {
+                    dependencies: _,
+                    discards: _,
+                } => {
+                    assert!(read_data.is_empty());
+                    if jobs_completed_ok == 2 {
+                        notify_guest = true;
+                        job.ack_status = AckStatus::AckReady;
+                        cdt::up__to__ds__discard__done!(|| job.guest_id);
+                    }
+                }
+                IOop::WriteZeroes {
+                    dependencies: _,
+                    zeroes: _,
+                } => {
+                    assert!(read_data.is_empty());
+                    if jobs_completed_ok == 2 {
+                        notify_guest = true;
+                        job.ack_status = AckStatus::AckReady;
+                        cdt::up__to__ds__write__zeroes__done!(|| job.guest_id);
+                    }
+                }
+                IOop::CompareAndWrite {
+                    dependencies: _,
+                    writes: _,
+                } => {
+                    assert!(read_data.is_empty());
+                    if jobs_completed_ok == 2 {
+                        notify_guest = true;
+                        job.ack_status = AckStatus::AckReady;
+                        cdt::up__to__ds__compare__and__write__done!(
+                            || job.guest_id
+                        );
+                    }
+                }
+                IOop::Flush {
                     dependencies: _dependencies,
                     flush_number: _flush_number,
                     gen_number: _gen_number,
@@ -3281,6 +3911,57 @@ impl Downstairs {
             Ok(())
         }
     }
+
+    /*
+     * Once every downstairs is finished with a compare and write, find the
+     * one (if any) whose blocks now differ from the others.  With three
+     * downstairs, at most one can be the odd one out:
+     *
+     * - If two wrote, one that found a mismatch is missing the write.
+     * - Otherwise the write did not take effect, so one that wrote has
+     *   data the others do not.
+     *
+     * Downstairs that failed or were skipped are being dealt with already.
+     * A replayed job may find a downstairs already did the write before it
+     * went away, so a replay is only reported, as with read hashes.
+     */
+    fn compare_and_write_diverged(&self, ds_id: u64) -> Option<u8> {
+        let job = self.active.get(&ds_id)?;
+        if !matches!(job.work, IOop::CompareAndWrite { .. }) {
+            return None;
+        }
+
+        let wc = job.state_count();
+        if wc.active != 0 {
+            return None;
+        }
+
+        let mismatches = job.compare_mismatches();
+        if wc.done == 0 || mismatches == 0 {
+            return None;
+        }
+
+        let odd_one_out = if wc.done >= 2 {
+            IOState::Error(CrucibleError::CompareMismatch)
+        } else {
+            IOState::Done
+        };
+        let client_id = job
+            .state
+            .iter()
+            .find(|(_, state)| **state == odd_one_out)
+            .map(|(client_id, _)| *client_id)?;
+
+        if job.replay {
+            println!(
+                "[{}] compare and write {} diverged on replay {:?}",
+                client_id, ds_id, job.state,
+            );
+            return None;
+        }
+
+        Some(client_id)
+    }
 }
 
 /// Implement AES-GCM-SIV encryption
@@ -3521,6 +4202,12 @@ pub struct Upstairs {
      * Operate in read-only mode
      */
     read_only: bool,
+
+    /*
+     * If set, record each connection to a downstairs to a capture file
+     * in this directory.
+     */
+    capture_dir: Option<PathBuf>,
 }
 
 impl Upstairs {
@@ -3536,6 +4223,7 @@ impl Upstairs {
             root_cert_pem: None,
             control: None,
             read_only: false,
+            capture_dir: None,
         };
         Self::new(
             &opts,
@@ -3602,6 +4290,7 @@ impl Upstairs {
             stats,
             lossy: opt.lossy,
             read_only: opt.read_only,
+            capture_dir: opt.capture_dir.clone(),
         })
     }
 
@@ -3943,6 +4632,62 @@ impl Upstairs {
         ds.ds_last_flush[client_id as usize]
     }
 
+    fn ds_features(&self, client_id: u8) -> Features {
+        let ds = self.downstairs.lock().unwrap();
+        ds.ds_features[client_id as usize]
+    }
+
+    /*
+     * A Write of zeros to each of num_blocks blocks from offset, for a
+     * downstairs that can't be asked to zero them any other way.
+     */
+    fn zero_writes(
+        &self,
+        eid: u64,
+        offset: Block,
+        num_blocks: u64,
+    ) -> Result<Vec<crucible_protocol::Write>, CrucibleError> {
+        let zeros =
+            Bytes::from(vec![0u8; offset.block_size_in_bytes() as usize]);
+        let zeros_hash = integrity_hash(&[&zeros[..]]);
+
+        let mut writes = Vec::with_capacity(num_blocks as usize);
+        for block in offset.value..offset.value + num_blocks {
+            let (data, encryption_context, hash) =
+                if let Some(context) = &self.encryption_context {
+                    let mut data = zeros.to_vec();
+                    let (nonce, tag, hash) =
+                        context.encrypt_in_place(&mut data[..])?;
+                    (
+                        Bytes::from(data),
+                        Some(crucible_protocol::EncryptionContext {
+                            nonce: Vec::from(nonce.as_slice()),
+                            tag: Vec::from(tag.as_slice()),
+                        }),
+                        hash,
+                    )
+                } else {
+                    (zeros.clone(), None, zeros_hash)
+                };
+
+            writes.push(crucible_protocol::Write {
+                eid,
+                offset: Block::new(block, offset.shift),
+                data,
+                encryption_context,
+                hash,
+            });
+        }
+
+        Ok(writes)
+    }
+
+    fn set_ds_features(&self, client_id: u8, features: Features) {
+        let mut ds = self.downstairs.lock().unwrap();
+        println!("[{}] negotiated features {}", client_id, features);
+        ds.ds_features[client_id as usize] = features;
+    }
+
     fn set_flush_clear(&self) {
         let mut flush = self.need_flush.lock().unwrap();
         *flush = false;
@@ -4020,7 +4765,7 @@ impl Upstairs {
          * Build the flush request, and take note of the request ID that
          * will be assigned to this new piece of work.
          */
-        let fl = create_flush(
+        let mut fl = create_flush(
             next_id,
             dep,
             next_flush,
@@ -4028,6 +4773,7 @@ impl Upstairs {
             self.get_generation(),
             snapshot_details,
         );
+        fl.trace = current_trace_context();
 
         let mut sub = HashMap::new();
         sub.insert(next_id, 0);
@@ -4110,9 +4856,6 @@ impl Upstairs {
         let next_id = downstairs.next_id();
         let mut cur_offset: usize = 0;
 
-        let mut dep = downstairs.active.keys().cloned().collect::<Vec<u64>>();
-        dep.sort_unstable();
-
         let mut writes: Vec<crucible_protocol::Write> =
             Vec::with_capacity(nwo.len());
 
@@ -4155,13 +4898,11 @@ impl Upstairs {
             cur_offset += byte_len;
         }
 
-        let wr = create_write_eob(
-            next_id,
-            dep.clone(),
-            gw_id,
-            writes,
-            is_write_unwritten,
-        );
+        let dep = downstairs
+            .dependencies(writes.iter().map(ExtentBlocks::from), false);
+        let mut wr =
+            create_write_eob(next_id, dep, gw_id, writes, is_write_unwritten);
+        wr.trace = current_trace_context();
 
         sub.insert(next_id, 0); // XXX does value here matter?
 
@@ -4183,6 +4924,272 @@ impl Upstairs {
         Ok(())
     }
 
+    /*
+     * When we have a guest discard request with offset and length, build
+     * the upstairs and downstairs work for it the same way a write does.
+     *
+     * The guest is promised the range reads back as zeros afterwards, so
+     * if any downstairs did not negotiate Features::DISCARD then this
+     * turns into a write zeroes instead.
+     */
+    #[instrument]
+    fn submit_discard(
+        &self,
+        offset: Block,
+        len: Block,
+        sender: Option<std_mpsc::Sender<Result<(), CrucibleError>>>,
+    ) -> Result<(), CrucibleError> {
+        if !self.guest_io_ready() {
+            crucible_bail!(UpstairsInactive);
+        }
+
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        let mut gw = self.guest.guest_work.lock().unwrap();
+        let mut downstairs = self.downstairs.lock().unwrap();
+
+        if !downstairs
+            .ds_features
+            .iter()
+            .all(|f| f.contains(Features::DISCARD))
+        {
+            /*
+             * submit_write_zeroes takes both of these locks itself.
+             */
+            drop(downstairs);
+            drop(gw);
+
+            return self.submit_write_zeroes(offset, len, sender);
+        }
+
+        self.set_flush_need();
+
+        let ddef = self.ddef.lock().unwrap();
+        let ranges = extent_ranges_from_offset(*ddef, offset, len)?;
+
+        /*
+         * Grab this ID after extent_ranges_from_offset: in case of Err we
+         * don't want to create a gap in the IDs.
+         */
+        let gw_id: u64 = gw.next_gw_id();
+        cdt::gw__discard__start!(|| (gw_id));
+
+        let mut sub = HashMap::new();
+        let next_id = downstairs.next_id();
+
+        let discards: Vec<crucible_protocol::Discard> = ranges
+            .into_iter()
+            .map(|(eid, offset, num_blocks)| crucible_protocol::Discard {
+                eid,
+                offset,
+                num_blocks,
+            })
+            .collect();
+
+        let dep = downstairs
+            .dependencies(discards.iter().map(ExtentBlocks::from), false);
+        let mut di = create_discard_eob(next_id, dep, gw_id, discards);
+        di.trace = current_trace_context();
+
+        sub.insert(next_id, 0);
+
+        let new_gtos = GtoS::new(sub, Vec::new(), None, HashMap::new(), sender);
+        gw.active.insert(gw_id, new_gtos);
+
+        downstairs.enqueue(di);
+        cdt::up__to__ds__discard__start!(|| (gw_id));
+
+        Ok(())
+    }
+
+    /*
+     * When we have a guest write zeroes request with offset and length,
+     * build the upstairs and downstairs work for it the same way a write
+     * does, but without any data to hash or encrypt.
+     *
+     * If any downstairs did not negotiate Features::WRITE_ZEROES this
+     * turns into a regular write of a buffer full of zeros.  So does it
+     * for an encrypted region, as only we can make the encryption context
+     * the zeroed blocks need to stay written.
+     */
+    #[instrument]
+    fn submit_write_zeroes(
+        &self,
+        offset: Block,
+        len: Block,
+        sender: Option<std_mpsc::Sender<Result<(), CrucibleError>>>,
+    ) -> Result<(), CrucibleError> {
+        if !self.guest_io_ready() {
+            crucible_bail!(UpstairsInactive);
+        }
+
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        let mut gw = self.guest.guest_work.lock().unwrap();
+        let mut downstairs = self.downstairs.lock().unwrap();
+
+        if self.encryption_context.is_some()
+            || !downstairs
+                .ds_features
+                .iter()
+                .all(|f| f.contains(Features::WRITE_ZEROES))
+        {
+            /*
+             * submit_write takes both of these locks itself.
+             */
+            drop(downstairs);
+            drop(gw);
+
+            let data = Bytes::from(vec![0; len.bytes()]);
+            return self.submit_write(offset, data, sender, false);
+        }
+
+        self.set_flush_need();
+
+        let ddef = self.ddef.lock().unwrap();
+        let ranges = extent_ranges_from_offset(*ddef, offset, len)?;
+
+        /*
+         * Grab this ID after extent_ranges_from_offset: in case of Err we
+         * don't want to create a gap in the IDs.
+         */
+        let gw_id: u64 = gw.next_gw_id();
+        cdt::gw__write__zeroes__start!(|| (gw_id));
+
+        let mut sub = HashMap::new();
+        let next_id = downstairs.next_id();
+
+        let zeroes: Vec<crucible_protocol::WriteZeroes> = ranges
+            .into_iter()
+            .map(|(eid, offset, num_blocks)| crucible_protocol::WriteZeroes {
+                eid,
+                offset,
+                num_blocks,
+            })
+            .collect();
+
+        let dep = downstairs
+            .dependencies(zeroes.iter().map(ExtentBlocks::from), false);
+        let mut wz = create_write_zeroes_eob(next_id, dep, gw_id, zeroes);
+        wz.trace = current_trace_context();
+
+        sub.insert(next_id, 0);
+
+        let new_gtos = GtoS::new(sub, Vec::new(), None, HashMap::new(), sender);
+        gw.active.insert(gw_id, new_gtos);
+
+        downstairs.enqueue(wz);
+        cdt::up__to__ds__write__zeroes__start!(|| (gw_id));
+
+        Ok(())
+    }
+
+    /*
+     * When we have a guest compare and write request, build one downstairs
+     * job holding every block with what it should hold now and what to
+     * write there.  The downstairs does the compare and the write as one
+     * job, and like any write it waits for the jobs using the same blocks,
+     * so nothing this upstairs sends can land between the compare and the
+     * write.
+     *
+     * There is no falling back to a read and then a write, as that is not
+     * atomic, so every downstairs must have negotiated the feature.
+     */
+    #[instrument]
+    fn submit_compare_and_write(
+        &self,
+        offset: Block,
+        expected: Bytes,
+        data: Bytes,
+        sender: Option<std_mpsc::Sender<Result<(), CrucibleError>>>,
+    ) -> Result<(), CrucibleError> {
+        if !self.guest_io_ready() {
+            crucible_bail!(UpstairsInactive);
+        }
+
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        if self.encryption_context.is_some() {
+            crucible_bail!(
+                Unsupported,
+                "compare and write on an encrypted region"
+            );
+        }
+
+        let mut gw = self.guest.guest_work.lock().unwrap();
+        let mut downstairs = self.downstairs.lock().unwrap();
+
+        if !downstairs
+            .ds_features
+            .iter()
+            .all(|f| f.contains(Features::COMPARE_AND_WRITE))
+        {
+            crucible_bail!(
+                Unsupported,
+                "compare and write not negotiated with every downstairs"
+            );
+        }
+
+        self.set_flush_need();
+
+        let ddef = self.ddef.lock().unwrap();
+        let nwo = extent_from_offset(
+            *ddef,
+            offset,
+            Block::from_bytes(data.len(), &ddef),
+        )?;
+
+        /*
+         * Grab this ID after extent_from_offset: in case of Err we don't
+         * want to create a gap in the IDs.
+         */
+        let gw_id: u64 = gw.next_gw_id();
+        cdt::gw__compare__and__write__start!(|| (gw_id));
+
+        let mut sub = HashMap::new();
+        let next_id = downstairs.next_id();
+
+        let byte_len: usize = ddef.block_size() as usize;
+        let writes: Vec<crucible_protocol::CompareAndWrite> = nwo
+            .into_iter()
+            .enumerate()
+            .map(|(i, (eid, bo))| {
+                let range = (i * byte_len)..((i + 1) * byte_len);
+                let sub_data = data.slice(range.clone());
+                let hash = integrity_hash(&[&sub_data[..]]);
+
+                crucible_protocol::CompareAndWrite {
+                    eid,
+                    offset: bo,
+                    expected: expected.slice(range),
+                    data: sub_data,
+                    hash,
+                }
+            })
+            .collect();
+
+        let dep = downstairs
+            .dependencies(writes.iter().map(ExtentBlocks::from), false);
+        let mut cw = create_compare_and_write_eob(next_id, dep, gw_id, writes);
+        cw.trace = current_trace_context();
+
+        sub.insert(next_id, 0);
+
+        let new_gtos = GtoS::new(sub, Vec::new(), None, HashMap::new(), sender);
+        gw.active.insert(gw_id, new_gtos);
+
+        downstairs.enqueue(cw);
+        cdt::up__to__ds__compare__and__write__start!(|| (gw_id));
+
+        Ok(())
+    }
+
     /*
      * When we have a guest read request with offset and buffer, take them
      * and build both the upstairs work guest tracking struct as well as the
@@ -4214,15 +5221,15 @@ impl Upstairs {
          * and length may span many extents, and eventually, TODO, regions.
          */
         let ddef = self.ddef.lock().unwrap();
-        let nwo = extent_from_offset(
+        let nwo = extent_ranges_from_offset(
             *ddef,
             offset,
             Block::from_bytes(data.len(), &ddef),
         )?;
 
         /*
-         * Grab this ID after extent_from_offset: in case of Err we don't
-         * want to create a gap in the IDs.
+         * Grab this ID after extent_ranges_from_offset: in case of Err we
+         * don't want to create a gap in the IDs.
          */
         let gw_id: u64 = gw.next_gw_id();
         cdt::gw__read__start!(|| (gw_id));
@@ -4235,21 +5242,24 @@ impl Upstairs {
         let next_id = downstairs.next_id();
 
         /*
-         * Now create a downstairs work job for each (eid, bo, len) returned
-         * from extent_from_offset
+         * Now create a downstairs read request for each (eid, bo, len)
+         * returned from extent_ranges_from_offset
          */
-        let mut dep = downstairs.active.keys().cloned().collect::<Vec<u64>>();
-        dep.sort_unstable();
-
-        let mut requests: Vec<ReadRequest> = Vec::with_capacity(nwo.len());
-
-        for (eid, bo) in nwo {
-            requests.push(ReadRequest { eid, offset: bo });
-        }
+        let requests: Vec<ReadRequest> = nwo
+            .into_iter()
+            .map(|(eid, offset, num_blocks)| ReadRequest {
+                eid,
+                offset,
+                num_blocks,
+            })
+            .collect();
 
         sub.insert(next_id, 0); // XXX does this value matter?
 
-        let wr = create_read_eob(next_id, dep.clone(), gw_id, requests);
+        let dep = downstairs
+            .dependencies(requests.iter().map(ExtentBlocks::from), true);
+        let mut wr = create_read_eob(next_id, dep, gw_id, requests);
+        wr.trace = current_trace_context();
 
         /*
          * New work created, add to the guest_work HM. New work must be put
@@ -5028,6 +6038,8 @@ impl Upstairs {
             bail!("Encryption expectation mismatch!");
         }
 
+        let active = self.guest_io_ready();
+
         /*
          * XXX Eventually we will be provided UUIDs when the upstairs
          * starts, so we can compare those with what we get here.
@@ -5073,11 +6085,23 @@ impl Upstairs {
             println!("Setting expected region info to: {:?}", client_ddef);
         }
 
+        /*
+         * Until we are active, the three downstairs have to agree on the
+         * extent count so they can be reconciled.  After that, one that
+         * comes back may have been grown while it was away, but it can't
+         * have fewer extents than we are already using.
+         */
+        let extent_count_ok = if active {
+            client_ddef.extent_count() >= ddef.extent_count()
+        } else {
+            client_ddef.extent_count() == ddef.extent_count()
+        };
+
         if ddef.block_size() != client_ddef.block_size()
             || ddef.extent_size().value != client_ddef.extent_size().value
             || ddef.extent_size().block_size_in_bytes()
                 != client_ddef.extent_size().block_size_in_bytes()
-            || ddef.extent_count() != client_ddef.extent_count()
+            || !extent_count_ok
         {
             // XXX Figure out if we can handle this error. Possibly not.
             panic!(
@@ -5085,6 +6109,73 @@ impl Upstairs {
                 ddef, client_ddef
             );
         }
+        ds.ds_extent_count
+            .insert(client_id, client_ddef.extent_count());
+
+        Ok(())
+    }
+
+    /*
+     * A downstairs tells us its region has grown.  We only grow ours once
+     * all three have, and then only as far as the smallest of them.
+     */
+    fn ds_region_grown(
+        &self,
+        client_id: u8,
+        client_ddef: RegionDefinition,
+    ) -> Result<()> {
+        let mut ds = self.downstairs.lock().unwrap();
+        match ds.ds_uuid.get(&client_id) {
+            Some(uuid) if *uuid == client_ddef.uuid() => {}
+            uuid => bail!(
+                "[{}] region grown with uuid {} instead of {:?}",
+                client_id,
+                client_ddef.uuid(),
+                uuid
+            ),
+        }
+
+        let mut ddef = self.ddef.lock().unwrap();
+        if ddef.block_size() != client_ddef.block_size()
+            || ddef.extent_size().value != client_ddef.extent_size().value
+            || ddef.extent_size().block_size_in_bytes()
+                != client_ddef.extent_size().block_size_in_bytes()
+        {
+            bail!(
+                "[{}] grown region info mismatch {:?} vs. {:?}",
+                client_id,
+                ddef,
+                client_ddef
+            );
+        }
+        if client_ddef.extent_count() < ddef.extent_count() {
+            bail!(
+                "[{}] region shrank from {} to {} extents",
+                client_id,
+                ddef.extent_count(),
+                client_ddef.extent_count()
+            );
+        }
+
+        println!(
+            "[{}] region grown to {} extents",
+            client_id,
+            client_ddef.extent_count()
+        );
+        ds.ds_extent_count
+            .insert(client_id, client_ddef.extent_count());
+
+        if ds.ds_extent_count.len() == 3 {
+            let extent_count = *ds.ds_extent_count.values().min().unwrap();
+            if extent_count > ddef.extent_count() {
+                println!(
+                    "Region grown from {} to {} extents",
+                    ddef.extent_count(),
+                    extent_count
+                );
+                ddef.set_extent_count(extent_count);
+            }
+        }
 
         Ok(())
     }
@@ -5159,6 +6250,27 @@ impl Upstairs {
             Ok(ng) => ng,
         };
 
+        /*
+         * A downstairs that came out on the other side of a compare and
+         * write from the rest no longer holds the same blocks they do.
+         * Treat it as if its write had failed.
+         */
+        if let Some(diverged) = ds.compare_and_write_diverged(ds_id) {
+            println!(
+                "[{}] {} compare and write {} diverged from the others",
+                diverged, self.uuid, ds_id
+            );
+            if ds.ds_state[diverged as usize] != DsState::Failed {
+                self.ds_transition_with_lock(
+                    ds,
+                    up_state,
+                    diverged,
+                    DsState::Failed,
+                );
+                ds = self.downstairs.lock().unwrap();
+            }
+        }
+
         // Mark this downstairs as bad if this was a write or flush
         if let Err(err) = ds.client_error(ds_id, client_id) {
             if err == CrucibleError::UpstairsInactive {
@@ -5183,7 +6295,9 @@ impl Upstairs {
                 // here:
                 //
                 // ds_transition_with_lock( ...  DsState::Untrusted);
-            } else if matches!(err, CrucibleError::SnapshotExistsAlready(_)) {
+            } else if matches!(err, CrucibleError::SnapshotExistsAlready(_))
+                || err == CrucibleError::CompareMismatch
+            {
                 // skip
             }
             /*
@@ -5204,6 +6318,15 @@ impl Upstairs {
                     } | IOop::WriteUnwritten {
                         dependencies: _,
                         writes: _,
+                    } | IOop::Discard {
+                        dependencies: _,
+                        discards: _,
+                    } | IOop::WriteZeroes {
+                        dependencies: _,
+                        zeroes: _,
+                    } | IOop::CompareAndWrite {
+                        dependencies: _,
+                        writes: _,
                     }
                 ) {
                     self.ds_transition_with_lock(
@@ -5367,6 +6490,12 @@ struct DownstairsIO {
      */
     data: Option<Vec<ReadResponse>>,
     read_response_hashes: Vec<Option<u64>>,
+
+    /*
+     * The span this job was submitted under, sent along with it so the
+     * downstairs can continue the trace.
+     */
+    trace: Option<TraceContext>,
 }
 
 impl DownstairsIO {
@@ -5385,6 +6514,20 @@ impl DownstairsIO {
         wc
     }
 
+    /*
+     * How many downstairs found the blocks of a compare and write did not
+     * hold what was expected.  These are counted as errors in
+     * state_count, though the downstairs did nothing wrong.
+     */
+    fn compare_mismatches(&self) -> u64 {
+        self.state
+            .values()
+            .filter(|state| {
+                matches!(state, IOState::Error(CrucibleError::CompareMismatch))
+            })
+            .count() as u64
+    }
+
     /*
      * Return the size of the IO in bytes
      * Depending on the IO (write or read) we have to look in a different
@@ -5406,6 +6549,24 @@ impl DownstairsIO {
                 gen_number: _,
                 snapshot_details: _,
             } => 0,
+            IOop::Discard {
+                dependencies: _,
+                discards: _,
+            } => 0,
+            IOop::WriteZeroes {
+                dependencies: _,
+                zeroes,
+            } => zeroes
+                .iter()
+                .map(|z| {
+                    z.num_blocks as usize
+                        * z.offset.block_size_in_bytes() as usize
+                })
+                .sum(),
+            IOop::CompareAndWrite {
+                dependencies: _,
+                writes,
+            } => writes.iter().map(|w| w.data.len()).sum(),
             IOop::Read {
                 dependencies: _,
                 requests: _,
@@ -5454,11 +6615,23 @@ pub enum IOop {
         dependencies: Vec<u64>, // Jobs that must finish before this
         requests: Vec<ReadRequest>,
     },
-    Flush {
+    Flush {
+        dependencies: Vec<u64>, // Jobs that must finish before this
+        flush_number: u64,
+        gen_number: u64,
+        snapshot_details: Option<SnapshotDetails>,
+    },
+    Discard {
+        dependencies: Vec<u64>, // Jobs that must finish before this
+        discards: Vec<crucible_protocol::Discard>,
+    },
+    WriteZeroes {
+        dependencies: Vec<u64>, // Jobs that must finish before this
+        zeroes: Vec<crucible_protocol::WriteZeroes>,
+    },
+    CompareAndWrite {
         dependencies: Vec<u64>, // Jobs that must finish before this
-        flush_number: u64,
-        gen_number: u64,
-        snapshot_details: Option<SnapshotDetails>,
+        writes: Vec<crucible_protocol::CompareAndWrite>,
     },
 }
 
@@ -5483,7 +6656,130 @@ impl IOop {
                 dependencies,
                 writes: _,
             } => dependencies,
+            IOop::Discard {
+                dependencies,
+                discards: _,
+            } => dependencies,
+            IOop::WriteZeroes {
+                dependencies,
+                zeroes: _,
+            } => dependencies,
+            IOop::CompareAndWrite {
+                dependencies,
+                writes: _,
+            } => dependencies,
+        }
+    }
+
+    /*
+     * The blocks this IO uses.  A flush is not about any blocks in
+     * particular, it is ordered against everything instead.
+     */
+    fn blocks(&self) -> Vec<ExtentBlocks> {
+        match self {
+            IOop::Write { writes, .. }
+            | IOop::WriteUnwritten { writes, .. } => {
+                ExtentBlocks::merge(writes.iter().map(ExtentBlocks::from))
+            }
+            IOop::Read { requests, .. } => {
+                ExtentBlocks::merge(requests.iter().map(ExtentBlocks::from))
+            }
+            IOop::Flush { .. } => Vec::new(),
+            IOop::Discard { discards, .. } => {
+                ExtentBlocks::merge(discards.iter().map(ExtentBlocks::from))
+            }
+            IOop::WriteZeroes { zeroes, .. } => {
+                ExtentBlocks::merge(zeroes.iter().map(ExtentBlocks::from))
+            }
+            IOop::CompareAndWrite { writes, .. } => {
+                ExtentBlocks::merge(writes.iter().map(ExtentBlocks::from))
+            }
+        }
+    }
+}
+
+/*
+ * A run of blocks within one extent.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct ExtentBlocks {
+    eid: u64,
+    start: u64,
+    count: u64,
+}
+
+impl ExtentBlocks {
+    fn new(eid: u64, offset: Block, count: u64) -> ExtentBlocks {
+        ExtentBlocks {
+            eid,
+            start: offset.value,
+            count,
+        }
+    }
+
+    /*
+     * Collapse blocks into the fewest runs that cover them.  A write comes
+     * a block at a time, so without this checking a large write against
+     * another one would compare every block of one with every block of
+     * the other.  Contiguous blocks end up as one run for each extent.
+     */
+    fn merge<I>(blocks: I) -> Vec<ExtentBlocks>
+    where
+        I: IntoIterator<Item = ExtentBlocks>,
+    {
+        let mut blocks: Vec<ExtentBlocks> = blocks.into_iter().collect();
+        blocks.sort_unstable_by_key(|b| (b.eid, b.start));
+
+        let mut merged: Vec<ExtentBlocks> = Vec::with_capacity(blocks.len());
+        for b in blocks {
+            match merged.last_mut() {
+                Some(last)
+                    if last.eid == b.eid
+                        && b.start <= last.start + last.count =>
+                {
+                    let end = (b.start + b.count).max(last.start + last.count);
+                    last.count = end - last.start;
+                }
+                _ => merged.push(b),
+            }
         }
+        merged
+    }
+
+    fn overlaps(&self, other: &ExtentBlocks) -> bool {
+        self.eid == other.eid
+            && self.start < other.start + other.count
+            && other.start < self.start + self.count
+    }
+}
+
+impl From<&crucible_protocol::Write> for ExtentBlocks {
+    fn from(w: &crucible_protocol::Write) -> ExtentBlocks {
+        ExtentBlocks::new(w.eid, w.offset, 1)
+    }
+}
+
+impl From<&ReadRequest> for ExtentBlocks {
+    fn from(r: &ReadRequest) -> ExtentBlocks {
+        ExtentBlocks::new(r.eid, r.offset, r.num_blocks)
+    }
+}
+
+impl From<&crucible_protocol::Discard> for ExtentBlocks {
+    fn from(d: &crucible_protocol::Discard) -> ExtentBlocks {
+        ExtentBlocks::new(d.eid, d.offset, d.num_blocks)
+    }
+}
+
+impl From<&crucible_protocol::WriteZeroes> for ExtentBlocks {
+    fn from(z: &crucible_protocol::WriteZeroes) -> ExtentBlocks {
+        ExtentBlocks::new(z.eid, z.offset, z.num_blocks)
+    }
+}
+
+impl From<&crucible_protocol::CompareAndWrite> for ExtentBlocks {
+    fn from(w: &crucible_protocol::CompareAndWrite) -> ExtentBlocks {
+        ExtentBlocks::new(w.eid, w.offset, 1)
     }
 }
 
@@ -5760,6 +7056,19 @@ enum BlockOp {
     Flush {
         snapshot_details: Option<SnapshotDetails>,
     },
+    Discard {
+        offset: Block,
+        len: Block,
+    },
+    WriteZeroes {
+        offset: Block,
+        len: Block,
+    },
+    CompareAndWrite {
+        offset: Block,
+        expected: Bytes,
+        data: Bytes,
+    },
     GoActive {
         gen: u64,
     },
@@ -5962,10 +7271,12 @@ impl GtoS {
                             span!(Level::TRACE, "copy to guest buffer")
                                 .entered();
 
-                        for i in &response.data {
-                            vec[offset] = *i;
-                            owned_vec[offset] = !response.hashes.is_empty();
-                            offset += 1;
+                        for (data, block) in response.iter_blocks() {
+                            let end = offset + data.len();
+                            vec[offset..end].copy_from_slice(data);
+                            owned_vec[offset..end]
+                                .fill(!block.hashes.is_empty());
+                            offset = end;
                         }
                     }
                 }
@@ -6135,6 +7446,12 @@ impl GuestWork {
 pub struct BlockReq {
     op: BlockOp,
     send: std_mpsc::Sender<Result<(), CrucibleError>>,
+
+    /*
+     * Created when the guest hands us the request, this is the root of
+     * the trace for the IO through the upstairs and downstairs.
+     */
+    span: tracing::Span,
 }
 
 impl BlockReq {
@@ -6145,7 +7462,8 @@ impl BlockReq {
         op: BlockOp,
         send: std_mpsc::Sender<Result<(), CrucibleError>>,
     ) -> BlockReq {
-        Self { op, send }
+        let span = span!(Level::INFO, "guest io");
+        Self { op, send, span }
     }
 }
 
@@ -6538,6 +7856,91 @@ impl Guest {
         Ok(self.send(wio))
     }
 
+    pub fn discard(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        if !self.is_active() {
+            return Err(CrucibleError::UpstairsInactive);
+        }
+
+        let bs = self.query_block_size()?;
+
+        if offset.block_size_in_bytes() as u64 != bs
+            || len.block_size_in_bytes() as u64 != bs
+        {
+            crucible_bail!(BlockSizeMismatch);
+        }
+
+        if len.value == 0 {
+            crucible_bail!(InvalidNumberOfBlocks, "cannot discard 0 blocks");
+        }
+
+        let dio = BlockOp::Discard { offset, len };
+        Ok(self.send(dio))
+    }
+
+    pub fn write_zeroes(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        if !self.is_active() {
+            return Err(CrucibleError::UpstairsInactive);
+        }
+
+        let bs = self.query_block_size()?;
+
+        if offset.block_size_in_bytes() as u64 != bs
+            || len.block_size_in_bytes() as u64 != bs
+        {
+            crucible_bail!(BlockSizeMismatch);
+        }
+
+        if len.value == 0 {
+            crucible_bail!(InvalidNumberOfBlocks, "cannot zero 0 blocks");
+        }
+
+        let zio = BlockOp::WriteZeroes { offset, len };
+        Ok(self.send(zio))
+    }
+
+    pub fn compare_and_write(
+        &self,
+        offset: Block,
+        expected: Bytes,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        if !self.is_active() {
+            return Err(CrucibleError::UpstairsInactive);
+        }
+
+        let bs = self.query_block_size()?;
+
+        if (data.len() % bs as usize) != 0 || expected.len() != data.len() {
+            crucible_bail!(DataLenUnaligned);
+        }
+
+        if offset.block_size_in_bytes() as u64 != bs {
+            crucible_bail!(BlockSizeMismatch);
+        }
+
+        if data.is_empty() {
+            crucible_bail!(
+                InvalidNumberOfBlocks,
+                "cannot compare and write 0 blocks"
+            );
+        }
+
+        let cio = BlockOp::CompareAndWrite {
+            offset,
+            expected,
+            data,
+        };
+        Ok(self.send(cio))
+    }
+
     /*
      * `read_from_byte_offset` and `write_to_byte_offset` accept a byte
      * offset, and data must be a multiple of block size.
@@ -6768,6 +8171,31 @@ impl BlockIO for Guest {
         self.flush(snapshot_details)
     }
 
+    fn discard(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        self.discard(offset, len)
+    }
+
+    fn write_zeroes(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        self.write_zeroes(offset, len)
+    }
+
+    fn compare_and_write(
+        &self,
+        offset: Block,
+        expected: Bytes,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        self.compare_and_write(offset, expected, data)
+    }
+
     fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         self.show_work()
     }
@@ -6944,6 +8372,13 @@ async fn process_new_io(
     req: BlockReq,
     lastcast: &mut u64,
 ) {
+    /*
+     * Anything the submit_* functions do is part of the trace for this
+     * request.  Nothing in here waits, so holding the span entered is fine.
+     */
+    let span = req.span.clone();
+    let _guard = span.enter();
+
     /*
      * If any of the submit_* functions fail to send to the downstairs, they
      * return an error.  These are reported to the Guest.
@@ -7029,6 +8464,43 @@ async fn process_new_io(
             send_work(dst, *lastcast);
             *lastcast += 1;
         }
+        BlockOp::Discard { offset, len } => {
+            if let Err(e) =
+                up.submit_discard(offset, len, Some(req.send.clone()))
+            {
+                let _ = req.send.send(Err(e));
+                return;
+            }
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
+        BlockOp::WriteZeroes { offset, len } => {
+            if let Err(e) =
+                up.submit_write_zeroes(offset, len, Some(req.send.clone()))
+            {
+                let _ = req.send.send(Err(e));
+                return;
+            }
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
+        BlockOp::CompareAndWrite {
+            offset,
+            expected,
+            data,
+        } => {
+            if let Err(e) = up.submit_compare_and_write(
+                offset,
+                expected,
+                data,
+                Some(req.send.clone()),
+            ) {
+                let _ = req.send.send(Err(e));
+                return;
+            }
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
         BlockOp::Flush { snapshot_details } => {
             /*
              * Submit for read and write both check if the upstairs is
@@ -7444,6 +8916,25 @@ pub async fn up_main(
     Ok(())
 }
 
+/*
+ * The span we are in, in a form we can send to the downstairs.  This is
+ * None unless something is exporting traces from this process.
+ */
+fn current_trace_context() -> Option<TraceContext> {
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    if !span_context.is_valid() {
+        return None;
+    }
+
+    Some(TraceContext {
+        trace_id: span_context.trace_id().to_bytes(),
+        span_id: span_context.span_id().to_bytes(),
+        flags: span_context.trace_flags().to_u8(),
+    })
+}
+
 /*
  * Create a write DownstairsIO structure from an EID, and offset, and
  * the data buffer
@@ -7489,6 +8980,7 @@ fn create_write_eob(
         replay: false,
         data: None,
         read_response_hashes: Vec::new(),
+        trace: None,
     }
 }
 
@@ -7522,6 +9014,106 @@ fn create_read_eob(
         replay: false,
         data: None,
         read_response_hashes: Vec::new(),
+        trace: None,
+    }
+}
+
+/*
+ * Create a discard DownstairsIO structure from a list of block ranges,
+ * each of which is within a single extent.
+ */
+fn create_discard_eob(
+    ds_id: u64,
+    dependencies: Vec<u64>,
+    gw_id: u64,
+    discards: Vec<crucible_protocol::Discard>,
+) -> DownstairsIO {
+    let adiscard = IOop::Discard {
+        dependencies,
+        discards,
+    };
+
+    let mut state = HashMap::new();
+    for cl in 0..3 {
+        state.insert(cl, IOState::New);
+    }
+
+    DownstairsIO {
+        ds_id,
+        guest_id: gw_id,
+        work: adiscard,
+        state,
+        ack_status: AckStatus::NotAcked,
+        replay: false,
+        data: None,
+        read_response_hashes: Vec::new(),
+        trace: None,
+    }
+}
+
+/*
+ * Create a write zeroes DownstairsIO structure from a list of block
+ * ranges, each of which is within a single extent.
+ */
+fn create_write_zeroes_eob(
+    ds_id: u64,
+    dependencies: Vec<u64>,
+    gw_id: u64,
+    zeroes: Vec<crucible_protocol::WriteZeroes>,
+) -> DownstairsIO {
+    let azero = IOop::WriteZeroes {
+        dependencies,
+        zeroes,
+    };
+
+    let mut state = HashMap::new();
+    for cl in 0..3 {
+        state.insert(cl, IOState::New);
+    }
+
+    DownstairsIO {
+        ds_id,
+        guest_id: gw_id,
+        work: azero,
+        state,
+        ack_status: AckStatus::NotAcked,
+        replay: false,
+        data: None,
+        read_response_hashes: Vec::new(),
+        trace: None,
+    }
+}
+
+/*
+ * Create a compare and write DownstairsIO structure from a list of
+ * blocks, each with the data we expect it holds and the data to write.
+ */
+fn create_compare_and_write_eob(
+    ds_id: u64,
+    dependencies: Vec<u64>,
+    gw_id: u64,
+    writes: Vec<crucible_protocol::CompareAndWrite>,
+) -> DownstairsIO {
+    let acw = IOop::CompareAndWrite {
+        dependencies,
+        writes,
+    };
+
+    let mut state = HashMap::new();
+    for cl in 0..3 {
+        state.insert(cl, IOState::New);
+    }
+
+    DownstairsIO {
+        ds_id,
+        guest_id: gw_id,
+        work: acw,
+        state,
+        ack_status: AckStatus::NotAcked,
+        replay: false,
+        data: None,
+        read_response_hashes: Vec::new(),
+        trace: None,
     }
 }
 
@@ -7556,6 +9148,7 @@ fn create_flush(
         replay: false,
         data: None,
         read_response_hashes: Vec::new(),
+        trace: None,
     }
 }
 
@@ -7610,8 +9203,9 @@ fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
                     requests,
                 } => {
                     let job_type = "Read".to_string();
-                    let num_blocks = requests.len();
-                    (job_type, num_blocks)
+                    let num_blocks: u64 =
+                        requests.iter().map(|r| r.num_blocks).sum();
+                    (job_type, num_blocks as usize)
                 }
                 IOop::Write {
                     dependencies: _dependencies,
@@ -7650,6 +9244,31 @@ fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
                     let job_type = "Flush".to_string();
                     (job_type, 0)
                 }
+                IOop::Discard {
+                    dependencies: _dependencies,
+                    discards,
+                } => {
+                    let job_type = "Discard".to_string();
+                    let num_blocks: u64 =
+                        discards.iter().map(|d| d.num_blocks).sum();
+                    (job_type, num_blocks as usize)
+                }
+                IOop::WriteZeroes {
+                    dependencies: _dependencies,
+                    zeroes,
+                } => {
+                    let job_type = "WriteZ".to_string();
+                    let num_blocks: u64 =
+                        zeroes.iter().map(|z| z.num_blocks).sum();
+                    (job_type, num_blocks as usize)
+                }
+                IOop::CompareAndWrite {
+                    dependencies: _dependencies,
+                    writes,
+                } => {
+                    let job_type = "CmpWr".to_string();
+                    (job_type, writes.len())
+                }
             };
 
             print!(
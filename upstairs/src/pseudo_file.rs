@@ -308,8 +308,21 @@ impl<T: BlockIO> CruciblePseudoFile<T> {
                 self.offset / self.block_size,
                 self.block_size.trailing_zeros(),
             );
-            let bytes = BytesMut::from(buf);
-            let mut waiter = self.block_io.write(offset, bytes.freeze())?;
+
+            /*
+             * Don't send a buffer full of zeros down to the downstairs when
+             * write_zeroes can say the same thing in a few bytes.
+             */
+            let mut waiter = if !buf.is_empty() && buf.iter().all(|x| *x == 0) {
+                let len = Block::new(
+                    buf.len() as u64 / self.block_size,
+                    self.block_size.trailing_zeros(),
+                );
+                self.block_io.write_zeroes(offset, len)?
+            } else {
+                let bytes = BytesMut::from(buf);
+                self.block_io.write(offset, bytes.freeze())?
+            };
             waiter.block_wait()?;
         }
 
@@ -638,6 +638,723 @@ mod test {
         assert_eq!(ds.completed.len(), 1);
     }
 
+    #[test]
+    fn work_discard_two_ok_acks() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active().unwrap();
+        let mut ds = upstairs.downstairs.lock().unwrap();
+
+        let next_id = ds.next_id();
+
+        let op = create_discard_eob(
+            next_id,
+            vec![],
+            10,
+            vec![crucible_protocol::Discard {
+                eid: 0,
+                offset: Block::new_512(7),
+                num_blocks: 2,
+            }],
+        );
+
+        ds.enqueue(op);
+
+        ds.in_progress(next_id, 0);
+        ds.in_progress(next_id, 1);
+        ds.in_progress(next_id, 2);
+
+        assert_eq!(
+            ds.process_ds_completion(
+                next_id,
+                0,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap(),
+            false
+        );
+        assert_eq!(ds.ackable_work().len(), 0);
+
+        assert_eq!(
+            ds.process_ds_completion(
+                next_id,
+                1,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap(),
+            true
+        );
+        assert_eq!(ds.ackable_work().len(), 1);
+        ds.ack(next_id);
+
+        assert_eq!(
+            ds.process_ds_completion(
+                next_id,
+                2,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap(),
+            false
+        );
+
+        // Only a flush retires jobs
+        assert_eq!(ds.completed.len(), 0);
+    }
+
+    #[test]
+    fn submit_discard_without_feature_is_write_zeroes() {
+        let up = make_upstairs();
+        up.set_active().unwrap();
+        for cid in 0..3 {
+            up.set_ds_features(cid, Features::WRITE_ZEROES);
+        }
+
+        up.submit_discard(Block::new_512(0), Block::new_512(4), None)
+            .unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 1);
+        let job = ds.active.values().next().unwrap();
+        assert_eq!(
+            job.work,
+            IOop::WriteZeroes {
+                dependencies: vec![],
+                zeroes: vec![crucible_protocol::WriteZeroes {
+                    eid: 0,
+                    offset: Block::new_512(0),
+                    num_blocks: 4,
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn submit_dependencies_only_on_overlapping_jobs() {
+        // A job waits for the last flush, and for jobs since then that use
+        // the same blocks, but a read does not wait for a read.
+        let up = make_upstairs();
+        up.set_active().unwrap();
+        for cid in 0..3 {
+            up.set_ds_features(cid, Features::supported());
+        }
+
+        let one = Bytes::from(vec![1; 512]);
+        let two = Bytes::from(vec![2; 1024]);
+        let deps = || {
+            let ds = up.downstairs.lock().unwrap();
+            let mut jobs: Vec<_> = ds.active.values().collect();
+            jobs.sort_by_key(|job| job.ds_id);
+            jobs.iter()
+                .map(|job| job.work.deps().clone())
+                .collect::<Vec<_>>()
+        };
+
+        // 1000: write extent 0 block 0, 1001: write extent 1 block 50
+        up.submit_write(Block::new_512(0), one.clone(), None, false)
+            .unwrap();
+        up.submit_write(Block::new_512(150), one.clone(), None, false)
+            .unwrap();
+        // 1002: read extent 0 blocks 0 and 1, 1003: read extent 0 block 0
+        up.submit_read(Block::new_512(0), Buffer::new(1024), None)
+            .unwrap();
+        up.submit_read(Block::new_512(0), Buffer::new(512), None)
+            .unwrap();
+        // 1004: write extent 0 block 1
+        up.submit_write(Block::new_512(1), one.clone(), None, false)
+            .unwrap();
+        // 1005: flush
+        up.submit_flush(None, None).unwrap();
+        // 1006: write extent 0 block 0, 1007: zero extent 0 blocks 0, 1
+        up.submit_write(Block::new_512(0), one, None, false)
+            .unwrap();
+        up.submit_write_zeroes(Block::new_512(0), Block::new_512(2), None)
+            .unwrap();
+        // 1008: compare and write extent 5 blocks 0, 1
+        up.submit_compare_and_write(
+            Block::new_512(500),
+            Bytes::from(vec![0; 1024]),
+            two,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            deps(),
+            vec![
+                vec![],
+                vec![],
+                vec![1000],
+                vec![1000],
+                vec![1002],
+                vec![1000, 1001, 1002, 1003, 1004],
+                vec![1005],
+                vec![1005, 1006],
+                vec![1005],
+            ]
+        );
+    }
+
+    #[test]
+    fn extent_blocks_merge_into_runs() {
+        // A write a block at a time across two extents, out of order,
+        // with a separate run and a repeated block in extent 1.
+        let blocks = vec![
+            ExtentBlocks::new(1, Block::new_512(0), 1),
+            ExtentBlocks::new(0, Block::new_512(98), 1),
+            ExtentBlocks::new(0, Block::new_512(99), 1),
+            ExtentBlocks::new(1, Block::new_512(1), 1),
+            ExtentBlocks::new(1, Block::new_512(0), 1),
+            ExtentBlocks::new(1, Block::new_512(5), 2),
+        ];
+        assert_eq!(
+            ExtentBlocks::merge(blocks),
+            vec![
+                ExtentBlocks::new(0, Block::new_512(98), 2),
+                ExtentBlocks::new(1, Block::new_512(0), 2),
+                ExtentBlocks::new(1, Block::new_512(5), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn submit_discard_groups_by_extent() {
+        let up = make_upstairs();
+        up.set_active().unwrap();
+        for cid in 0..3 {
+            up.set_ds_features(cid, Features::supported());
+        }
+
+        // Blocks 98 through 102 cross from extent 0 into extent 1
+        up.submit_discard(Block::new_512(98), Block::new_512(5), None)
+            .unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 1);
+        let job = ds.active.values().next().unwrap();
+        assert_eq!(
+            job.work,
+            IOop::Discard {
+                dependencies: vec![],
+                discards: vec![
+                    crucible_protocol::Discard {
+                        eid: 0,
+                        offset: Block::new_512(98),
+                        num_blocks: 2,
+                    },
+                    crucible_protocol::Discard {
+                        eid: 1,
+                        offset: Block::new_512(0),
+                        num_blocks: 3,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn submit_write_zeroes_without_feature_is_write() {
+        let up = make_upstairs();
+        up.set_active().unwrap();
+        up.set_ds_features(0, Features::supported());
+        up.set_ds_features(1, Features::supported());
+        up.set_ds_features(2, Features::empty());
+
+        up.submit_write_zeroes(Block::new_512(0), Block::new_512(2), None)
+            .unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 1);
+        let job = ds.active.values().next().unwrap();
+        match &job.work {
+            IOop::Write {
+                dependencies: _,
+                writes,
+            } => {
+                assert_eq!(writes.len(), 2);
+                for write in writes {
+                    assert!(write.data.iter().all(|x| *x == 0));
+                }
+            }
+            _ => panic!("expected a write, not {:?}", job.work),
+        }
+    }
+
+    #[test]
+    fn submit_write_zeroes_encrypted_is_write() {
+        let mut def = RegionDefinition::default();
+        def.set_block_size(512);
+        def.set_extent_size(Block::new_512(100));
+        def.set_extent_count(10);
+
+        let opts = CrucibleOpts {
+            target: vec![],
+            lossy: false,
+            key: Some(base64::encode([7u8; 32])),
+            ..Default::default()
+        };
+        let up = Upstairs::new(&opts, 0, def, Arc::new(Guest::new()));
+        up.set_active().unwrap();
+        for cid in 0..3 {
+            up.set_ds_features(cid, Features::supported());
+        }
+
+        up.submit_write_zeroes(Block::new_512(0), Block::new_512(2), None)
+            .unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 1);
+        let job = ds.active.values().next().unwrap();
+        match &job.work {
+            IOop::Write {
+                dependencies: _,
+                writes,
+            } => {
+                assert_eq!(writes.len(), 2);
+                for write in writes {
+                    assert!(write.encryption_context.is_some());
+                }
+            }
+            _ => panic!("expected a write, not {:?}", job.work),
+        }
+    }
+
+    #[test]
+    fn zero_writes_read_back_as_zeros() {
+        let up = make_upstairs();
+        let writes = up.zero_writes(1, Block::new_512(3), 2).unwrap();
+        assert_eq!(writes.len(), 2);
+        for (i, write) in writes.iter().enumerate() {
+            assert_eq!(write.eid, 1);
+            assert_eq!(write.offset.value, 3 + i as u64);
+            assert_eq!(write.data[..], [0u8; 512][..]);
+            assert_eq!(write.hash, integrity_hash(&[&write.data[..]]));
+            assert!(write.encryption_context.is_none());
+        }
+
+        let mut def = RegionDefinition::default();
+        def.set_block_size(512);
+        def.set_extent_size(Block::new_512(100));
+        def.set_extent_count(10);
+
+        let opts = CrucibleOpts {
+            target: vec![],
+            lossy: false,
+            key: Some(base64::encode([7u8; 32])),
+            ..Default::default()
+        };
+        let up = Upstairs::new(&opts, 0, def, Arc::new(Guest::new()));
+        let context = up.encryption_context.as_ref().unwrap();
+
+        let writes = up.zero_writes(0, Block::new_512(0), 2).unwrap();
+        assert_eq!(writes.len(), 2);
+        for write in writes {
+            let ctx = write.encryption_context.unwrap();
+            let mut data = write.data.to_vec();
+            context
+                .decrypt_in_place(
+                    &mut data[..],
+                    Nonce::from_slice(&ctx.nonce[..]),
+                    Tag::from_slice(&ctx.tag[..]),
+                )
+                .unwrap();
+            assert_eq!(data[..], [0u8; 512][..]);
+        }
+    }
+
+    #[test]
+    fn submit_write_zeroes_groups_by_extent() {
+        let up = make_upstairs();
+        up.set_active().unwrap();
+        for cid in 0..3 {
+            up.set_ds_features(cid, Features::supported());
+        }
+
+        up.submit_write_zeroes(Block::new_512(99), Block::new_512(2), None)
+            .unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 1);
+        let job = ds.active.values().next().unwrap();
+        assert_eq!(
+            job.work,
+            IOop::WriteZeroes {
+                dependencies: vec![],
+                zeroes: vec![
+                    crucible_protocol::WriteZeroes {
+                        eid: 0,
+                        offset: Block::new_512(99),
+                        num_blocks: 1,
+                    },
+                    crucible_protocol::WriteZeroes {
+                        eid: 1,
+                        offset: Block::new_512(0),
+                        num_blocks: 1,
+                    },
+                ],
+            }
+        );
+    }
+
+    fn compare_and_write_job(ds: &mut Downstairs) -> u64 {
+        let next_id = ds.next_id();
+
+        let data = Bytes::from(vec![1u8; 512]);
+        let op = create_compare_and_write_eob(
+            next_id,
+            vec![],
+            10,
+            vec![crucible_protocol::CompareAndWrite {
+                eid: 0,
+                offset: Block::new_512(7),
+                expected: Bytes::from(vec![0u8; 512]),
+                hash: integrity_hash(&[&data[..]]),
+                data,
+            }],
+        );
+
+        ds.enqueue(op);
+
+        ds.in_progress(next_id, 0);
+        ds.in_progress(next_id, 1);
+        ds.in_progress(next_id, 2);
+
+        next_id
+    }
+
+    #[test]
+    fn work_compare_and_write_two_matches_ack_ok() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active().unwrap();
+        let mut ds = upstairs.downstairs.lock().unwrap();
+        let next_id = compare_and_write_job(&mut ds);
+
+        assert!(!ds
+            .process_ds_completion(
+                next_id,
+                0,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert!(ds
+            .process_ds_completion(
+                next_id,
+                1,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 1);
+        assert_eq!(ds.result(next_id), Ok(()));
+        assert_eq!(ds.compare_and_write_diverged(next_id), None);
+        ds.ack(next_id);
+
+        // The last one to answer did not write, so it no longer matches.
+        assert!(!ds
+            .process_ds_completion(
+                next_id,
+                2,
+                Err(CrucibleError::CompareMismatch),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert_eq!(ds.compare_and_write_diverged(next_id), Some(2));
+
+        // A mismatch is not the downstairs' fault.
+        assert!(ds.downstairs_errors.is_empty());
+    }
+
+    #[test]
+    fn work_compare_and_write_two_mismatches_ack_mismatch() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active().unwrap();
+        let mut ds = upstairs.downstairs.lock().unwrap();
+        let next_id = compare_and_write_job(&mut ds);
+
+        assert!(!ds
+            .process_ds_completion(
+                next_id,
+                0,
+                Err(CrucibleError::CompareMismatch),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert!(!ds
+            .process_ds_completion(
+                next_id,
+                1,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 0);
+
+        assert!(ds
+            .process_ds_completion(
+                next_id,
+                2,
+                Err(CrucibleError::CompareMismatch),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 1);
+        assert_eq!(ds.result(next_id), Err(CrucibleError::CompareMismatch));
+
+        // The one that wrote holds data the others do not.
+        assert_eq!(ds.compare_and_write_diverged(next_id), Some(1));
+        assert!(ds.downstairs_errors.is_empty());
+    }
+
+    #[test]
+    fn work_compare_and_write_no_majority() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active().unwrap();
+        let mut ds = upstairs.downstairs.lock().unwrap();
+        let next_id = compare_and_write_job(&mut ds);
+
+        assert!(!ds
+            .process_ds_completion(
+                next_id,
+                0,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert!(!ds
+            .process_ds_completion(
+                next_id,
+                1,
+                Err(CrucibleError::CompareMismatch),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert!(ds
+            .process_ds_completion(
+                next_id,
+                2,
+                Err(CrucibleError::GenericError("bad".to_string())),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+
+        // Without two that wrote, the write did not take effect, and the
+        // guest can not be told it did.
+        assert!(matches!(ds.result(next_id), Err(CrucibleError::IoError(_))));
+        assert_eq!(ds.compare_and_write_diverged(next_id), Some(0));
+        assert_eq!(ds.downstairs_errors.get(&2), Some(&1));
+    }
+
+    #[test]
+    fn submit_compare_and_write_needs_feature() {
+        let up = make_upstairs();
+        up.set_active().unwrap();
+        up.set_ds_features(0, Features::supported());
+        up.set_ds_features(1, Features::supported());
+        up.set_ds_features(2, Features::empty());
+
+        let expected = Bytes::from(vec![0u8; 1024]);
+        let data = Bytes::from(vec![1u8; 1024]);
+        assert!(matches!(
+            up.submit_compare_and_write(
+                Block::new_512(99),
+                expected.clone(),
+                data.clone(),
+                None
+            ),
+            Err(CrucibleError::Unsupported(_))
+        ));
+        assert!(up.downstairs.lock().unwrap().active.is_empty());
+
+        up.set_ds_features(2, Features::supported());
+        up.submit_compare_and_write(Block::new_512(99), expected, data, None)
+            .unwrap();
+
+        // Blocks 99 and 100 are in different extents, but are still one
+        // job so they are written together or not at all.
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 1);
+        let job = ds.active.values().next().unwrap();
+        match &job.work {
+            IOop::CompareAndWrite {
+                dependencies: _,
+                writes,
+            } => {
+                assert_eq!(writes.len(), 2);
+                assert_eq!(writes[0].eid, 0);
+                assert_eq!(writes[0].offset, Block::new_512(99));
+                assert_eq!(writes[1].eid, 1);
+                assert_eq!(writes[1].offset, Block::new_512(0));
+                for write in writes {
+                    assert_eq!(write.expected[..], [0u8; 512][..]);
+                    assert_eq!(write.data[..], [1u8; 512][..]);
+                }
+            }
+            _ => panic!("expected a compare and write, not {:?}", job.work),
+        }
+    }
+
+    #[test]
+    fn submit_read_one_request_per_extent() {
+        let up = make_upstairs();
+        up.set_active().unwrap();
+
+        // Blocks 98 through 102 cross from extent 0 into extent 1
+        let data = Buffer::new(512 * 5);
+        up.submit_read(Block::new_512(98), data, None).unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 1);
+        let job = ds.active.values().next().unwrap();
+        assert_eq!(
+            job.work,
+            IOop::Read {
+                dependencies: vec![],
+                requests: vec![
+                    ReadRequest {
+                        eid: 0,
+                        offset: Block::new_512(98),
+                        num_blocks: 2,
+                    },
+                    ReadRequest {
+                        eid: 1,
+                        offset: Block::new_512(0),
+                        num_blocks: 3,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn submit_without_exporter_has_no_trace() {
+        // With nothing exporting traces, there is no trace to send along
+        // with a job, even when we are inside a span.
+        let up = make_upstairs();
+        up.set_active().unwrap();
+
+        let span = span!(Level::INFO, "guest io");
+        let _guard = span.enter();
+        assert_eq!(current_trace_context(), None);
+
+        up.submit_write(
+            Block::new_512(0),
+            Bytes::from(vec![1; 512]),
+            None,
+            false,
+        )
+        .unwrap();
+        up.submit_flush(None, None).unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 2);
+        for job in ds.active.values() {
+            assert_eq!(job.trace, None);
+            assert_eq!(ds.trace_context(job.ds_id), None);
+        }
+    }
+
+    #[test]
+    fn submit_with_exporter_traces_every_io() {
+        // When traces are being exported, every kind of job carries the
+        // trace it was submitted in.
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let up = make_upstairs();
+        up.set_active().unwrap();
+        for cid in 0..3 {
+            up.set_ds_features(cid, Features::supported());
+        }
+
+        let provider = opentelemetry::sdk::trace::TracerProvider::default();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("test")),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let span = span!(Level::INFO, "guest io");
+        let _guard = span.enter();
+        let trace_id = current_trace_context().unwrap().trace_id;
+
+        let data = Bytes::from(vec![1; 512]);
+        up.submit_write(Block::new_512(0), data.clone(), None, false)
+            .unwrap();
+        up.submit_read(Block::new_512(0), Buffer::new(512), None)
+            .unwrap();
+        up.submit_discard(Block::new_512(1), Block::new_512(1), None)
+            .unwrap();
+        up.submit_write_zeroes(Block::new_512(2), Block::new_512(1), None)
+            .unwrap();
+        up.submit_compare_and_write(
+            Block::new_512(3),
+            Bytes::from(vec![0; 512]),
+            data,
+            None,
+        )
+        .unwrap();
+        up.submit_flush(None, None).unwrap();
+
+        let ds = up.downstairs.lock().unwrap();
+        assert_eq!(ds.active.len(), 6);
+        for job in ds.active.values() {
+            let trace = ds.trace_context(job.ds_id).unwrap();
+            assert_eq!(trace.trace_id, trace_id);
+        }
+    }
+
+    #[test]
+    fn work_read_range_bad_block_count() {
+        // A response with data for more blocks than it has metadata for is
+        // an error, not something to hand back to the guest.
+        let upstairs = Upstairs::default();
+        upstairs.set_active().unwrap();
+        let mut ds = upstairs.downstairs.lock().unwrap();
+
+        let next_id = ds.next_id();
+
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+            num_blocks: 2,
+        };
+        let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
+
+        ds.enqueue(op);
+        ds.in_progress(next_id, 0);
+
+        let mut response =
+            ReadResponse::from_request_with_data(&request, &[0u8; 1024]);
+        response.blocks.pop();
+
+        assert!(!ds
+            .process_ds_completion(
+                next_id,
+                0,
+                Ok(vec![response]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert!(matches!(
+            ds.active.get(&next_id).unwrap().state.get(&0),
+            Some(IOState::Error(CrucibleError::InvalidNumberOfBlocks(_)))
+        ));
+    }
+
     #[test]
     fn work_read_one_ok() {
         let upstairs = Upstairs::default();
@@ -649,6 +1366,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
 
@@ -732,6 +1450,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
 
@@ -811,6 +1530,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
 
@@ -885,6 +1605,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
 
@@ -950,6 +1671,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
 
         let next_id = {
@@ -1028,6 +1750,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(id, vec![], 10, vec![request.clone()]);
 
@@ -1077,6 +1800,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(id, vec![], 10, vec![request.clone()]);
 
@@ -1124,6 +1848,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(id, vec![], 10, vec![request.clone()]);
 
@@ -1177,6 +1902,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(id, vec![], 10, vec![request.clone()]);
 
@@ -1230,6 +1956,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(id, vec![], 10, vec![request.clone()]);
 
@@ -1274,6 +2001,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(id, vec![], 10, vec![request.clone()]);
 
@@ -1317,6 +2045,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(id, vec![], 10, vec![request.clone()]);
 
@@ -1442,6 +2171,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
 
@@ -1492,6 +2222,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
 
@@ -1567,6 +2298,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
 
@@ -1646,6 +2378,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
 
@@ -2357,6 +3090,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
         ds.enqueue(op);
@@ -2413,6 +3147,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
         ds.enqueue(op);
@@ -2507,6 +3242,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
         ds.enqueue(op);
@@ -2610,6 +3346,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
         ds.enqueue(op);
@@ -2708,6 +3445,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
         ds.enqueue(op);
@@ -4166,6 +4904,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
 
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
@@ -4208,11 +4947,12 @@ mod test {
             offset: request.offset,
 
             data: BytesMut::from(&data[..]),
-            encryption_contexts: vec![crucible_protocol::EncryptionContext {
-                nonce,
-                tag,
+            blocks: vec![ReadResponseBlockMetadata {
+                encryption_contexts: vec![
+                    crucible_protocol::EncryptionContext { nonce, tag },
+                ],
+                hashes: vec![hash],
             }],
-            hashes: vec![hash],
         }]);
 
         let result =
@@ -4240,6 +4980,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
 
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
@@ -4257,10 +4998,12 @@ mod test {
             offset: request.offset,
 
             data: BytesMut::from(&data[..]),
-            encryption_contexts: vec![],
-            hashes: vec![
-                10000, // junk hash
-            ],
+            blocks: vec![ReadResponseBlockMetadata {
+                encryption_contexts: vec![],
+                hashes: vec![
+                    10000, // junk hash
+                ],
+            }],
         }]);
 
         let result =
@@ -4286,6 +5029,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
 
         let op = create_read_eob(next_id, vec![], 10, vec![request.clone()]);
@@ -4317,13 +5061,14 @@ mod test {
             offset: request.offset,
 
             data: BytesMut::from(&data[..]),
-            encryption_contexts: vec![crucible_protocol::EncryptionContext {
-                nonce,
-                tag,
+            blocks: vec![ReadResponseBlockMetadata {
+                encryption_contexts: vec![
+                    crucible_protocol::EncryptionContext { nonce, tag },
+                ],
+                hashes: vec![
+                    10000, // junk hash
+                ],
             }],
-            hashes: vec![
-                10000, // junk hash
-            ],
         }]);
 
         let result =
@@ -4859,6 +5604,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
 
         let next_id = {
@@ -5022,6 +5768,7 @@ mod test {
         let request = ReadRequest {
             eid: 0,
             offset: Block::new_512(7),
+            num_blocks: 1,
         };
 
         let next_id = {
@@ -3,7 +3,7 @@
 use super::*;
 use oximeter::types::ProducerRegistry;
 use std::ops::Range;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 use crucible_client_types::VolumeConstructionRequest;
 
@@ -19,6 +19,12 @@ pub struct Volume {
      */
     block_size: u64,
     count: AtomicU32,
+
+    /*
+     * How many blocks the last sub volume has grown by since it was
+     * added, as of the last time anyone asked for our size.
+     */
+    grown_blocks: AtomicU64,
 }
 
 pub struct SubVolume {
@@ -42,6 +48,7 @@ impl Volume {
             read_only_parent: None,
             block_size,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         }
     }
 
@@ -74,6 +81,7 @@ impl Volume {
             read_only_parent: None,
             block_size,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         })
     }
 
@@ -230,8 +238,16 @@ impl Volume {
     ) -> Vec<(Range<u64>, &SubVolume)> {
         let mut sv_vec = vec![];
 
-        for sub_volume in &self.sub_volumes {
-            let coverage = sub_volume.lba_range_coverage(start, length);
+        for (i, sub_volume) in self.sub_volumes.iter().enumerate() {
+            /*
+             * The last sub volume covers any blocks it has grown by.
+             */
+            let mut lba_range = sub_volume.lba_range.clone();
+            if i == self.sub_volumes.len() - 1 {
+                lba_range.end += self.grown_blocks.load(Ordering::SeqCst);
+            }
+
+            let coverage = range_coverage(&lba_range, start, length);
             if let Some(coverage) = coverage {
                 sv_vec.push((coverage, sub_volume));
             }
@@ -240,6 +256,30 @@ impl Volume {
         sv_vec
     }
 
+    /*
+     * Find out if the last sub volume has grown, and remember by how much.
+     * Only the last one can grow, as the others have a sub volume after
+     * them where their new blocks would be.
+     */
+    fn refresh_grown_blocks(&self) -> Result<u64, CrucibleError> {
+        let last = match self.sub_volumes.last() {
+            Some(last) => last,
+            None => return Ok(0),
+        };
+
+        let blocks = last.total_size()? / self.block_size;
+        let range_blocks = last.lba_range.end - last.lba_range.start;
+        let grown_blocks = blocks.saturating_sub(range_blocks);
+
+        /*
+         * A sub volume can't shrink back out from under the guest.
+         */
+        Ok(self
+            .grown_blocks
+            .fetch_max(grown_blocks, Ordering::SeqCst)
+            .max(grown_blocks))
+    }
+
     pub fn read_only_parent_for_lba_range(
         &self,
         start: u64,
@@ -350,16 +390,24 @@ impl Volume {
 
 impl BlockIO for Volume {
     fn activate(&self, gen: u64) -> Result<(), CrucibleError> {
-        for sub_volume in &self.sub_volumes {
+        for (i, sub_volume) in self.sub_volumes.iter().enumerate() {
             sub_volume.conditional_activate(gen)?;
 
             let sub_volume_computed_size = self.block_size
                 * (sub_volume.lba_range.end - sub_volume.lba_range.start);
-
-            if sub_volume.total_size()? != sub_volume_computed_size {
+            let sub_volume_size = sub_volume.total_size()?;
+
+            /*
+             * The last sub volume may have grown since it was added.
+             */
+            let is_last = i == self.sub_volumes.len() - 1;
+            if sub_volume_size != sub_volume_computed_size
+                && !(is_last && sub_volume_size > sub_volume_computed_size)
+            {
                 crucible_bail!(SubvolumeSizeMismatch);
             }
         }
+        self.refresh_grown_blocks()?;
 
         if let Some(ref read_only_parent) = &self.read_only_parent {
             read_only_parent.conditional_activate(gen)?;
@@ -396,6 +444,7 @@ impl BlockIO for Volume {
                     - sub_volume.lba_range.start)
                     as u64;
             }
+            total_blocks += self.refresh_grown_blocks()?;
 
             Ok(total_blocks * self.block_size)
         } else if let Some(ref read_only_parent) = &self.read_only_parent {
@@ -612,6 +661,176 @@ impl BlockIO for Volume {
         BlockReqWaiter::immediate()
     }
 
+    fn discard(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        if self.sub_volumes.is_empty() {
+            crucible_bail!(CannotReceiveBlocks, "No sub volumes!");
+        }
+        let cc = self.next_count();
+        cdt::volume__discard__start!(|| (cc, self.uuid));
+
+        let affected_sub_volumes =
+            self.sub_volumes_for_lba_range(offset.value, len.value);
+
+        // TODO parallel dispatch!
+        for (coverage, sub_volume) in affected_sub_volumes {
+            let mut discard_start = coverage.start;
+
+            // A block discarded from the sub volume is no longer owned, so
+            // reads would fall through to the read only parent's data. For
+            // the range the parent covers, write zeros instead so the
+            // block still reads back as zeros.
+            let parent_coverage = self.read_only_parent_for_lba_range(
+                coverage.start,
+                coverage.end - coverage.start,
+            );
+
+            if let Some(parent_coverage) = parent_coverage {
+                let sub_offset = Block::new(
+                    sub_volume.compute_sub_volume_lba(parent_coverage.start),
+                    offset.shift,
+                );
+                let sz = self.block_size as usize
+                    * (parent_coverage.end - parent_coverage.start) as usize;
+
+                let mut waiter =
+                    sub_volume.write(sub_offset, Bytes::from(vec![0; sz]))?;
+                waiter.block_wait()?;
+
+                discard_start = parent_coverage.end;
+            }
+
+            if discard_start < coverage.end {
+                let sub_offset = Block::new(
+                    sub_volume.compute_sub_volume_lba(discard_start),
+                    offset.shift,
+                );
+                let sub_len =
+                    Block::new(coverage.end - discard_start, offset.shift);
+
+                let mut waiter = sub_volume.discard(sub_offset, sub_len)?;
+                waiter.block_wait()?;
+            }
+        }
+
+        cdt::volume__discard__done!(|| (cc, self.uuid));
+        BlockReqWaiter::immediate()
+    }
+
+    fn write_zeroes(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        if self.sub_volumes.is_empty() {
+            crucible_bail!(CannotReceiveBlocks, "No sub volumes!");
+        }
+        let cc = self.next_count();
+        cdt::volume__writezeroes__start!(|| (cc, self.uuid));
+
+        let affected_sub_volumes =
+            self.sub_volumes_for_lba_range(offset.value, len.value);
+
+        // TODO parallel dispatch!
+        for (coverage, sub_volume) in affected_sub_volumes {
+            let mut zero_start = coverage.start;
+
+            // The downstairs zeros blocks by making them unwritten, which
+            // for the range the read only parent covers would let reads
+            // fall through to the parent's data. Write real zeros there,
+            // same as discard does.
+            let parent_coverage = self.read_only_parent_for_lba_range(
+                coverage.start,
+                coverage.end - coverage.start,
+            );
+
+            if let Some(parent_coverage) = parent_coverage {
+                let sub_offset = Block::new(
+                    sub_volume.compute_sub_volume_lba(parent_coverage.start),
+                    offset.shift,
+                );
+                let sz = self.block_size as usize
+                    * (parent_coverage.end - parent_coverage.start) as usize;
+
+                let mut waiter =
+                    sub_volume.write(sub_offset, Bytes::from(vec![0; sz]))?;
+                waiter.block_wait()?;
+
+                zero_start = parent_coverage.end;
+            }
+
+            if zero_start < coverage.end {
+                let sub_offset = Block::new(
+                    sub_volume.compute_sub_volume_lba(zero_start),
+                    offset.shift,
+                );
+                let sub_len =
+                    Block::new(coverage.end - zero_start, offset.shift);
+
+                let mut waiter =
+                    sub_volume.write_zeroes(sub_offset, sub_len)?;
+                waiter.block_wait()?;
+            }
+        }
+
+        cdt::volume__writezeroes__done!(|| (cc, self.uuid));
+        BlockReqWaiter::immediate()
+    }
+
+    fn compare_and_write(
+        &self,
+        offset: Block,
+        expected: Bytes,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        if self.sub_volumes.is_empty() {
+            crucible_bail!(CannotReceiveBlocks, "No sub volumes!");
+        }
+        let cc = self.next_count();
+        cdt::volume__compareandwrite__start!(|| (cc, self.uuid));
+
+        let len = data.len() as u64 / self.block_size;
+
+        // The compare happens where the blocks are stored, so it can only
+        // be atomic if they are all stored in the one sub volume.  Blocks
+        // still coming from the read only parent are not stored anywhere
+        // the compare can see.
+        if self
+            .read_only_parent_for_lba_range(offset.value, len)
+            .is_some()
+        {
+            crucible_bail!(
+                Unsupported,
+                "compare and write over a read only parent"
+            );
+        }
+
+        let mut affected_sub_volumes =
+            self.sub_volumes_for_lba_range(offset.value, len);
+        if affected_sub_volumes.len() != 1 {
+            crucible_bail!(
+                Unsupported,
+                "compare and write across {} sub volumes",
+                affected_sub_volumes.len()
+            );
+        }
+        let (coverage, sub_volume) = affected_sub_volumes.pop().unwrap();
+
+        let sub_offset = Block::new(
+            sub_volume.compute_sub_volume_lba(coverage.start),
+            offset.shift,
+        );
+        let mut waiter =
+            sub_volume.compare_and_write(sub_offset, expected, data)?;
+        waiter.block_wait()?;
+
+        cdt::volume__compareandwrite__done!(|| (cc, self.uuid));
+        BlockReqWaiter::immediate()
+    }
+
     fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         let mut wq_counts = WQCounts {
             up_count: 0,
@@ -652,8 +871,11 @@ impl SubVolume {
     //     = 1234 - 1024
     //     = 210
     //
+    // The last sub volume of a volume may have grown past the end of its
+    // range, so an address past the end is fine.
+    //
     pub fn compute_sub_volume_lba(&self, address: u64) -> u64 {
-        assert!(self.lba_range.contains(&address));
+        assert!(address >= self.lba_range.start);
         address - self.lba_range.start
     }
 
@@ -662,61 +884,71 @@ impl SubVolume {
         start: u64,
         length: u64,
     ) -> Option<Range<u64>> {
-        assert!(length >= 1);
+        range_coverage(&self.lba_range, start, length)
+    }
+}
 
-        let end = start + length - 1;
+// Work out what part of the blocks from start for length an LBA range
+// covers, if any.
+fn range_coverage(
+    lba_range: &Range<u64>,
+    start: u64,
+    length: u64,
+) -> Option<Range<u64>> {
+    assert!(length >= 1);
 
-        // No coverage:
-        //
-        // lba_range:                  |-------------|
-        // argument range:  |-------|
-        // argument range:                                  |--------|
-        //
+    let end = start + length - 1;
 
-        if end < self.lba_range.start {
-            return None;
-        }
+    // No coverage:
+    //
+    // lba_range:                  |-------------|
+    // argument range:  |-------|
+    // argument range:                                  |--------|
+    //
 
-        if start >= self.lba_range.end {
-            return None;
-        }
+    if end < lba_range.start {
+        return None;
+    }
 
-        // Total coverage:
-        //
-        // lba_range:                  |-------------|
-        // argument range:              |-------|
-        // argument range:                 |--------|
+    if start >= lba_range.end {
+        return None;
+    }
 
-        if self.lba_range.contains(&start) && self.lba_range.contains(&end) {
-            return Some(start..(start + length));
-        }
+    // Total coverage:
+    //
+    // lba_range:                  |-------------|
+    // argument range:              |-------|
+    // argument range:                 |--------|
 
-        // Partial coverage:
+    if lba_range.contains(&start) && lba_range.contains(&end) {
+        return Some(start..(start + length));
+    }
 
-        if self.lba_range.contains(&start) {
-            assert!(!self.lba_range.contains(&end));
+    // Partial coverage:
 
-            // lba_range:                  |-------------|
-            // argument range:                         |--------|
-            // coverage:                               ^^^
+    if lba_range.contains(&start) {
+        assert!(!lba_range.contains(&end));
 
-            Some(start..self.lba_range.end)
-        } else if self.lba_range.contains(&end) {
-            assert!(!self.lba_range.contains(&start));
+        // lba_range:                  |-------------|
+        // argument range:                         |--------|
+        // coverage:                               ^^^
 
-            // lba_range:                  |-------------|
-            // argument range:          |-------|
-            // coverage:                   ^^^^^^
-            Some(self.lba_range.start..(end + 1))
-        } else if start < self.lba_range.start && end > self.lba_range.end {
-            // lba_range:                  |-------------|
-            // argument range:          |--------------------|
-            // coverage:                   ^^^^^^^^^^^^^^^
-            Some(self.lba_range.clone())
-        } else {
-            println!("{:?} {} {}", self.lba_range, start, length);
-            panic!("should never get here!");
-        }
+        Some(start..lba_range.end)
+    } else if lba_range.contains(&end) {
+        assert!(!lba_range.contains(&start));
+
+        // lba_range:                  |-------------|
+        // argument range:          |-------|
+        // coverage:                   ^^^^^^
+        Some(lba_range.start..(end + 1))
+    } else if start < lba_range.start && end > lba_range.end {
+        // lba_range:                  |-------------|
+        // argument range:          |--------------------|
+        // coverage:                   ^^^^^^^^^^^^^^^
+        Some(lba_range.clone())
+    } else {
+        println!("{:?} {} {}", lba_range, start, length);
+        panic!("should never get here!");
     }
 }
 
@@ -772,6 +1004,31 @@ impl BlockIO for SubVolume {
         self.block_io.flush(snapshot_details)
     }
 
+    fn discard(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        self.block_io.discard(offset, len)
+    }
+
+    fn write_zeroes(
+        &self,
+        offset: Block,
+        len: Block,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        self.block_io.write_zeroes(offset, len)
+    }
+
+    fn compare_and_write(
+        &self,
+        offset: Block,
+        expected: Bytes,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        self.block_io.compare_and_write(offset, expected, data)
+    }
+
     fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         self.block_io.show_work()
     }
@@ -944,6 +1201,7 @@ mod test {
             read_only_parent: None,
             block_size: 512,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         };
 
         assert_eq!(volume.total_size()?, 512 * 1024);
@@ -951,6 +1209,61 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_volume_last_sub_volume_grows() -> Result<()> {
+        let last =
+            Arc::new(InMemoryBlockIO::new(Uuid::new_v4(), 512, 512 * 512));
+        let volume = Volume {
+            uuid: Uuid::new_v4(),
+            sub_volumes: vec![
+                SubVolume {
+                    lba_range: Range { start: 0, end: 512 },
+                    block_io: Arc::new(InMemoryBlockIO::new(
+                        Uuid::new_v4(),
+                        512,
+                        512 * 512,
+                    )),
+                },
+                SubVolume {
+                    lba_range: Range {
+                        start: 512,
+                        end: 1024,
+                    },
+                    block_io: last.clone(),
+                },
+            ],
+            read_only_parent: None,
+            block_size: 512,
+            count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
+        };
+
+        volume.activate(0)?;
+        assert!(volume.sub_volumes_for_lba_range(1024, 1).is_empty());
+
+        // Grow the last sub volume by 256 blocks.  The volume only knows
+        // about it once someone has asked for its size.
+        last.extend(768 * 512);
+        assert_eq!(volume.total_size()?, 512 * 1280);
+
+        let affected = volume.sub_volumes_for_lba_range(1000, 100);
+        assert_eq!(affected.len(), 1);
+        assert_eq!(affected[0].0, 1000..1100);
+        assert_eq!(affected[0].1.compute_sub_volume_lba(1024), 512);
+
+        // The new blocks can be written and read back.
+        volume.write(Block::new(1200, 9), Bytes::from(vec![7; 512]))?;
+        let buffer = Buffer::new(512);
+        volume.read(Block::new(1200, 9), buffer.clone())?;
+        assert_eq!(*buffer.as_vec(), vec![7; 512]);
+
+        let buffer = Buffer::new(512);
+        last.read(Block::new(688, 9), buffer.clone())?;
+        assert_eq!(*buffer.as_vec(), vec![7; 512]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_affected_subvolumes() -> Result<()> {
         // volume:       |--------|--------|--------|
@@ -994,6 +1307,7 @@ mod test {
             read_only_parent: None,
             block_size: 512,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         };
 
         // volume:       |--------|--------|--------|
@@ -1061,6 +1375,7 @@ mod test {
             read_only_parent: None,
             block_size: 512,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         };
 
         assert!(volume.read_only_parent_for_lba_range(0, 512).is_none());
@@ -1092,6 +1407,7 @@ mod test {
             }),
             block_size: 512,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         };
 
         assert!(volume.read_only_parent_for_lba_range(0, 512).is_some());
@@ -1657,6 +1973,7 @@ mod test {
             }),
             block_size: BLOCK_SIZE,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         };
 
         volume.activate(0)?;
@@ -1695,6 +2012,7 @@ mod test {
             }),
             block_size: BLOCK_SIZE,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         };
 
         volume.activate(0).unwrap();
@@ -1726,6 +2044,7 @@ mod test {
             }),
             block_size: BLOCK_SIZE,
             count: AtomicU32::new(0),
+            grown_blocks: AtomicU64::new(0),
         };
 
         volume.activate(0).unwrap();
@@ -2185,4 +2504,196 @@ mod test {
         )
         .await
     }
+
+    #[tokio::test]
+    async fn test_discard_with_read_only_parent() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        // this layout has two volumes that the parent lba range overlaps:
+        //
+        // volumes: 0 0 0
+        //                1 1 1 1 1
+        //  parent: P P P P P
+        let parent = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 5,
+        ));
+        parent
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![0x55; BLOCK_SIZE * 5]),
+            )?
+            .block_wait()?;
+
+        let subvolume_1 = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 3,
+        ));
+        let subvolume_2 = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 5,
+        ));
+
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        volume.add_subvolume(subvolume_1.clone())?;
+        volume.add_subvolume(subvolume_2.clone())?;
+        volume.add_read_only_parent(parent)?;
+
+        volume
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![0xFF; BLOCK_SIZE * 8]),
+            )?
+            .block_wait()?;
+
+        // Discard everything but the first and last block
+        volume
+            .discard(
+                Block::new(1, BLOCK_SIZE.trailing_zeros()),
+                Block::new(6, BLOCK_SIZE.trailing_zeros()),
+            )?
+            .block_wait()?;
+
+        // Discarded blocks must read as zeros, not as the parent's data
+        let buffer = Buffer::new(BLOCK_SIZE * 8);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())?
+            .block_wait()?;
+
+        let mut expected = vec![0xFF; BLOCK_SIZE];
+        expected.extend(vec![0x00; BLOCK_SIZE * 6]);
+        expected.extend(vec![0xFF; BLOCK_SIZE]);
+        assert_eq!(expected, *buffer.as_vec());
+
+        // Blocks under the parent were zeroed, the rest were discarded
+        let buffer = Buffer::new(BLOCK_SIZE * 5);
+        subvolume_2
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())?
+            .block_wait()?;
+
+        let owned = buffer.owned_vec();
+        assert!(owned[..(BLOCK_SIZE * 2)].iter().all(|x| *x));
+        assert!(owned[(BLOCK_SIZE * 2)..(BLOCK_SIZE * 4)]
+            .iter()
+            .all(|x| !*x));
+        assert!(owned[(BLOCK_SIZE * 4)..].iter().all(|x| *x));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_zeroes_across_subvolumes() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        let subvolume_1 = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 3,
+        ));
+        let subvolume_2 = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 3,
+        ));
+
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        volume.add_subvolume(subvolume_1)?;
+        volume.add_subvolume(subvolume_2)?;
+
+        volume
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![0xFF; BLOCK_SIZE * 6]),
+            )?
+            .block_wait()?;
+
+        // Zero everything but the first and last block
+        volume
+            .write_zeroes(
+                Block::new(1, BLOCK_SIZE.trailing_zeros()),
+                Block::new(4, BLOCK_SIZE.trailing_zeros()),
+            )?
+            .block_wait()?;
+
+        let buffer = Buffer::new(BLOCK_SIZE * 6);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())?
+            .block_wait()?;
+
+        let mut expected = vec![0xFF; BLOCK_SIZE];
+        expected.extend(vec![0x00; BLOCK_SIZE * 4]);
+        expected.extend(vec![0xFF; BLOCK_SIZE]);
+        assert_eq!(expected, *buffer.as_vec());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_write() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        // volumes: 0 0 0
+        //                1 1 1
+        let subvolume_1 = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 3,
+        ));
+        let subvolume_2 = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 3,
+        ));
+
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        volume.add_subvolume(subvolume_1)?;
+        volume.add_subvolume(subvolume_2)?;
+
+        let zeros = Bytes::from(vec![0x00; BLOCK_SIZE * 2]);
+        let ones = Bytes::from(vec![0x11; BLOCK_SIZE * 2]);
+        let twos = Bytes::from(vec![0x22; BLOCK_SIZE * 2]);
+
+        volume
+            .compare_and_write(
+                Block::new(3, BLOCK_SIZE.trailing_zeros()),
+                zeros.clone(),
+                ones.clone(),
+            )?
+            .block_wait()?;
+
+        // The blocks no longer hold zeros, so nothing is written.
+        assert_eq!(
+            volume
+                .compare_and_write(
+                    Block::new(3, BLOCK_SIZE.trailing_zeros()),
+                    zeros.clone(),
+                    twos.clone(),
+                )
+                .err(),
+            Some(CrucibleError::CompareMismatch)
+        );
+
+        let buffer = Buffer::new(BLOCK_SIZE * 2);
+        volume
+            .read(Block::new(3, BLOCK_SIZE.trailing_zeros()), buffer.clone())?
+            .block_wait()?;
+        assert_eq!(vec![0x11; BLOCK_SIZE * 2], *buffer.as_vec());
+
+        // A compare across two sub volumes could not be atomic.
+        assert!(matches!(
+            volume
+                .compare_and_write(
+                    Block::new(2, BLOCK_SIZE.trailing_zeros()),
+                    zeros,
+                    twos,
+                )
+                .err(),
+            Some(CrucibleError::Unsupported(_))
+        ));
+
+        Ok(())
+    }
 }